@@ -0,0 +1,388 @@
+use std::time::Instant;
+
+use crate::loader::json::{self, Number, Value};
+use crate::math::vec::*;
+use crate::math::vec3::*;
+use crate::scene::{Camera, CameraKeyframe};
+use crate::{log_error, log_info};
+
+/// How the camera moves between keyframes
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Uniform Catmull-Rom spline through the keyframes, the end keyframes are repeated
+    CatmullRom,
+}
+
+/// State of the camera at `time` in seconds, angles are in degrees
+#[derive(Clone, Copy, Default)]
+pub struct Keyframe {
+    pub time: f32,
+    pub position: Vec3f,
+    pub pitch: f32,
+    pub yaw: f32,
+    /// Vertical field of view
+    pub fov: f32,
+}
+
+impl Keyframe {
+    /// Keyframe with the current state of `camera`
+    pub fn from_camera(time: f32, camera: &Camera) -> Self {
+        return Self {
+            time,
+            position: camera.position,
+            pitch: camera.pitch,
+            yaw: camera.yaw,
+            fov: camera.fov(),
+        };
+    }
+
+    /// Keyframe with an orientation given as a quaternion `[x, y, z, w]` that rotates a camera
+    /// looking down -z with +y up, like in glTF. The camera has no roll, so any roll in the
+    /// rotation is dropped.
+    pub fn from_quaternion(time: f32, position: Vec3f, rotation: [f32; 4], fov: f32) -> Self {
+        let [x, y, z, w] = rotation;
+        let forward = Vec3f::new(
+            -2.0 * (x * z + w * y),
+            -2.0 * (y * z - w * x),
+            -(1.0 - 2.0 * (x * x + y * y)),
+        )
+        .normalized();
+        return Self {
+            time,
+            position,
+            pitch: f32::to_degrees(f32::asin(forward.y().clamp(-1.0, 1.0))),
+            yaw: f32::to_degrees(f32::atan2(forward.z(), forward.x())),
+            fov,
+        };
+    }
+}
+
+/// Keyframed path the camera follows during an animation
+#[derive(Clone, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+    pub interpolation: Interpolation,
+}
+
+impl CameraPath {
+    /// Sorts the keyframes by time. Returns None if there are none or two share a time.
+    pub fn new(keyframes: Vec<Keyframe>, interpolation: Interpolation) -> Option<Self> {
+        if keyframes.is_empty() {
+            log_error!("A camera path needs at least one keyframe");
+            return None;
+        }
+        let mut keyframes = keyframes;
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        for i in 1..keyframes.len() {
+            if keyframes[i].time == keyframes[i - 1].time {
+                log_error!(
+                    "Camera keyframes can't share the time {}",
+                    keyframes[i].time
+                );
+                return None;
+            }
+            // Turn the short way around, so 350 to 10 degrees doesn't pass through 180
+            let previous_yaw = keyframes[i - 1].yaw;
+            let keyframe = &mut keyframes[i];
+            keyframe.yaw =
+                previous_yaw + (keyframe.yaw - previous_yaw + 180.0).rem_euclid(360.0) - 180.0;
+        }
+        return Some(Self {
+            keyframes,
+            interpolation,
+        });
+    }
+
+    pub fn start_time(&self) -> f32 {
+        return self.keyframes[0].time;
+    }
+
+    pub fn end_time(&self) -> f32 {
+        return self.keyframes[self.keyframes.len() - 1].time;
+    }
+
+    /// Interpolated keyframe at `time`, clamped to the ends of the path
+    pub fn at(&self, time: f32) -> Keyframe {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len() - 1;
+        if time <= self.start_time() {
+            return Keyframe {
+                time,
+                ..keyframes[0]
+            };
+        }
+        if time >= self.end_time() {
+            return Keyframe {
+                time,
+                ..keyframes[last]
+            };
+        }
+
+        let i = keyframes.partition_point(|keyframe| keyframe.time <= time) - 1;
+        let k_1 = &keyframes[i];
+        let k_2 = &keyframes[i + 1];
+        let t = (time - k_1.time) / (k_2.time - k_1.time);
+        match self.interpolation {
+            Interpolation::Linear => {
+                return Keyframe {
+                    time,
+                    position: k_1.position + (k_2.position - k_1.position) * t,
+                    pitch: k_1.pitch + (k_2.pitch - k_1.pitch) * t,
+                    yaw: k_1.yaw + (k_2.yaw - k_1.yaw) * t,
+                    fov: k_1.fov + (k_2.fov - k_1.fov) * t,
+                };
+            }
+            Interpolation::CatmullRom => {
+                let k_0 = &keyframes[i.saturating_sub(1)];
+                let k_3 = &keyframes[usize::min(i + 2, last)];
+                let spline = |p_0: f32, p_1: f32, p_2: f32, p_3: f32| -> f32 {
+                    return 0.5
+                        * (2.0 * p_1
+                            + (p_2 - p_0) * t
+                            + (2.0 * p_0 - 5.0 * p_1 + 4.0 * p_2 - p_3) * t * t
+                            + (3.0 * p_1 - p_0 - 3.0 * p_2 + p_3) * t * t * t);
+                };
+                let mut position = Vec3f::from(0.0);
+                for j in 0..3 {
+                    position.data[j] = spline(
+                        k_0.position.data[j],
+                        k_1.position.data[j],
+                        k_2.position.data[j],
+                        k_3.position.data[j],
+                    );
+                }
+                return Keyframe {
+                    time,
+                    position,
+                    pitch: spline(k_0.pitch, k_1.pitch, k_2.pitch, k_3.pitch),
+                    yaw: spline(k_0.yaw, k_1.yaw, k_2.yaw, k_3.yaw),
+                    fov: spline(k_0.fov, k_1.fov, k_2.fov, k_3.fov),
+                };
+            }
+        }
+    }
+
+    /// Moves `camera` to where the path is at `time`. With the shutter open the camera moves on
+    /// to where the path is `frame_duration` seconds later, which blurs the motion.
+    pub fn apply(&self, camera: &mut Camera, time: f32, frame_duration: f32) {
+        let keyframe = self.at(time);
+        camera.position = keyframe.position;
+        camera.pitch = keyframe.pitch;
+        camera.yaw = keyframe.yaw;
+        camera.set_fov(keyframe.fov);
+        camera.end_keyframe = None;
+        if camera.shutter_close > camera.shutter_open {
+            let end = self.at(time + frame_duration);
+            camera.end_keyframe = Some(CameraKeyframe {
+                position: end.position,
+                pitch: end.pitch,
+                yaw: end.yaw,
+            });
+        }
+        camera.update_view();
+    }
+
+    /// Loads a camera path saved with `CameraPath::save`
+    pub fn load(path: &str) -> Option<Self> {
+        log_info!("Loading camera path from '{}'", path);
+        let Ok(buffer) = std::fs::read_to_string(path) else {
+            log_error!("Could not read camera path at path: '{}'", path);
+            return None;
+        };
+        let root = json::parse(&buffer)?;
+
+        let interpolation = match root.get("interpolation") {
+            Some(Value::String(name)) if name == "linear" => Interpolation::Linear,
+            Some(Value::String(name)) if name == "catmull_rom" => Interpolation::CatmullRom,
+            None => Interpolation::Linear,
+            _ => {
+                log_error!("Unknown camera interpolation in '{}'", path);
+                return None;
+            }
+        };
+
+        let Some(Value::Array(values)) = root.get("keyframes") else {
+            log_error!("Camera path '{}' has no keyframes array", path);
+            return None;
+        };
+        let mut keyframes = Vec::with_capacity(values.len());
+        for value in values {
+            let Some(keyframe) = keyframe_from_json(value) else {
+                log_error!("Invalid keyframe in camera path '{}'", path);
+                return None;
+            };
+            keyframes.push(keyframe);
+        }
+
+        return Self::new(keyframes, interpolation);
+    }
+
+    /// Writes the path as JSON to `path`, returns false if the file couldn't be written
+    pub fn save(&self, path: &str) -> bool {
+        let interpolation = match self.interpolation {
+            Interpolation::Linear => "linear",
+            Interpolation::CatmullRom => "catmull_rom",
+        };
+        let keyframes = self
+            .keyframes
+            .iter()
+            .map(|keyframe| {
+                return format!(
+                    "    {{\"time\": {}, \"position\": [{}, {}, {}], \"pitch\": {}, \"yaw\": {}, \"fov\": {}}}",
+                    keyframe.time,
+                    keyframe.position.x(),
+                    keyframe.position.y(),
+                    keyframe.position.z(),
+                    keyframe.pitch,
+                    keyframe.yaw,
+                    keyframe.fov
+                );
+            })
+            .collect::<Vec<String>>()
+            .join(",\n");
+        let contents = format!(
+            "{{\n  \"interpolation\": \"{}\",\n  \"keyframes\": [\n{}\n  ]\n}}\n",
+            interpolation, keyframes
+        );
+
+        if let Err(error) = std::fs::write(path, contents) {
+            log_error!(
+                "Could not write camera path to '{}' with error {:?}",
+                path,
+                error
+            );
+            return false;
+        }
+        log_info!(
+            "Wrote camera path with {} keyframes to '{}'",
+            self.keyframes.len(),
+            path
+        );
+        return true;
+    }
+}
+
+fn keyframe_from_json(value: &Value) -> Option<Keyframe> {
+    let Value::Object(object) = value else {
+        return None;
+    };
+    let Some(Value::Array(position)) = object.get("position") else {
+        return None;
+    };
+    if position.len() != 3 {
+        return None;
+    }
+    let field = |name: &str| -> Option<f32> { return json_f32(object.get(name)?) };
+    return Some(Keyframe {
+        time: field("time")?,
+        position: Vec3f::new(
+            json_f32(&position[0])?,
+            json_f32(&position[1])?,
+            json_f32(&position[2])?,
+        ),
+        pitch: field("pitch")?,
+        yaw: field("yaw")?,
+        fov: field("fov")?,
+    });
+}
+
+fn json_f32(value: &Value) -> Option<f32> {
+    match value {
+        Value::Number(Number::Integer(number)) => return Some(*number as f32),
+        Value::Number(Number::Float(number)) => return Some(*number as f32),
+        _ => return None,
+    }
+}
+
+/// Builds a camera path from the camera while flying around in the realtime window
+pub struct CameraRecorder {
+    pub keyframes: Vec<Keyframe>,
+    pub sample_interval: f32,
+    /// Seconds recorded before the current sampling run
+    recorded_time: f32,
+    /// When sampling was last started, None while paused so the pause isn't part of the path
+    resume_time: Option<Instant>,
+    last_sample_time: f32,
+}
+
+impl CameraRecorder {
+    pub fn new(sample_interval: f32) -> Self {
+        return Self {
+            keyframes: Vec::new(),
+            sample_interval,
+            recorded_time: 0.0,
+            resume_time: None,
+            last_sample_time: 0.0,
+        };
+    }
+
+    /// Whether a keyframe is taken every `sample_interval` seconds
+    pub fn is_sampling(&self) -> bool {
+        return self.resume_time.is_some();
+    }
+
+    /// Starts or pauses sampling, the clock of the path only runs while sampling
+    pub fn set_sampling(&mut self, is_sampling: bool) {
+        if is_sampling == self.is_sampling() {
+            return;
+        }
+        if is_sampling {
+            self.resume_time = Some(Instant::now());
+        } else {
+            self.recorded_time = self.time();
+            self.resume_time = None;
+        }
+    }
+
+    fn time(&self) -> f32 {
+        match self.resume_time {
+            Some(resume_time) => return self.recorded_time + resume_time.elapsed().as_secs_f32(),
+            None => return self.recorded_time,
+        }
+    }
+
+    /// Adds a keyframe with the current state of `camera`. Keyframes added while paused are
+    /// spaced `sample_interval` apart since the clock isn't running.
+    pub fn add_keyframe(&mut self, camera: &Camera) {
+        if !self.is_sampling() && !self.keyframes.is_empty() {
+            self.recorded_time += self.sample_interval;
+        }
+        let time = self.time();
+        if let Some(last) = self.keyframes.last()
+            && last.time == time
+        {
+            return;
+        }
+        self.keyframes.push(Keyframe::from_camera(time, camera));
+        self.last_sample_time = time;
+    }
+
+    /// Adds a keyframe if sampling and `sample_interval` has passed since the last one
+    pub fn update(&mut self, camera: &Camera) {
+        if !self.is_sampling() {
+            return;
+        }
+        let time = self.time();
+        if self.keyframes.is_empty() || time - self.last_sample_time >= self.sample_interval {
+            self.add_keyframe(camera);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+        self.recorded_time = 0.0;
+        self.resume_time = None;
+        self.last_sample_time = 0.0;
+    }
+
+    /// Path through the recorded keyframes, None if nothing has been recorded
+    pub fn path(&self) -> Option<CameraPath> {
+        if self.keyframes.is_empty() {
+            return None;
+        }
+        return CameraPath::new(self.keyframes.clone(), Interpolation::CatmullRom);
+    }
+}
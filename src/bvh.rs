@@ -1,7 +1,7 @@
 use crate::{
     log_info,
     math::vec3::*,
-    scene::{Scene, Triangle},
+    scene::{Motion, Scene, Triangle},
 };
 
 #[derive(Clone, Default)]
@@ -18,7 +18,7 @@ impl BVH {
         let mut bvh = Self::default();
         let mut root = Node::default();
         for tri in &scene.tris {
-            root.grow_by_tri(tri);
+            root.grow_by_tri(tri, &scene.motions);
         }
         root.num_tris = scene.tris.len() as u32;
         bvh.nodes.push(root);
@@ -69,7 +69,7 @@ impl BVH {
             let mut centroid_bounds_max = f32::MIN;
             for i in 0..node.num_tris {
                 let tri = scene.tris[(node.first_tri_or_child + i) as usize];
-                let tri_bounds_mid = tri.bounds_mid();
+                let tri_bounds_mid = tri.bounds_mid(&scene.motions);
                 centroid_bounds_min =
                     f32::min(centroid_bounds_min, tri_bounds_mid.data[split_axis]);
                 centroid_bounds_max =
@@ -99,7 +99,8 @@ impl BVH {
         let mut i: u32 = node.first_tri_or_child;
         let mut j: u32 = i + node.num_tris - 1;
         while i <= j {
-            if scene.tris[i as usize].bounds_mid().data[best_split_axis] < best_split_pos {
+            let tri = &scene.tris[i as usize];
+            if tri.bounds_mid(&scene.motions).data[best_split_axis] < best_split_pos {
                 i += 1;
             } else {
                 scene.tris.swap(i as usize, j as usize);
@@ -122,10 +123,16 @@ impl BVH {
         node.num_tris = 0;
 
         for i in 0..a.num_tris {
-            a.grow_by_tri(&scene.tris[(a.first_tri_or_child + i) as usize]);
+            a.grow_by_tri(
+                &scene.tris[(a.first_tri_or_child + i) as usize],
+                &scene.motions,
+            );
         }
         for i in 0..b.num_tris {
-            b.grow_by_tri(&scene.tris[(b.first_tri_or_child + i) as usize]);
+            b.grow_by_tri(
+                &scene.tris[(b.first_tri_or_child + i) as usize],
+                &scene.motions,
+            );
         }
 
         bvh.nodes.push(a);
@@ -141,11 +148,11 @@ impl BVH {
 
         for i in 0..node.num_tris {
             let tri = &scene.tris[(node.first_tri_or_child + i) as usize];
-            if tri.bounds_mid().data[split_axis] < split_pos {
-                left.grow_by_tri(tri);
+            if tri.bounds_mid(&scene.motions).data[split_axis] < split_pos {
+                left.grow_by_tri(tri, &scene.motions);
                 left.num_tris += 1;
             } else {
-                right.grow_by_tri(tri);
+                right.grow_by_tri(tri, &scene.motions);
                 right.num_tris += 1;
             }
         }
@@ -182,14 +189,11 @@ impl Default for Node {
 }
 
 impl Node {
-    fn grow_by_tri(&mut self, tri: &Triangle) {
-        for vertex in tri.vertices {
-            for i in 0..3 {
-                self.bounds_min.data[i] =
-                    f32::min(self.bounds_min.data[i], vertex.position.data[i]);
-                self.bounds_max.data[i] =
-                    f32::max(self.bounds_max.data[i], vertex.position.data[i]);
-            }
+    fn grow_by_tri(&mut self, tri: &Triangle, motions: &[Motion]) {
+        let (bounds_min, bounds_max) = tri.bounds(motions);
+        for i in 0..3 {
+            self.bounds_min.data[i] = f32::min(self.bounds_min.data[i], bounds_min.data[i]);
+            self.bounds_max.data[i] = f32::max(self.bounds_max.data[i], bounds_max.data[i]);
         }
     }
 
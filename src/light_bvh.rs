@@ -0,0 +1,332 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use crate::{
+    log_info,
+    math::{ONE_MINUS_EPSILON, vec::*, vec3::*},
+    scene::{Material, NO_LIGHT, Scene, Triangle},
+};
+
+/// Hierarchy over the emissive triangles of a scene, used to pick lights proportionally to their
+/// estimated contribution to a shading point.
+///
+/// https://fpsunflower.github.io/ckulla/data/many-lights-hpg2018.pdf
+#[derive(Clone, Default)]
+pub struct LightBVH {
+    pub nodes: Vec<LightNode>,
+}
+
+impl LightBVH {
+    /// Builds the light BVH for `scene`. This must run after `BVH::build` since leaves store
+    /// indices into the (reordered) scene triangles.
+    pub fn build(scene: &mut Scene) {
+        log_info!("Building light BVH for scene");
+
+        let start_time = std::time::Instant::now();
+
+        let materials = scene.materials.values().collect::<Vec<&Material>>();
+        let mut emission_tex_averages: Vec<Option<Vec3f>> = vec![None; scene.textures.len()];
+
+        let mut lights: Vec<LightNode> = vec![];
+        for (i, tri) in scene.tris.iter_mut().enumerate() {
+            tri.light_id = NO_LIGHT;
+
+            let material = materials[tri.material_id as usize];
+            let emission: Vec3f;
+            if material.emission_tex_id != u32::MAX {
+                let tex_id = material.emission_tex_id as usize;
+                emission = *emission_tex_averages[tex_id]
+                    .get_or_insert_with(|| scene.textures[tex_id].average_color())
+                    * material.emission_scale;
+            } else {
+                emission = material.emission;
+            }
+
+            let luminance = 0.2126 * emission.x() + 0.7152 * emission.y() + 0.0722 * emission.z();
+            if luminance <= 0.0 {
+                continue;
+            }
+
+            // Degenerate triangles end up with zero power and are never sampled
+            let light = LightNode::from_tri(tri, i as u32, luminance);
+            if light.power > 0.0 {
+                lights.push(light);
+            }
+        }
+
+        let mut bvh = Self::default();
+        if lights.is_empty() {
+            log_info!("No emissive triangles found, skipping light BVH\n");
+            scene.light_bvh = bvh;
+            return;
+        }
+
+        let root = LightNode::from_lights(&lights);
+        bvh.nodes.push(root);
+        let light_count = lights.len();
+        Self::split_node(0, &mut bvh, lights.as_mut_slice());
+
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            if node.num_tris > 0 {
+                scene.tris[node.first_tri_or_child as usize].light_id = i as u32;
+            }
+        }
+
+        log_info!("Light BVH statistics");
+        log_info!("- Build time:  {} ms", start_time.elapsed().as_millis());
+        log_info!("- Total nodes: {}", bvh.nodes.len());
+        log_info!("- Lights:      {}", light_count);
+        log_info!("- Total power: {}\n", bvh.nodes[0].power);
+
+        scene.light_bvh = bvh;
+    }
+
+    fn split_node(index: usize, bvh: &mut Self, lights: &mut [LightNode]) {
+        if lights.len() == 1 {
+            let parent = bvh.nodes[index].parent;
+            bvh.nodes[index] = lights[0];
+            bvh.nodes[index].parent = parent;
+            return;
+        }
+
+        // Median split along the longest axis of the centroid bounds
+        let mut centroid_min = Vec3f::from(f32::MAX);
+        let mut centroid_max = Vec3f::from(-f32::MAX);
+        for light in lights.iter() {
+            centroid_min = Vec3f::min(centroid_min, light.center());
+            centroid_max = Vec3f::max(centroid_max, light.center());
+        }
+        let extent = centroid_max - centroid_min;
+        let mut split_axis: usize = 0;
+        if extent.y() > extent.data[split_axis] {
+            split_axis = 1;
+        }
+        if extent.z() > extent.data[split_axis] {
+            split_axis = 2;
+        }
+
+        lights.sort_by(|a, b| a.center().data[split_axis].total_cmp(&b.center().data[split_axis]));
+        let (a_lights, b_lights) = lights.split_at_mut(lights.len() / 2);
+
+        let first_child = bvh.nodes.len();
+        bvh.nodes[index].first_tri_or_child = first_child as u32;
+        bvh.nodes[index].num_tris = 0;
+        bvh.nodes.push(LightNode::from_lights(a_lights));
+        bvh.nodes.push(LightNode::from_lights(b_lights));
+        bvh.nodes[first_child].parent = index as u32;
+        bvh.nodes[first_child + 1].parent = index as u32;
+
+        Self::split_node(first_child, bvh, a_lights);
+        Self::split_node(first_child + 1, bvh, b_lights);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.nodes.is_empty();
+    }
+
+    /// Stochastically traverses the hierarchy and returns the index of the chosen emissive
+    /// triangle along with the probability of choosing it. The sample value `u` is rescaled at
+    /// every level so that a single value makes all the choices.
+    pub fn sample(&self, point: Vec3f, normal: Vec3f, u: f32) -> Option<(u32, f32)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut node = &self.nodes[0];
+        let mut pmf: f32 = 1.0;
+        let mut u = u;
+        while node.num_tris == 0 {
+            let child_1 = &self.nodes[node.first_tri_or_child as usize];
+            let child_2 = &self.nodes[(node.first_tri_or_child + 1) as usize];
+            let importance_1 = child_1.importance(point, normal);
+            let importance_2 = child_2.importance(point, normal);
+            if importance_1 + importance_2 <= 0.0 {
+                return None;
+            }
+
+            let probability_1 = importance_1 / (importance_1 + importance_2);
+            if u < probability_1 {
+                node = child_1;
+                pmf *= probability_1;
+                u /= probability_1;
+            } else {
+                node = child_2;
+                pmf *= 1.0 - probability_1;
+                u = (u - probability_1) / (1.0 - probability_1);
+            }
+            u = f32::min(u, ONE_MINUS_EPSILON);
+        }
+
+        return Some((node.first_tri_or_child, pmf));
+    }
+
+    /// Probability of `sample` choosing the leaf `light_id` for the same point and normal,
+    /// found by walking up from the leaf
+    pub fn pmf(&self, point: Vec3f, normal: Vec3f, light_id: u32) -> f32 {
+        let mut index = light_id as usize;
+        let mut pmf: f32 = 1.0;
+        while index != 0 {
+            let parent = &self.nodes[self.nodes[index].parent as usize];
+            let child_1 = parent.first_tri_or_child as usize;
+            let importance_1 = self.nodes[child_1].importance(point, normal);
+            let importance_2 = self.nodes[child_1 + 1].importance(point, normal);
+            if importance_1 + importance_2 <= 0.0 {
+                return 0.0;
+            }
+
+            let probability_1 = importance_1 / (importance_1 + importance_2);
+            if index == child_1 {
+                pmf *= probability_1;
+            } else {
+                pmf *= 1.0 - probability_1;
+            }
+            index = self.nodes[index].parent as usize;
+        }
+        return pmf;
+    }
+}
+
+/// A node of the light BVH, leaves hold exactly one emissive triangle.
+///
+/// Emitters are one-sided, they emit towards the side the geometric normal
+/// (`cross(v_1 - v_0, v_2 - v_0)`) points to.
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct LightNode {
+    pub bounds_min: Vec3f,
+    pub power: f32,
+    pub bounds_max: Vec3f,
+    /// Angle bounding the emitter normals around `axis`
+    pub theta_o: f32,
+    pub axis: Vec3f,
+    /// Angle bounding the emission around each normal
+    pub theta_e: f32,
+    pub first_tri_or_child: u32,
+    pub num_tris: u32,
+    /// Index of the parent node, unused for the root
+    pub parent: u32,
+    _pad: u32,
+}
+
+impl Default for LightNode {
+    fn default() -> Self {
+        return Self {
+            bounds_min: Vec3f::from(f32::MAX),
+            power: 0.0,
+            bounds_max: Vec3f::from(-f32::MAX),
+            theta_o: 0.0,
+            axis: Vec3f::new(0.0, 1.0, 0.0),
+            theta_e: FRAC_PI_2,
+            first_tri_or_child: 0,
+            num_tris: 0,
+            parent: 0,
+            _pad: 0,
+        };
+    }
+}
+
+impl LightNode {
+    fn from_tri(tri: &Triangle, tri_index: u32, luminance: f32) -> Self {
+        let mut node = Self::default();
+        for vertex in tri.vertices {
+            node.bounds_min = Vec3f::min(node.bounds_min, vertex.position);
+            node.bounds_max = Vec3f::max(node.bounds_max, vertex.position);
+        }
+
+        let edge_1 = tri.vertices[1].position - tri.vertices[0].position;
+        let edge_2 = tri.vertices[2].position - tri.vertices[0].position;
+        let cross = Vec3f::cross(edge_1, edge_2);
+        let area = cross.length() * 0.5;
+
+        node.axis = cross.normalized();
+        node.power = luminance * area * PI;
+        node.first_tri_or_child = tri_index;
+        node.num_tris = 1;
+        return node;
+    }
+
+    fn from_lights(lights: &[Self]) -> Self {
+        let mut node = lights[0];
+        for light in &lights[1..] {
+            node.bounds_min = Vec3f::min(node.bounds_min, light.bounds_min);
+            node.bounds_max = Vec3f::max(node.bounds_max, light.bounds_max);
+            node.power += light.power;
+            (node.axis, node.theta_o, node.theta_e) = Self::merge_cones(
+                (node.axis, node.theta_o, node.theta_e),
+                (light.axis, light.theta_o, light.theta_e),
+            );
+        }
+        node.first_tri_or_child = 0;
+        node.num_tris = 0;
+        return node;
+    }
+
+    fn merge_cones(a: (Vec3f, f32, f32), b: (Vec3f, f32, f32)) -> (Vec3f, f32, f32) {
+        let (a, b) = if b.1 > a.1 { (b, a) } else { (a, b) };
+        let (a_axis, a_theta_o, a_theta_e) = a;
+        let (b_axis, b_theta_o, b_theta_e) = b;
+
+        let theta_e = f32::max(a_theta_e, b_theta_e);
+        let theta_d = f32::acos(Vec3f::dot(a_axis, b_axis).clamp(-1.0, 1.0));
+        if f32::min(theta_d + b_theta_o, PI) <= a_theta_o {
+            return (a_axis, a_theta_o, theta_e);
+        }
+
+        let theta_o = (a_theta_o + theta_d + b_theta_o) * 0.5;
+        if theta_o >= PI {
+            return (a_axis, PI, theta_e);
+        }
+
+        // Rotate the axis of a towards b so the new cone covers both
+        let theta_r = theta_o - a_theta_o;
+        let mut ortho = b_axis - a_axis * Vec3f::dot(a_axis, b_axis);
+        if ortho.length() < 1e-6 {
+            ortho = Vec3f::cross(
+                a_axis,
+                if f32::abs(a_axis.x()) < 0.9 {
+                    Vec3f::new(1.0, 0.0, 0.0)
+                } else {
+                    Vec3f::new(0.0, 1.0, 0.0)
+                },
+            );
+        }
+        let axis =
+            (a_axis * f32::cos(theta_r) + ortho.normalized() * f32::sin(theta_r)).normalized();
+        return (axis, theta_o, theta_e);
+    }
+
+    fn center(&self) -> Vec3f {
+        return (self.bounds_min + self.bounds_max) * 0.5;
+    }
+
+    /// Conservative estimate of the contribution of this node to a point with the given normal
+    pub fn importance(&self, point: Vec3f, normal: Vec3f) -> f32 {
+        let center = self.center();
+        let radius = (self.bounds_max - self.bounds_min).length() * 0.5;
+        let to_node = center - point;
+        let distance_squared = Vec3f::dot(to_node, to_node);
+        let distance = f32::sqrt(distance_squared);
+
+        let theta_u: f32;
+        if distance <= radius {
+            theta_u = PI;
+        } else {
+            theta_u = f32::asin(radius / distance);
+        }
+
+        let w = to_node / f32::max(distance, 1e-6);
+        let theta = f32::acos(Vec3f::dot(self.axis, w.reversed()).clamp(-1.0, 1.0));
+        let theta_prime = f32::max(0.0, theta - self.theta_o - theta_u);
+        if theta_prime >= self.theta_e {
+            return 0.0;
+        }
+
+        let theta_i = f32::acos(Vec3f::dot(normal, w).clamp(-1.0, 1.0));
+        let theta_i_prime = f32::max(0.0, theta_i - theta_u);
+        if theta_i_prime >= FRAC_PI_2 {
+            return 0.0;
+        }
+
+        return self.power * f32::cos(theta_prime) * f32::cos(theta_i_prime)
+            / f32::max(distance_squared, radius * radius);
+    }
+}
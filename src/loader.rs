@@ -1,3 +1,5 @@
 pub mod gltf;
 pub mod json;
+pub mod mikktspace;
 pub mod obj;
+pub mod voxel;
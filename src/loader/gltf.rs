@@ -1 +1,915 @@
+use std::{collections::HashMap, path::PathBuf};
 
+use crate::{
+    loader::json::{self, Number, Value},
+    log_error, log_info, log_warning,
+    math::{mat4::Mat4f, vec::*, vec3::*},
+    medium::Medium,
+    scene::{Material, Vertex},
+    texture::{Texture, TextureType},
+};
+
+/// Meshes and materials of a glTF 2.0 scene, loaded from a .gltf file with external or base64
+/// embedded buffers, or from a binary .glb file.
+///
+/// Only the triangle meshes of the default scene are loaded, cameras, lights, skins and
+/// animations are ignored. Textures replace their factors instead of being multiplied with
+/// them, the same as the textures of MTL files.
+///
+/// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+#[derive(Default)]
+pub struct GLTF {
+    pub tris: Vec<Triangle>,
+    pub materials: HashMap<String, Material>,
+    pub textures: Vec<Texture>,
+    pub media: Vec<Medium>,
+}
+
+/// Triangle with its vertices already transformed to world space
+pub struct Triangle {
+    pub vertices: [Vertex; 3],
+    pub material_id: u32,
+    /// Every node with a mesh is its own object
+    pub object_id: u32,
+    /// Whether the tangents were read from the file, otherwise they are generated
+    pub has_tangents: bool,
+}
+
+/// Everything accessors, materials and textures refer to while loading
+struct Document<'a> {
+    path: &'a str,
+    root: Value,
+    buffers: Vec<Vec<u8>>,
+}
+
+/// Elements of an accessor inside its buffer
+struct Accessor<'a> {
+    data: &'a [u8],
+    stride: usize,
+    component_type: usize,
+    components: usize,
+    count: usize,
+    normalized: bool,
+}
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const GLB_JSON_CHUNK: u32 = 0x4E4F534A;
+const GLB_BIN_CHUNK: u32 = 0x004E4942;
+const MODE_TRIANGLES: usize = 4;
+const MAX_NODE_DEPTH: usize = 64;
+
+impl GLTF {
+    pub fn load(path: &str) -> Option<Self> {
+        log_info!("Loading scene from '{}'", path);
+
+        let start_time = std::time::Instant::now();
+
+        let Ok(bytes) = std::fs::read(path) else {
+            log_error!("Could not read glTF file at path: '{}'", path);
+            return None;
+        };
+
+        let (text, binary_chunk) = if bytes.starts_with(GLB_MAGIC) {
+            Self::split_glb(&bytes, path)?
+        } else {
+            let Ok(text) = std::str::from_utf8(&bytes) else {
+                log_error!("glTF file '{}' isn't valid UTF-8", path);
+                return None;
+            };
+            (text, None)
+        };
+        let root = Value::Object(json::parse(text)?);
+
+        let mut buffers: Vec<Vec<u8>> = vec![];
+        for (i, buffer) in array(get(&root, "buffers")).iter().enumerate() {
+            let data = match (get(buffer, "uri"), binary_chunk) {
+                (Some(Value::String(uri)), _) => Self::load_uri(path, uri)?,
+                (None, Some(binary_chunk)) if i == 0 => binary_chunk.to_vec(),
+                _ => {
+                    log_error!("Buffer {} of '{}' has no data", i, path);
+                    return None;
+                }
+            };
+            buffers.push(data);
+        }
+        let document = Document {
+            path,
+            root,
+            buffers,
+        };
+
+        let mut gltf = GLTF::default();
+        let material_ids = gltf.load_materials(&document);
+
+        let scene_index = index(get(&document.root, "scene")).unwrap_or(0);
+        let Some(scene) = array(get(&document.root, "scenes")).get(scene_index) else {
+            log_warning!("'{}' has no scene, nothing to load", path);
+            return Some(gltf);
+        };
+        let mut object_id: u32 = 0;
+        for node in array(get(scene, "nodes")) {
+            let Some(node) = index(Some(node)) else {
+                continue;
+            };
+            gltf.load_node(
+                &document,
+                node,
+                Mat4f::new(),
+                &material_ids,
+                &mut object_id,
+                0,
+            )?;
+        }
+
+        log_info!(
+            "'{}' took {} ms to load\n",
+            path,
+            start_time.elapsed().as_millis()
+        );
+
+        return Some(gltf);
+    }
+
+    /// Returns the JSON chunk and the binary chunk of a .glb file
+    fn split_glb<'a>(bytes: &'a [u8], path: &str) -> Option<(&'a str, Option<&'a [u8]>)> {
+        let read_u32 = |offset: usize| -> Option<u32> {
+            let word = bytes.get(offset..offset + 4)?;
+            return Some(u32::from_le_bytes(word.try_into().ok()?));
+        };
+        if read_u32(4) != Some(2) {
+            log_error!("Unsupported GLB version in '{}'", path);
+            return None;
+        }
+
+        let mut text: Option<&str> = None;
+        let mut binary_chunk: Option<&[u8]> = None;
+        let mut offset: usize = 12;
+        while offset + 8 <= bytes.len() {
+            let length = read_u32(offset)? as usize;
+            let chunk_type = read_u32(offset + 4)?;
+            let Some(data) = bytes.get(offset + 8..offset + 8 + length) else {
+                log_error!("GLB file '{}' is truncated", path);
+                return None;
+            };
+            match chunk_type {
+                GLB_JSON_CHUNK => text = std::str::from_utf8(data).ok(),
+                GLB_BIN_CHUNK => binary_chunk = Some(data),
+                _ => (),
+            }
+            offset += 8 + length;
+        }
+
+        let Some(text) = text else {
+            log_error!("GLB file '{}' has no JSON chunk", path);
+            return None;
+        };
+        return Some((text, binary_chunk));
+    }
+
+    /// Reads a base64 data URI or a file relative to the glTF file
+    fn load_uri(path: &str, uri: &str) -> Option<Vec<u8>> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            let Some((_, encoded)) = data.split_once(";base64,") else {
+                log_error!("Only base64 data URIs are supported in '{}'", path);
+                return None;
+            };
+            let Some(decoded) = decode_base64(encoded) else {
+                log_error!("Invalid base64 data URI in '{}'", path);
+                return None;
+            };
+            return Some(decoded);
+        }
+
+        let resource_path = resource_path(path, uri);
+        let Ok(data) = std::fs::read(&resource_path) else {
+            log_error!(
+                "Could not read '{}' referenced by '{}'",
+                resource_path,
+                path
+            );
+            return None;
+        };
+        return Some(data);
+    }
+
+    /// Loads all materials and returns the material id of every glTF material, the last id is
+    /// the default material for primitives without one
+    fn load_materials(&mut self, document: &Document) -> Vec<u32> {
+        let mut names: Vec<String> = vec![];
+        let mut image_textures: HashMap<usize, u32> = HashMap::new();
+        for (i, gltf_material) in array(get(&document.root, "materials")).iter().enumerate() {
+            let material = self.load_material(document, gltf_material, &mut image_textures);
+            let mut name = match get(gltf_material, "name") {
+                Some(Value::String(name)) => name.clone(),
+                _ => format!("material_{}", i),
+            };
+            if self.materials.contains_key(&name) {
+                name = format!("{}_{}", name, i);
+            }
+            self.materials.insert(name.clone(), material);
+            names.push(name);
+        }
+        self.materials
+            .insert("default_material".into(), Material::default());
+        names.push("default_material".into());
+
+        // Material ids follow the iteration order of the map, which is fixed from now on
+        return names
+            .iter()
+            .map(|name| self.materials.keys().position(|key| key == name).unwrap() as u32)
+            .collect();
+    }
+
+    fn load_material(
+        &mut self,
+        document: &Document,
+        gltf_material: &Value,
+        image_textures: &mut HashMap<usize, u32>,
+    ) -> Material {
+        let mut material = Material::default();
+
+        let pbr = get(gltf_material, "pbrMetallicRoughness");
+        let pbr_value = |name: &str| pbr.and_then(|pbr| get(pbr, name));
+        let base_color = floats::<4>(pbr_value("baseColorFactor")).unwrap_or([1.0; 4]);
+        material.base_color = Vec3f::new(base_color[0], base_color[1], base_color[2]);
+        material.metallic = float(pbr_value("metallicFactor")).unwrap_or(1.0);
+        material.roughness = float(pbr_value("roughnessFactor")).unwrap_or(1.0);
+        material.base_color_tex_id = self.load_texture(
+            document,
+            pbr_value("baseColorTexture"),
+            image_textures,
+            TextureType::BaseColor,
+        );
+        // Roughness is read from the green channel and metalness from the blue channel, which
+        // is how glTF packs them
+        let metallic_roughness_tex_id = self.load_texture(
+            document,
+            pbr_value("metallicRoughnessTexture"),
+            image_textures,
+            TextureType::Roughness,
+        );
+        material.roughness_tex_id = metallic_roughness_tex_id;
+        material.metallic_tex_id = metallic_roughness_tex_id;
+
+        match get(gltf_material, "alphaMode") {
+            Some(Value::String(mode)) if mode == "BLEND" => {
+                material.transparency = base_color[3];
+                material.transparency_tex_id = material.base_color_tex_id;
+            }
+            Some(Value::String(mode)) if mode == "MASK" => {
+                // A cutoff of zero or less keeps every texel, so the material stays opaque
+                let alpha_cutoff = float(get(gltf_material, "alphaCutoff")).unwrap_or(0.5);
+                if alpha_cutoff > 0.0 {
+                    material.transparency = base_color[3];
+                    material.transparency_tex_id = material.base_color_tex_id;
+                    material.alpha_cutoff = alpha_cutoff;
+                }
+            }
+            _ => (),
+        }
+
+        material.normal_tex_id = self.load_texture(
+            document,
+            get(gltf_material, "normalTexture"),
+            image_textures,
+            TextureType::Normal,
+        );
+
+        let extension = |name: &str| get(gltf_material, "extensions").and_then(|e| get(e, name));
+
+        let emission = floats::<3>(get(gltf_material, "emissiveFactor")).unwrap_or([0.0; 3]);
+        let emission_strength = extension("KHR_materials_emissive_strength")
+            .and_then(|emissive_strength| float(get(emissive_strength, "emissiveStrength")))
+            .unwrap_or(1.0);
+        material.emission = Vec3f::from(emission) * emission_strength;
+        if material.emission.x() + material.emission.y() + material.emission.z() > 0.0 {
+            material.emission_tex_id = self.load_texture(
+                document,
+                get(gltf_material, "emissiveTexture"),
+                image_textures,
+                TextureType::Emission,
+            );
+            // The texture is multiplied by the factor and strength
+            material.emission_scale = material.emission;
+        }
+
+        // glTF dielectrics have an IOR of 1.5 unless the material says otherwise
+        material.ior = extension("KHR_materials_ior")
+            .and_then(|ior| float(get(ior, "ior")))
+            .unwrap_or(1.5);
+        if let Some(transmission) = extension("KHR_materials_transmission") {
+            material.transmission = float(get(transmission, "transmissionFactor")).unwrap_or(0.0);
+        }
+        // A specular of 0.5 gives the Fresnel reflectance of the IOR, which the specular factor
+        // scales
+        if let Some(specular) = extension("KHR_materials_specular") {
+            material.specular = 0.5 * float(get(specular, "specularFactor")).unwrap_or(1.0);
+            let tint = floats::<3>(get(specular, "specularColorFactor")).unwrap_or([1.0; 3]);
+            material.specular_tint = Vec3f::from(tint);
+        }
+        if let Some(clearcoat) = extension("KHR_materials_clearcoat") {
+            material.clearcoat = float(get(clearcoat, "clearcoatFactor")).unwrap_or(0.0);
+            let roughness = float(get(clearcoat, "clearcoatRoughnessFactor")).unwrap_or(0.0);
+            material.clearcoat_gloss = 1.0 - roughness.clamp(0.0, 1.0);
+        }
+        // The sheen can only be tinted towards the base color, so saturated sheen colors get
+        // more of the base color instead of their own hue
+        if let Some(sheen) = extension("KHR_materials_sheen") {
+            let [r, g, b] = floats::<3>(get(sheen, "sheenColorFactor")).unwrap_or([0.0; 3]);
+            let max = f32::max(f32::max(r, g), b);
+            material.sheen = max;
+            if max > 0.0 {
+                material.sheen_tint = 1.0 - f32::min(f32::min(r, g), b) / max;
+            }
+        }
+        // The rotation is in radians counterclockwise from the tangent
+        if let Some(anisotropy) = extension("KHR_materials_anisotropy") {
+            material.anisotropic = float(get(anisotropy, "anisotropyStrength")).unwrap_or(0.0);
+            material.anisotropic_rotation =
+                float(get(anisotropy, "anisotropyRotation")).unwrap_or(0.0) / std::f32::consts::TAU;
+        }
+
+        // The thickness texture blends from the minimum to the maximum thickness in its green
+        // channel, which is also how thin films read their texture
+        if let Some(iridescence) = extension("KHR_materials_iridescence")
+            && float(get(iridescence, "iridescenceFactor")).unwrap_or(0.0) > 0.0
+        {
+            material.thin_film_ior = float(get(iridescence, "iridescenceIor")).unwrap_or(1.3);
+            material.thin_film_thickness_min =
+                float(get(iridescence, "iridescenceThicknessMinimum")).unwrap_or(100.0);
+            material.thin_film_thickness =
+                float(get(iridescence, "iridescenceThicknessMaximum")).unwrap_or(400.0);
+            material.thin_film_thickness_tex_id = self.load_texture(
+                document,
+                get(iridescence, "iridescenceThicknessTexture"),
+                image_textures,
+                TextureType::ThinFilmThickness,
+            );
+        }
+
+        // Without a volume the material is an infinitely thin sheet
+        material.thin_walled = 1;
+        let mut attenuation: Option<(Vec3f, f32)> = None;
+        if let Some(volume) = extension("KHR_materials_volume") {
+            let thickness = float(get(volume, "thicknessFactor")).unwrap_or(0.0);
+            material.thin_walled = (thickness <= 0.0) as u32;
+            let color = floats::<3>(get(volume, "attenuationColor")).unwrap_or([1.0; 3]);
+            if let Some(distance) = float(get(volume, "attenuationDistance"))
+                && thickness > 0.0
+                && distance > 0.0
+            {
+                attenuation = Some((Vec3f::from(color), distance));
+            }
+        }
+
+        // Diffuse transmission through a volume scatters below the surface, the attenuation
+        // distance of the volume becomes the mean free path of the random walk
+        if let Some(diffuse_transmission) = extension("KHR_materials_diffuse_transmission") {
+            material.subsurface =
+                float(get(diffuse_transmission, "diffuseTransmissionFactor")).unwrap_or(0.0);
+            let albedo = floats::<3>(get(diffuse_transmission, "diffuseTransmissionColorFactor"))
+                .unwrap_or([1.0; 3]);
+            material.subsurface_albedo = Vec3f::from(albedo);
+            if let Some((_, distance)) = attenuation {
+                material.subsurface_mfp = Vec3f::from(distance);
+            }
+        }
+
+        // Otherwise the volume only absorbs, following Beer's law
+        if material.subsurface <= 0.0
+            && let Some((color, distance)) = attenuation
+        {
+            let sigma_a = Vec3f::new(
+                -f32::ln(f32::max(color.x(), 1e-4)),
+                -f32::ln(f32::max(color.y(), 1e-4)),
+                -f32::ln(f32::max(color.z(), 1e-4)),
+            ) / distance;
+            self.media
+                .push(Medium::homogeneous(sigma_a, Vec3f::from(0.0), 0.0));
+            material.medium_id = (self.media.len() - 1) as u32;
+        }
+
+        return material;
+    }
+
+    /// Returns the scene texture for a glTF texture info object, or `u32::MAX` if there is none
+    fn load_texture(
+        &mut self,
+        document: &Document,
+        texture_info: Option<&Value>,
+        image_textures: &mut HashMap<usize, u32>,
+        texture_type: TextureType,
+    ) -> u32 {
+        let Some(texture_info) = texture_info else {
+            return u32::MAX;
+        };
+        if index(get(texture_info, "texCoord")).unwrap_or(0) != 0 {
+            log_warning!(
+                "Only the first UV set is supported, textures using other sets in '{}' may look wrong",
+                document.path
+            );
+        }
+        let Some(image) = index(get(texture_info, "index"))
+            .and_then(|texture| array(get(&document.root, "textures")).get(texture))
+            .and_then(|texture| index(get(texture, "source")))
+        else {
+            log_error!("Invalid texture reference in '{}'", document.path);
+            return u32::MAX;
+        };
+        if let Some(tex_id) = image_textures.get(&image) {
+            return *tex_id;
+        }
+
+        let Some(gltf_image) = array(get(&document.root, "images")).get(image) else {
+            log_error!("Image {} doesn't exist in '{}'", image, document.path);
+            return u32::MAX;
+        };
+        let texture = match (get(gltf_image, "uri"), index(get(gltf_image, "bufferView"))) {
+            (Some(Value::String(uri)), _) if !uri.starts_with("data:") => {
+                Texture::load(resource_path(document.path, uri).as_str(), texture_type)
+            }
+            (Some(Value::String(uri)), _) => Self::load_uri(document.path, uri)
+                .and_then(|bytes| Texture::load_from_memory(&bytes, texture_type)),
+            (None, Some(buffer_view)) => Self::buffer_view(document, buffer_view)
+                .and_then(|(bytes, _)| Texture::load_from_memory(bytes, texture_type)),
+            _ => None,
+        };
+        let Some(texture) = texture else {
+            log_error!("Could not load image {} of '{}'", image, document.path);
+            return u32::MAX;
+        };
+
+        self.textures.push(texture);
+        let tex_id = (self.textures.len() - 1) as u32;
+        image_textures.insert(image, tex_id);
+        return tex_id;
+    }
+
+    fn load_node(
+        &mut self,
+        document: &Document,
+        node_index: usize,
+        parent_transform: Mat4f,
+        material_ids: &[u32],
+        object_id: &mut u32,
+        depth: usize,
+    ) -> Option<()> {
+        if depth > MAX_NODE_DEPTH {
+            log_error!("Node hierarchy of '{}' is too deep", document.path);
+            return None;
+        }
+        let Some(node) = array(get(&document.root, "nodes")).get(node_index) else {
+            log_error!("Node {} doesn't exist in '{}'", node_index, document.path);
+            return None;
+        };
+        let transform = parent_transform * node_transform(node);
+
+        if let Some(mesh) = index(get(node, "mesh")) {
+            let Some(mesh) = array(get(&document.root, "meshes")).get(mesh) else {
+                log_error!("Mesh {} doesn't exist in '{}'", mesh, document.path);
+                return None;
+            };
+            for primitive in array(get(mesh, "primitives")) {
+                self.load_primitive(document, primitive, transform, material_ids, *object_id)?;
+            }
+            *object_id += 1;
+        }
+
+        for child in array(get(node, "children")) {
+            let Some(child) = index(Some(child)) else {
+                continue;
+            };
+            self.load_node(
+                document,
+                child,
+                transform,
+                material_ids,
+                object_id,
+                depth + 1,
+            )?;
+        }
+        return Some(());
+    }
+
+    fn load_primitive(
+        &mut self,
+        document: &Document,
+        primitive: &Value,
+        transform: Mat4f,
+        material_ids: &[u32],
+        object_id: u32,
+    ) -> Option<()> {
+        let mode = index(get(primitive, "mode")).unwrap_or(MODE_TRIANGLES);
+        if mode != MODE_TRIANGLES {
+            log_warning!(
+                "Skipping primitive with mode {} in '{}', only triangle lists are supported",
+                mode,
+                document.path
+            );
+            return Some(());
+        }
+
+        let attributes = get(primitive, "attributes");
+        let attribute = |name: &str| -> Option<Vec<f32>> {
+            let accessor = index(attributes.and_then(|attributes| get(attributes, name)))?;
+            return Self::read_floats(document, accessor);
+        };
+        let Some(positions) = attribute("POSITION") else {
+            log_error!("Primitive without positions in '{}'", document.path);
+            return None;
+        };
+        let normals = attribute("NORMAL");
+        let tex_coords = attribute("TEXCOORD_0");
+        let vertex_count = positions.len() / 3;
+        // Tangents are only defined together with normals
+        let tangents = attribute("TANGENT")
+            .filter(|tangents| normals.is_some() && tangents.len() >= vertex_count * 4);
+
+        let indices = match index(get(primitive, "indices")) {
+            Some(accessor) => Self::read_indices(document, accessor)?,
+            None => (0..vertex_count as u32).collect(),
+        };
+        if indices.iter().any(|i| *i as usize >= vertex_count) {
+            log_error!("Primitive with out of range indices in '{}'", document.path);
+            return None;
+        }
+
+        let material_id = match index(get(primitive, "material")) {
+            Some(material) if material < material_ids.len() - 1 => material_ids[material],
+            _ => *material_ids.last().unwrap(),
+        };
+
+        // Normals transform with the inverse transpose, mirroring transforms flip the winding
+        let normal_transform = Mat4f::inverse(transform);
+        let mirrored = determinant(&transform) < 0.0;
+
+        for corners in indices.chunks_exact(3) {
+            let mut vertices = [Vertex::default(); 3];
+            for (vertex, i) in vertices.iter_mut().zip(corners) {
+                let i = *i as usize;
+                let position =
+                    Vec3f::new(positions[i * 3], positions[i * 3 + 1], positions[i * 3 + 2]);
+                vertex.position = transform.transform_point(position);
+                if let Some(normals) = &normals
+                    && normals.len() >= (i + 1) * 3
+                {
+                    let normal = Vec3f::new(normals[i * 3], normals[i * 3 + 1], normals[i * 3 + 2]);
+                    vertex.normal = transpose_mul(&normal_transform, normal).normalized();
+                }
+                // The handedness is stored in w, mirroring transforms flip it
+                if let Some(tangents) = &tangents {
+                    let tangent =
+                        Vec3f::new(tangents[i * 4], tangents[i * 4 + 1], tangents[i * 4 + 2]);
+                    vertex.tangent = (transform * tangent).normalized();
+                    vertex.bitangent_sign = if (tangents[i * 4 + 3] < 0.0) != mirrored {
+                        -1.0
+                    } else {
+                        1.0
+                    };
+                }
+                // glTF puts the UV origin in the top left corner, textures are flipped on load
+                if let Some(tex_coords) = &tex_coords
+                    && tex_coords.len() >= (i + 1) * 2
+                {
+                    vertex.tex_coord_x = tex_coords[i * 2];
+                    vertex.tex_coord_y = 1.0 - tex_coords[i * 2 + 1];
+                }
+            }
+            if mirrored {
+                vertices.swap(1, 2);
+            }
+
+            // Primitives without normals are flat shaded
+            if normals.is_none() {
+                let normal = Vec3f::cross(
+                    vertices[1].position - vertices[0].position,
+                    vertices[2].position - vertices[0].position,
+                )
+                .normalized();
+                for vertex in &mut vertices {
+                    vertex.normal = normal;
+                }
+            }
+
+            self.tris.push(Triangle {
+                vertices,
+                material_id,
+                object_id,
+                has_tangents: tangents.is_some(),
+            });
+        }
+        return Some(());
+    }
+
+    /// Bytes of a buffer view and its stride, zero if the elements are tightly packed
+    fn buffer_view<'a>(document: &'a Document, buffer_view: usize) -> Option<(&'a [u8], usize)> {
+        let Some(view) = array(get(&document.root, "bufferViews")).get(buffer_view) else {
+            log_error!(
+                "Buffer view {} doesn't exist in '{}'",
+                buffer_view,
+                document.path
+            );
+            return None;
+        };
+        let buffer = index(get(view, "buffer")).and_then(|buffer| document.buffers.get(buffer));
+        let offset = index(get(view, "byteOffset")).unwrap_or(0);
+        let length = index(get(view, "byteLength")).unwrap_or(0);
+        let Some(data) = buffer.and_then(|buffer| buffer.get(offset..offset + length)) else {
+            log_error!(
+                "Buffer view {} is out of bounds in '{}'",
+                buffer_view,
+                document.path
+            );
+            return None;
+        };
+        return Some((data, index(get(view, "byteStride")).unwrap_or(0)));
+    }
+
+    fn accessor<'a>(document: &'a Document, accessor: usize) -> Option<Accessor<'a>> {
+        let Some(gltf_accessor) = array(get(&document.root, "accessors")).get(accessor) else {
+            log_error!("Accessor {} doesn't exist in '{}'", accessor, document.path);
+            return None;
+        };
+        if get(gltf_accessor, "sparse").is_some() {
+            log_error!("Sparse accessors aren't supported in '{}'", document.path);
+            return None;
+        }
+        let Some(buffer_view) = index(get(gltf_accessor, "bufferView")) else {
+            log_error!(
+                "Accessor {} has no buffer view in '{}'",
+                accessor,
+                document.path
+            );
+            return None;
+        };
+        let (data, stride) = Self::buffer_view(document, buffer_view)?;
+        let offset = index(get(gltf_accessor, "byteOffset")).unwrap_or(0);
+        let component_type = index(get(gltf_accessor, "componentType")).unwrap_or(0);
+        let components = match get(gltf_accessor, "type") {
+            Some(Value::String(element_type)) => match element_type.as_str() {
+                "SCALAR" => 1,
+                "VEC2" => 2,
+                "VEC3" => 3,
+                "VEC4" => 4,
+                "MAT2" => 4,
+                "MAT3" => 9,
+                "MAT4" => 16,
+                _ => 0,
+            },
+            _ => 0,
+        };
+        let component_size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => 0,
+        };
+        if components == 0 || component_size == 0 {
+            log_error!(
+                "Accessor {} has an unknown type in '{}'",
+                accessor,
+                document.path
+            );
+            return None;
+        }
+
+        let count = index(get(gltf_accessor, "count")).unwrap_or(0);
+        let stride = if stride == 0 {
+            components * component_size
+        } else {
+            stride
+        };
+        let data = data.get(offset..).unwrap_or(&[]);
+        if count > 0 && data.len() < (count - 1) * stride + components * component_size {
+            log_error!(
+                "Accessor {} is out of bounds in '{}'",
+                accessor,
+                document.path
+            );
+            return None;
+        }
+
+        return Some(Accessor {
+            data,
+            stride,
+            component_type,
+            components,
+            count,
+            normalized: matches!(get(gltf_accessor, "normalized"), Some(Value::Boolean(true))),
+        });
+    }
+
+    /// All components of an accessor as floats, normalized integers are mapped to 0.0 - 1.0
+    /// (or -1.0 - 1.0 for signed integers)
+    fn read_floats(document: &Document, accessor: usize) -> Option<Vec<f32>> {
+        let accessor = Self::accessor(document, accessor)?;
+        let mut values = Vec::with_capacity(accessor.count * accessor.components);
+        for element in 0..accessor.count {
+            for component in 0..accessor.components {
+                values.push(accessor.component(element, component));
+            }
+        }
+        return Some(values);
+    }
+
+    fn read_indices(document: &Document, accessor: usize) -> Option<Vec<u32>> {
+        let accessor = Self::accessor(document, accessor)?;
+        let size = match accessor.component_type {
+            5121 => 1,
+            5123 => 2,
+            5125 => 4,
+            _ => {
+                log_error!("Indices must be unsigned integers in '{}'", document.path);
+                return None;
+            }
+        };
+        let indices = (0..accessor.count)
+            .map(|element| {
+                let start = element * accessor.stride;
+                let bytes = &accessor.data[start..start + size];
+                return bytes
+                    .iter()
+                    .rev()
+                    .fold(0u32, |index, byte| (index << 8) | *byte as u32);
+            })
+            .collect();
+        return Some(indices);
+    }
+}
+
+impl Accessor<'_> {
+    fn component(&self, element: usize, component: usize) -> f32 {
+        let size = match self.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            _ => 4,
+        };
+        let start = element * self.stride + component * size;
+        let bytes = &self.data[start..start + size];
+        let value = match self.component_type {
+            5120 => (bytes[0] as i8) as f32,
+            5121 => bytes[0] as f32,
+            5122 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+            5123 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+            5125 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32,
+            _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        };
+        if !self.normalized {
+            return value;
+        }
+        match self.component_type {
+            5120 => return f32::max(value / 127.0, -1.0),
+            5121 => return value / 255.0,
+            5122 => return f32::max(value / 32767.0, -1.0),
+            5123 => return value / 65535.0,
+            _ => return value,
+        }
+    }
+}
+
+/// Local transform of a node, either a column major matrix or translation, rotation and scale
+fn node_transform(node: &Value) -> Mat4f {
+    if let Some(matrix) = floats::<16>(get(node, "matrix")) {
+        let mut m = Mat4f::new();
+        for (i, value) in matrix.iter().enumerate() {
+            m.data[i / 4][i % 4] = *value;
+        }
+        return m;
+    }
+
+    let translation = floats::<3>(get(node, "translation")).unwrap_or([0.0; 3]);
+    let [x, y, z, w] = floats::<4>(get(node, "rotation")).unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let scale = floats::<3>(get(node, "scale")).unwrap_or([1.0; 3]);
+
+    let mut m = Mat4f::new();
+    let rotation = [
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y + z * w),
+            2.0 * (x * z - y * w),
+        ],
+        [
+            2.0 * (x * y - z * w),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z + x * w),
+        ],
+        [
+            2.0 * (x * z + y * w),
+            2.0 * (y * z - x * w),
+            1.0 - 2.0 * (x * x + y * y),
+        ],
+    ];
+    for (column, rotation_column) in rotation.iter().enumerate() {
+        for (row, value) in rotation_column.iter().enumerate() {
+            m.data[column][row] = value * scale[column];
+        }
+        m.data[3][column] = translation[column];
+    }
+    return m;
+}
+
+fn determinant(m: &Mat4f) -> f32 {
+    let column = |i: usize| Vec3f::new(m.data[i][0], m.data[i][1], m.data[i][2]);
+    return Vec3f::dot(Vec3f::cross(column(0), column(1)), column(2));
+}
+
+/// Multiplies a direction with the transpose of `m`
+fn transpose_mul(m: &Mat4f, direction: Vec3f) -> Vec3f {
+    let row = |i: usize| Vec3f::new(m.data[i][0], m.data[i][1], m.data[i][2]);
+    return Vec3f::new(
+        Vec3f::dot(row(0), direction),
+        Vec3f::dot(row(1), direction),
+        Vec3f::dot(row(2), direction),
+    );
+}
+
+/// Resolves a URI relative to the glTF file, spaces and other characters may be percent encoded
+fn resource_path(file_path: &str, uri: &str) -> String {
+    let mut decoded: Vec<u8> = vec![];
+    let mut bytes = uri.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%'
+            && let Some(hex) = bytes.next().zip(bytes.next())
+            && let Ok(value) =
+                u8::from_str_radix(&format!("{}{}", hex.0 as char, hex.1 as char), 16)
+        {
+            decoded.push(value);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    let mut path = PathBuf::from(file_path);
+    path.pop();
+    return path
+        .join(String::from_utf8_lossy(&decoded).as_ref())
+        .to_string_lossy()
+        .to_string();
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in encoded.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    return Some(decoded);
+}
+
+fn get<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(object) => return object.get(name),
+        _ => return None,
+    }
+}
+
+/// Elements of a JSON array, empty if the value is missing or isn't an array
+fn array(value: Option<&Value>) -> &[Value] {
+    match value {
+        Some(Value::Array(values)) => return values,
+        _ => return &[],
+    }
+}
+
+fn float(value: Option<&Value>) -> Option<f32> {
+    match value {
+        Some(Value::Number(Number::Integer(number))) => return Some(*number as f32),
+        Some(Value::Number(Number::Float(number))) => return Some(*number as f32),
+        _ => return None,
+    }
+}
+
+fn index(value: Option<&Value>) -> Option<usize> {
+    match value {
+        Some(Value::Number(Number::Integer(number))) if *number >= 0 => {
+            return Some(*number as usize);
+        }
+        _ => return None,
+    }
+}
+
+fn floats<const N: usize>(value: Option<&Value>) -> Option<[f32; N]> {
+    let values = array(value);
+    if values.len() != N {
+        return None;
+    }
+    let mut result = [0.0; N];
+    for (result, value) in result.iter_mut().zip(values) {
+        *result = float(Some(value))?;
+    }
+    return Some(result);
+}
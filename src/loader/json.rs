@@ -39,7 +39,6 @@ pub fn parse(input: &str) -> Option<HashMap<String, Value>> {
         return None;
     }
     let tokens = lex(input);
-    log_info!("{:?}", tokens);
     return parse_tokens(tokens);
 }
 
@@ -336,7 +335,7 @@ fn get_number(chars: &mut Peekable<Chars>) -> Option<Number> {
     let mut number_string = String::new();
     while let Some(c) = chars.peek() {
         match c {
-            '-' | '0'..='9' | '.' => {
+            '-' | '+' | '0'..='9' | '.' | 'e' | 'E' => {
                 number_string.push(*c);
                 chars.next();
             }
@@ -344,7 +343,7 @@ fn get_number(chars: &mut Peekable<Chars>) -> Option<Number> {
         }
     }
 
-    if number_string.contains('.') {
+    if number_string.contains(['.', 'e', 'E']) {
         if let Ok(float) = str::parse::<f64>(&number_string) {
             return Some(Number::Float(float));
         } else {
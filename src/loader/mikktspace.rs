@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::{
+    log_info,
+    math::{vec::*, vec3::*},
+    scene::Triangle,
+};
+
+/// Generates per vertex tangents with handedness the way MikkTSpace does, so that tangent space
+/// normal maps baked in Blender or Substance decode the same way here.
+///
+/// Face tangents are normalized and weighted by the corner angle, then averaged over corners
+/// that share position, normal, UV and UV orientation. Mirrored UV islands therefore get their
+/// own tangents instead of cancelling out at the seam.
+///
+/// http://www.mikktspace.com/
+pub fn generate_tangents(tris: &mut [Triangle]) {
+    let start_time = std::time::Instant::now();
+
+    let mut group_ids: HashMap<[u32; 9], usize> = HashMap::new();
+    let mut tangents: Vec<Vec3f> = vec![];
+    let mut corner_groups: Vec<[usize; 3]> = Vec::with_capacity(tris.len());
+
+    for tri in tris.iter() {
+        let [v_0, v_1, v_2] = tri.vertices;
+        let edge_1 = v_1.position - v_0.position;
+        let edge_2 = v_2.position - v_0.position;
+        let du_1 = v_1.tex_coord_x - v_0.tex_coord_x;
+        let dv_1 = v_1.tex_coord_y - v_0.tex_coord_y;
+        let du_2 = v_2.tex_coord_x - v_0.tex_coord_x;
+        let dv_2 = v_2.tex_coord_y - v_0.tex_coord_y;
+
+        // Only the direction matters, so the UV area doesn't have to be divided out
+        let signed_area = du_1 * dv_2 - du_2 * dv_1;
+        let orientation_preserving = signed_area > 0.0;
+        let mut face_tangent = edge_1 * dv_2 - edge_2 * dv_1;
+        if signed_area < 0.0 {
+            face_tangent = face_tangent.reversed();
+        }
+        if f32::abs(signed_area) <= f32::EPSILON || face_tangent.length() <= f32::EPSILON {
+            face_tangent = Vec3f::from(0.0);
+        } else {
+            face_tangent = face_tangent.normalized();
+        }
+
+        let mut groups = [0; 3];
+        for (i, vertex) in tri.vertices.iter().enumerate() {
+            let normal = vertex.normal.normalized();
+            let to_prev = tri.vertices[(i + 2) % 3].position - vertex.position;
+            let to_next = tri.vertices[(i + 1) % 3].position - vertex.position;
+            let angle = Vec3f::dot(to_prev.normalized(), to_next.normalized())
+                .clamp(-1.0, 1.0)
+                .acos();
+
+            let key = [
+                vertex.position.x().to_bits(),
+                vertex.position.y().to_bits(),
+                vertex.position.z().to_bits(),
+                vertex.normal.x().to_bits(),
+                vertex.normal.y().to_bits(),
+                vertex.normal.z().to_bits(),
+                vertex.tex_coord_x.to_bits(),
+                vertex.tex_coord_y.to_bits(),
+                orientation_preserving as u32,
+            ];
+            let id = *group_ids.entry(key).or_insert_with(|| {
+                tangents.push(Vec3f::from(0.0));
+                return tangents.len() - 1;
+            });
+
+            // Project into the tangent plane of the vertex before averaging
+            let projected = face_tangent - normal * Vec3f::dot(normal, face_tangent);
+            if projected.length() > f32::EPSILON && angle.is_finite() {
+                tangents[id] += projected.normalized() * angle;
+            }
+            groups[i] = id;
+        }
+        corner_groups.push(groups);
+    }
+
+    let mut degenerate_count: usize = 0;
+    for (tri, groups) in tris.iter_mut().zip(corner_groups) {
+        let [v_0, v_1, v_2] = tri.vertices;
+        let signed_area = (v_1.tex_coord_x - v_0.tex_coord_x) * (v_2.tex_coord_y - v_0.tex_coord_y)
+            - (v_2.tex_coord_x - v_0.tex_coord_x) * (v_1.tex_coord_y - v_0.tex_coord_y);
+        let bitangent_sign = if signed_area < 0.0 { -1.0 } else { 1.0 };
+
+        for i in 0..3 {
+            let tangent = tangents[groups[i]];
+            if tangent.length() > f32::EPSILON {
+                tri.vertices[i].tangent = tangent.normalized();
+            } else {
+                tri.vertices[i].tangent = Vec3f::from(0.0);
+                degenerate_count += 1;
+            }
+            tri.vertices[i].bitangent_sign = bitangent_sign;
+        }
+    }
+
+    log_info!(
+        "Generated {} tangents in {} ms ({} triangle corners without a usable UV layout)\n",
+        tangents.len(),
+        start_time.elapsed().as_millis(),
+        degenerate_count
+    );
+}
@@ -1,6 +1,14 @@
 use crate::{
-    log_error, log_info, log_warning, math::vec::*, math::vec3::*, scene::Material,
-    texture::Texture, texture::TextureType,
+    animation::{CameraPath, Interpolation, Keyframe},
+    loader::voxel::VoxelGrid,
+    log_error, log_info, log_warning,
+    math::vec::*,
+    math::vec3::*,
+    medium::Medium,
+    scene::Material,
+    sky::Sky,
+    texture::Texture,
+    texture::TextureType,
 };
 use std::{collections::HashMap, path::PathBuf};
 
@@ -10,8 +18,23 @@ pub struct OBJ {
     pub vertex_buffer: VertexBuffer,
     pub materials: HashMap<String, Material>,
     pub textures: Vec<Texture>,
+    pub media: Vec<Medium>,
+    pub density_grid_data: Vec<f32>,
+    pub camera_keyframes: Vec<Keyframe>,
+    pub camera_interpolation: Interpolation,
+    /// Camera path loaded from a JSON file, takes precedence over `camera_keyframes`
+    pub camera_path: Option<CameraPath>,
+    /// Replaces the default constant sky when set
+    pub sky: Option<Sky>,
+    /// Medium filling the space outside of all closed meshes
+    pub global_medium: Option<Medium>,
+    /// Object index with its offsets at the start and end of the shutter
+    pub object_motions: Vec<(u32, Vec3f, Vec3f)>,
 }
 
+/// Field of view of camera keyframes that don't specify one, the same as the default camera
+const DEFAULT_KEYFRAME_FOV: f32 = 90.0;
+
 impl OBJ {
     pub fn load(path: &str) -> Self {
         let mut obj = OBJ::default();
@@ -50,6 +73,9 @@ impl OBJ {
         }
 
         let mut active_material_id: u32 = 0;
+        // Every `o` line starts a new object, faces before the first one belong to object 0
+        let mut active_object_id: u32 = 0;
+        let mut has_object = false;
         for line in lines {
             let mut split = line.split_whitespace();
             if let Some(prefix) = split.nth(0) {
@@ -90,14 +116,122 @@ impl OBJ {
                             active_material_id = mtl_id as u32;
                         }
                     }
+                    "o" => {
+                        if has_object {
+                            active_object_id += 1;
+                        }
+                        has_object = true;
+                    }
                     "f" => {
                         let triangles =
                             Triangle::from_str(line.strip_prefix("f ").unwrap()).unwrap();
                         for mut triangle in triangles {
                             triangle.material_id = active_material_id;
+                            triangle.object_id = active_object_id;
                             obj.tris.push(triangle);
                         }
                     }
+                    // Extension: camera_keyframe time x y z pitch yaw [fov]
+                    "camera_keyframe" => {
+                        let data = split
+                            .map(|value| value.parse::<f32>().unwrap())
+                            .collect::<Vec<f32>>();
+                        if data.len() < 6 {
+                            log_error!("Camera keyframe needs at least 6 values: '{}'", line);
+                            continue;
+                        }
+                        obj.camera_keyframes.push(Keyframe {
+                            time: data[0],
+                            position: Vec3f::new(data[1], data[2], data[3]),
+                            pitch: data[4],
+                            yaw: data[5],
+                            fov: *data.get(6).unwrap_or(&DEFAULT_KEYFRAME_FOV),
+                        });
+                    }
+                    // Extension: camera_keyframe_quat time x y z qx qy qz qw [fov]
+                    "camera_keyframe_quat" => {
+                        let data = split
+                            .map(|value| value.parse::<f32>().unwrap())
+                            .collect::<Vec<f32>>();
+                        if data.len() < 8 {
+                            log_error!("Camera keyframe needs at least 8 values: '{}'", line);
+                            continue;
+                        }
+                        obj.camera_keyframes.push(Keyframe::from_quaternion(
+                            data[0],
+                            Vec3f::new(data[1], data[2], data[3]),
+                            [data[4], data[5], data[6], data[7]],
+                            *data.get(8).unwrap_or(&DEFAULT_KEYFRAME_FOV),
+                        ));
+                    }
+                    // Extension: camera_path path/to/path.json, as saved from the realtime window
+                    "camera_path" => {
+                        let json_path = line.trim_start().strip_prefix("camera_path ").unwrap();
+                        let Some(json_path) = Self::get_resource_path(path, json_path.trim())
+                        else {
+                            log_error!("Could not find camera path: '{}'", line);
+                            continue;
+                        };
+                        obj.camera_path = CameraPath::load(json_path.as_str());
+                    }
+                    // Extension: global_medium sigma_a sigma_s [g], coefficients as r g b
+                    "global_medium" => {
+                        let data = split
+                            .map(|value| value.parse::<f32>().unwrap())
+                            .collect::<Vec<f32>>();
+                        if data.len() < 6 {
+                            log_error!("Global medium needs at least 6 values: '{}'", line);
+                            continue;
+                        }
+                        obj.global_medium = Some(Medium::homogeneous(
+                            Vec3f::new(data[0], data[1], data[2]),
+                            Vec3f::new(data[3], data[4], data[5]),
+                            *data.get(6).unwrap_or(&0.0),
+                        ));
+                    }
+                    // Extension: object_motion object start_x start_y start_z end_x end_y end_z
+                    "object_motion" => {
+                        let Some(Ok(object_id)) = split.next().map(|value| value.parse::<u32>())
+                        else {
+                            log_error!("Invalid object index in object motion: '{}'", line);
+                            continue;
+                        };
+                        let data = split
+                            .map(|value| value.parse::<f32>().unwrap())
+                            .collect::<Vec<f32>>();
+                        if data.len() < 6 {
+                            log_error!("Object motion needs 6 offset values: '{}'", line);
+                            continue;
+                        }
+                        obj.object_motions.push((
+                            object_id,
+                            Vec3f::new(data[0], data[1], data[2]),
+                            Vec3f::new(data[3], data[4], data[5]),
+                        ));
+                    }
+                    // Extension: sky preetham elevation azimuth turbidity
+                    "sky" => match split.next() {
+                        Some("preetham") => {
+                            let data = split
+                                .map(|value| value.parse::<f32>().unwrap())
+                                .collect::<Vec<f32>>();
+                            if data.len() < 3 {
+                                log_error!("Preetham sky needs 3 values: '{}'", line);
+                                continue;
+                            }
+                            obj.sky = Some(Sky::physical(data[0], data[1], data[2]));
+                        }
+                        _ => {
+                            log_error!("Unknown sky model: '{}'", line);
+                        }
+                    },
+                    "camera_interpolation" => match split.next() {
+                        Some("linear") => obj.camera_interpolation = Interpolation::Linear,
+                        Some("catmull_rom") => obj.camera_interpolation = Interpolation::CatmullRom,
+                        _ => {
+                            log_error!("Unknown camera interpolation: '{}'", line);
+                        }
+                    },
                     _ => (),
                 }
             }
@@ -138,6 +272,7 @@ impl OBJ {
                     line.strip_prefix("newmtl ").unwrap().to_string(),
                     Material::default(),
                 );
+                let mut new_medium: Option<Medium> = None;
 
                 while let Some(line) = lines.next() {
                     let mut attribute = line.split_whitespace().into_iter();
@@ -171,6 +306,23 @@ impl OBJ {
                         "Pm" => {
                             new_material.1.metallic = attribute.next().unwrap().parse().unwrap();
                         }
+                        "Ps" => {
+                            new_material.1.sheen = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "Pc" => {
+                            new_material.1.clearcoat = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "Pcr" => {
+                            let roughness: f32 = attribute.next().unwrap().parse().unwrap();
+                            new_material.1.clearcoat_gloss = 1.0 - roughness.clamp(0.0, 1.0);
+                        }
+                        "aniso" => {
+                            new_material.1.anisotropic = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "anisor" => {
+                            new_material.1.anisotropic_rotation =
+                                attribute.next().unwrap().parse().unwrap();
+                        }
                         // NOTE: Blender exports "Tf" as a 3D vector, we only care about the
                         // first component. AFAIK the components are always the same.
                         "Tf" => {
@@ -255,10 +407,119 @@ impl OBJ {
                                 );
                             }
                         }
+                        // NOTE: Not part of the MTL spec
+                        "thin_walled" => {
+                            new_material.1.thin_walled = attribute.next().unwrap().parse().unwrap();
+                        }
+                        // NOTE: These are not part of the MTL spec, they expose the principled
+                        // parameters that have no PBR extension key
+                        "specular" => {
+                            new_material.1.specular = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "sheen_tint" => {
+                            new_material.1.sheen_tint = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "clearcoat_gloss" => {
+                            new_material.1.clearcoat_gloss =
+                                attribute.next().unwrap().parse().unwrap();
+                        }
+                        // NOTE: These are not part of the MTL spec, they describe a thin film
+                        // on top of the specular layers
+                        "thin_film_thickness" => {
+                            new_material.1.thin_film_thickness =
+                                attribute.next().unwrap().parse().unwrap();
+                        }
+                        "thin_film_thickness_min" => {
+                            new_material.1.thin_film_thickness_min =
+                                attribute.next().unwrap().parse().unwrap();
+                        }
+                        "thin_film_ior" => {
+                            new_material.1.thin_film_ior =
+                                attribute.next().unwrap().parse().unwrap();
+                        }
+                        "map_thin_film_thickness" => {
+                            if let Some(texture_path) =
+                                Self::get_resource_path(path, attribute.next().unwrap())
+                            {
+                                Self::load_texture(
+                                    texture_path.as_str(),
+                                    obj,
+                                    &mut new_material.1,
+                                    TextureType::ThinFilmThickness,
+                                );
+                            }
+                        }
+                        // NOTE: These are not part of the MTL spec, they describe how the IOR
+                        // varies with wavelength in spectral mode
+                        "cauchy_b" => {
+                            new_material.1.cauchy_b = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "sellmeier_b" => {
+                            attribute.enumerate().for_each(|(i, val)| {
+                                new_material.1.sellmeier_b.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        "sellmeier_c" => {
+                            attribute.enumerate().for_each(|(i, val)| {
+                                new_material.1.sellmeier_c.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        // NOTE: These are not part of the MTL spec, they describe random walk
+                        // subsurface scattering inside a closed mesh
+                        "subsurface" => {
+                            new_material.1.subsurface = attribute.next().unwrap().parse().unwrap();
+                        }
+                        "subsurface_albedo" => {
+                            attribute.enumerate().for_each(|(i, val)| {
+                                new_material.1.subsurface_albedo.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        "subsurface_mfp" => {
+                            attribute.enumerate().for_each(|(i, val)| {
+                                new_material.1.subsurface_mfp.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        "subsurface_anisotropy" => {
+                            let g: f32 = attribute.next().unwrap().parse().unwrap();
+                            new_material.1.subsurface_anisotropy = g.clamp(-0.99, 0.99);
+                        }
+                        // NOTE: These are not part of the MTL spec, they describe the
+                        // participating medium inside a closed mesh
+                        "medium_sigma_a" => {
+                            let medium = new_medium.get_or_insert_with(Medium::default);
+                            attribute.enumerate().for_each(|(i, val)| {
+                                medium.sigma_a.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        "medium_sigma_s" => {
+                            let medium = new_medium.get_or_insert_with(Medium::default);
+                            attribute.enumerate().for_each(|(i, val)| {
+                                medium.sigma_s.data[i] = val.parse().unwrap();
+                            });
+                        }
+                        "medium_g" => {
+                            let medium = new_medium.get_or_insert_with(Medium::default);
+                            let g: f32 = attribute.next().unwrap().parse().unwrap();
+                            medium.g = g.clamp(-0.99, 0.99);
+                        }
+                        "medium_density" => {
+                            let medium = new_medium.get_or_insert_with(Medium::default);
+                            if let Some(grid_path) =
+                                Self::get_resource_path(path, attribute.next().unwrap())
+                                && let Some(grid) = VoxelGrid::load(grid_path.as_str())
+                            {
+                                medium.set_density_grid(&grid, &mut obj.density_grid_data);
+                            }
+                        }
                         _ => continue,
                     }
                 }
 
+                if let Some(medium) = new_medium {
+                    obj.media.push(medium);
+                    new_material.1.medium_id = (obj.media.len() - 1) as u32;
+                }
+
                 obj.materials.insert(new_material.0, new_material.1);
             }
         }
@@ -305,6 +566,11 @@ impl OBJ {
             TextureType::Normal => {
                 material.normal_tex_id = tex_id;
             }
+            TextureType::ThinFilmThickness => {
+                material.thin_film_thickness_tex_id = tex_id;
+            }
+            // Not used by materials
+            TextureType::Aperture => (),
         }
     }
 
@@ -347,6 +613,7 @@ pub struct Triangle {
     pub tex_coords: [usize; 3],
     pub normals: [usize; 3],
     pub material_id: u32,
+    pub object_id: u32,
 }
 
 impl Triangle {
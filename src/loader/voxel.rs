@@ -0,0 +1,99 @@
+use crate::{log_error, log_info, math::vec3::*};
+
+/// Density grid loaded from a simple text based voxel file:
+///
+/// ```text
+/// # Comments start with '#'
+/// resolution <nx> <ny> <nz>
+/// bounds <min_x> <min_y> <min_z> <max_x> <max_y> <max_z>
+/// <nx * ny * nz whitespace separated densities, x varies fastest, then y, then z>
+/// ```
+#[derive(Default)]
+pub struct VoxelGrid {
+    pub resolution: [u32; 3],
+    pub bounds_min: Vec3f,
+    pub bounds_max: Vec3f,
+    pub data: Vec<f32>,
+}
+
+impl VoxelGrid {
+    pub fn load(path: &str) -> Option<Self> {
+        if !std::fs::exists(path).unwrap() {
+            log_error!("Could not find voxel grid at path: '{}'", path);
+            return None;
+        }
+
+        let start_time = std::time::Instant::now();
+
+        let buffer = std::fs::read_to_string(path).unwrap();
+        let mut grid = Self::default();
+
+        for line in buffer.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let split = line.split_whitespace();
+            match split.clone().next() {
+                Some("resolution") => {
+                    let values = split
+                        .skip(1)
+                        .map(|value| value.parse::<u32>().ok())
+                        .collect::<Option<Vec<u32>>>();
+                    let Some(&[x, y, z]) = values.as_deref() else {
+                        log_error!("Invalid resolution '{}' in voxel grid '{}'", line, path);
+                        return None;
+                    };
+                    grid.resolution = [x, y, z];
+                }
+                Some("bounds") => {
+                    let values = split
+                        .skip(1)
+                        .map(|value| value.parse::<f32>().ok())
+                        .collect::<Option<Vec<f32>>>();
+                    let Some(&[min_x, min_y, min_z, max_x, max_y, max_z]) = values.as_deref()
+                    else {
+                        log_error!("Invalid bounds '{}' in voxel grid '{}'", line, path);
+                        return None;
+                    };
+                    grid.bounds_min = Vec3f::new(min_x, min_y, min_z);
+                    grid.bounds_max = Vec3f::new(max_x, max_y, max_z);
+                }
+                _ => {
+                    for value in split {
+                        let Ok(density) = value.parse::<f32>() else {
+                            log_error!("Invalid density '{}' in voxel grid '{}'", value, path);
+                            return None;
+                        };
+                        grid.data.push(f32::max(density, 0.0));
+                    }
+                }
+            }
+        }
+
+        let voxel_count = grid.resolution.iter().product::<u32>() as usize;
+        if voxel_count == 0 || grid.data.len() != voxel_count {
+            log_error!(
+                "Voxel grid '{}' has {} densities but a resolution of {}x{}x{}",
+                path,
+                grid.data.len(),
+                grid.resolution[0],
+                grid.resolution[1],
+                grid.resolution[2]
+            );
+            return None;
+        }
+
+        log_info!(
+            "'{}' took {} ms to load ({}x{}x{} voxels)",
+            path,
+            start_time.elapsed().as_millis(),
+            grid.resolution[0],
+            grid.resolution[1],
+            grid.resolution[2]
+        );
+
+        return Some(grid);
+    }
+}
@@ -2,16 +2,22 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::math::vec3::Vec3f;
-use crate::renderer::backend::RendererBackend;
+use crate::renderer::backend::{Integrator, RendererBackend, SamplerType};
+use crate::renderer::post_process::PostProcess;
 use crate::renderer::*;
 use crate::scene::{Camera, Scene};
 
+mod animation;
 mod bvh;
+mod light_bvh;
 mod loader;
 mod log;
 mod math;
+mod medium;
 mod renderer;
 mod scene;
+mod sky;
+mod spectrum;
 mod texture;
 
 const WIDTH: usize = 1920;
@@ -28,7 +34,17 @@ fn main() {
         output_image_dimensions: (WIDTH, HEIGHT),
         output_image_path: Some(IMAGE_PATH),
         backend: RendererBackend::GPU,
+        integrator: Integrator::PathTracing,
+        sampler: SamplerType::Sobol,
         is_realtime: true,
+        spectral: false,
+        frame_sequence: None,
+        output_format: OutputFormat::ToneMapped,
+        aovs: &[],
+        denoise: false,
+        post_process: PostProcess::default(),
+        adaptive_sampling: None,
+        time_budget: None,
     }) else {
         return;
     };
@@ -3,6 +3,9 @@ pub mod vec;
 pub mod vec2;
 pub mod vec3;
 
+/// Largest f32 below 1, sample values are kept under it when they get rescaled
+pub const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
 fn xor_shift(input: &mut u32) -> u32 {
     let mut x: u32 = *input;
     x ^= x << 13;
@@ -18,7 +21,34 @@ fn rand_f32_nd(input: &mut u32) -> f32 {
     return rho * f32::cos(theta);
 }
 
+/// Seed for `rand_f32` from `value`, never zero since xor shift would get stuck there
+pub fn rng_seed(value: u32) -> u32 {
+    return u32::max(hash_u32(value), 1);
+}
+
 /// Returns a random f32 in the range 0.0 - 1.0
 pub fn rand_f32(input: &mut u32) -> f32 {
     return xor_shift(input) as f32 / u32::MAX as f32;
 }
+
+/// Integer hash with good avalanche, a bijection so different inputs never collide
+///
+/// https://nullprogram.com/blog/2018/07/31/
+pub fn hash_u32(input: u32) -> u32 {
+    let mut x = input;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    return x;
+}
+
+/// Mixes `value` into the hash `seed`
+pub fn hash_combine(seed: u32, value: u32) -> u32 {
+    return seed
+        ^ value
+            .wrapping_add(0x9e3779b9)
+            .wrapping_add(seed << 6)
+            .wrapping_add(seed >> 2);
+}
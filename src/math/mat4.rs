@@ -22,6 +22,41 @@ impl Mat4f {
         };
     }
 
+    pub fn translation(offset: Vec3f) -> Self {
+        let mut m = Self::new();
+        m.data[3][0] = offset.x();
+        m.data[3][1] = offset.y();
+        m.data[3][2] = offset.z();
+        return m;
+    }
+
+    /// Rotation by `degrees` around `axis`, counterclockwise when looking against the axis
+    pub fn rotation(axis: Vec3f, degrees: f32) -> Self {
+        let axis = axis.normalized();
+        let (sin, cos) = f32::sin_cos(f32::to_radians(degrees));
+        let mut m = Self::new();
+        for column in 0..3 {
+            for row in 0..3 {
+                let mut value = (1.0 - cos) * axis.data[column] * axis.data[row];
+                if column == row {
+                    value += cos;
+                } else {
+                    // The cross product matrix of the axis
+                    let other = 3 - column - row;
+                    let sign = if (column + 1) % 3 == row { 1.0 } else { -1.0 };
+                    value += sign * sin * axis.data[other];
+                }
+                m.data[column][row] = value;
+            }
+        }
+        return m;
+    }
+
+    /// Transforms a point, unlike multiplying with a `Vec3f` this includes the translation
+    pub fn transform_point(&self, point: Vec3f) -> Vec3f {
+        return *self * point + Vec3f::new(self.data[3][0], self.data[3][1], self.data[3][2]);
+    }
+
     pub fn look_at(from: Vec3f, to: Vec3f, up: Vec3f) -> Self {
         let forward = (from - to).normalized();
         let right = Vec3f::cross(up, forward).normalized();
@@ -140,6 +175,22 @@ impl Mat4f {
     }
 }
 
+impl Mul<Mat4f> for Mat4f {
+    type Output = Mat4f;
+
+    fn mul(self, rhs: Mat4f) -> Self::Output {
+        let mut m = Self::default();
+        for column in 0..4 {
+            for row in 0..4 {
+                m.data[column][row] = (0..4)
+                    .map(|i| self.data[i][row] * rhs.data[column][i])
+                    .sum();
+            }
+        }
+        return m;
+    }
+}
+
 impl Mul<Vec3f> for Mat4f {
     type Output = Vec3f;
 
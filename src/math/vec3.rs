@@ -259,6 +259,16 @@ impl From<[u8; 4]> for Vec3f {
     }
 }
 
+impl From<Vec3f> for [u16; 3] {
+    fn from(vector: Vec3f) -> Self {
+        [
+            f32::floor(vector.x() * 65535.0).clamp(0.0, 65535.0) as u16,
+            f32::floor(vector.y() * 65535.0).clamp(0.0, 65535.0) as u16,
+            f32::floor(vector.z() * 65535.0).clamp(0.0, 65535.0) as u16,
+        ]
+    }
+}
+
 impl From<Vec3f> for [u8; 3] {
     fn from(vector: Vec3f) -> Self {
         [
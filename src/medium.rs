@@ -0,0 +1,284 @@
+use std::f32::consts::PI;
+
+use crate::{
+    loader::voxel::VoxelGrid,
+    math::{rand_f32, vec::*, vec3::*},
+};
+
+/// Participating medium, either filling the inside of a closed mesh (through
+/// `Material::medium_id`) or the whole scene (through `Scene::global_medium_id`).
+///
+/// Coefficients are in inverse scene units. Heterogeneous media scale the coefficients by a
+/// density grid stored in `Scene::density_grid_data`, homogeneous media have `grid_offset` set to
+/// `u32::MAX`.
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct Medium {
+    pub sigma_a: Vec3f,
+    /// Henyey-Greenstein asymmetry parameter in the range -1.0 - 1.0
+    pub g: f32,
+    pub sigma_s: Vec3f,
+    pub max_density: f32,
+    pub grid_min: Vec3f,
+    pub grid_offset: u32,
+    pub grid_max: Vec3f,
+    _pad_0: u32,
+    pub grid_resolution: [u32; 3],
+    _pad_1: u32,
+}
+
+impl Default for Medium {
+    fn default() -> Self {
+        return Self {
+            sigma_a: Vec3f::from(0.0),
+            g: 0.0,
+            sigma_s: Vec3f::from(0.0),
+            max_density: 1.0,
+            grid_min: Vec3f::from(0.0),
+            grid_offset: u32::MAX,
+            grid_max: Vec3f::from(0.0),
+            _pad_0: 0,
+            grid_resolution: [0; 3],
+            _pad_1: 0,
+        };
+    }
+}
+
+pub enum MediumInteraction {
+    None,
+    Absorbed,
+    Scattered(Vec3f),
+}
+
+impl Medium {
+    pub fn homogeneous(sigma_a: Vec3f, sigma_s: Vec3f, g: f32) -> Self {
+        return Self {
+            sigma_a,
+            sigma_s,
+            g: g.clamp(-0.99, 0.99),
+            ..Default::default()
+        };
+    }
+
+    /// Appends the grid densities to `density_grid_data` and makes this medium heterogeneous
+    pub fn set_density_grid(&mut self, grid: &VoxelGrid, density_grid_data: &mut Vec<f32>) {
+        self.grid_offset = density_grid_data.len() as u32;
+        self.grid_min = grid.bounds_min;
+        self.grid_max = grid.bounds_max;
+        self.grid_resolution = grid.resolution;
+        self.max_density = grid
+            .data
+            .iter()
+            .fold(0.0, |max, density| f32::max(max, *density));
+        density_grid_data.extend_from_slice(&grid.data);
+    }
+
+    pub fn is_homogeneous(&self) -> bool {
+        return self.grid_offset == u32::MAX;
+    }
+
+    fn majorant(&self) -> f32 {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        return f32::max(f32::max(sigma_t.x(), sigma_t.y()), sigma_t.z()) * self.max_density;
+    }
+
+    /// Trilinearly interpolated density at `point`, zero outside of the grid
+    pub fn density_at(&self, point: Vec3f, density_grid_data: &[f32]) -> f32 {
+        if self.is_homogeneous() {
+            return 1.0;
+        }
+
+        let [res_x, res_y, res_z] = self.grid_resolution;
+        let local = (point - self.grid_min) / (self.grid_max - self.grid_min);
+        let mut voxel = [0.0f32; 3];
+        for (i, value) in voxel.iter_mut().enumerate() {
+            if local.data[i] < 0.0 || local.data[i] > 1.0 {
+                return 0.0;
+            }
+            *value = local.data[i] * self.grid_resolution[i] as f32 - 0.5;
+        }
+
+        let lookup = |x: i32, y: i32, z: i32| -> f32 {
+            let x = x.clamp(0, res_x as i32 - 1) as u32;
+            let y = y.clamp(0, res_y as i32 - 1) as u32;
+            let z = z.clamp(0, res_z as i32 - 1) as u32;
+            return density_grid_data[(self.grid_offset + x + res_x * (y + res_y * z)) as usize];
+        };
+
+        let base = voxel.map(|v| f32::floor(v) as i32);
+        let frac = Vec3f::new(
+            voxel[0] - base[0] as f32,
+            voxel[1] - base[1] as f32,
+            voxel[2] - base[2] as f32,
+        );
+
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut weight = 1.0;
+            for (i, bit) in offset.iter().enumerate() {
+                if *bit == 1 {
+                    weight *= frac.data[i];
+                } else {
+                    weight *= 1.0 - frac.data[i];
+                }
+            }
+            density += weight
+                * lookup(
+                    base[0] + offset[0],
+                    base[1] + offset[1],
+                    base[2] + offset[2],
+                );
+        }
+        return density;
+    }
+
+    /// Returns the parametric range of the ray inside the medium, clipped to the density grid
+    fn ray_range(&self, origin: Vec3f, direction: Vec3f, t_max: f32) -> Option<(f32, f32)> {
+        if self.is_homogeneous() {
+            return Some((0.0, t_max));
+        }
+
+        let t_min_bounds = (self.grid_min - origin) / direction;
+        let t_max_bounds = (self.grid_max - origin) / direction;
+        let t_1 = Vec3f::min(t_min_bounds, t_max_bounds);
+        let t_2 = Vec3f::max(t_min_bounds, t_max_bounds);
+        let t_near = f32::max(f32::max(f32::max(t_1.x(), t_1.y()), t_1.z()), 0.0);
+        let t_far = f32::min(f32::min(f32::min(t_2.x(), t_2.y()), t_2.z()), t_max);
+        if t_near >= t_far {
+            return None;
+        }
+        return Some((t_near, t_far));
+    }
+
+    /// Delta tracking with spectral weights. Samples a collision along the ray before `t_max`
+    /// and updates `throughput` with the weight of the chosen event. Event probabilities take
+    /// the current throughput into account so that chromatic media don't produce fireflies.
+    ///
+    /// https://cs.dartmouth.edu/~wjarosz/publications/kutz17spectral.pdf
+    pub fn sample_interaction(
+        &self,
+        origin: Vec3f,
+        direction: Vec3f,
+        t_max: f32,
+        density_grid_data: &[f32],
+        throughput: &mut Vec3f,
+        rng_state: &mut u32,
+    ) -> MediumInteraction {
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return MediumInteraction::None;
+        }
+        let Some((mut t, t_end)) = self.ray_range(origin, direction, t_max) else {
+            return MediumInteraction::None;
+        };
+
+        loop {
+            t -= f32::ln(1.0 - rand_f32(rng_state) * 0.999999) / majorant;
+            if t >= t_end {
+                return MediumInteraction::None;
+            }
+
+            let point = origin + direction * t;
+            let density = self.density_at(point, density_grid_data);
+            let sigma_a = self.sigma_a * density;
+            let sigma_s = self.sigma_s * density;
+            let sigma_n = Vec3f::from(majorant) - sigma_a - sigma_s;
+
+            let absorb_weight = Self::average(sigma_a * *throughput);
+            let scatter_weight = Self::average(sigma_s * *throughput);
+            let null_weight = Self::average(Vec3f::max(sigma_n, Vec3f::from(0.0)) * *throughput);
+            let total_weight = absorb_weight + scatter_weight + null_weight;
+            if total_weight <= 0.0 {
+                return MediumInteraction::Absorbed;
+            }
+            let absorb_probability = absorb_weight / total_weight;
+            let scatter_probability = scatter_weight / total_weight;
+            let null_probability = null_weight / total_weight;
+
+            let xi = rand_f32(rng_state);
+            if xi < absorb_probability {
+                return MediumInteraction::Absorbed;
+            } else if xi < absorb_probability + scatter_probability {
+                *throughput *= sigma_s / (majorant * scatter_probability);
+                return MediumInteraction::Scattered(point);
+            } else if null_probability > 0.0 {
+                *throughput *= sigma_n / (majorant * null_probability);
+            }
+        }
+    }
+
+    /// Transmittance along the ray up to `t_max`, analytic for homogeneous media and estimated
+    /// with ratio tracking for heterogeneous ones
+    pub fn transmittance(
+        &self,
+        origin: Vec3f,
+        direction: Vec3f,
+        t_max: f32,
+        density_grid_data: &[f32],
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        if self.is_homogeneous() {
+            return Vec3f::new(
+                f32::exp(-sigma_t.x() * t_max),
+                f32::exp(-sigma_t.y() * t_max),
+                f32::exp(-sigma_t.z() * t_max),
+            );
+        }
+
+        let majorant = self.majorant();
+        let mut transmittance = Vec3f::from(1.0);
+        if majorant <= 0.0 {
+            return transmittance;
+        }
+        let Some((mut t, t_end)) = self.ray_range(origin, direction, t_max) else {
+            return transmittance;
+        };
+
+        loop {
+            t -= f32::ln(1.0 - rand_f32(rng_state) * 0.999999) / majorant;
+            if t >= t_end {
+                return transmittance;
+            }
+
+            let density = self.density_at(origin + direction * t, density_grid_data);
+            transmittance *= Vec3f::from(1.0) - sigma_t * (density / majorant);
+        }
+    }
+
+    /// Samples a new direction from the Henyey-Greenstein phase function around the direction
+    /// of travel
+    pub fn sample_phase(&self, direction: Vec3f, rng_state: &mut u32) -> Vec3f {
+        let g = self.g;
+        let u_1 = rand_f32(rng_state);
+        let u_2 = rand_f32(rng_state);
+
+        let cos_theta: f32;
+        if f32::abs(g) < 1e-3 {
+            cos_theta = 1.0 - 2.0 * u_1;
+        } else {
+            let sqr_term = (1.0 - g * g) / (1.0 - g + 2.0 * g * u_1);
+            cos_theta = ((1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)).clamp(-1.0, 1.0);
+        }
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * PI * u_2;
+
+        let up = if f32::abs(direction.y()) < 0.999 {
+            Vec3f::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3f::new(1.0, 0.0, 0.0)
+        };
+        let tangent = Vec3f::cross(up, direction).normalized();
+        let bitangent = Vec3f::cross(direction, tangent);
+
+        return (tangent * (f32::cos(phi) * sin_theta)
+            + bitangent * (f32::sin(phi) * sin_theta)
+            + direction * cos_theta)
+            .normalized();
+    }
+
+    fn average(v: Vec3f) -> f32 {
+        return (v.x() + v.y() + v.z()) / 3.0;
+    }
+}
@@ -1,9 +1,14 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, path::Path, rc::Rc};
 
-use crate::{log_error, log_info, scene::Scene};
-use backend::RendererBackend;
+use crate::{log_error, log_info, log_warning, scene::Scene};
+use aov::{Aov, AovPixel};
+use backend::{Integrator, RendererBackend, SamplerType};
+use exr::prelude::f16;
+use post_process::{PostProcess, ToneMapping};
 
+pub mod aov;
 pub mod backend;
+pub mod post_process;
 
 #[derive(Clone, Copy)]
 pub struct Renderer {
@@ -24,6 +29,57 @@ impl Renderer {
             log_error!("Sample count must be greater than 0");
             return None;
         }
+        if options.output_format.is_hdr() && options.is_realtime {
+            log_error!(
+                "{:?} output is only supported offline",
+                options.output_format
+            );
+            return None;
+        }
+        if !options.aovs.is_empty() && options.is_realtime {
+            log_error!("AOVs can only be written offline");
+            return None;
+        }
+        if let ToneMapping::ReinhardExtended { white_point } = options.post_process.tone_mapping
+            && white_point <= 0.0
+        {
+            log_error!("Reinhard white point must be greater than 0");
+            return None;
+        }
+        if options.post_process.white_balance <= 0.0 {
+            log_error!("White balance temperature must be greater than 0");
+            return None;
+        }
+        if options.post_process.glare.size < 0.0 || options.post_process.glare.threshold < 0.0 {
+            log_error!("Glare size and threshold can't be negative");
+            return None;
+        }
+        if (options.adaptive_sampling.is_some() || options.time_budget.is_some())
+            && options.integrator != Integrator::PathTracing
+        {
+            log_error!("Adaptive sampling and time budgets are only supported with path tracing");
+            return None;
+        }
+        if let Some(adaptive_sampling) = options.adaptive_sampling
+            && (adaptive_sampling.noise_target <= 0.0 || adaptive_sampling.min_samples < 2)
+        {
+            log_error!(
+                "Adaptive sampling needs a positive noise target and at least 2 samples per pixel"
+            );
+            return None;
+        }
+        if options.time_budget.is_some() && options.is_realtime {
+            log_error!("Time budgets are only supported offline");
+            return None;
+        }
+        if options.post_process.vignette < 0.0 {
+            log_error!("Vignette can't be negative");
+            return None;
+        }
+        if options.post_process.chromatic_aberration.abs() >= 1.0 {
+            log_error!("Chromatic aberration must be between -1 and 1");
+            return None;
+        }
         if options.output_image_path.is_none() && !options.is_realtime {
             log_error!("Output image path must be Some if realtime mode is disabled");
             return None;
@@ -32,6 +88,58 @@ impl Renderer {
             log_error!("Only the GPU backend is supported for realtime mode");
             return None;
         }
+        if options.integrator != Integrator::PathTracing && options.backend != RendererBackend::CPU
+        {
+            log_error!(
+                "Only the CPU backend supports the {:?} integrator",
+                options.integrator
+            );
+            return None;
+        }
+        if options.integrator != Integrator::PathTracing && options.spectral {
+            log_error!("Spectral rendering is only supported by the path tracing integrator");
+            return None;
+        }
+        if let Some(path) = options.output_image_path {
+            let extension = Path::new(path)
+                .extension()
+                .and_then(|extension| extension.to_str());
+            if options.frame_sequence.is_none()
+                && options.output_format.is_hdr()
+                && extension != Some(options.output_format.extension())
+            {
+                log_warning!(
+                    "Output image path '{}' doesn't end in .{}",
+                    path,
+                    options.output_format.extension()
+                );
+            }
+        }
+        if let Some(frame_sequence) = options.frame_sequence {
+            if options.is_realtime {
+                log_error!("Frame sequences can only be rendered offline");
+                return None;
+            }
+            if frame_sequence.frame_rate.is_nan() || frame_sequence.frame_rate <= 0.0 {
+                log_error!("Frame rate must be greater than 0");
+                return None;
+            }
+            if let Some((first, last)) = frame_sequence.frame_range
+                && (first == 0 || first > last)
+            {
+                log_error!("Frame range must start at 1 or later and not end before it starts");
+                return None;
+            }
+        }
+        if let Integrator::Sppm {
+            photons_per_iteration,
+            initial_radius,
+        } = options.integrator
+            && (photons_per_iteration == 0 || initial_radius <= 0.0)
+        {
+            log_error!("SPPM needs at least one photon per iteration and a positive radius");
+            return None;
+        }
 
         log_info!("Renderer info");
         log_info!(
@@ -42,6 +150,24 @@ impl Renderer {
         log_info!("- Sample count:            {}", options.samples);
         log_info!("- Max bounces:             {}", options.max_ray_depth);
         log_info!("- Backend:                 {:?}", options.backend);
+        log_info!("- Integrator:              {:?}", options.integrator);
+        log_info!("- Sampler:                 {:?}", options.sampler);
+        log_info!("- Spectral:                {}", options.spectral);
+        log_info!("- Output format:           {:?}", options.output_format);
+        if !options.aovs.is_empty() {
+            log_info!("- AOVs:                    {:?}", options.aovs);
+        }
+        log_info!("- Denoise:                 {}", options.denoise);
+        log_info!("- Post process:            {:?}", options.post_process);
+        if let Some(adaptive_sampling) = options.adaptive_sampling {
+            log_info!("- Adaptive sampling:       {:?}", adaptive_sampling);
+        }
+        if let Some(time_budget) = options.time_budget {
+            log_info!("- Time budget:             {} s", time_budget);
+        }
+        if let Some(frame_sequence) = options.frame_sequence {
+            log_info!("- Frame rate:              {}", frame_sequence.frame_rate);
+        }
         log_info!("- Realtime:                {}\n", options.is_realtime);
 
         return Some(Self { options });
@@ -52,39 +178,201 @@ impl Renderer {
 
         if self.options.is_realtime {
             backend::gpu::window::render_scene_to_window(self, scene);
+        } else if let Some(frame_sequence) = self.options.frame_sequence {
+            self.render_frame_sequence(&scene, frame_sequence);
         } else {
             let start_time = std::time::Instant::now();
-            let bytes = match self.options.backend {
-                RendererBackend::CPU => backend::cpu::render_scene(self, &scene.clone().borrow()),
-                RendererBackend::GPU => pollster::block_on(backend::gpu::render_scene_to_buffer(
-                    self,
-                    &scene.clone().borrow(),
-                )),
-            };
+            let (bytes, aovs) = self.render_image(&scene.borrow());
             log_info!("Rendering took {} ms", start_time.elapsed().as_millis());
 
-            let path = self.options.output_image_path.unwrap();
-            let image_result = image::save_buffer(
-                path,
-                bytes.as_slice(),
-                self.options.output_image_dimensions.0 as u32,
-                self.options.output_image_dimensions.1 as u32,
-                image::ColorType::Rgba16,
+            self.save_image(self.options.output_image_path.unwrap(), &bytes);
+            self.save_aovs(self.options.output_image_path.unwrap(), &aovs);
+        }
+    }
+
+    /// Renders one image per frame of the camera path of the scene
+    fn render_frame_sequence(self, scene: &Rc<RefCell<Scene>>, frame_sequence: FrameSequence) {
+        let Some(camera_path) = scene.borrow().camera_path.clone() else {
+            log_error!("Rendering a frame sequence needs a scene with a camera path");
+            return;
+        };
+
+        let directory = Path::new(self.options.output_image_path.unwrap());
+        if let Err(error) = std::fs::create_dir_all(directory) {
+            log_error!(
+                "Could not create the output directory '{}' with error {:?}",
+                directory.display(),
+                error
+            );
+            return;
+        }
+
+        let frame_duration = 1.0 / frame_sequence.frame_rate;
+        let frame_count = ((camera_path.end_time() - camera_path.start_time())
+            * frame_sequence.frame_rate) as usize
+            + 1;
+        let (first, mut last) = frame_sequence.frame_range.unwrap_or((1, frame_count));
+        if first > frame_count {
+            log_error!(
+                "Frame range starts at {} but the camera path only has {} frames",
+                first,
+                frame_count
+            );
+            return;
+        }
+        if last > frame_count {
+            log_warning!(
+                "Frame range ends at {} but the camera path only has {} frames",
+                last,
+                frame_count
             );
+            last = frame_count;
+        }
+
+        log_info!(
+            "Rendering frames {} to {} of {} at {} fps",
+            first,
+            last,
+            frame_count,
+            frame_sequence.frame_rate
+        );
+        let sequence_start_time = std::time::Instant::now();
+        for frame in first..=last {
+            let time = camera_path.start_time() + (frame - 1) as f32 * frame_duration;
+            camera_path.apply(&mut scene.borrow_mut().camera, time, frame_duration);
 
-            if image_result.is_err() {
-                log_error!(
-                    "Could not write image data to '{}' with error {:?}",
+            let start_time = std::time::Instant::now();
+            let (bytes, aovs) = self.render_image(&scene.borrow());
+            log_info!(
+                "Frame {} ({}/{}) took {} ms",
+                frame,
+                frame - first + 1,
+                last - first + 1,
+                start_time.elapsed().as_millis()
+            );
+
+            let path = directory.join(format!(
+                "frame_{:04}.{}",
+                frame,
+                self.options.output_format.extension()
+            ));
+            self.save_image(path.to_str().unwrap(), &bytes);
+            self.save_aovs(path.to_str().unwrap(), &aovs);
+        }
+
+        let elapsed = sequence_start_time.elapsed().as_millis();
+        log_info!(
+            "Rendering {} frames took {} ms, {} ms per frame",
+            last - first + 1,
+            elapsed,
+            elapsed / (last - first + 1) as u128
+        );
+    }
+
+    /// Image bytes in the layout `save_image` expects and the AOVs of every pixel, which are
+    /// empty if no AOVs were requested
+    fn render_image(self, scene: &Scene) -> (Vec<u8>, Vec<AovPixel>) {
+        match self.options.backend {
+            RendererBackend::CPU => return backend::cpu::render_scene(self, scene),
+            RendererBackend::GPU => {
+                return pollster::block_on(backend::gpu::render_scene_to_buffer(self, scene));
+            }
+        }
+    }
+
+    fn save_aovs(self, image_path: &str, aovs: &[AovPixel]) {
+        if self.options.aovs.is_empty() {
+            return;
+        }
+        aov::save(
+            image_path,
+            self.options.aovs,
+            aovs,
+            self.options.output_image_dimensions.0,
+            self.options.output_image_dimensions.1,
+        );
+    }
+
+    /// Writes the bytes returned by `render_image`, 16-bit RGBA for tone mapped output and
+    /// linear 32-bit float RGBA for HDR formats
+    fn save_image(self, path: &str, bytes: &[u8]) {
+        let (width, height) = self.options.output_image_dimensions;
+        let image_result: Result<(), String> = match self.options.output_format {
+            OutputFormat::ToneMapped => image::save_buffer(
+                path,
+                bytes,
+                width as u32,
+                height as u32,
+                image::ColorType::Rgba16,
+            )
+            .map_err(|error| error.to_string()),
+            OutputFormat::Exr { half } => {
+                let pixels = hdr_pixels(bytes);
+                let pixel = |x: usize, y: usize| pixels[y * width + x];
+                if half {
+                    exr::prelude::write_rgba_file(path, width, height, |x, y| {
+                        let [r, g, b, a] = pixel(x, y).map(f16::from_f32);
+                        return (r, g, b, a);
+                    })
+                } else {
+                    exr::prelude::write_rgba_file(path, width, height, |x, y| {
+                        let [r, g, b, a] = pixel(x, y);
+                        return (r, g, b, a);
+                    })
+                }
+                .map_err(|error| error.to_string())
+            }
+            OutputFormat::Hdr => {
+                let pixels = hdr_pixels(bytes);
+                let rgb = pixels
+                    .iter()
+                    .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                    .collect::<Vec<f32>>();
+                image::save_buffer_with_format(
                     path,
-                    image_result.err()
-                );
-            } else {
-                log_info!("Succesfully wrote image data to '{}'", path);
+                    bytemuck::cast_slice(&rgb),
+                    width as u32,
+                    height as u32,
+                    image::ColorType::Rgb32F,
+                    image::ImageFormat::Hdr,
+                )
+                .map_err(|error| error.to_string())
             }
+            OutputFormat::Pfm => {
+                let pixels = hdr_pixels(bytes);
+                // A negative scale means little endian, rows are stored from the bottom up
+                let mut data = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+                for row in pixels.chunks_exact(width).rev() {
+                    for pixel in row {
+                        for channel in &pixel[..3] {
+                            data.extend_from_slice(&channel.to_le_bytes());
+                        }
+                    }
+                }
+                std::fs::write(path, data).map_err(|error| error.to_string())
+            }
+        };
+
+        if let Err(error) = image_result {
+            log_error!(
+                "Could not write image data to '{}' with error {:?}",
+                path,
+                error
+            );
+        } else {
+            log_info!("Succesfully wrote image data to '{}'", path);
         }
     }
 }
 
+/// Float RGBA pixels of an HDR render, the bytes aren't necessarily aligned for f32
+fn hdr_pixels(bytes: &[u8]) -> Vec<[f32; 4]> {
+    return bytes
+        .chunks_exact(16)
+        .map(bytemuck::pod_read_unaligned::<[f32; 4]>)
+        .collect();
+}
+
 impl Default for Renderer {
     fn default() -> Self {
         return Self {
@@ -100,7 +388,110 @@ pub struct RendererOptions {
     pub output_image_dimensions: (usize, usize),
     pub output_image_path: Option<&'static str>,
     pub backend: RendererBackend,
+    pub integrator: Integrator,
+    /// Random numbers of the path tracing integrator, the others always use independent ones
+    pub sampler: SamplerType,
     pub is_realtime: bool,
+    /// Trace a few wavelengths per path instead of RGB, needed for dispersion
+    pub spectral: bool,
+    /// Render the camera path of the scene instead of a single image, `output_image_path` is
+    /// then the directory the frames are written to
+    pub frame_sequence: Option<FrameSequence>,
+    pub output_format: OutputFormat,
+    /// Auxiliary passes written as float EXR files next to the image
+    pub aovs: &'static [Aov],
+    /// Filter the image with an edge-avoiding À-trous wavelet filter guided by the albedo, normal
+    /// and depth AOVs. Toggled with N in the realtime window.
+    pub denoise: bool,
+    /// Exposure, white balance and tone mapping of the displayed image
+    pub post_process: PostProcess,
+    /// Lets pixels stop before `samples` once their noise is low enough
+    pub adaptive_sampling: Option<AdaptiveSampling>,
+    /// Stops taking samples after this many seconds per image, whatever has accumulated by
+    /// then is saved
+    pub time_budget: Option<f32>,
+}
+
+/// Pixels stop taking samples once the standard error of the mean of their luminance, relative
+/// to the luminance, falls below `noise_target`. `samples` stays the upper limit.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSampling {
+    pub noise_target: f32,
+    /// Samples every pixel takes before its error estimate is trusted
+    pub min_samples: usize,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        return Self {
+            noise_target: 0.01,
+            min_samples: 16,
+        };
+    }
+}
+
+impl AdaptiveSampling {
+    /// Added to the luminance the error is relative to, so black pixels can converge too
+    const LUMINANCE_FLOOR: f32 = 0.01;
+
+    /// `mean` and `m2` are the running mean and sum of squared differences of the luminance of
+    /// `count` samples. Mirrors `is_converged` in rt_compute.wgsl.
+    pub fn is_converged(&self, count: u32, mean: f32, m2: f32) -> bool {
+        if (count as usize) < self.min_samples {
+            return false;
+        }
+        let variance = m2 / (count - 1) as f32;
+        let standard_error = f32::sqrt(variance / count as f32);
+        return standard_error / (mean + Self::LUMINANCE_FLOOR) < self.noise_target;
+    }
+}
+
+/// How the rendered image is written to disk
+#[allow(dead_code)]
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    /// Tone mapped 16-bit RGBA in the format the extension of the output path names
+    #[default]
+    ToneMapped,
+    /// OpenEXR with the linear accumulation, `half` stores 16-bit instead of 32-bit floats
+    Exr { half: bool },
+    /// Radiance RGBE with the linear accumulation
+    Hdr,
+    /// Portable float map with the linear accumulation
+    Pfm,
+}
+
+impl OutputFormat {
+    /// Whether the linear radiance is written as is, without tone mapping or post processing
+    pub fn is_hdr(&self) -> bool {
+        return *self != OutputFormat::ToneMapped;
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::ToneMapped => return "png",
+            OutputFormat::Exr { .. } => return "exr",
+            OutputFormat::Hdr => return "hdr",
+            OutputFormat::Pfm => return "pfm",
+        }
+    }
+
+    /// Size of a pixel in the bytes returned by the backends
+    pub fn bytes_per_pixel(&self) -> usize {
+        if self.is_hdr() {
+            return 16;
+        }
+        return 8;
+    }
+}
+
+/// Numbered images `frame_0001.png`, `frame_0002.png`, ... taken along the camera path, the
+/// first frame is at the first keyframe. HDR output formats use their own extension.
+#[derive(Clone, Copy)]
+pub struct FrameSequence {
+    pub frame_rate: f32,
+    /// First and last frame to render, counting from 1. None renders the whole path.
+    pub frame_range: Option<(usize, usize)>,
 }
 
 impl Default for RendererOptions {
@@ -111,7 +502,17 @@ impl Default for RendererOptions {
             output_image_dimensions: (1920, 1080),
             output_image_path: None,
             backend: RendererBackend::default(),
+            integrator: Integrator::default(),
+            sampler: SamplerType::default(),
             is_realtime: true,
+            spectral: false,
+            frame_sequence: None,
+            output_format: OutputFormat::default(),
+            aovs: &[],
+            denoise: false,
+            post_process: PostProcess::default(),
+            adaptive_sampling: None,
+            time_budget: None,
         };
     }
 }
@@ -0,0 +1,133 @@
+use std::path::Path;
+
+use exr::prelude::*;
+
+use crate::{log_error, log_info};
+
+/// Auxiliary pass written next to the beauty image, taken at the first surface the camera sees
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aov {
+    /// Base color with textures applied
+    Albedo,
+    /// World space shading normal after normal mapping, facing the camera
+    Normal,
+    /// Distance from the camera to the hit along the camera ray
+    Depth,
+    /// World space position of the hit
+    Position,
+    Uv,
+    MaterialId,
+    /// Index of the `o` line of the OBJ file the triangle belongs to
+    ObjectId,
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => return "albedo",
+            Aov::Normal => return "normal",
+            Aov::Depth => return "depth",
+            Aov::Position => return "position",
+            Aov::Uv => return "uv",
+            Aov::MaterialId => return "material_id",
+            Aov::ObjectId => return "object_id",
+        }
+    }
+
+    /// Names of the channels in the EXR file
+    fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo | Aov::Normal | Aov::Position => return &["R", "G", "B"],
+            Aov::Depth => return &["Z"],
+            Aov::Uv => return &["R", "G"],
+            Aov::MaterialId | Aov::ObjectId => return &["Y"],
+        }
+    }
+}
+
+/// Every AOV of a pixel, laid out like the four AOV textures of the GPU backend. All values are
+/// averaged over the samples except the IDs, which come from the first sample that hits.
+/// Pixels where the camera rays escape are zero with IDs of -1.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct AovPixel {
+    pub albedo: [f32; 3],
+    pub depth: f32,
+    pub normal: [f32; 3],
+    pub material_id: f32,
+    pub position: [f32; 3],
+    pub object_id: f32,
+    pub uv: [f32; 2],
+    _pad: [f32; 2],
+}
+
+impl Default for AovPixel {
+    fn default() -> Self {
+        return Self {
+            albedo: [0.0; 3],
+            depth: 0.0,
+            normal: [0.0; 3],
+            material_id: -1.0,
+            position: [0.0; 3],
+            object_id: -1.0,
+            uv: [0.0; 2],
+            _pad: [0.0; 2],
+        };
+    }
+}
+
+impl AovPixel {
+    fn values(&self, aov: Aov) -> &[f32] {
+        match aov {
+            Aov::Albedo => return &self.albedo,
+            Aov::Normal => return &self.normal,
+            Aov::Depth => return std::slice::from_ref(&self.depth),
+            Aov::Position => return &self.position,
+            Aov::Uv => return &self.uv,
+            Aov::MaterialId => return std::slice::from_ref(&self.material_id),
+            Aov::ObjectId => return std::slice::from_ref(&self.object_id),
+        }
+    }
+}
+
+/// Writes every AOV in `aovs` as a float EXR next to `image_path`, `out.png` gets
+/// `out.albedo.exr`, `out.normal.exr` and so on
+pub fn save(image_path: &str, aovs: &[Aov], pixels: &[AovPixel], width: usize, height: usize) {
+    for aov in aovs {
+        let path = Path::new(image_path).with_extension(format!("{}.exr", aov.name()));
+        let channels = aov
+            .channels()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let samples = pixels
+                    .iter()
+                    .map(|pixel| pixel.values(*aov)[i])
+                    .collect::<Vec<f32>>();
+                return AnyChannel::new(*name, FlatSamples::F32(samples));
+            })
+            .collect::<Vec<AnyChannel<FlatSamples>>>();
+        let layer = Layer::new(
+            (width, height),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(SmallVec::from_vec(channels)),
+        );
+
+        if let Err(error) = Image::from_layer(layer).write().to_file(&path) {
+            log_error!(
+                "Could not write the {} AOV to '{}' with error {:?}",
+                aov.name(),
+                path.display(),
+                error
+            );
+        } else {
+            log_info!(
+                "Succesfully wrote the {} AOV to '{}'",
+                aov.name(),
+                path.display()
+            );
+        }
+    }
+}
@@ -8,3 +8,54 @@ pub enum RendererBackend {
     GPU,
     CPU,
 }
+
+/// Light transport algorithm used for rendering
+#[allow(dead_code)]
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum Integrator {
+    #[default]
+    PathTracing,
+    /// Bidirectional path tracing, only supported by the CPU backend. Participating media and
+    /// subsurface scattering are ignored.
+    Bdpt,
+    /// Stochastic progressive photon mapping, only supported by the CPU backend. Every sample
+    /// is an iteration shooting `photons_per_iteration` photons, the gather radius starts at
+    /// `initial_radius` in scene units and shrinks as photons are collected
+    Sppm {
+        photons_per_iteration: usize,
+        initial_radius: f32,
+    },
+}
+
+/// Where the random numbers of the paths come from. Every decision along a path, like the
+/// position in the pixel or the direction of a bounce, gets its own dimension of the sample.
+/// Only used by path tracing, the other integrators use independent random numbers.
+#[allow(dead_code)]
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum SamplerType {
+    /// Uncorrelated random numbers
+    Independent,
+    /// Jittered strata, every sample of a pixel falls into a different stratum of each
+    /// dimension
+    Stratified,
+    /// Owen scrambled Sobol sequence, shuffled and scrambled independently per pixel and
+    /// dimension
+    #[default]
+    Sobol,
+    /// Owen scrambled Sobol sequence spread over the pixels along a scrambled Morton curve.
+    /// Neighboring pixels get complementary samples, so the remaining error looks like blue
+    /// noise instead of white noise. Works best with a power of two samples.
+    BlueNoise,
+}
+
+impl SamplerType {
+    /// Index of the sampler in rt_compute.wgsl
+    pub fn id(&self) -> u32 {
+        match self {
+            SamplerType::Independent => return 0,
+            SamplerType::Stratified => return 1,
+            SamplerType::Sobol => return 2,
+            SamplerType::BlueNoise => return 3,
+        }
+    }
+}
@@ -1,68 +1,243 @@
 use crate::log_info;
-use crate::math::rand_f32;
-use crate::math::vec::*;
+use crate::math::rng_seed;
 use crate::math::vec3::*;
 use crate::renderer::Renderer;
+use crate::renderer::aov::AovPixel;
+use crate::renderer::backend::Integrator;
 use crate::scene::Scene;
+use crate::spectrum::Wavelengths;
+use camera::FilmCamera;
 use ray::Ray;
 use rayon::prelude::*;
+use sampler::Sampler;
+use std::time::Instant;
 
+mod aov;
+mod bdpt;
+mod bsdf;
+mod camera;
+mod denoise;
+mod lens_effects;
 mod ray;
+mod sampler;
+mod sppm;
+
+/// Samples per pixel between checks of the time budget and the noise of the pixels
+const ROUND_SAMPLES: usize = 8;
 
 // TODO: A simple progress indicator for rendering would be nice
-pub fn render_scene(renderer: Renderer, scene: &Scene) -> Vec<u8> {
+/// Returns linear float RGBA for HDR output formats and 16-bit sRGB RGBA otherwise, along with
+/// the AOVs if any were requested or the denoiser needed them
+pub fn render_scene(renderer: Renderer, scene: &Scene) -> (Vec<u8>, Vec<AovPixel>) {
     log_info!(
         "Using {} threads for rendering",
         rayon::current_num_threads()
     );
 
+    let colors = match renderer.options.integrator {
+        Integrator::PathTracing => trace_paths(renderer, scene),
+        Integrator::Bdpt => bdpt::render(renderer, scene),
+        Integrator::Sppm {
+            photons_per_iteration,
+            initial_radius,
+        } => sppm::render(renderer, scene, photons_per_iteration, initial_radius),
+    };
+
+    let aovs = if renderer.options.aovs.is_empty() && !renderer.options.denoise {
+        Vec::new()
+    } else {
+        aov::render(renderer, scene)
+    };
+
+    let colors = if renderer.options.denoise {
+        let (width, height) = renderer.options.output_image_dimensions;
+        denoise::denoise(colors, &aovs, width, height)
+    } else {
+        colors
+    };
+
+    if renderer.options.output_format.is_hdr() {
+        let pixels = colors
+            .into_iter()
+            .map(|color| [color.x(), color.y(), color.z(), 1.0])
+            .collect::<Vec<[f32; 4]>>();
+        return (bytemuck::cast_slice(&pixels).to_vec(), aovs);
+    }
+
+    let post_process = renderer.options.post_process;
+    let colors = if post_process.has_lens_effects() {
+        let (width, height) = renderer.options.output_image_dimensions;
+        let kernel = post_process.glare_kernel(&scene.camera);
+        lens_effects::apply(colors, post_process, kernel, width, height)
+    } else {
+        colors
+    };
+
+    let pixels = colors
+        .into_iter()
+        .map(|color| {
+            let rgb: [u16; 3] = post_process.apply(color).into();
+            return [rgb[0], rgb[1], rgb[2], u16::MAX];
+        })
+        .collect::<Vec<[u16; 4]>>();
+    return (bytemuck::cast_slice(&pixels).to_vec(), aovs);
+}
+
+/// Unidirectional path tracing, returns the linear color of every pixel.
+///
+/// Samples are taken in rounds so that the time budget can be checked and converged pixels can
+/// be left out with adaptive sampling, the remaining pixels are spread over the threads again
+/// every round.
+fn trace_paths(renderer: Renderer, scene: &Scene) -> Vec<Vec3f> {
     let width = renderer.options.output_image_dimensions.0;
     let height = renderer.options.output_image_dimensions.1;
+    let samples = renderer.options.samples;
+    let adaptive_sampling = renderer.options.adaptive_sampling;
+    let camera = FilmCamera::new(scene, width, height);
 
-    let block_size = (width * height) / rayon::current_num_threads();
-
-    (0..width * height)
-        .into_par_iter()
-        .by_uniform_blocks(block_size)
-        .map(|index: usize| {
-            let mut rng_state: u32 =
-                987612486u32.wrapping_mul((index as u32).wrapping_add(87636354u32));
-            let mut final_color = Vec3f::new(0.0, 0.0, 0.0);
-            let x: usize = index % width;
-            let y: usize = height - (index / width);
-            let screen_x =
-                (((x as f32 / width as f32) * 2.0) - 1.0) * (width as f32 / height as f32);
-            let screen_y = ((y as f32 / height as f32) * 2.0) - 1.0;
-
-            for _ in 0..renderer.options.samples {
-                let jitter = Vec3f::new(
-                    rand_f32(&mut rng_state) * 2.0 - 1.0,
-                    rand_f32(&mut rng_state) * 2.0 - 1.0,
-                    0.0,
-                ) * 0.0005;
-                let direction = (scene.camera.look_at
-                    * Vec3f::new(-screen_x + jitter.x(), screen_y + jitter.y(), 1.0))
-                .normalized();
-                let mut ray = Ray::new(
-                    // Hard coded camera position
-                    scene.camera.position,
-                    direction,
-                );
-
-                final_color += Ray::trace(
-                    &mut ray,
-                    renderer.options.max_ray_depth,
-                    &scene,
-                    &mut rng_state,
-                );
-            }
-
-            final_color /= renderer.options.samples as f32;
-            final_color = Vec3f::linear_to_srgb(final_color);
-
-            let rgb: [u8; 3] = final_color.into();
-            return [rgb[0], rgb[1], rgb[2], 255];
-        })
-        .collect::<Vec<[u8; 4]>>()
-        .into_flattened()
+    let mut pixels = (0..width * height)
+        .map(|index| PixelEstimate::new(rng_seed(index as u32)))
+        .collect::<Vec<PixelEstimate>>();
+
+    let start_time = Instant::now();
+    let mut samples_taken = 0;
+    while samples_taken < samples {
+        let round_samples = match (adaptive_sampling, renderer.options.time_budget) {
+            (Some(adaptive_sampling), _) if samples_taken == 0 => adaptive_sampling.min_samples,
+            (None, None) => samples,
+            _ => ROUND_SAMPLES,
+        };
+        let round_samples = usize::min(round_samples, samples - samples_taken);
+
+        pixels
+            .par_iter_mut()
+            .enumerate()
+            .filter(|(_, pixel)| !pixel.is_converged)
+            .for_each(|(index, pixel)| {
+                let mut sampler = sampler::new(renderer.options.sampler, samples);
+                for _ in 0..round_samples {
+                    sampler.start_sample(
+                        (index % width) as u32,
+                        (index / width) as u32,
+                        pixel.count,
+                    );
+                    let color = sample_pixel(
+                        renderer,
+                        scene,
+                        &camera,
+                        index,
+                        sampler.as_ref(),
+                        &mut pixel.rng_state,
+                    );
+                    pixel.add(color);
+                }
+                if let Some(adaptive_sampling) = adaptive_sampling {
+                    pixel.is_converged =
+                        adaptive_sampling.is_converged(pixel.count, pixel.mean, pixel.m2);
+                }
+            });
+        samples_taken += round_samples;
+
+        if let Some(time_budget) = renderer.options.time_budget
+            && samples_taken < samples
+            && start_time.elapsed().as_secs_f32() >= time_budget
+        {
+            log_info!("Time budget ran out after {} samples", samples_taken);
+            break;
+        }
+        if pixels.iter().all(|pixel| pixel.is_converged) {
+            break;
+        }
+    }
+
+    if adaptive_sampling.is_some() {
+        let total_samples = pixels
+            .iter()
+            .map(|pixel| pixel.count as usize)
+            .sum::<usize>();
+        let converged = pixels.iter().filter(|pixel| pixel.is_converged).count();
+        log_info!(
+            "Took {:.1} samples per pixel on average, {:.1}% of the pixels converged",
+            total_samples as f32 / pixels.len() as f32,
+            100.0 * converged as f32 / pixels.len() as f32
+        );
+    }
+
+    return pixels
+        .into_iter()
+        .map(|pixel| pixel.color / f32::max(pixel.count as f32, 1.0))
+        .collect::<Vec<Vec3f>>();
+}
+
+/// Traces one path through pixel `index`
+fn sample_pixel(
+    renderer: Renderer,
+    scene: &Scene,
+    camera: &FilmCamera,
+    index: usize,
+    sampler: &dyn Sampler,
+    rng_state: &mut u32,
+) -> Vec3f {
+    let time = camera.sample_time(sampler.get_1d(sampler::TIME_DIMENSION));
+    let Some(mut ray) = camera.jittered_ray(
+        index,
+        time,
+        sampler.get_2d(sampler::PIXEL_DIMENSION),
+        sampler.get_2d(sampler::LENS_DIMENSION),
+    ) else {
+        return Vec3f::from(0.0);
+    };
+
+    let wavelengths = if renderer.options.spectral {
+        Some(Wavelengths::sample(
+            sampler.get_1d(sampler::WAVELENGTH_DIMENSION),
+        ))
+    } else {
+        None
+    };
+    let color = Ray::trace(
+        &mut ray,
+        renderer.options.max_ray_depth,
+        scene,
+        wavelengths,
+        sampler,
+        rng_state,
+    );
+    match wavelengths {
+        Some(wavelengths) => return wavelengths.to_rgb(color),
+        None => return color,
+    }
+}
+
+/// Running estimate of a pixel, the luminance variance uses Welford's algorithm
+struct PixelEstimate {
+    color: Vec3f,
+    count: u32,
+    mean: f32,
+    m2: f32,
+    is_converged: bool,
+    rng_state: u32,
+}
+
+impl PixelEstimate {
+    fn new(rng_state: u32) -> Self {
+        return Self {
+            color: Vec3f::from(0.0),
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            is_converged: false,
+            rng_state,
+        };
+    }
+
+    fn add(&mut self, color: Vec3f) {
+        self.color += color;
+        self.count += 1;
+
+        let luminance = 0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z();
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
 }
@@ -0,0 +1,107 @@
+use super::camera::FilmCamera;
+use super::ray::{HitInfo, Ray};
+use super::sampler;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+use crate::renderer::Renderer;
+use crate::renderer::aov::AovPixel;
+use crate::scene::Scene;
+use rayon::prelude::*;
+
+/// First hit data of every pixel, with one camera ray per sample so edges are antialiased like
+/// the image. Surfaces that are more transparent than opaque are looked through.
+pub fn render(renderer: Renderer, scene: &Scene) -> Vec<AovPixel> {
+    let width = renderer.options.output_image_dimensions.0;
+    let height = renderer.options.output_image_dimensions.1;
+    let samples = renderer.options.samples;
+    let camera = FilmCamera::new(scene, width, height);
+
+    let block_size = (width * height) / rayon::current_num_threads();
+
+    (0..width * height)
+        .into_par_iter()
+        .by_uniform_blocks(block_size)
+        .map(|index: usize| {
+            // The lens and shutter are sampled with the same values as the image
+            let mut sampler = sampler::new(renderer.options.sampler, samples);
+            let mut pixel = AovPixel::default();
+            let mut albedo = Vec3f::from(0.0);
+            let mut normal = Vec3f::from(0.0);
+            let mut position = Vec3f::from(0.0);
+            let mut uv = [0.0f32; 2];
+            let mut depth = 0.0;
+
+            for sample_index in 0..samples {
+                sampler.start_sample(
+                    (index % width) as u32,
+                    (index / width) as u32,
+                    sample_index as u32,
+                );
+                let time = camera.sample_time(sampler.get_1d(sampler::TIME_DIMENSION));
+                let Some(ray) = camera.jittered_ray(
+                    index,
+                    time,
+                    sampler.get_2d(sampler::PIXEL_DIMENSION),
+                    sampler.get_2d(sampler::LENS_DIMENSION),
+                ) else {
+                    continue;
+                };
+                let Some(hit_info) = first_hit(ray, scene, renderer.options.max_ray_depth) else {
+                    continue;
+                };
+
+                let material = scene.material(hit_info.material_id);
+                albedo += Ray::surface_material(scene, material, &hit_info, None).base_color;
+                normal += hit_info.normal;
+                position += hit_info.point;
+                uv[0] += hit_info.uv.x();
+                uv[1] += hit_info.uv.y();
+                depth += hit_info.distance;
+                if pixel.material_id < 0.0 {
+                    pixel.material_id = hit_info.material_id as f32;
+                    pixel.object_id = scene.tris[hit_info.tri_index as usize].object_id as f32;
+                }
+            }
+
+            let inv_samples = 1.0 / samples as f32;
+            pixel.albedo = (albedo * inv_samples).data;
+            pixel.normal = (normal * inv_samples).data;
+            pixel.position = (position * inv_samples).data;
+            pixel.uv = [uv[0] * inv_samples, uv[1] * inv_samples];
+            pixel.depth = depth * inv_samples;
+            return pixel;
+        })
+        .collect::<Vec<AovPixel>>()
+}
+
+/// First surface along `ray` that is at least half opaque, with its normal map applied.
+/// `distance` is measured from the origin of `ray`.
+fn first_hit(ray: Ray, scene: &Scene, max_surfaces: usize) -> Option<HitInfo> {
+    let mut ray = ray;
+    let mut distance = 0.0;
+    for _ in 0..max_surfaces {
+        let mut hit_info = HitInfo::default();
+        Ray::traverse_bvh(&ray, scene, &mut hit_info);
+        if !hit_info.has_hit {
+            return None;
+        }
+
+        let material = scene.material(hit_info.material_id);
+        distance += hit_info.distance;
+        if Ray::transparency_at(scene, material, hit_info.uv) < 0.5 {
+            ray = Ray::new(
+                hit_info.point + ray.direction * 0.0001,
+                ray.direction,
+                ray.time,
+            );
+            continue;
+        }
+
+        if material.normal_tex_id != u32::MAX {
+            Ray::apply_normal_map(scene, material, &mut hit_info);
+        }
+        hit_info.distance = distance;
+        return Some(hit_info);
+    }
+    return None;
+}
@@ -0,0 +1,886 @@
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::bsdf::{self, Bsdf, Frame, Lobe};
+use super::camera::FilmCamera;
+use super::ray::{HitInfo, Ray};
+use crate::math::vec::*;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+use crate::math::{rand_f32, rng_seed};
+use crate::renderer::Renderer;
+use crate::scene::{Material, Scene, Triangle};
+use crate::spectrum::RGB_WAVELENGTHS;
+use rayon::prelude::*;
+
+/// Bidirectional path tracing. Every sample traces one subpath from the camera and one from a
+/// light, then connects every vertex of the one to every vertex of the other. Each connection is
+/// weighted against all other ways of sampling the same path with the balance heuristic, so
+/// caustics and light coming through small openings converge much faster than with the path
+/// tracer.
+///
+/// Participating media and subsurface scattering are ignored, surfaces with subsurface
+/// scattering are shaded like their base. The sky is only found by camera subpaths, with the
+/// sun sampled directly. Random numbers come from an independent generator per pixel, the
+/// sampler chosen in the render options only drives the path tracer.
+///
+/// https://graphics.stanford.edu/papers/veach_thesis/thesis.pdf (chapter 10)
+pub fn render(renderer: Renderer, scene: &Scene) -> Vec<Vec3f> {
+    let width = renderer.options.output_image_dimensions.0;
+    let height = renderer.options.output_image_dimensions.1;
+    let samples = renderer.options.samples;
+
+    let integrator = Bdpt {
+        scene,
+        camera: FilmCamera::new(scene, width, height),
+        lights: LightDistribution::new(scene),
+        max_depth: renderer.options.max_ray_depth,
+    };
+
+    // Connections of light subpaths to the camera can land on any pixel
+    let splats: Vec<AtomicU32> = (0..width * height * 3).map(|_| AtomicU32::new(0)).collect();
+
+    let block_size = (width * height) / rayon::current_num_threads();
+
+    let mut colors = (0..width * height)
+        .into_par_iter()
+        .by_uniform_blocks(block_size)
+        .map(|index: usize| {
+            let mut rng_state = rng_seed(index as u32);
+            let mut camera_path: Vec<Vertex> = Vec::with_capacity(integrator.max_depth + 2);
+            let mut light_path: Vec<Vertex> = Vec::with_capacity(integrator.max_depth + 1);
+            let mut final_color = Vec3f::new(0.0, 0.0, 0.0);
+
+            for _ in 0..samples {
+                camera_path.clear();
+                light_path.clear();
+                final_color += integrator.sample(
+                    index,
+                    &mut camera_path,
+                    &mut light_path,
+                    &splats,
+                    &mut rng_state,
+                );
+            }
+
+            return final_color / samples as f32;
+        })
+        .collect::<Vec<Vec3f>>();
+
+    for (i, color) in colors.iter_mut().enumerate() {
+        let splat = Vec3f::new(
+            f32::from_bits(splats[i * 3].load(Ordering::Relaxed)),
+            f32::from_bits(splats[i * 3 + 1].load(Ordering::Relaxed)),
+            f32::from_bits(splats[i * 3 + 2].load(Ordering::Relaxed)),
+        );
+        *color += splat / samples as f32;
+    }
+
+    return colors;
+}
+
+/// Offset along the direction of rays leaving a surface, same as the path tracer
+pub(super) const RAY_OFFSET: f32 = 0.0001;
+
+struct Bdpt<'a> {
+    scene: &'a Scene,
+    camera: FilmCamera,
+    lights: LightDistribution,
+    max_depth: usize,
+}
+
+impl Bdpt<'_> {
+    /// Estimates the radiance arriving at the camera through pixel `index`. Connections to the
+    /// camera are added to `splats` instead since they can land on any pixel.
+    fn sample(
+        &self,
+        index: usize,
+        camera_path: &mut Vec<Vertex>,
+        light_path: &mut Vec<Vertex>,
+        splats: &[AtomicU32],
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        // Both subpaths and all connections between them see the scene at the same time
+        let time = self.camera.sample_time(rand_f32(rng_state));
+        let mut radiance = self.camera_subpath(index, time, camera_path, rng_state);
+        self.light_subpath(time, light_path, rng_state);
+
+        // The sun is too small to be hit by chance, so it's sampled from every camera vertex
+        if self.scene.sky.has_sun() {
+            for vertex in camera_path.iter().take(self.max_depth + 1).skip(1) {
+                radiance += self.sample_sun(vertex, time, rng_state);
+            }
+        }
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // A light vertex connected to the camera is the same path as the camera seeing
+                // the light, which the camera subpath already found
+                if s + t < 2 || s + t - 2 > self.max_depth || (s == 1 && t == 1) {
+                    continue;
+                }
+
+                if t == 1 {
+                    if let Some((pixel, contribution)) =
+                        self.connect_to_camera(light_path, s, time, rng_state)
+                    {
+                        for i in 0..3 {
+                            atomic_add(&splats[pixel * 3 + i], contribution.data[i]);
+                        }
+                    }
+                } else {
+                    radiance += self.connect(camera_path, light_path, s, t, time, rng_state);
+                }
+            }
+        }
+
+        return radiance;
+    }
+
+    /// Traces a subpath from the camera through pixel `index`. Returns the radiance of the sky
+    /// if the subpath escapes the scene.
+    fn camera_subpath(
+        &self,
+        index: usize,
+        time: f32,
+        path: &mut Vec<Vertex>,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let Some(ray) = self.camera.sample_ray(
+            index,
+            time,
+            Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+            Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+        ) else {
+            return Vec3f::from(0.0);
+        };
+        let mut camera_vertex = Vertex::endpoint(
+            VertexKind::Camera,
+            ray.origin,
+            self.camera.axis,
+            Vec3f::from(0.0),
+            1.0,
+        );
+        // Keeps light paths connected to the camera out of the MIS weights
+        camera_vertex.delta = !self.camera.has_importance();
+        path.push(camera_vertex);
+        let pdf_dir = self.camera.pdf_dir(ray.origin, ray.direction);
+        return self.random_walk(
+            ray,
+            Vec3f::from(1.0),
+            pdf_dir,
+            self.max_depth + 2,
+            false,
+            path,
+            rng_state,
+        );
+    }
+
+    /// Traces a subpath from a point on an emitter, leaving it in a cosine distributed
+    /// direction
+    fn light_subpath(&self, time: f32, path: &mut Vec<Vertex>, rng_state: &mut u32) {
+        let Some(light) = self.lights.sample_light(self.scene, rng_state) else {
+            return;
+        };
+
+        let frame = Frame::new(light.normal);
+        let direction = frame.to_world(bsdf::cosine_sample_hemisphere(Vec2f::new(
+            rand_f32(rng_state),
+            rand_f32(rng_state),
+        )));
+        let cos = Vec3f::dot(direction, light.normal);
+        if cos <= 0.0 {
+            return;
+        }
+        let pdf_dir = cos / PI;
+        let beta = light.emission * (cos / (light.pdf_fwd * pdf_dir));
+        let origin = light.point + direction * RAY_OFFSET;
+        path.push(light);
+
+        self.random_walk(
+            Ray::new(origin, direction, time),
+            beta,
+            pdf_dir,
+            self.max_depth + 1,
+            true,
+            path,
+            rng_state,
+        );
+    }
+
+    /// Extends `path` by following `ray` through the scene until it escapes, is absorbed or the
+    /// path has `max_vertices` vertices. `pdf_dir` is the solid angle density `ray` was sampled
+    /// with. Light subpaths are `adjoint`, they carry importance instead of radiance. Returns
+    /// the sky radiance picked up by a camera subpath that escapes.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk(
+        &self,
+        mut ray: Ray,
+        mut beta: Vec3f,
+        mut pdf_dir: f32,
+        max_vertices: usize,
+        adjoint: bool,
+        path: &mut Vec<Vertex>,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let scene = self.scene;
+
+        while path.len() < max_vertices {
+            let mut hit_info = HitInfo::default();
+            Ray::traverse_bvh(&ray, scene, &mut hit_info);
+
+            if !hit_info.has_hit {
+                if adjoint {
+                    return Vec3f::from(0.0);
+                }
+                let sky = &scene.sky;
+                if !sky.in_sun(ray.direction) {
+                    return beta * sky.radiance(ray.direction, false);
+                }
+                // The sun was also sampled from the previous vertex unless it scattered
+                // specularly
+                let prev = path.last().unwrap();
+                let sun_weight =
+                    if sky.has_sun() && prev.kind == VertexKind::Surface && pdf_dir > 0.0 {
+                        balance_heuristic(pdf_dir, 1.0 / sky.sun_solid_angle())
+                    } else {
+                        1.0
+                    };
+                return beta * sky.sun_radiance() * sun_weight;
+            }
+
+            let hit_material = scene.material(hit_info.material_id);
+            if Ray::transparency_at(scene, hit_material, hit_info.uv) < rand_f32(rng_state) {
+                ray = Ray::new(
+                    hit_info.point + ray.direction * RAY_OFFSET,
+                    ray.direction,
+                    ray.time,
+                );
+                continue;
+            }
+
+            let mut vertex = Vertex::surface(scene, hit_info, ray.direction.reversed(), beta);
+            vertex.pdf_fwd = to_area(pdf_dir, path.last().unwrap().point, &vertex);
+
+            if path.len() + 1 >= max_vertices {
+                path.push(vertex);
+                break;
+            }
+
+            let Some((wi, sample)) = vertex.sample(rng_state) else {
+                path.push(vertex);
+                break;
+            };
+            beta *= sample.weight;
+            if adjoint && sample.lobe.contains(Lobe::TRANSMISSION) {
+                // Radiance is scaled by the squared relative IOR when refracting, importance
+                // isn't
+                let eta = vertex.eta(vertex.wo);
+                beta *= eta * eta;
+            }
+
+            let prev = path.last_mut().unwrap();
+            if sample.lobe.contains(Lobe::SPECULAR) {
+                vertex.delta = true;
+                pdf_dir = 0.0;
+                prev.pdf_rev = 0.0;
+            } else {
+                pdf_dir = sample.pdf;
+                prev.pdf_rev = to_area(vertex.pdf(wi, vertex.wo), vertex.point, prev);
+            }
+            ray = Ray::new(vertex.point + wi * RAY_OFFSET, wi, ray.time);
+            path.push(vertex);
+
+            if Vec3f::dot(beta, Vec3f::from(1.0)) <= 0.0 {
+                break;
+            }
+        }
+
+        return Vec3f::from(0.0);
+    }
+
+    /// Contribution of the path made of the first `s` light subpath vertices and the first `t`
+    /// camera subpath vertices, for `t` > 1
+    fn connect(
+        &self,
+        camera_path: &[Vertex],
+        light_path: &[Vertex],
+        s: usize,
+        t: usize,
+        time: f32,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let pt = &camera_path[t - 1];
+        let contribution: Vec3f;
+        let mut sampled: Option<Vertex> = None;
+        if s == 0 {
+            // The camera subpath found an emitter on its own
+            contribution = pt.beta * pt.emission;
+        } else if s == 1 {
+            // Connect to a new point on a light, sampled the same way light subpaths start
+            if !pt.connectible {
+                return Vec3f::from(0.0);
+            }
+            let Some(light) = self.lights.sample_light(self.scene, rng_state) else {
+                return Vec3f::from(0.0);
+            };
+            let to_light = light.point - pt.point;
+            let distance_sqr = Vec3f::dot(to_light, to_light);
+            let light_dir = to_light / f32::sqrt(distance_sqr);
+            let cos_light = Vec3f::dot(light.normal, light_dir.reversed());
+            if cos_light <= 0.0 {
+                return Vec3f::from(0.0);
+            }
+            let unoccluded = pt.beta
+                * pt.scatter(pt.wo, light_dir, false)
+                * light.emission
+                * (cos_light / (distance_sqr * light.pdf_fwd));
+            if Vec3f::dot(unoccluded, Vec3f::from(1.0)) <= 0.0 {
+                return Vec3f::from(0.0);
+            }
+            contribution =
+                unoccluded * visibility(self.scene, pt.point, light.point, time, rng_state);
+            sampled = Some(light);
+        } else {
+            let qs = &light_path[s - 1];
+            if !qs.connectible || !pt.connectible {
+                return Vec3f::from(0.0);
+            }
+            let offset = pt.point - qs.point;
+            let distance_sqr = Vec3f::dot(offset, offset);
+            let direction = offset / f32::sqrt(distance_sqr);
+            let unoccluded = qs.beta
+                * qs.scatter(qs.wo, direction, true)
+                * pt.scatter(pt.wo, direction.reversed(), false)
+                * pt.beta
+                / distance_sqr;
+            if Vec3f::dot(unoccluded, Vec3f::from(1.0)) <= 0.0 {
+                return Vec3f::from(0.0);
+            }
+            contribution = unoccluded * visibility(self.scene, pt.point, qs.point, time, rng_state);
+        }
+
+        if Vec3f::dot(contribution, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        let weight = self.mis_weight(camera_path, light_path, sampled.as_ref(), s, t);
+        return contribution * weight;
+    }
+
+    /// Connects light subpath vertex `s - 1` to the camera. Returns the pixel the connection
+    /// lands on and its contribution.
+    fn connect_to_camera(
+        &self,
+        light_path: &[Vertex],
+        s: usize,
+        time: f32,
+        rng_state: &mut u32,
+    ) -> Option<(usize, Vec3f)> {
+        let qs = &light_path[s - 1];
+        if !qs.connectible {
+            return None;
+        }
+
+        // The camera subpath started from another point on the aperture
+        let lens_point = self.camera.sample_lens(
+            self.camera.position,
+            Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+        );
+        let offset = lens_point - qs.point;
+        let distance_sqr = Vec3f::dot(offset, offset);
+        let direction = offset / f32::sqrt(distance_sqr);
+        let camera_dir = direction.reversed();
+        let pixel = self.camera.pixel(lens_point, camera_dir)?;
+
+        let cos_camera = Vec3f::dot(camera_dir, self.camera.axis);
+        let contribution = qs.beta
+            * qs.scatter(qs.wo, direction, true)
+            * (self.camera.importance(lens_point, camera_dir) * cos_camera / distance_sqr);
+        let sum = Vec3f::dot(contribution, Vec3f::from(1.0));
+        if sum.is_nan() || sum <= 0.0 {
+            return None;
+        }
+        let contribution =
+            contribution * visibility(self.scene, qs.point, lens_point, time, rng_state);
+        if Vec3f::dot(contribution, Vec3f::from(1.0)) <= 0.0 {
+            return None;
+        }
+
+        let camera_vertex = Vertex::endpoint(
+            VertexKind::Camera,
+            lens_point,
+            self.camera.axis,
+            Vec3f::from(0.0),
+            1.0,
+        );
+        let weight = self.mis_weight(&[camera_vertex], light_path, None, s, 1);
+        return Some((pixel, contribution * weight));
+    }
+
+    /// Balance heuristic weight of the path made of the first `s` light and `t` camera subpath
+    /// vertices, against all other connections that produce the same path. `sampled` is the
+    /// light vertex used instead of the first one of the light subpath when `s` is 1.
+    ///
+    /// The densities of each vertex relative to the technique used are found by walking
+    /// outwards from the connection and multiplying ratios of reverse and forward densities.
+    fn mis_weight(
+        &self,
+        camera_path: &[Vertex],
+        light_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let light_vertex = |i: usize| -> &Vertex {
+            match sampled {
+                Some(vertex) if i == 0 => return vertex,
+                _ => return &light_path[i],
+            }
+        };
+
+        // Forward density, reverse density and delta flag of every vertex
+        let mut camera_pdfs: Vec<(f32, f32, bool)> = camera_path[..t]
+            .iter()
+            .map(|vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta))
+            .collect();
+        let mut light_pdfs: Vec<(f32, f32, bool)> = (0..s)
+            .map(|i| {
+                let vertex = light_vertex(i);
+                return (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+            })
+            .collect();
+
+        let pt = &camera_path[t - 1];
+        let pt_minus = if t > 1 {
+            Some(&camera_path[t - 2])
+        } else {
+            None
+        };
+        let qs = if s > 0 {
+            Some(light_vertex(s - 1))
+        } else {
+            None
+        };
+        let qs_minus = if s > 1 {
+            Some(&light_path[s - 2])
+        } else {
+            None
+        };
+
+        // The reverse densities around the connection depend on the vertices on the other side
+        // of it, and the connected vertices can't be specular
+        camera_pdfs[t - 1].2 = false;
+        camera_pdfs[t - 1].1 = match qs {
+            Some(qs) => self.pdf_area(qs, qs_minus, pt),
+            None => self.lights.density(pt.tri_index),
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera_pdfs[t - 2].1 = match qs {
+                Some(qs) => self.pdf_area(pt, Some(qs), pt_minus),
+                None => pdf_light(pt, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light_pdfs[s - 1].2 = false;
+            light_pdfs[s - 1].1 = self.pdf_area(pt, pt_minus, qs);
+            if let Some(qs_minus) = qs_minus {
+                light_pdfs[s - 2].1 = self.pdf_area(qs, Some(pt), qs_minus);
+            }
+        }
+
+        // Delta densities are stored as zero, they cancel out in the ratios
+        let remap = |pdf: f32| -> f32 {
+            if pdf != 0.0 {
+                return pdf;
+            } else {
+                return 1.0;
+            }
+        };
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+            if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+            // Area lights are never delta
+            let prev_delta = i > 0 && light_pdfs[i - 1].2;
+            if !light_pdfs[i].2 && !prev_delta {
+                sum += ratio;
+            }
+        }
+
+        return 1.0 / (1.0 + sum);
+    }
+
+    /// Density per unit area at `next` of `vertex` scattering towards it, after arriving from
+    /// `prev`
+    fn pdf_area(&self, vertex: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let direction = (next.point - vertex.point).normalized();
+        let pdf = match vertex.kind {
+            VertexKind::Camera => self.camera.pdf_dir(vertex.point, direction),
+            VertexKind::Light => return pdf_light(vertex, next),
+            VertexKind::Surface => {
+                let Some(prev) = prev else {
+                    return 0.0;
+                };
+                vertex.pdf((prev.point - vertex.point).normalized(), direction)
+            }
+        };
+        return to_area(pdf, vertex.point, next);
+    }
+
+    /// Sun light reflected off of camera subpath vertex `vertex`, weighted against finding the
+    /// sun by scattering
+    fn sample_sun(&self, vertex: &Vertex, time: f32, rng_state: &mut u32) -> Vec3f {
+        let sky = &self.scene.sky;
+        if vertex.kind != VertexKind::Surface || !vertex.connectible {
+            return Vec3f::from(0.0);
+        }
+
+        let sun_dir =
+            sky.sample_sun_direction(Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)));
+        let value = vertex.beta * vertex.scatter(vertex.wo, sun_dir, false);
+        if Vec3f::dot(value, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        let shadow_ray = Ray::new(vertex.point + sun_dir * RAY_OFFSET, sun_dir, time);
+        let visibility =
+            Ray::shadow_transmittance(self.scene, &shadow_ray, f32::MAX, None, None, rng_state);
+
+        let pdf_sun = 1.0 / sky.sun_solid_angle();
+        let weight = balance_heuristic(pdf_sun, vertex.pdf(vertex.wo, sun_dir));
+        return sky.sun_radiance() * value * visibility * (weight / pdf_sun);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// Vertex of a camera or light subpath. Densities are per unit area.
+pub(super) struct Vertex {
+    kind: VertexKind,
+    pub(super) point: Vec3f,
+    /// Shading normal, surfaces have it facing the side the subpath arrived from
+    pub(super) normal: Vec3f,
+    frame: Frame,
+    /// Material with its textures resolved
+    material: Material,
+    front_face: bool,
+    tri_index: u32,
+    /// Direction towards the previous vertex of the subpath
+    pub(super) wo: Vec3f,
+    /// Throughput of the subpath up to this vertex
+    pub(super) beta: Vec3f,
+    /// Radiance emitted towards the previous vertex, or by the light for light vertices
+    pub(super) emission: Vec3f,
+    /// Density of sampling this vertex from the previous one of its subpath
+    pub(super) pdf_fwd: f32,
+    /// Density of sampling this vertex from the next one, as if the subpath was traced the
+    /// other way around
+    pdf_rev: f32,
+    /// The subpath continued through a specular lobe
+    delta: bool,
+    /// Has lobes that can be evaluated for connections
+    pub(super) connectible: bool,
+}
+
+impl Vertex {
+    fn endpoint(kind: VertexKind, point: Vec3f, normal: Vec3f, emission: Vec3f, pdf: f32) -> Self {
+        return Self {
+            kind,
+            point,
+            normal,
+            frame: Frame::new(normal),
+            material: Material::default(),
+            front_face: true,
+            tri_index: 0,
+            wo: normal,
+            beta: Vec3f::from(1.0),
+            emission,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+            delta: false,
+            connectible: true,
+        };
+    }
+
+    /// Vertex on the surface at `hit_info`, reached from direction `wo` with throughput `beta`
+    pub(super) fn surface(scene: &Scene, mut hit_info: HitInfo, wo: Vec3f, beta: Vec3f) -> Self {
+        let hit_material = scene.material(hit_info.material_id);
+        if hit_material.normal_tex_id != u32::MAX {
+            Ray::apply_normal_map(scene, hit_material, &mut hit_info);
+        }
+
+        let material = Ray::surface_material(scene, hit_material, &hit_info, None);
+        let emission = if hit_info.front_face {
+            Ray::emission_at(scene, hit_material, hit_info.uv, None)
+        } else {
+            Vec3f::from(0.0)
+        };
+        let frame = Frame::with_tangent(hit_info.normal, hit_info.tangent, hit_info.bitangent_sign)
+            .rotated(material.anisotropic_rotation * 2.0 * PI);
+        let bsdf = bsdf::from_material(&material, hit_info.front_face, RGB_WAVELENGTHS.into());
+
+        return Self {
+            kind: VertexKind::Surface,
+            point: hit_info.point,
+            normal: hit_info.normal,
+            frame,
+            material,
+            front_face: hit_info.front_face,
+            tri_index: hit_info.tri_index,
+            wo,
+            beta,
+            emission,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            connectible: bsdf.lobes().intersects(Lobe::DIFFUSE | Lobe::GLOSSY),
+        };
+    }
+
+    /// BSDF as seen from `wo`. BSDFs expect `wo` above the surface, so the local frame is
+    /// mirrored if it lies below, which is returned as well.
+    fn bsdf(&self, wo: Vec3f) -> (Box<dyn Bsdf>, bool) {
+        let flipped = Vec3f::dot(wo, self.normal) < 0.0;
+        let bsdf = bsdf::from_material(
+            &self.material,
+            self.front_face != flipped,
+            RGB_WAVELENGTHS.into(),
+        );
+        return (bsdf, flipped);
+    }
+
+    fn to_local(&self, world: Vec3f, flipped: bool) -> Vec3f {
+        let mut local = self.frame.to_local(world);
+        if flipped {
+            local.data[2] = -local.z();
+        }
+        return local;
+    }
+
+    /// IOR on the other side of the surface relative to the side of `wo`
+    pub(super) fn eta(&self, wo: Vec3f) -> f32 {
+        let entering = self.front_face == (Vec3f::dot(wo, self.normal) >= 0.0);
+        if self.material.thin_walled != 0 {
+            return 1.0;
+        } else if entering {
+            return self.material.ior;
+        } else {
+            return 1.0 / self.material.ior;
+        }
+    }
+
+    /// BSDF value for the pair of directions. For `adjoint` light subpaths refraction doesn't
+    /// scale by the squared relative IOR.
+    pub(super) fn eval(&self, wo: Vec3f, wi: Vec3f, adjoint: bool) -> Vec3f {
+        let (bsdf, flipped) = self.bsdf(wo);
+        let mut value = bsdf.eval(self.to_local(wo, flipped), self.to_local(wi, flipped));
+        if adjoint && Vec3f::dot(wo, self.normal) * Vec3f::dot(wi, self.normal) < 0.0 {
+            let eta = self.eta(wo);
+            value *= eta * eta;
+        }
+        return value;
+    }
+
+    /// BSDF value times the cosine term at this vertex
+    pub(super) fn scatter(&self, wo: Vec3f, wi: Vec3f, adjoint: bool) -> Vec3f {
+        return self.eval(wo, wi, adjoint) * f32::abs(Vec3f::dot(wi, self.normal));
+    }
+
+    /// Solid angle density of scattering towards `wi` after arriving from `wo`
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        let (bsdf, flipped) = self.bsdf(wo);
+        return bsdf.pdf(self.to_local(wo, flipped), self.to_local(wi, flipped));
+    }
+
+    /// Samples the direction the subpath continues in, returned in world space
+    pub(super) fn sample(&self, rng_state: &mut u32) -> Option<(Vec3f, bsdf::BsdfSample)> {
+        let (bsdf, flipped) = self.bsdf(self.wo);
+        let sample = bsdf.sample(
+            self.to_local(self.wo, flipped),
+            rand_f32(rng_state),
+            Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+        )?;
+        let mut wi = sample.wi;
+        if flipped {
+            wi.data[2] = -wi.z();
+        }
+        return Some((self.frame.to_world(wi).normalized(), sample));
+    }
+}
+
+/// Picks emissive triangles proportionally to their power. Unlike the light BVH this doesn't
+/// need a shading point, which light subpaths start without.
+pub(super) struct LightDistribution {
+    tri_indices: Vec<u32>,
+    cdf: Vec<f32>,
+    /// Density per unit area of sampling a point on each triangle of the scene, zero for
+    /// triangles that don't emit
+    densities: Vec<f32>,
+}
+
+impl LightDistribution {
+    pub(super) fn new(scene: &Scene) -> Self {
+        let mut distribution = Self {
+            tri_indices: vec![],
+            cdf: vec![],
+            densities: vec![0.0; scene.tris.len()],
+        };
+
+        // Leaves of the light BVH hold one emissive triangle each
+        let mut total_power = 0.0;
+        for node in scene
+            .light_bvh
+            .nodes
+            .iter()
+            .filter(|node| node.num_tris > 0)
+        {
+            total_power += node.power;
+            distribution.tri_indices.push(node.first_tri_or_child);
+            distribution.cdf.push(total_power);
+        }
+
+        for (i, &tri_index) in distribution.tri_indices.iter().enumerate() {
+            let prev = if i > 0 { distribution.cdf[i - 1] } else { 0.0 };
+            let pmf = (distribution.cdf[i] - prev) / total_power;
+            let tri = &scene.tris[tri_index as usize];
+            let area = Vec3f::cross(
+                tri.vertices[1].position - tri.vertices[0].position,
+                tri.vertices[2].position - tri.vertices[0].position,
+            )
+            .length()
+                * 0.5;
+            distribution.densities[tri_index as usize] = pmf / area;
+        }
+        for value in distribution.cdf.iter_mut() {
+            *value /= total_power;
+        }
+
+        return distribution;
+    }
+
+    fn sample(&self, rng_state: &mut u32) -> Option<u32> {
+        if self.tri_indices.is_empty() {
+            return None;
+        }
+        let u = rand_f32(rng_state);
+        let i = self.cdf.partition_point(|&value| value < u);
+        return Some(self.tri_indices[usize::min(i, self.tri_indices.len() - 1)]);
+    }
+
+    fn density(&self, tri_index: u32) -> f32 {
+        return self.densities[tri_index as usize];
+    }
+
+    /// Picks a point on an emitter proportionally to power
+    pub(super) fn sample_light(&self, scene: &Scene, rng_state: &mut u32) -> Option<Vertex> {
+        let tri_index = self.sample(rng_state)?;
+        let tri = &scene.tris[tri_index as usize];
+
+        let r = f32::sqrt(rand_f32(rng_state));
+        let b_0 = 1.0 - r;
+        let b_1 = rand_f32(rng_state) * r;
+        let b_2 = 1.0 - b_0 - b_1;
+        let point = tri.vertices[0].position * b_0
+            + tri.vertices[1].position * b_1
+            + tri.vertices[2].position * b_2;
+        let uv = Vec2f::new(tri.vertices[0].tex_coord_x, tri.vertices[0].tex_coord_y) * b_0
+            + Vec2f::new(tri.vertices[1].tex_coord_x, tri.vertices[1].tex_coord_y) * b_1
+            + Vec2f::new(tri.vertices[2].tex_coord_x, tri.vertices[2].tex_coord_y) * b_2;
+
+        let emission = Ray::emission_at(scene, scene.material(tri.material_id), uv, None);
+        let mut vertex = Vertex::endpoint(
+            VertexKind::Light,
+            point,
+            geometric_normal(tri),
+            emission,
+            self.density(tri_index),
+        );
+        vertex.tri_index = tri_index;
+        return Some(vertex);
+    }
+}
+
+/// Fraction of the light passing the segment between two points at `time`, transparent surfaces
+/// let it through the same way the subpaths pass them
+pub(super) fn visibility(
+    scene: &Scene,
+    from: Vec3f,
+    to: Vec3f,
+    time: f32,
+    rng_state: &mut u32,
+) -> Vec3f {
+    let offset = to - from;
+    let distance = offset.length();
+    let direction = offset / distance;
+    let shadow_ray = Ray::new(from + direction * RAY_OFFSET, direction, time);
+    return Ray::shadow_transmittance(scene, &shadow_ray, distance, None, None, rng_state);
+}
+
+/// Converts a solid angle density at `from` into a density per unit area at `to`
+fn to_area(pdf: f32, from: Vec3f, to: &Vertex) -> f32 {
+    let offset = to.point - from;
+    let distance_sqr = Vec3f::dot(offset, offset);
+    if distance_sqr == 0.0 {
+        return 0.0;
+    }
+    let mut pdf = pdf / distance_sqr;
+    // The camera is a point, it has no surface to project onto
+    if to.kind != VertexKind::Camera {
+        pdf *= f32::abs(Vec3f::dot(to.normal, offset)) / f32::sqrt(distance_sqr);
+    }
+    return pdf;
+}
+
+/// Density per unit area at `next` of an emitter at `light` emitting towards it
+fn pdf_light(light: &Vertex, next: &Vertex) -> f32 {
+    let direction = (next.point - light.point).normalized();
+    let cos = Vec3f::dot(light.normal, direction);
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    return to_area(cos / PI, light.point, next);
+}
+
+/// Emitters emit towards the side their geometric normal points to
+fn geometric_normal(tri: &Triangle) -> Vec3f {
+    return Vec3f::cross(
+        tri.vertices[1].position - tri.vertices[0].position,
+        tri.vertices[2].position - tri.vertices[0].position,
+    )
+    .normalized();
+}
+
+/// The heuristic `mis_weight` uses, so that the sun is weighted the same way as every other
+/// connection
+fn balance_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    return pdf_a / (pdf_a + pdf_b);
+}
+
+/// Adds to an `f32` stored as bits in an atomic
+pub(super) fn atomic_add(atomic: &AtomicU32, value: f32) {
+    let mut current = atomic.load(Ordering::Relaxed);
+    loop {
+        let new = (f32::from_bits(current) + value).to_bits();
+        match atomic.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
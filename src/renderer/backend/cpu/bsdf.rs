@@ -0,0 +1,875 @@
+use std::f32::consts::PI;
+use std::ops::BitOr;
+
+use crate::math::vec::*;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+use crate::scene::Material;
+
+/// Below this GGX alpha lobes are treated as perfectly smooth
+const SPECULAR_ALPHA: f32 = 1e-3;
+
+/// Flags describing the lobes of a BSDF or the lobe a direction was sampled from. The same
+/// flags are used by `select_bsdf` in rt_compute.wgsl.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Lobe(u32);
+
+#[allow(dead_code)]
+impl Lobe {
+    pub const NONE: Self = Self(0);
+    pub const DIFFUSE: Self = Self(1);
+    pub const GLOSSY: Self = Self(1 << 1);
+    pub const SPECULAR: Self = Self(1 << 2);
+    pub const REFLECTION: Self = Self(1 << 3);
+    pub const TRANSMISSION: Self = Self(1 << 4);
+
+    pub fn contains(self, other: Self) -> bool {
+        return (self.0 & other.0) == other.0;
+    }
+
+    pub fn intersects(self, other: Self) -> bool {
+        return (self.0 & other.0) != 0;
+    }
+}
+
+impl BitOr for Lobe {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        return Self(self.0 | rhs.0);
+    }
+}
+
+pub struct BsdfSample {
+    /// Sampled incoming direction in local space
+    pub wi: Vec3f,
+    /// BSDF value times the cosine term divided by the pdf
+    pub weight: Vec3f,
+    /// Zero for specular lobes since they are Dirac deltas
+    pub pdf: f32,
+    pub lobe: Lobe,
+}
+
+/// Scattering function of a surface. All directions are in the local shading frame where the
+/// normal is +Z and point away from the surface, `wo` always lies on the +Z side.
+pub trait Bsdf {
+    /// BSDF value for the pair of directions, specular lobes evaluate to zero
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f;
+    /// Samples a direction, `uc` picks the lobe and `u` the direction within it
+    fn sample(&self, wo: Vec3f, uc: f32, u: Vec2f) -> Option<BsdfSample>;
+    /// Solid angle density of sampling `wi`, specular lobes have a density of zero
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32;
+    fn lobes(&self) -> Lobe;
+}
+
+/// Principled BSDF following Blender's Principled BSDF: a diffuse and sheen base under a
+/// tinted dielectric specular layer, blended with glass and metal, all under a clearcoat.
+/// Texture lookups are already resolved into `material`. `wavelengths` are the wavelengths in
+/// nanometers the three color channels stand for.
+///
+/// https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+pub fn from_material(material: &Material, front_face: bool, wavelengths: Vec3f) -> Box<dyn Bsdf> {
+    let base_color = material.base_color;
+    let alpha = material.roughness * material.roughness;
+    let aspect = f32::sqrt(1.0 - 0.9 * material.anisotropic.clamp(0.0, 1.0));
+    let alpha_x = alpha / aspect;
+    let alpha_y = alpha * aspect;
+
+    let luminance = 0.3 * base_color.x() + 0.6 * base_color.y() + 0.1 * base_color.z();
+    let tint = if luminance > 0.0 {
+        base_color / luminance
+    } else {
+        Vec3f::from(1.0)
+    };
+    let sheen_color = (Vec3f::from(1.0) * (1.0 - material.sheen_tint) + tint * material.sheen_tint)
+        * material.sheen;
+
+    let thin_film = if material.thin_film_thickness > 0.0 {
+        Some(ThinFilm {
+            thickness: material.thin_film_thickness,
+            eta: material.thin_film_ior,
+            wavelengths,
+        })
+    } else {
+        None
+    };
+
+    let f0 = (material.ior - 1.0) / (material.ior + 1.0);
+    let specular_color = Vec3f::min(
+        material.specular_tint * (f0 * f0 * 2.0 * material.specular),
+        Vec3f::from(1.0),
+    );
+
+    let diffuse = Box::new(DisneyDiffuse {
+        base_color,
+        roughness: material.roughness,
+        sheen_color,
+    });
+    let mut bsdf: Box<dyn Bsdf> = Box::new(Coated {
+        base: diffuse,
+        f0: specular_color,
+        weight: 1.0,
+        alpha_x,
+        alpha_y,
+        thin_film,
+    });
+
+    if material.transmission > 0.0 {
+        let dielectric: Box<dyn Bsdf>;
+        if material.thin_walled != 0 {
+            dielectric = Box::new(ThinDielectric {
+                eta: material.ior,
+                tint: base_color,
+                thin_film,
+            });
+        } else {
+            // Tinted at both interfaces, so a closed mesh ends up with the base color
+            dielectric = Box::new(RoughDielectric {
+                eta: if front_face {
+                    material.ior
+                } else {
+                    1.0 / material.ior
+                },
+                alpha,
+                tint: Vec3f::new(
+                    f32::sqrt(base_color.x()),
+                    f32::sqrt(base_color.y()),
+                    f32::sqrt(base_color.z()),
+                ),
+                // The film sits on the outside of the mesh
+                thin_film: thin_film.map(|film| ThinFilm {
+                    eta: if front_face {
+                        film.eta
+                    } else {
+                        film.eta / material.ior
+                    },
+                    ..film
+                }),
+            });
+        }
+        bsdf = Mix::boxed(bsdf, dielectric, material.transmission);
+    }
+
+    if material.metallic > 0.0 {
+        let conductor = Box::new(Conductor {
+            f0: base_color,
+            alpha_x,
+            alpha_y,
+            thin_film,
+        });
+        bsdf = Mix::boxed(bsdf, conductor, material.metallic);
+    }
+
+    if material.clearcoat > 0.0 {
+        let clearcoat_alpha = 0.1 + (0.001 - 0.1) * material.clearcoat_gloss;
+        bsdf = Box::new(Coated {
+            base: bsdf,
+            f0: Vec3f::from(0.04),
+            weight: material.clearcoat,
+            alpha_x: clearcoat_alpha,
+            alpha_y: clearcoat_alpha,
+            thin_film: None,
+        });
+    }
+
+    return bsdf;
+}
+
+/// Orthonormal basis used to move directions in and out of the local shading frame
+pub struct Frame {
+    tangent: Vec3f,
+    bitangent: Vec3f,
+    normal: Vec3f,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3f) -> Self {
+        let normal = normal.normalized();
+        let up = if f32::abs(normal.z()) < 0.9999999 {
+            Vec3f::new(0.0, 0.0, 1.0)
+        } else {
+            Vec3f::new(1.0, 0.0, 0.0)
+        };
+        let tangent = Vec3f::cross(up, normal).normalized();
+        let bitangent = Vec3f::cross(normal, tangent);
+        return Self {
+            tangent,
+            bitangent,
+            normal,
+        };
+    }
+
+    /// Frame with the tangent following the UV layout, falls back to an arbitrary tangent if
+    /// `tangent` is degenerate
+    pub fn with_tangent(normal: Vec3f, tangent: Vec3f, bitangent_sign: f32) -> Self {
+        let normal = normal.normalized();
+        let tangent = tangent - normal * Vec3f::dot(normal, tangent);
+        if tangent.length() < 1e-6 {
+            return Self::new(normal);
+        }
+        let tangent = tangent.normalized();
+        return Self {
+            tangent,
+            bitangent: Vec3f::cross(normal, tangent) * bitangent_sign,
+            normal,
+        };
+    }
+
+    /// Rotates the tangent and bitangent around the normal by `angle` radians
+    pub fn rotated(self, angle: f32) -> Self {
+        if angle == 0.0 {
+            return self;
+        }
+        let (sin, cos) = f32::sin_cos(angle);
+        return Self {
+            tangent: self.tangent * cos + self.bitangent * sin,
+            bitangent: self.bitangent * cos - self.tangent * sin,
+            normal: self.normal,
+        };
+    }
+
+    pub fn to_local(&self, world: Vec3f) -> Vec3f {
+        return Vec3f::new(
+            Vec3f::dot(world, self.tangent),
+            Vec3f::dot(world, self.bitangent),
+            Vec3f::dot(world, self.normal),
+        );
+    }
+
+    pub fn to_world(&self, local: Vec3f) -> Vec3f {
+        return self.tangent * local.x() + self.bitangent * local.y() + self.normal * local.z();
+    }
+}
+
+pub struct Lambert {
+    pub albedo: Vec3f,
+}
+
+impl Bsdf for Lambert {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        return self.albedo / PI;
+    }
+
+    fn sample(&self, wo: Vec3f, _uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        let wi = cosine_sample_hemisphere(u);
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return None;
+        }
+        return Some(BsdfSample {
+            wi,
+            weight: self.albedo,
+            pdf: wi.z() / PI,
+            lobe: Lobe::DIFFUSE | Lobe::REFLECTION,
+        });
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        return wi.z() / PI;
+    }
+
+    fn lobes(&self) -> Lobe {
+        return Lobe::DIFFUSE | Lobe::REFLECTION;
+    }
+}
+
+/// Burley diffuse with retroreflection at grazing angles plus a sheen lobe for cloth
+pub struct DisneyDiffuse {
+    pub base_color: Vec3f,
+    pub roughness: f32,
+    pub sheen_color: Vec3f,
+}
+
+impl Bsdf for DisneyDiffuse {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        let h = (wo + wi).normalized();
+        let cos_d = Vec3f::dot(wi, h);
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = f32::powi(1.0 - wi.z(), 5);
+        let fv = f32::powi(1.0 - wo.z(), 5);
+        let retro = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        return self.base_color * (retro / PI)
+            + self.sheen_color * f32::powi(1.0 - cos_d.clamp(0.0, 1.0), 5);
+    }
+
+    fn sample(&self, wo: Vec3f, _uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        let wi = cosine_sample_hemisphere(u);
+        return sampled(self, wo, wi, Lobe::DIFFUSE | Lobe::REFLECTION);
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        return wi.z() / PI;
+    }
+
+    fn lobes(&self) -> Lobe {
+        return Lobe::DIFFUSE | Lobe::REFLECTION;
+    }
+}
+
+/// Metal with an anisotropic GGX microfacet distribution and Schlick Fresnel tinted by `f0`
+pub struct Conductor {
+    pub f0: Vec3f,
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+    pub thin_film: Option<ThinFilm>,
+}
+
+impl Conductor {
+    fn is_specular(&self) -> bool {
+        return f32::max(self.alpha_x, self.alpha_y) < SPECULAR_ALPHA;
+    }
+
+    fn fresnel(&self, cos_theta: f32) -> Vec3f {
+        match self.thin_film {
+            Some(film) => return film.reflectance_schlick(cos_theta, self.f0),
+            None => return schlick_fresnel(cos_theta, self.f0),
+        }
+    }
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        if self.is_specular() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        let (alpha_x, alpha_y) = (self.alpha_x, self.alpha_y);
+        let h = (wo + wi).normalized();
+        return self.fresnel(Vec3f::dot(wo, h))
+            * (ggx_d(h, alpha_x, alpha_y)
+                * ggx_g1(wo, alpha_x, alpha_y)
+                * ggx_g1(wi, alpha_x, alpha_y)
+                / (4.0 * wo.z() * wi.z()));
+    }
+
+    fn sample(&self, wo: Vec3f, _uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        if self.is_specular() {
+            return Some(BsdfSample {
+                wi: Vec3f::new(-wo.x(), -wo.y(), wo.z()),
+                weight: self.fresnel(wo.z()),
+                pdf: 0.0,
+                lobe: Lobe::SPECULAR | Lobe::REFLECTION,
+            });
+        }
+
+        let h = sample_ggx_vndf(wo, self.alpha_x, self.alpha_y, u);
+        let wi = reflect(wo, h);
+        return sampled(self, wo, wi, Lobe::GLOSSY | Lobe::REFLECTION);
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        if self.is_specular() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalized();
+        return ggx_vndf_pdf(wo, h, self.alpha_x, self.alpha_y) / (4.0 * Vec3f::dot(wo, h));
+    }
+
+    fn lobes(&self) -> Lobe {
+        return glossy_or_specular(f32::max(self.alpha_x, self.alpha_y)) | Lobe::REFLECTION;
+    }
+}
+
+/// Rough glass, `eta` is the index of refraction on the other side of the surface divided by
+/// the one on the side of `wo`
+///
+/// https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
+pub struct RoughDielectric {
+    pub eta: f32,
+    pub alpha: f32,
+    pub tint: Vec3f,
+    pub thin_film: Option<ThinFilm>,
+}
+
+impl RoughDielectric {
+    fn fresnel(&self, cos_i: f32) -> Vec3f {
+        match self.thin_film {
+            Some(film) => return film.reflectance_dielectric(cos_i, self.eta),
+            None => return Vec3f::from(fresnel_dielectric(cos_i, self.eta)),
+        }
+    }
+
+    /// Generalized half vector, facing the same side as the normal
+    fn half_vector(&self, wo: Vec3f, wi: Vec3f) -> Option<Vec3f> {
+        let reflected = wi.z() > 0.0;
+        let eta = if reflected { 1.0 } else { self.eta };
+        let mut h = wo + wi * eta;
+        if h.length() == 0.0 {
+            return None;
+        }
+        h = h.normalized();
+        if h.z() < 0.0 {
+            h = h.reversed();
+        }
+        // Discard back facing microfacets
+        if Vec3f::dot(h, wi) * wi.z() < 0.0 || Vec3f::dot(h, wo) * wo.z() < 0.0 {
+            return None;
+        }
+        return Some(h);
+    }
+}
+
+impl Bsdf for RoughDielectric {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        if self.alpha < SPECULAR_ALPHA || wo.z() <= 0.0 || wi.z() == 0.0 {
+            return Vec3f::from(0.0);
+        }
+        let Some(h) = self.half_vector(wo, wi) else {
+            return Vec3f::from(0.0);
+        };
+
+        let fresnel = self.fresnel(Vec3f::dot(wo, h));
+        let alpha = self.alpha;
+        let d_g = ggx_d(h, alpha, alpha) * ggx_g1(wo, alpha, alpha) * ggx_g1(wi, alpha, alpha);
+        if wi.z() > 0.0 {
+            return fresnel * (d_g / (4.0 * wo.z() * wi.z()));
+        }
+
+        let denom = Vec3f::dot(wi, h) + Vec3f::dot(wo, h) / self.eta;
+        let transmitted = d_g
+            * f32::abs(Vec3f::dot(wi, h) * Vec3f::dot(wo, h) / (denom * denom * wi.z() * wo.z()));
+        // Radiance is compressed into the smaller solid angle on the denser side
+        return self.tint * (Vec3f::from(1.0) - fresnel) * (transmitted / (self.eta * self.eta));
+    }
+
+    fn sample(&self, wo: Vec3f, uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        if self.alpha < SPECULAR_ALPHA {
+            let fresnel = self.fresnel(wo.z());
+            let reflect_probability = average(fresnel);
+            if uc < reflect_probability {
+                return Some(BsdfSample {
+                    wi: Vec3f::new(-wo.x(), -wo.y(), wo.z()),
+                    weight: fresnel / reflect_probability,
+                    pdf: 0.0,
+                    lobe: Lobe::SPECULAR | Lobe::REFLECTION,
+                });
+            }
+            let wi = refract(wo, Vec3f::new(0.0, 0.0, 1.0), self.eta)?;
+            return Some(BsdfSample {
+                wi,
+                weight: self.tint * (Vec3f::from(1.0) - fresnel)
+                    / ((1.0 - reflect_probability) * self.eta * self.eta),
+                pdf: 0.0,
+                lobe: Lobe::SPECULAR | Lobe::TRANSMISSION,
+            });
+        }
+
+        let h = sample_ggx_vndf(wo, self.alpha, self.alpha, u);
+        let reflect_probability = average(self.fresnel(Vec3f::dot(wo, h)));
+        if uc < reflect_probability {
+            return sampled(self, wo, reflect(wo, h), Lobe::GLOSSY | Lobe::REFLECTION);
+        }
+        let wi = refract(wo, h, self.eta)?;
+        return sampled(self, wo, wi, Lobe::GLOSSY | Lobe::TRANSMISSION);
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        if self.alpha < SPECULAR_ALPHA || wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        let Some(h) = self.half_vector(wo, wi) else {
+            return 0.0;
+        };
+
+        let fresnel = average(self.fresnel(Vec3f::dot(wo, h)));
+        if wi.z() > 0.0 {
+            return fresnel * ggx_vndf_pdf(wo, h, self.alpha, self.alpha)
+                / (4.0 * Vec3f::dot(wo, h));
+        }
+
+        let denom = Vec3f::dot(wi, h) + Vec3f::dot(wo, h) / self.eta;
+        let dh_dwi = f32::abs(Vec3f::dot(wi, h)) / (denom * denom);
+        return (1.0 - fresnel) * ggx_vndf_pdf(wo, h, self.alpha, self.alpha) * dh_dwi;
+    }
+
+    fn lobes(&self) -> Lobe {
+        return glossy_or_specular(self.alpha) | Lobe::REFLECTION | Lobe::TRANSMISSION;
+    }
+}
+
+/// Infinitely thin glass sheet, light passes straight through after bouncing around between
+/// both interfaces
+pub struct ThinDielectric {
+    pub eta: f32,
+    pub tint: Vec3f,
+    pub thin_film: Option<ThinFilm>,
+}
+
+impl Bsdf for ThinDielectric {
+    fn eval(&self, _wo: Vec3f, _wi: Vec3f) -> Vec3f {
+        return Vec3f::from(0.0);
+    }
+
+    fn sample(&self, wo: Vec3f, uc: f32, _u: Vec2f) -> Option<BsdfSample> {
+        let mut reflectance = match self.thin_film {
+            Some(film) => film.reflectance_dielectric(wo.z(), self.eta),
+            None => Vec3f::from(fresnel_dielectric(wo.z(), self.eta)),
+        };
+        for i in 0..3 {
+            let r = reflectance.data[i];
+            if r < 1.0 {
+                reflectance.data[i] += (1.0 - r) * (1.0 - r) * r / (1.0 - r * r);
+            }
+        }
+
+        let reflect_probability = average(reflectance);
+        if uc < reflect_probability {
+            return Some(BsdfSample {
+                wi: Vec3f::new(-wo.x(), -wo.y(), wo.z()),
+                weight: reflectance / reflect_probability,
+                pdf: 0.0,
+                lobe: Lobe::SPECULAR | Lobe::REFLECTION,
+            });
+        }
+        return Some(BsdfSample {
+            wi: wo.reversed(),
+            weight: self.tint * (Vec3f::from(1.0) - reflectance) / (1.0 - reflect_probability),
+            pdf: 0.0,
+            lobe: Lobe::SPECULAR | Lobe::TRANSMISSION,
+        });
+    }
+
+    fn pdf(&self, _wo: Vec3f, _wi: Vec3f) -> f32 {
+        return 0.0;
+    }
+
+    fn lobes(&self) -> Lobe {
+        return Lobe::SPECULAR | Lobe::REFLECTION | Lobe::TRANSMISSION;
+    }
+}
+
+/// Layers a dielectric GGX reflection with Schlick Fresnel over `base`. Light reaching the base
+/// has to pass through the coat twice, the coat is picked with its reflectance at `wo`.
+pub struct Coated {
+    pub base: Box<dyn Bsdf>,
+    pub f0: Vec3f,
+    /// Strength of the coat in the range 0.0 - 1.0
+    pub weight: f32,
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+    pub thin_film: Option<ThinFilm>,
+}
+
+impl Coated {
+    fn coat(&self) -> Conductor {
+        return Conductor {
+            f0: self.f0,
+            alpha_x: self.alpha_x,
+            alpha_y: self.alpha_y,
+            thin_film: self.thin_film,
+        };
+    }
+
+    fn coat_probability(&self, wo: Vec3f) -> f32 {
+        return self.weight * average(self.coat().fresnel(wo.z()));
+    }
+
+    fn coat_transmittance(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        let coat = self.coat();
+        return (Vec3f::from(1.0) - coat.fresnel(wo.z()) * self.weight)
+            * (Vec3f::from(1.0) - coat.fresnel(f32::abs(wi.z())) * self.weight);
+    }
+}
+
+impl Bsdf for Coated {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        if wo.z() <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        return self.coat().eval(wo, wi) * self.weight
+            + self.base.eval(wo, wi) * self.coat_transmittance(wo, wi);
+    }
+
+    fn sample(&self, wo: Vec3f, uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        let coat_probability = self.coat_probability(wo);
+        if uc < coat_probability {
+            let mut sample = self.coat().sample(wo, uc / coat_probability, u)?;
+            if sample.lobe.contains(Lobe::SPECULAR) {
+                sample.weight *= self.weight / coat_probability;
+                return Some(sample);
+            }
+            return sampled(self, wo, sample.wi, sample.lobe);
+        }
+
+        let uc = (uc - coat_probability) / (1.0 - coat_probability);
+        let mut sample = self.base.sample(wo, uc, u)?;
+        if sample.lobe.contains(Lobe::SPECULAR) {
+            sample.weight =
+                sample.weight * self.coat_transmittance(wo, sample.wi) / (1.0 - coat_probability);
+            return Some(sample);
+        }
+        return sampled(self, wo, sample.wi, sample.lobe);
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        let coat_probability = self.coat_probability(wo);
+        return coat_probability * self.coat().pdf(wo, wi)
+            + (1.0 - coat_probability) * self.base.pdf(wo, wi);
+    }
+
+    fn lobes(&self) -> Lobe {
+        return self.coat().lobes() | self.base.lobes();
+    }
+}
+
+/// Thin film on top of a specular interface. Light reflected at the top and the bottom of the
+/// film interferes, which tints the reflection depending on the viewing angle and thickness.
+///
+/// https://belcour.github.io/blog/research/publication/2017/05/01/brdf-thin-film.html
+#[derive(Clone, Copy)]
+pub struct ThinFilm {
+    /// Thickness in nanometers
+    pub thickness: f32,
+    /// IOR of the film relative to the side of `wo`
+    pub eta: f32,
+    /// Wavelengths in nanometers the three color channels are evaluated at
+    pub wavelengths: Vec3f,
+}
+
+impl ThinFilm {
+    /// Reflectance of the film on a dielectric, `eta` is the IOR of the dielectric relative to
+    /// the side of `wo`
+    fn reflectance_dielectric(&self, cos_i: f32, eta: f32) -> Vec3f {
+        return self.reflectance(cos_i, |_, cos_film| {
+            let sin_2_t = self.eta * self.eta * (1.0 - cos_film * cos_film) / (eta * eta);
+            if sin_2_t >= 1.0 {
+                return (1.0, 1.0);
+            }
+            let cos_t = f32::sqrt(1.0 - sin_2_t);
+            return (
+                (self.eta * cos_film - eta * cos_t) / (self.eta * cos_film + eta * cos_t),
+                (eta * cos_film - self.eta * cos_t) / (eta * cos_film + self.eta * cos_t),
+            );
+        });
+    }
+
+    /// Reflectance of the film on a surface with Schlick Fresnel. The reflection at the bottom
+    /// of the film flips its phase if the surface is denser than the film, which is always the
+    /// case for metals.
+    fn reflectance_schlick(&self, cos_i: f32, f0: Vec3f) -> Vec3f {
+        return self.reflectance(cos_i, |i, cos_film| {
+            let sqrt_f0 = f32::sqrt(f0.data[i].clamp(0.0, 0.9999));
+            let amplitude = f32::sqrt(schlick_fresnel(cos_film, Vec3f::from(f0.data[i])).x());
+            let ior = (1.0 + sqrt_f0) / (1.0 - sqrt_f0);
+            let r = if ior > self.eta {
+                -amplitude
+            } else {
+                amplitude
+            };
+            return (r, r);
+        });
+    }
+
+    /// `base` returns the s and p polarized amplitude reflection coefficients at the bottom of
+    /// the film for a color channel and the cosine inside the film
+    fn reflectance(&self, cos_i: f32, base: impl Fn(usize, f32) -> (f32, f32)) -> Vec3f {
+        let cos_i = cos_i.clamp(0.0, 1.0);
+        let sin_2_film = (1.0 - cos_i * cos_i) / (self.eta * self.eta);
+        if sin_2_film >= 1.0 {
+            return Vec3f::from(1.0);
+        }
+        let cos_film = f32::sqrt(1.0 - sin_2_film);
+        let r_s = (cos_i - self.eta * cos_film) / (cos_i + self.eta * cos_film);
+        let r_p = (self.eta * cos_i - cos_film) / (self.eta * cos_i + cos_film);
+
+        let mut reflectance = Vec3f::from(0.0);
+        for i in 0..3 {
+            // Phase difference of a round trip through the film
+            let phase = 4.0 * PI * self.eta * self.thickness * cos_film / self.wavelengths.data[i];
+            let (base_s, base_p) = base(i, cos_film);
+            reflectance.data[i] = 0.5 * (airy(r_s, base_s, phase) + airy(r_p, base_p, phase));
+        }
+        return reflectance;
+    }
+}
+
+/// Linear blend of two BSDFs, `amount` is the weight of `b`
+pub struct Mix {
+    pub a: Box<dyn Bsdf>,
+    pub b: Box<dyn Bsdf>,
+    pub amount: f32,
+}
+
+impl Mix {
+    /// Blend of `a` and `b`, or just one of them if `amount` leaves the other out
+    pub fn boxed(a: Box<dyn Bsdf>, b: Box<dyn Bsdf>, amount: f32) -> Box<dyn Bsdf> {
+        if amount <= 0.0 {
+            return a;
+        } else if amount >= 1.0 {
+            return b;
+        }
+        return Box::new(Self { a, b, amount });
+    }
+}
+
+impl Bsdf for Mix {
+    fn eval(&self, wo: Vec3f, wi: Vec3f) -> Vec3f {
+        return self.a.eval(wo, wi) * (1.0 - self.amount) + self.b.eval(wo, wi) * self.amount;
+    }
+
+    fn sample(&self, wo: Vec3f, uc: f32, u: Vec2f) -> Option<BsdfSample> {
+        // The lobe choice is remapped so the nested choices get a uniform value again
+        let sample = if uc < self.amount {
+            self.b.sample(wo, uc / self.amount, u)?
+        } else {
+            self.a
+                .sample(wo, (uc - self.amount) / (1.0 - self.amount), u)?
+        };
+        // The selection probability cancels out with the blend weight for Dirac deltas
+        if sample.lobe.contains(Lobe::SPECULAR) {
+            return Some(sample);
+        }
+        return sampled(self, wo, sample.wi, sample.lobe);
+    }
+
+    fn pdf(&self, wo: Vec3f, wi: Vec3f) -> f32 {
+        return self.a.pdf(wo, wi) * (1.0 - self.amount) + self.b.pdf(wo, wi) * self.amount;
+    }
+
+    fn lobes(&self) -> Lobe {
+        return self.a.lobes() | self.b.lobes();
+    }
+}
+
+/// Builds a sample for a non specular direction from the full BSDF, so that lobes sharing
+/// directions are weighted correctly
+fn sampled(bsdf: &dyn Bsdf, wo: Vec3f, wi: Vec3f, lobe: Lobe) -> Option<BsdfSample> {
+    let pdf = bsdf.pdf(wo, wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    return Some(BsdfSample {
+        wi,
+        weight: bsdf.eval(wo, wi) * (f32::abs(wi.z()) / pdf),
+        pdf,
+        lobe,
+    });
+}
+
+fn average(v: Vec3f) -> f32 {
+    return (v.x() + v.y() + v.z()) / 3.0;
+}
+
+/// Reflectance of two interfaces with amplitude coefficients `r_1` and `r_2`, summed over all
+/// bounces between them with a phase difference of `phase` per round trip
+fn airy(r_1: f32, r_2: f32, phase: f32) -> f32 {
+    let interference = 2.0 * r_1 * r_2 * f32::cos(phase);
+    return ((r_1 * r_1 + r_2 * r_2 + interference) / (1.0 + r_1 * r_1 * r_2 * r_2 + interference))
+        .clamp(0.0, 1.0);
+}
+
+fn glossy_or_specular(alpha: f32) -> Lobe {
+    if alpha < SPECULAR_ALPHA {
+        return Lobe::SPECULAR;
+    }
+    return Lobe::GLOSSY;
+}
+
+pub fn cosine_sample_hemisphere(u: Vec2f) -> Vec3f {
+    let r = f32::sqrt(u.x());
+    let phi = 2.0 * PI * u.y();
+    let z = f32::sqrt(f32::max(0.0, 1.0 - r * r));
+    return Vec3f::new(r * f32::cos(phi), r * f32::sin(phi), z);
+}
+
+fn reflect(wo: Vec3f, h: Vec3f) -> Vec3f {
+    return h * (2.0 * Vec3f::dot(wo, h)) - wo;
+}
+
+/// Refracts `wo` through a surface with normal `h`, returns None on total internal reflection
+fn refract(wo: Vec3f, h: Vec3f, eta: f32) -> Option<Vec3f> {
+    let cos_i = Vec3f::dot(wo, h);
+    let sin_2_t = f32::max(0.0, 1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = f32::sqrt(1.0 - sin_2_t);
+    return Some((wo.reversed() / eta + h * (cos_i / eta - cos_t)).normalized());
+}
+
+fn schlick_fresnel(cos_theta: f32, f0: Vec3f) -> Vec3f {
+    return f0 + (Vec3f::from(1.0) - f0) * f32::powi(1.0 - cos_theta.clamp(0.0, 1.0), 5);
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let mut cos_i = cos_i.clamp(-1.0, 1.0);
+    let mut eta = eta;
+    if cos_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_i = -cos_i;
+    }
+
+    let sin_2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = f32::sqrt(1.0 - sin_2_t);
+
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return (r_parallel * r_parallel + r_perpendicular * r_perpendicular) * 0.5;
+}
+
+fn ggx_d(h: Vec3f, alpha_x: f32, alpha_y: f32) -> f32 {
+    let x = h.x() / alpha_x;
+    let y = h.y() / alpha_y;
+    let denom = x * x + y * y + h.z() * h.z();
+    return 1.0 / (PI * alpha_x * alpha_y * denom * denom);
+}
+
+/// Smith masking for a single direction
+fn ggx_g1(w: Vec3f, alpha_x: f32, alpha_y: f32) -> f32 {
+    let cos_2 = w.z() * w.z();
+    if cos_2 <= 0.0 {
+        return 0.0;
+    }
+    let alpha_2_tan_2 =
+        (alpha_x * alpha_x * w.x() * w.x() + alpha_y * alpha_y * w.y() * w.y()) / cos_2;
+    return 2.0 / (1.0 + f32::sqrt(1.0 + alpha_2_tan_2));
+}
+
+fn ggx_vndf_pdf(wo: Vec3f, h: Vec3f, alpha_x: f32, alpha_y: f32) -> f32 {
+    return ggx_g1(wo, alpha_x, alpha_y)
+        * f32::max(Vec3f::dot(wo, h), 0.0)
+        * ggx_d(h, alpha_x, alpha_y)
+        / wo.z();
+}
+
+// https://jcgt.org/published/0007/04/01/paper.pdf
+fn sample_ggx_vndf(wo: Vec3f, alpha_x: f32, alpha_y: f32, u: Vec2f) -> Vec3f {
+    let u_1 = u.x();
+    let u_2 = u.y();
+
+    let v_h = Vec3f::new(alpha_x * wo.x(), alpha_y * wo.y(), wo.z()).normalized();
+
+    let len_sq = v_h.x() * v_h.x() + v_h.y() * v_h.y();
+    let t_1 = if len_sq > 0.0 {
+        Vec3f::new(-v_h.y(), v_h.x(), 0.0) / f32::sqrt(len_sq)
+    } else {
+        Vec3f::new(1.0, 0.0, 0.0)
+    };
+    let t_2 = Vec3f::cross(v_h, t_1);
+
+    let r = f32::sqrt(u_1);
+    let phi = 2.0 * PI * u_2;
+    let p_1 = r * f32::cos(phi);
+    let s = 0.5 * (1.0 + v_h.z());
+    let p_2 = (1.0 - s) * f32::sqrt(1.0 - p_1 * p_1) + s * r * f32::sin(phi);
+
+    let n_h = t_1 * p_1 + t_2 * p_2 + v_h * f32::sqrt(f32::max(0.0, 1.0 - p_1 * p_1 - p_2 * p_2));
+    return Vec3f::new(alpha_x * n_h.x(), alpha_y * n_h.y(), f32::max(0.0, n_h.z())).normalized();
+}
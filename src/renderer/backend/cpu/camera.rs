@@ -0,0 +1,387 @@
+use std::f32::consts::PI;
+
+use super::ray::Ray;
+use crate::math::vec::*;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+use crate::scene::{Aperture, Projection, Scene, Stereo, StereoLayout};
+use crate::texture::Texture;
+
+/// Rejection sampling attempts for aperture textures before falling back to the center
+const APERTURE_TEXTURE_TRIES: usize = 64;
+/// Offset between rejection sampling attempts, the R2 sequence keeps the attempts spread out
+const APERTURE_TEXTURE_STEP: [f32; 2] = [0.7548777, 0.5698403];
+
+/// Camera shared by the integrators, maps positions on the film to rays.
+///
+/// With the perspective projection rays leave from a point on the aperture and pass through the
+/// point on the plane in focus that the pinhole ray through the same film position reaches, so
+/// the importance and densities per direction are the same as for a pinhole. The other
+/// projections, stereo cameras and moving cameras have no importance that light paths could be
+/// connected to.
+pub(super) struct FilmCamera {
+    /// Position at the start of the frame
+    pub(super) position: Vec3f,
+    right: Vec3f,
+    up: Vec3f,
+    /// Direction through the center of the film at the start of the frame
+    pub(super) axis: Vec3f,
+    /// View at the end of the frame if the camera moves
+    end_view: Option<View>,
+    shutter_open: f32,
+    shutter_close: f32,
+    /// Aspect ratio of the view of one eye
+    aspect: f32,
+    tan_half_fov: f32,
+    lens_radius: f32,
+    focus_distance: f32,
+    aperture: Aperture,
+    aperture_texture: Option<Texture>,
+    projection: Projection,
+    stereo: Option<Stereo>,
+    width: usize,
+    height: usize,
+}
+
+impl FilmCamera {
+    pub(super) fn new(scene: &Scene, width: usize, height: usize) -> Self {
+        let camera = &scene.camera;
+        let look_at = camera.look_at;
+        let end_view = camera.end_keyframe.map(|_| {
+            let (position, look_at) = camera.end_view();
+            View {
+                position,
+                right: look_at * Vec3f::new(1.0, 0.0, 0.0),
+                up: look_at * Vec3f::new(0.0, 1.0, 0.0),
+                axis: look_at * Vec3f::new(0.0, 0.0, 1.0),
+            }
+        });
+        let aperture_texture = match camera.aperture {
+            Aperture::Texture(texture_id) => Some(scene.textures[texture_id as usize].clone()),
+            _ => None,
+        };
+        let aspect = match camera.stereo {
+            None => width as f32 / height as f32,
+            Some(stereo) if stereo.layout == StereoLayout::SideBySide => {
+                width as f32 * 0.5 / height as f32
+            }
+            Some(_) => width as f32 / (height as f32 * 0.5),
+        };
+        return Self {
+            position: camera.position,
+            right: look_at * Vec3f::new(1.0, 0.0, 0.0),
+            up: look_at * Vec3f::new(0.0, 1.0, 0.0),
+            axis: look_at * Vec3f::new(0.0, 0.0, 1.0),
+            end_view,
+            shutter_open: camera.shutter_open,
+            shutter_close: camera.shutter_close,
+            aspect,
+            tan_half_fov: camera.tan_half_fov(),
+            lens_radius: camera.lens_radius(),
+            focus_distance: camera.focus_distance,
+            aperture: camera.aperture,
+            aperture_texture,
+            projection: camera.projection,
+            stereo: camera.stereo,
+            width,
+            height,
+        };
+    }
+
+    /// Position on the film of a point in pixel coordinates, with y going up from the bottom of
+    /// the image. Returns y from -1 to 1 and x scaled by the aspect ratio, both within the view
+    /// of the eye the point belongs to, along with that eye. The eye is -1 for the left one, 1
+    /// for the right one and 0 for a mono camera.
+    pub(super) fn screen_position(&self, x: f32, y: f32) -> (f32, f32, f32) {
+        let mut x = x;
+        let mut y = y;
+        let mut view_width = self.width as f32;
+        let mut view_height = self.height as f32;
+        let mut eye = 0.0;
+        if let Some(stereo) = self.stereo {
+            if stereo.layout == StereoLayout::SideBySide {
+                view_width *= 0.5;
+                if x < view_width {
+                    eye = -1.0;
+                } else {
+                    eye = 1.0;
+                    x -= view_width;
+                }
+            } else {
+                view_height *= 0.5;
+                // The left eye is on top, y goes up
+                if y >= view_height {
+                    eye = -1.0;
+                    y -= view_height;
+                } else {
+                    eye = 1.0;
+                }
+            }
+        }
+
+        let screen_x = ((x / view_width) * 2.0 - 1.0) * self.aspect;
+        let screen_y = (y / view_height) * 2.0 - 1.0;
+        return (screen_x, screen_y, eye);
+    }
+
+    /// Time while the shutter is open for the sample value `u`
+    pub(super) fn sample_time(&self, u: f32) -> f32 {
+        if self.shutter_close == self.shutter_open {
+            return self.shutter_open;
+        }
+        return self.shutter_open + (self.shutter_close - self.shutter_open) * u;
+    }
+
+    /// Position and orientation of the camera at `time`, the basis is interpolated and made
+    /// orthonormal again
+    fn view(&self, time: f32) -> View {
+        let start = View {
+            position: self.position,
+            right: self.right,
+            up: self.up,
+            axis: self.axis,
+        };
+        let Some(end) = self.end_view else {
+            return start;
+        };
+        let lerp = |a: Vec3f, b: Vec3f| a + (b - a) * time;
+        let axis = lerp(start.axis, end.axis).normalized();
+        let right = lerp(start.right, end.right);
+        let right = (right - axis * Vec3f::dot(right, axis)).normalized();
+        let up = lerp(start.up, end.up);
+        let up = (up - axis * Vec3f::dot(up, axis) - right * Vec3f::dot(up, right)).normalized();
+        return View {
+            position: lerp(start.position, end.position),
+            right,
+            up,
+            axis,
+        };
+    }
+
+    /// Ray at `time` through a position on the film of `eye`, None if the projection doesn't
+    /// cover it. `u_lens` picks the point on the aperture.
+    pub(super) fn ray(
+        &self,
+        screen_x: f32,
+        screen_y: f32,
+        eye: f32,
+        time: f32,
+        u_lens: Vec2f,
+    ) -> Option<Ray> {
+        let view = self.view(time);
+        // Screen x goes right on the image, which is the negative right axis of the view
+        let image_right = view.right.reversed();
+        let (half_ipd, convergence) = match self.stereo {
+            Some(stereo) => (
+                eye * stereo.interpupillary_distance * 0.5,
+                stereo.convergence_distance,
+            ),
+            None => (0.0, 0.0),
+        };
+
+        let (eye_offset, mut direction) = match self.projection {
+            Projection::Perspective => {
+                let eye_offset = image_right * half_ipd;
+                let mut direction =
+                    (image_right * screen_x + view.up * screen_y) * self.tan_half_fov + view.axis;
+                // Shifts the film instead of turning the eye, so both views share the plane
+                // at the convergence distance
+                if convergence > 0.0 {
+                    direction -= eye_offset / convergence;
+                }
+
+                let eye_position = view.position + eye_offset;
+                if self.lens_radius == 0.0 {
+                    return Some(Ray::new(eye_position, direction.normalized(), time));
+                }
+                let focus_point = eye_position + direction * self.focus_distance;
+                let lens = self.sample_aperture(u_lens) * self.lens_radius;
+                let origin = eye_position + view.right * lens.x() + view.up * lens.y();
+                return Some(Ray::new(origin, (focus_point - origin).normalized(), time));
+            }
+            Projection::Orthographic { width } => {
+                let scale = width * 0.5 / self.aspect;
+                let origin = view.position
+                    + (image_right * (screen_x * scale + half_ipd))
+                    + view.up * (screen_y * scale);
+                return Some(Ray::new(origin, view.axis, time));
+            }
+            Projection::Equirectangular => {
+                let phi = screen_x / self.aspect * PI;
+                let theta = screen_y * PI * 0.5;
+                let direction = (image_right * f32::sin(phi) + view.axis * f32::cos(phi))
+                    * f32::cos(theta)
+                    + view.up * f32::sin(theta);
+                // The eyes sit on a circle, to the sides of the horizontal viewing direction
+                let side = image_right * f32::cos(phi) - view.axis * f32::sin(phi);
+                (side * half_ipd, direction)
+            }
+            Projection::Fisheye { fov } => {
+                let radius = f32::sqrt(screen_x * screen_x + screen_y * screen_y);
+                if radius > 1.0 {
+                    return None;
+                }
+                let theta = radius * f32::to_radians(fov) * 0.5;
+                let (sin_phi, cos_phi) = if radius > 0.0 {
+                    (screen_y / radius, screen_x / radius)
+                } else {
+                    (0.0, 0.0)
+                };
+                let direction = (image_right * cos_phi + view.up * sin_phi) * f32::sin(theta)
+                    + view.axis * f32::cos(theta);
+                (image_right * half_ipd, direction)
+            }
+        };
+
+        if convergence > 0.0 {
+            direction = (direction * convergence - eye_offset).normalized();
+        }
+        return Some(Ray::new(view.position + eye_offset, direction, time));
+    }
+
+    /// Ray at `time` through the position `u_pixel` in pixel `index`
+    pub(super) fn sample_ray(
+        &self,
+        index: usize,
+        time: f32,
+        u_pixel: Vec2f,
+        u_lens: Vec2f,
+    ) -> Option<Ray> {
+        let x = (index % self.width) as f32 + u_pixel.x();
+        let y = (self.height - index / self.width) as f32 - u_pixel.y();
+        let (screen_x, screen_y, eye) = self.screen_position(x, y);
+        return self.ray(screen_x, screen_y, eye, time, u_lens);
+    }
+
+    /// Ray at `time` through pixel `index`, jittered by `u_pixel` only as far as the path tracer
+    /// and the GPU backend do
+    pub(super) fn jittered_ray(
+        &self,
+        index: usize,
+        time: f32,
+        u_pixel: Vec2f,
+        u_lens: Vec2f,
+    ) -> Option<Ray> {
+        let x = (index % self.width) as f32;
+        let y = (self.height - index / self.width) as f32;
+        let (screen_x, screen_y, eye) = self.screen_position(x, y);
+        let jitter = Vec2f::new(u_pixel.x() * 2.0 - 1.0, u_pixel.y() * 2.0 - 1.0) * 0.0005;
+        return self.ray(
+            screen_x - jitter.x(),
+            screen_y + jitter.y(),
+            eye,
+            time,
+            u_lens,
+        );
+    }
+
+    /// Whether light paths can be connected to the camera
+    pub(super) fn has_importance(&self) -> bool {
+        return self.projection == Projection::Perspective
+            && self.stereo.is_none()
+            && self.end_view.is_none();
+    }
+
+    /// Point on the aperture around `center` for the sample value `u`
+    pub(super) fn sample_lens(&self, center: Vec3f, u: Vec2f) -> Vec3f {
+        if self.lens_radius == 0.0 {
+            return center;
+        }
+        let lens = self.sample_aperture(u) * self.lens_radius;
+        return center + self.right * lens.x() + self.up * lens.y();
+    }
+
+    /// Point on the aperture relative to its center, in units of the lens radius
+    fn sample_aperture(&self, u: Vec2f) -> Vec2f {
+        match self.aperture {
+            Aperture::Circle => {
+                let radius = f32::sqrt(u.x());
+                let theta = 2.0 * PI * u.y();
+                return Vec2f::new(f32::cos(theta), f32::sin(theta)) * radius;
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // Uniform point in the triangle between the center and the edge of one blade
+                let blades = u32::max(blades, 3);
+                let blade = u32::min((u.x() * blades as f32) as u32, blades - 1);
+                let step = 2.0 * PI / blades as f32;
+                let angle_0 = f32::to_radians(rotation) + blade as f32 * step;
+                let angle_1 = angle_0 + step;
+                // What is left of u.x after picking the blade is uniform again
+                let mut b0 = u.x() * blades as f32 - blade as f32;
+                let mut b1 = u.y();
+                if b0 + b1 > 1.0 {
+                    b0 = 1.0 - b0;
+                    b1 = 1.0 - b1;
+                }
+                return Vec2f::new(f32::cos(angle_0), f32::sin(angle_0)) * b0
+                    + Vec2f::new(f32::cos(angle_1), f32::sin(angle_1)) * b1;
+            }
+            Aperture::Texture(_) => {
+                let texture = self.aperture_texture.as_ref().unwrap();
+                for i in 0..APERTURE_TEXTURE_TRIES {
+                    let offset = Vec2f::from(APERTURE_TEXTURE_STEP) * i as f32;
+                    let uv = Vec2f::new(
+                        f32::fract(u.x() + offset.x()),
+                        f32::fract(u.y() + offset.y()),
+                    );
+                    if texture.color_at(uv)[0] >= 128 {
+                        return uv * 2.0 - Vec2f::new(1.0, 1.0);
+                    }
+                }
+                return Vec2f::new(0.0, 0.0);
+            }
+        }
+    }
+
+    /// Index of the pixel a ray leaving the aperture at `origin` passes through, None if it
+    /// misses the film
+    pub(super) fn pixel(&self, origin: Vec3f, direction: Vec3f) -> Option<usize> {
+        let cos = Vec3f::dot(direction, self.axis);
+        if cos <= 0.0 || !self.has_importance() {
+            return None;
+        }
+        // Where the ray crosses the plane in focus, relative to the image plane at distance one
+        let film_point = (origin - self.position) / self.focus_distance + direction / cos;
+        let screen_x = -Vec3f::dot(film_point, self.right) / self.tan_half_fov;
+        let screen_y = Vec3f::dot(film_point, self.up) / self.tan_half_fov;
+        let x = (screen_x / self.aspect + 1.0) * 0.5 * self.width as f32;
+        let row = self.height as f32 - (screen_y + 1.0) * 0.5 * self.height as f32;
+        if !(x >= 0.0 && x < self.width as f32 && row >= 0.0 && row < self.height as f32) {
+            return None;
+        }
+        return Some(row as usize * self.width + x as usize);
+    }
+
+    /// Area of the film at distance one
+    fn film_area(&self) -> f32 {
+        return 4.0 * self.aspect * self.tan_half_fov * self.tan_half_fov;
+    }
+
+    /// Importance emitted along `direction` from `origin` on the aperture, normalized so that a
+    /// pixel measures the average radiance arriving through it. The density of the point on
+    /// the aperture cancels out with the one it was sampled with, so it's left out.
+    pub(super) fn importance(&self, origin: Vec3f, direction: Vec3f) -> f32 {
+        if self.pixel(origin, direction).is_none() {
+            return 0.0;
+        }
+        let cos = Vec3f::dot(direction, self.axis);
+        return 1.0 / (self.film_area() * cos * cos * cos * cos);
+    }
+
+    /// Solid angle density of the camera sampling `direction` from `origin` on the aperture
+    pub(super) fn pdf_dir(&self, origin: Vec3f, direction: Vec3f) -> f32 {
+        if self.pixel(origin, direction).is_none() {
+            return 0.0;
+        }
+        let cos = Vec3f::dot(direction, self.axis);
+        return 1.0 / (self.film_area() * cos * cos * cos);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct View {
+    position: Vec3f,
+    right: Vec3f,
+    up: Vec3f,
+    axis: Vec3f,
+}
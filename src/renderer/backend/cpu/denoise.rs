@@ -0,0 +1,96 @@
+use crate::math::vec::*;
+use crate::math::vec3::*;
+use crate::renderer::aov::AovPixel;
+use rayon::prelude::*;
+
+/// Filter passes, each one spreads the 5x5 kernel twice as far as the one before
+const ITERATIONS: u32 = 5;
+/// How different two colors can be before they stop blurring into each other, halved every
+/// iteration since the noise is already lower by then
+const COLOR_PHI: f32 = 0.5;
+const NORMAL_PHI: f32 = 0.1;
+const ALBEDO_PHI: f32 = 0.05;
+/// Depth difference relative to the depth of the center pixel
+const DEPTH_PHI: f32 = 0.02;
+
+/// B3 spline, the 1D kernel of the filter
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Edge-avoiding À-trous wavelet filter guided by the albedo, normal and depth AOVs. Mirrors
+/// denoise_compute.wgsl.
+///
+/// https://jo.dreggn.org/home/2010_atrous.pdf
+pub fn denoise(colors: Vec<Vec3f>, aovs: &[AovPixel], width: usize, height: usize) -> Vec<Vec3f> {
+    let mut colors = colors;
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let color_phi = COLOR_PHI / (1 << iteration) as f32;
+        colors = (0..width * height)
+            .into_par_iter()
+            .map(|index: usize| {
+                let x = (index % width) as i32;
+                let y = (index / width) as i32;
+                let center = &aovs[index];
+                let center_color = colors[index];
+
+                let mut sum = Vec3f::from(0.0);
+                let mut weight_sum = 0.0;
+                for (j, kernel_y) in KERNEL.iter().enumerate() {
+                    for (i, kernel_x) in KERNEL.iter().enumerate() {
+                        let sample_x = x + (i as i32 - 2) * step;
+                        let sample_y = y + (j as i32 - 2) * step;
+                        if sample_x < 0
+                            || sample_y < 0
+                            || sample_x >= width as i32
+                            || sample_y >= height as i32
+                        {
+                            continue;
+                        }
+                        let sample_index = sample_y as usize * width + sample_x as usize;
+                        let sample_color = colors[sample_index];
+                        let weight = kernel_x
+                            * kernel_y
+                            * edge_weight(
+                                center,
+                                &aovs[sample_index],
+                                center_color,
+                                sample_color,
+                                color_phi,
+                                step as f32,
+                            );
+                        sum += sample_color * weight;
+                        weight_sum += weight;
+                    }
+                }
+                return sum / weight_sum;
+            })
+            .collect::<Vec<Vec3f>>();
+    }
+    return colors;
+}
+
+/// How much a sample contributes to the center pixel, falls off with the difference between
+/// their colors and AOVs
+fn edge_weight(
+    center: &AovPixel,
+    sample: &AovPixel,
+    center_color: Vec3f,
+    sample_color: Vec3f,
+    color_phi: f32,
+    step: f32,
+) -> f32 {
+    // Compress the colors so bright pixels don't stop the filter everywhere
+    let color = center_color / (center_color + Vec3f::from(1.0))
+        - sample_color / (sample_color + Vec3f::from(1.0));
+    let normal = Vec3f::from(center.normal) - Vec3f::from(sample.normal);
+    let albedo = Vec3f::from(center.albedo) - Vec3f::from(sample.albedo);
+    let depth =
+        f32::abs(center.depth - sample.depth) / f32::max(DEPTH_PHI * center.depth * step, 1e-4);
+
+    return f32::exp(
+        -Vec3f::dot(color, color) / color_phi
+            - Vec3f::dot(normal, normal) / NORMAL_PHI
+            - Vec3f::dot(albedo, albedo) / ALBEDO_PHI
+            - depth,
+    );
+}
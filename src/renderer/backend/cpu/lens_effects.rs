@@ -0,0 +1,187 @@
+use crate::math::vec::*;
+use crate::math::vec3::*;
+use crate::renderer::post_process::{GlareKernel, PostProcess};
+use rayon::prelude::*;
+
+/// Samples taken along every streak
+const STREAK_SAMPLES: u32 = 32;
+/// Larger glows are cut off here to keep the blur passes cheap
+const MAX_BLOOM_RADIUS: i32 = 128;
+const LUMINANCE: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Glare, chromatic aberration and vignetting on the linear image, before exposure and tone
+/// mapping. Mirrors glare_compute.wgsl and the start of pp_compute.wgsl.
+pub fn apply(
+    colors: Vec<Vec3f>,
+    post_process: PostProcess,
+    kernel: GlareKernel,
+    width: usize,
+    height: usize,
+) -> Vec<Vec3f> {
+    let glare = if post_process.glare.is_enabled() {
+        glare(&colors, post_process, kernel, width, height)
+    } else {
+        vec![Vec3f::from(0.0); width * height]
+    };
+
+    return (0..width * height)
+        .into_par_iter()
+        .map(|index: usize| {
+            let x = (index % width) as f32 + 0.5;
+            let y = (index / width) as f32 + 0.5;
+            let center_x = width as f32 * 0.5;
+            let center_y = height as f32 * 0.5;
+
+            let mut color = colors[index];
+            if post_process.chromatic_aberration != 0.0 {
+                let aberration = post_process.chromatic_aberration;
+                let red_x = center_x + (x - center_x) * (1.0 - aberration);
+                let red_y = center_y + (y - center_y) * (1.0 - aberration);
+                let blue_x = center_x + (x - center_x) * (1.0 + aberration);
+                let blue_y = center_y + (y - center_y) * (1.0 + aberration);
+                color.data[0] = sample_bilinear(&colors, width, height, red_x, red_y).x();
+                color.data[2] = sample_bilinear(&colors, width, height, blue_x, blue_y).z();
+            }
+
+            color += glare[index];
+
+            if post_process.vignette > 0.0 {
+                let dx = x - center_x;
+                let dy = y - center_y;
+                let r2 = (dx * dx + dy * dy) / (center_x * center_x + center_y * center_y);
+                let falloff = 1.0 / (1.0 + post_process.vignette * r2);
+                color *= falloff * falloff;
+            }
+            return color;
+        })
+        .collect::<Vec<Vec3f>>();
+}
+
+/// Bloom and streaks of every pixel, already scaled by their strengths
+fn glare(
+    colors: &[Vec3f],
+    post_process: PostProcess,
+    kernel: GlareKernel,
+    width: usize,
+    height: usize,
+) -> Vec<Vec3f> {
+    let threshold = post_process.glare.threshold;
+    let bright = colors
+        .par_iter()
+        .map(|color| {
+            let luminance = Vec3f::dot(*color, Vec3f::from(LUMINANCE));
+            return *color * (f32::max(luminance - threshold, 0.0) / f32::max(luminance, 1e-4));
+        })
+        .collect::<Vec<Vec3f>>();
+
+    let horizontal = blur(&bright, kernel.bloom_radius, (1, 0), width, height);
+    let bloom = blur(&horizontal, kernel.bloom_radius, (0, 1), width, height);
+
+    return (0..width * height)
+        .into_par_iter()
+        .map(|index: usize| {
+            let x = (index % width) as i32;
+            let y = (index / width) as i32;
+            let streaks = streaks(&bright, kernel, x, y, width, height);
+            return bloom[index] * post_process.glare.bloom + streaks * post_process.glare.streaks;
+        })
+        .collect::<Vec<Vec3f>>();
+}
+
+/// One direction of a separable gaussian, everything outside the image is black
+fn blur(
+    colors: &[Vec3f],
+    radius: f32,
+    direction: (i32, i32),
+    width: usize,
+    height: usize,
+) -> Vec<Vec3f> {
+    let taps = i32::min(f32::ceil(radius) as i32, MAX_BLOOM_RADIUS);
+    let sigma = f32::max(radius / 3.0, 1e-4);
+
+    return (0..width * height)
+        .into_par_iter()
+        .map(|index: usize| {
+            let x = (index % width) as i32;
+            let y = (index / width) as i32;
+
+            let mut sum = Vec3f::from(0.0);
+            let mut weight_sum = 0.0;
+            for i in -taps..=taps {
+                let weight = f32::exp(-(i * i) as f32 / (2.0 * sigma * sigma));
+                weight_sum += weight;
+
+                let sample_x = x + i * direction.0;
+                let sample_y = y + i * direction.1;
+                if sample_x < 0
+                    || sample_y < 0
+                    || sample_x >= width as i32
+                    || sample_y >= height as i32
+                {
+                    continue;
+                }
+                sum += colors[sample_y as usize * width + sample_x as usize] * weight;
+            }
+            return sum / weight_sum;
+        })
+        .collect::<Vec<Vec3f>>();
+}
+
+/// Light streaked into pixel `x`, `y` from the bright pixels along every streak direction,
+/// fading out quadratically over the streak length
+fn streaks(
+    bright: &[Vec3f],
+    kernel: GlareKernel,
+    x: i32,
+    y: i32,
+    width: usize,
+    height: usize,
+) -> Vec3f {
+    let mut sum = Vec3f::from(0.0);
+    let mut weight_sum = 0.0;
+    for i in 0..kernel.streak_count {
+        let angle =
+            kernel.streak_rotation + i as f32 * std::f32::consts::TAU / kernel.streak_count as f32;
+        let direction_x = f32::cos(angle);
+        let direction_y = f32::sin(angle);
+
+        for k in 1..=STREAK_SAMPLES {
+            let t = k as f32 / STREAK_SAMPLES as f32;
+            let weight = (1.0 - t) * (1.0 - t);
+            weight_sum += weight;
+
+            let sample_x = x + f32::round(direction_x * t * kernel.streak_length) as i32;
+            let sample_y = y + f32::round(direction_y * t * kernel.streak_length) as i32;
+            if sample_x < 0 || sample_y < 0 || sample_x >= width as i32 || sample_y >= height as i32
+            {
+                continue;
+            }
+            sum += bright[sample_y as usize * width + sample_x as usize] * weight;
+        }
+    }
+
+    if weight_sum == 0.0 {
+        return Vec3f::from(0.0);
+    }
+    return sum / weight_sum;
+}
+
+/// Bilinear lookup at pixel coordinates where pixel centers are at half integers, clamped to
+/// the edges of the image
+fn sample_bilinear(colors: &[Vec3f], width: usize, height: usize, x: f32, y: f32) -> Vec3f {
+    let x = x - 0.5;
+    let y = y - 0.5;
+    let x_0 = f32::floor(x);
+    let y_0 = f32::floor(y);
+    let t_x = x - x_0;
+    let t_y = y - y_0;
+
+    let texel = |x: f32, y: f32| -> Vec3f {
+        let x = (x as i32).clamp(0, width as i32 - 1) as usize;
+        let y = (y as i32).clamp(0, height as i32 - 1) as usize;
+        return colors[y * width + x];
+    };
+    let top = Vec3f::mix(texel(x_0, y_0), texel(x_0 + 1.0, y_0), t_x);
+    let bottom = Vec3f::mix(texel(x_0, y_0 + 1.0), texel(x_0 + 1.0, y_0 + 1.0), t_x);
+    return Vec3f::mix(top, bottom, t_y);
+}
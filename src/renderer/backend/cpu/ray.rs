@@ -1,19 +1,33 @@
+use std::f32::consts::PI;
+
+use super::bsdf::{self, Bsdf, Frame, Lambert, Lobe};
+use super::sampler::{self, Sampler};
 use crate::bvh::Node;
+use crate::math::rand_f32;
 use crate::math::vec::*;
 use crate::math::vec2::*;
 use crate::math::vec3::*;
+use crate::medium::{Medium, MediumInteraction};
 use crate::scene::Material;
-use crate::scene::{Scene, Triangle};
+use crate::scene::{NO_LIGHT, Scene, Triangle};
+use crate::spectrum::{RGB_WAVELENGTHS, Wavelengths};
 
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3f,
     pub direction: Vec3f,
+    /// Time within the frame at which the ray sees the scene, moving triangles are intersected
+    /// where they are at this time
+    pub time: f32,
 }
 
 impl Ray {
-    pub fn new(origin: Vec3f, direction: Vec3f) -> Self {
-        return Self { origin, direction };
+    pub fn new(origin: Vec3f, direction: Vec3f, time: f32) -> Self {
+        return Self {
+            origin,
+            direction,
+            time,
+        };
     }
 
     fn intersect_tri(ray: &Self, tri: &Triangle) -> HitInfo {
@@ -47,6 +61,12 @@ impl Ray {
             normal = normal.reversed();
         }
 
+        // Corners of a triangle always share the same handedness
+        let bitangent_sign = tri.vertices[0].bitangent_sign;
+        let tangent = tri.vertices[0].tangent * (1.0 - u - v)
+            + (tri.vertices[1].tangent * u)
+            + (tri.vertices[2].tangent * v);
+
         let t_0 = Vec2f::new(tri.vertices[0].tex_coord_x, tri.vertices[0].tex_coord_y);
         let t_1 = Vec2f::new(tri.vertices[1].tex_coord_x, tri.vertices[1].tex_coord_y);
         let t_2 = Vec2f::new(tri.vertices[2].tex_coord_x, tri.vertices[2].tex_coord_y);
@@ -59,9 +79,12 @@ impl Ray {
                 && !(v < 0.0 || u + v > 1.0),
             point: ray.origin + (ray.direction * t),
             normal: normal,
+            tangent: tangent,
+            bitangent_sign: bitangent_sign,
             distance: t,
             uv: uv,
             material_id: tri.material_id,
+            tri_index: 0,
             front_face: front_face,
         };
     }
@@ -81,7 +104,7 @@ impl Ray {
     }
 
     // https://jacco.ompf2.com/2022/04/18/how-to-build-a-bvh-part-2-faster-rays/
-    fn traverse_bvh(ray: &Self, scene: &Scene, hit_info: &mut HitInfo) {
+    pub(super) fn traverse_bvh(ray: &Self, scene: &Scene, hit_info: &mut HitInfo) {
         let mut stack: [Node; 32] = [Node::default(); 32];
         let mut node: &Node = scene.bvh.nodes.get(0).unwrap();
         let mut stack_ptr: usize = 0;
@@ -89,12 +112,12 @@ impl Ray {
         loop {
             if node.num_tris > 0 {
                 for i in 0..node.num_tris {
-                    let temp_hit_info = Self::intersect_tri(
-                        ray,
-                        &scene.tris[(node.first_tri_or_child + i) as usize],
-                    );
+                    let tri_index = node.first_tri_or_child + i;
+                    let tri = scene.tris[tri_index as usize].at_time(&scene.motions, ray.time);
+                    let temp_hit_info = Self::intersect_tri(ray, &tri);
                     if temp_hit_info.has_hit && temp_hit_info.distance < hit_info.distance {
                         *hit_info = temp_hit_info;
+                        hit_info.tri_index = tri_index;
                     }
                 }
                 if stack_ptr == 0 {
@@ -138,10 +161,26 @@ impl Ray {
         }
     }
 
-    pub fn trace(ray: &mut Self, max_bounces: usize, scene: &Scene, rng_state: &mut u32) -> Vec3f {
+    /// Traces a path through the scene. With `wavelengths` the returned values are spectral, one
+    /// per wavelength, otherwise they are linear RGB. Light and BSDF sampling take their values
+    /// from `sampler`, everything else from `rng_state`.
+    ///
+    /// Emitters and the sun are sampled directly at every vertex and also found by the BSDF
+    /// samples, both estimates are combined with the power heuristic.
+    pub fn trace(
+        ray: &mut Self,
+        max_bounces: usize,
+        scene: &Scene,
+        wavelengths: Option<Wavelengths>,
+        sampler: &dyn Sampler,
+        rng_state: &mut u32,
+    ) -> Vec3f {
         let mut ray_color = Vec3f::new(1.0, 1.0, 1.0);
         let mut incoming_light = Vec3f::new(0.0, 0.0, 0.0);
-        let mut emitted_light = Vec3f::new(0.0, 0.0, 0.0);
+        // Vertex the ray left from, if it sampled lights and the ray direction has a density
+        let mut prev_vertex: Option<PrevVertex> = None;
+        let mut medium_id = scene.global_medium_id;
+        let mut secondary_terminated = false;
 
         let mut curr_bounces: usize = 0;
         while curr_bounces < max_bounces {
@@ -149,67 +188,633 @@ impl Ray {
 
             Self::traverse_bvh(ray, scene, &mut hit_info);
 
-            if hit_info.has_hit {
-                let hit_material = &scene.materials.values().collect::<Vec<&Material>>()
-                    [hit_info.material_id as usize];
-                let ior: f32;
-                if hit_info.front_face {
-                    ior = 1.0 / hit_material.ior;
+            if let Some(id) = medium_id {
+                let medium = Self::medium(scene, id, wavelengths);
+                let t_max = if hit_info.has_hit {
+                    hit_info.distance
                 } else {
-                    ior = hit_material.ior;
+                    f32::MAX
+                };
+                match medium.sample_interaction(
+                    ray.origin,
+                    ray.direction,
+                    t_max,
+                    &scene.density_grid_data,
+                    &mut ray_color,
+                    rng_state,
+                ) {
+                    MediumInteraction::Absorbed => break,
+                    MediumInteraction::Scattered(point) => {
+                        let new_dir = medium.sample_phase(ray.direction, rng_state);
+                        *ray = Self::new(point, new_dir, ray.time);
+                        prev_vertex = None;
+                        curr_bounces += 1;
+                        continue;
+                    }
+                    MediumInteraction::None => (),
                 }
+            }
 
-                if hit_material.base_color_tex_id != u32::MAX {
-                    ray_color *= Vec3f::from(
-                        scene.textures[hit_material.base_color_tex_id as usize]
-                            .color_at(hit_info.uv),
+            if hit_info.has_hit {
+                let hit_material = scene.material(hit_info.material_id);
+
+                // Transparent surfaces let the ray pass through, this is also how the
+                // boundaries of media are usually modeled
+                if Self::transparency_at(scene, hit_material, hit_info.uv) < rand_f32(rng_state) {
+                    medium_id = Self::next_medium(scene, hit_material, &hit_info, medium_id);
+                    *ray = Self::new(
+                        hit_info.point + ray.direction * 0.0001,
+                        ray.direction,
+                        ray.time,
                     );
+                    continue;
+                }
+
+                if hit_material.normal_tex_id != u32::MAX {
+                    Self::apply_normal_map(scene, hit_material, &mut hit_info);
+                }
+
+                // Emitters are one-sided like in the light BVH, BDPT and SPPM
+                let emission = Self::emission_at(scene, hit_material, hit_info.uv, wavelengths);
+                if hit_info.front_face && Vec3f::dot(emission, Vec3f::from(1.0)) > 0.0 {
+                    let weight = match &prev_vertex {
+                        Some(prev) => Self::emitter_weight(scene, prev, &hit_info, ray.direction),
+                        None => 1.0,
+                    };
+                    incoming_light += emission * ray_color * weight;
+                }
+
+                let subsurface =
+                    hit_info.front_face && rand_f32(rng_state) < hit_material.subsurface;
+                let bsdf: Box<dyn Bsdf>;
+                if subsurface {
+                    match Self::random_walk(
+                        scene,
+                        &hit_info,
+                        ray.time,
+                        hit_material,
+                        wavelengths,
+                        &mut ray_color,
+                        rng_state,
+                    ) {
+                        Some(exit_hit_info) => hit_info = exit_hit_info,
+                        None => break,
+                    }
+                    // The path continues diffusely from where the walk left the mesh, the walk
+                    // already determined the color
+                    bsdf = Box::new(Lambert {
+                        albedo: Vec3f::from(1.0),
+                    });
                 } else {
-                    ray_color *= hit_material.base_color;
+                    bsdf = Self::surface_bsdf(scene, hit_material, &hit_info, wavelengths);
+
+                    // The IOR is only right for the hero wavelength, the others can't follow
+                    // the refracted path
+                    if wavelengths.is_some()
+                        && hit_material.is_dispersive()
+                        && hit_material.transmission > 0.0
+                        && !secondary_terminated
+                    {
+                        ray_color = Vec3f::new(ray_color.x() * 3.0, 0.0, 0.0);
+                        secondary_terminated = true;
+                    }
                 }
-                if hit_material.emission_tex_id != u32::MAX {
-                    emitted_light += Vec3f::from(
-                        scene.textures[hit_material.emission_tex_id as usize].color_at(hit_info.uv),
-                    );
+
+                let dimension = sampler::bounce_dimension(curr_bounces);
+                let frame = if subsurface {
+                    Frame::new(hit_info.normal)
                 } else {
-                    emitted_light += hit_material.emission;
+                    Frame::with_tangent(hit_info.normal, hit_info.tangent, hit_info.bitangent_sign)
+                        .rotated(hit_material.anisotropic_rotation * 2.0 * PI)
+                };
+                let shading = ShadingPoint {
+                    point: hit_info.point,
+                    normal: hit_info.normal,
+                    wo: frame.to_local(ray.direction.reversed()),
+                    frame,
+                    bsdf,
+                    medium_id,
+                    time: ray.time,
+                };
+
+                // Lights can't be sampled for Dirac delta lobes, those rely on hitting the
+                // emitters by chance instead
+                let samples_lights = shading
+                    .bsdf
+                    .lobes()
+                    .intersects(Lobe::DIFFUSE | Lobe::GLOSSY);
+                if samples_lights {
+                    if scene.sky.has_sun() {
+                        incoming_light += Self::sample_sun(
+                            scene,
+                            &shading,
+                            wavelengths,
+                            sampler.get_2d(dimension + sampler::SUN_DIMENSION),
+                            rng_state,
+                        ) * ray_color;
+                    }
+                    incoming_light += Self::sample_emitter(
+                        scene,
+                        &shading,
+                        wavelengths,
+                        sampler.get_1d(dimension + sampler::LIGHT_SELECTION_DIMENSION),
+                        sampler.get_2d(dimension + sampler::LIGHT_POINT_DIMENSION),
+                        rng_state,
+                    ) * ray_color;
                 }
-                incoming_light += emitted_light * ray_color;
 
-                let new_dir =
-                    (hit_info.normal + Vec3f::rand_in_unit_sphere(rng_state)).normalized();
-                *ray = Self::new(hit_info.point + new_dir * 0.0001, new_dir);
+                let Some(sample) = shading.bsdf.sample(
+                    shading.wo,
+                    sampler.get_1d(dimension + sampler::BSDF_LOBE_DIMENSION),
+                    sampler.get_2d(dimension + sampler::BSDF_DIRECTION_DIMENSION),
+                ) else {
+                    break;
+                };
+                ray_color *= sample.weight;
+
+                if samples_lights && !sample.lobe.contains(Lobe::SPECULAR) {
+                    prev_vertex = Some(PrevVertex {
+                        point: shading.point,
+                        normal: shading.normal,
+                        bsdf_pdf: sample.pdf,
+                    });
+                } else {
+                    prev_vertex = None;
+                }
+
+                if sample.lobe.contains(Lobe::TRANSMISSION) && hit_material.thin_walled == 0 {
+                    medium_id = Self::next_medium(scene, hit_material, &hit_info, medium_id);
+                }
+
+                let new_dir = shading.frame.to_world(sample.wi).normalized();
+                *ray = Self::new(hit_info.point + new_dir * 0.0001, new_dir, ray.time);
 
                 curr_bounces += 1;
             } else {
-                let sky_color = Vec3f::new(1.0, 1.0, 1.0);
-                let sky_strength = Vec3f::from(1.0);
-
-                ray_color *= sky_color;
-                emitted_light += sky_strength;
-                incoming_light += emitted_light * ray_color;
+                let sky_light: Vec3f;
+                if scene.sky.in_sun(ray.direction) {
+                    let weight = match &prev_vertex {
+                        Some(prev) if scene.sky.has_sun() => {
+                            power_heuristic(prev.bsdf_pdf, 1.0 / scene.sky.sun_solid_angle())
+                        }
+                        _ => 1.0,
+                    };
+                    sky_light = scene.sky.sun_radiance() * weight;
+                } else {
+                    sky_light = scene.sky.radiance(ray.direction, false);
+                }
+                incoming_light += Self::spectral(sky_light, wavelengths) * ray_color;
 
                 break;
             }
         }
 
-        // If we hit the sky directly
-        if curr_bounces == 0 {
-            return incoming_light;
+        return incoming_light;
+    }
+
+    /// MIS weight of an emitter found by the BSDF sample leaving `prev`, against picking the
+    /// same point through the light BVH
+    fn emitter_weight(
+        scene: &Scene,
+        prev: &PrevVertex,
+        hit_info: &HitInfo,
+        direction: Vec3f,
+    ) -> f32 {
+        let tri = &scene.tris[hit_info.tri_index as usize];
+        if tri.light_id == NO_LIGHT {
+            return 1.0;
+        }
+        let (light_normal, area) = Self::emitter_geometry(tri);
+        // Only front faces emit, so the cosine is positive
+        let cos_light = Vec3f::dot(light_normal, direction.reversed());
+
+        let distance_squared = Vec3f::dot(hit_info.point - prev.point, hit_info.point - prev.point);
+        let light_pdf = scene.light_bvh.pmf(prev.point, prev.normal, tri.light_id)
+            * distance_squared
+            / (cos_light * area);
+        return power_heuristic(prev.bsdf_pdf, light_pdf);
+    }
+
+    /// Builds the BSDF of the surface at `hit_info`, resolving the material textures
+    fn surface_bsdf(
+        scene: &Scene,
+        material: &Material,
+        hit_info: &HitInfo,
+        wavelengths: Option<Wavelengths>,
+    ) -> Box<dyn Bsdf> {
+        let material = Self::surface_material(scene, material, hit_info, wavelengths);
+        let channel_wavelengths = match wavelengths {
+            Some(wavelengths) => wavelengths.lambda,
+            None => Vec3f::from(RGB_WAVELENGTHS),
+        };
+        return bsdf::from_material(&material, hit_info.front_face, channel_wavelengths);
+    }
+
+    /// Copy of `material` with its textures resolved at `hit_info` and its colors uplifted in
+    /// spectral mode
+    pub(super) fn surface_material(
+        scene: &Scene,
+        material: &Material,
+        hit_info: &HitInfo,
+        wavelengths: Option<Wavelengths>,
+    ) -> Material {
+        let texture_at = |tex_id: u32| -> [u8; 4] {
+            return scene.textures[tex_id as usize].color_at(hit_info.uv);
+        };
+
+        let mut material = *material;
+        if material.base_color_tex_id != u32::MAX {
+            material.base_color = Vec3f::from(texture_at(material.base_color_tex_id));
+        }
+        if material.roughness_tex_id != u32::MAX {
+            material.roughness = texture_at(material.roughness_tex_id)[1] as f32 / 255.0;
+        }
+        if material.metallic_tex_id != u32::MAX {
+            material.metallic = texture_at(material.metallic_tex_id)[2] as f32 / 255.0;
+        }
+        if material.thin_film_thickness_tex_id != u32::MAX {
+            let blend = texture_at(material.thin_film_thickness_tex_id)[1] as f32 / 255.0;
+            material.thin_film_thickness = material.thin_film_thickness_min
+                + (material.thin_film_thickness - material.thin_film_thickness_min) * blend;
+        }
+        if let Some(wavelengths) = wavelengths {
+            material.base_color = wavelengths.uplift(material.base_color);
+            material.specular_tint = wavelengths.uplift(material.specular_tint);
+            if material.is_dispersive() {
+                material.ior = material.ior_at(wavelengths.hero());
+            }
+        }
+        return material;
+    }
+
+    /// Perturbs the shading normal with a tangent space normal map, decoded the same way as
+    /// MikkTSpace based bakers encode it
+    pub(super) fn apply_normal_map(scene: &Scene, material: &Material, hit_info: &mut HitInfo) {
+        let texel = scene.textures[material.normal_tex_id as usize].color_at(hit_info.uv);
+        let local = Vec3f::from(texel) * 2.0 - Vec3f::from(1.0);
+        let frame = Frame::with_tangent(hit_info.normal, hit_info.tangent, hit_info.bitangent_sign);
+        let normal = frame.to_world(local).normalized();
+        if Vec3f::dot(normal, normal) > 0.0 {
+            hit_info.normal = normal;
+        }
+    }
+
+    /// Medium on the other side of a surface, entering or leaving a closed mesh switches the
+    /// medium the ray travels through
+    fn next_medium(
+        scene: &Scene,
+        material: &Material,
+        hit_info: &HitInfo,
+        current: Option<u32>,
+    ) -> Option<u32> {
+        if material.medium_id == u32::MAX {
+            return current;
+        } else if hit_info.front_face {
+            return Some(material.medium_id);
+        } else {
+            return scene.global_medium_id;
+        }
+    }
+
+    /// Random walk subsurface scattering through the medium described by `material`, starting at
+    /// a front face hit. Returns where the walk left the mesh with the normal facing outwards, or
+    /// None if the walk was absorbed.
+    fn random_walk(
+        scene: &Scene,
+        hit_info: &HitInfo,
+        time: f32,
+        material: &Material,
+        wavelengths: Option<Wavelengths>,
+        ray_color: &mut Vec3f,
+        rng_state: &mut u32,
+    ) -> Option<HitInfo> {
+        const MAX_WALK_STEPS: usize = 256;
+
+        let mut material = *material;
+        material.subsurface_albedo = Self::spectral(material.subsurface_albedo, wavelengths);
+        material.subsurface_mfp = Self::spectral(material.subsurface_mfp, wavelengths);
+        let medium = material.subsurface_medium();
+        let entry_dir = (hit_info.normal + Vec3f::rand_in_unit_sphere(rng_state))
+            .normalized()
+            .reversed();
+        let mut walk_ray = Self::new(hit_info.point - hit_info.normal * 0.0001, entry_dir, time);
+
+        for _ in 0..MAX_WALK_STEPS {
+            let mut walk_hit_info = HitInfo::default();
+            Self::traverse_bvh(&walk_ray, scene, &mut walk_hit_info);
+            // Open meshes let the walk escape into nothing
+            if !walk_hit_info.has_hit {
+                return None;
+            }
+
+            match medium.sample_interaction(
+                walk_ray.origin,
+                walk_ray.direction,
+                walk_hit_info.distance,
+                &[],
+                ray_color,
+                rng_state,
+            ) {
+                MediumInteraction::Absorbed => return None,
+                MediumInteraction::Scattered(point) => {
+                    let direction = medium.sample_phase(walk_ray.direction, rng_state);
+                    walk_ray = Self::new(point, direction, time);
+                }
+                MediumInteraction::None => {
+                    walk_hit_info.normal = walk_hit_info.normal.reversed();
+                    return Some(walk_hit_info);
+                }
+            }
+        }
+
+        return None;
+    }
+
+    /// Samples the sun disk at `u` and returns the direct light it reflects off of `shading`,
+    /// divided by the sampling probability and weighted against BSDF sampling
+    fn sample_sun(
+        scene: &Scene,
+        shading: &ShadingPoint,
+        wavelengths: Option<Wavelengths>,
+        u: Vec2f,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let sun_dir = scene.sky.sample_sun_direction(u);
+        let wi = shading.frame.to_local(sun_dir);
+        let bsdf_value = shading.bsdf.eval(shading.wo, wi) * f32::abs(wi.z());
+        if Vec3f::dot(bsdf_value, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        let shadow_ray = Self::new(shading.point + sun_dir * 0.0001, sun_dir, shading.time);
+        let visibility = Self::shadow_transmittance(
+            scene,
+            &shadow_ray,
+            f32::MAX,
+            shading.medium_id,
+            wavelengths,
+            rng_state,
+        );
+        if Vec3f::dot(visibility, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        let sun_pdf = 1.0 / scene.sky.sun_solid_angle();
+        let weight = power_heuristic(sun_pdf, shading.bsdf.pdf(shading.wo, wi));
+        let sun_light = Self::spectral(scene.sky.sun_radiance(), wavelengths) * visibility;
+        return sun_light * bsdf_value * (weight / sun_pdf);
+    }
+
+    /// Picks an emissive triangle through the light BVH with `u_select`, samples the point
+    /// `u_point` on it and returns the direct light it reflects off of `shading`, divided by the
+    /// sampling probability and weighted against BSDF sampling
+    fn sample_emitter(
+        scene: &Scene,
+        shading: &ShadingPoint,
+        wavelengths: Option<Wavelengths>,
+        u_select: f32,
+        u_point: Vec2f,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let Some((tri_index, pmf)) =
+            scene
+                .light_bvh
+                .sample(shading.point, shading.normal, u_select)
+        else {
+            return Vec3f::from(0.0);
+        };
+        let tri = &scene.tris[tri_index as usize];
+
+        let r = f32::sqrt(u_point.x());
+        let b_0 = 1.0 - r;
+        let b_1 = u_point.y() * r;
+        let b_2 = 1.0 - b_0 - b_1;
+        let point = tri.vertices[0].position * b_0
+            + tri.vertices[1].position * b_1
+            + tri.vertices[2].position * b_2;
+        let uv = Vec2f::new(tri.vertices[0].tex_coord_x, tri.vertices[0].tex_coord_y) * b_0
+            + Vec2f::new(tri.vertices[1].tex_coord_x, tri.vertices[1].tex_coord_y) * b_1
+            + Vec2f::new(tri.vertices[2].tex_coord_x, tri.vertices[2].tex_coord_y) * b_2;
+        let (light_normal, area) = Self::emitter_geometry(tri);
+
+        let to_light = point - shading.point;
+        let distance = to_light.length();
+        let light_dir = to_light / distance;
+        let cos_light = Vec3f::dot(light_normal, light_dir.reversed());
+        if cos_light <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+        let wi = shading.frame.to_local(light_dir);
+        let bsdf_value = shading.bsdf.eval(shading.wo, wi) * f32::abs(wi.z());
+        if Vec3f::dot(bsdf_value, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        let shadow_ray = Self::new(shading.point + light_dir * 0.0001, light_dir, shading.time);
+        let visibility = Self::shadow_transmittance(
+            scene,
+            &shadow_ray,
+            distance,
+            shading.medium_id,
+            wavelengths,
+            rng_state,
+        );
+        if Vec3f::dot(visibility, Vec3f::from(1.0)) <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        // Density of the point in solid angle
+        let light_pdf = pmf * distance * distance / (cos_light * area);
+        let weight = power_heuristic(light_pdf, shading.bsdf.pdf(shading.wo, wi));
+        let emission =
+            Self::emission_at(scene, scene.material(tri.material_id), uv, wavelengths) * visibility;
+        return emission * bsdf_value * (weight / light_pdf);
+    }
+
+    /// Normal of the emitting side of a triangle and its area
+    fn emitter_geometry(tri: &Triangle) -> (Vec3f, f32) {
+        let cross = Vec3f::cross(
+            tri.vertices[1].position - tri.vertices[0].position,
+            tri.vertices[2].position - tri.vertices[0].position,
+        );
+        return (cross.normalized(), cross.length() * 0.5);
+    }
+
+    /// Transmittance of the medium the ray is currently in, up to `distance`
+    fn transmittance(
+        scene: &Scene,
+        medium_id: Option<u32>,
+        ray: &Self,
+        distance: f32,
+        wavelengths: Option<Wavelengths>,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let Some(id) = medium_id else {
+            return Vec3f::from(1.0);
+        };
+        return Self::medium(scene, id, wavelengths).transmittance(
+            ray.origin,
+            ray.direction,
+            distance,
+            &scene.density_grid_data,
+            rng_state,
+        );
+    }
+
+    /// Fraction of the light that makes it `distance` along `ray`, starting in `medium_id`.
+    /// Surfaces let it through as often as `trace` passes them and the media in between attenuate
+    /// it.
+    pub(super) fn shadow_transmittance(
+        scene: &Scene,
+        ray: &Self,
+        distance: f32,
+        medium_id: Option<u32>,
+        wavelengths: Option<Wavelengths>,
+        rng_state: &mut u32,
+    ) -> Vec3f {
+        let mut shadow_ray = *ray;
+        let mut remaining = distance;
+        let mut medium_id = medium_id;
+        let mut transmittance = Vec3f::from(1.0);
+        loop {
+            let mut hit_info = HitInfo::default();
+            Self::traverse_bvh(&shadow_ray, scene, &mut hit_info);
+            if !hit_info.has_hit || hit_info.distance >= remaining * 0.999 {
+                return transmittance
+                    * Self::transmittance(
+                        scene,
+                        medium_id,
+                        &shadow_ray,
+                        remaining,
+                        wavelengths,
+                        rng_state,
+                    );
+            }
+
+            let material = scene.material(hit_info.material_id);
+            let opacity = Self::transparency_at(scene, material, hit_info.uv);
+            if opacity >= 1.0 {
+                return Vec3f::from(0.0);
+            }
+            transmittance *= Self::transmittance(
+                scene,
+                medium_id,
+                &shadow_ray,
+                hit_info.distance,
+                wavelengths,
+                rng_state,
+            ) * (1.0 - opacity);
+
+            medium_id = Self::next_medium(scene, material, &hit_info, medium_id);
+            shadow_ray = Self::new(
+                hit_info.point + shadow_ray.direction * 0.0001,
+                shadow_ray.direction,
+                shadow_ray.time,
+            );
+            remaining -= hit_info.distance;
+        }
+    }
+
+    /// Opacity of the surface, despite the name a value of zero lets rays pass through
+    pub(super) fn transparency_at(scene: &Scene, material: &Material, uv: Vec2f) -> f32 {
+        let opacity: f32;
+        if material.transparency_tex_id != u32::MAX {
+            opacity = scene.textures[material.transparency_tex_id as usize].color_at(uv)[3] as f32
+                / 255.0;
+        } else {
+            opacity = material.transparency;
+        }
+
+        if material.alpha_cutoff > 0.0 {
+            return if opacity >= material.alpha_cutoff {
+                1.0
+            } else {
+                0.0
+            };
+        }
+        return opacity;
+    }
+
+    pub(super) fn emission_at(
+        scene: &Scene,
+        material: &Material,
+        uv: Vec2f,
+        wavelengths: Option<Wavelengths>,
+    ) -> Vec3f {
+        let emission: Vec3f;
+        if material.emission_tex_id != u32::MAX {
+            emission = Vec3f::from(scene.textures[material.emission_tex_id as usize].color_at(uv))
+                * material.emission_scale;
         } else {
-            return incoming_light / curr_bounces as f32;
+            emission = material.emission;
+        }
+        return Self::spectral(emission, wavelengths);
+    }
+
+    /// Medium `id` with its coefficients at the sampled wavelengths in spectral mode
+    fn medium(scene: &Scene, id: u32, wavelengths: Option<Wavelengths>) -> Medium {
+        let mut medium = scene.media[id as usize];
+        medium.sigma_a = Self::spectral(medium.sigma_a, wavelengths);
+        medium.sigma_s = Self::spectral(medium.sigma_s, wavelengths);
+        return medium;
+    }
+
+    /// Uplifts an RGB color to the sampled wavelengths in spectral mode, returns it as is
+    /// otherwise
+    fn spectral(rgb: Vec3f, wavelengths: Option<Wavelengths>) -> Vec3f {
+        match wavelengths {
+            Some(wavelengths) => return wavelengths.uplift(rgb),
+            None => return rgb,
         }
     }
 }
 
-struct HitInfo {
-    has_hit: bool,
+/// Surface point being shaded, in the local frame of the BSDF
+struct ShadingPoint {
     point: Vec3f,
+    /// Shading normal, the light BVH picks emitters for it
     normal: Vec3f,
-    distance: f32,
-    uv: Vec2f,
-    material_id: u32,
-    front_face: bool,
+    frame: Frame,
+    wo: Vec3f,
+    bsdf: Box<dyn Bsdf>,
+    /// Medium surrounding the point on the side of `wo`
+    medium_id: Option<u32>,
+    time: f32,
+}
+
+/// Vertex a ray left from after sampling lights, emitters and the sun found by the ray are
+/// weighted against having been sampled from here
+struct PrevVertex {
+    point: Vec3f,
+    normal: Vec3f,
+    /// Solid angle density of the BSDF sample that chose the ray direction
+    bsdf_pdf: f32,
+}
+
+/// Power heuristic with an exponent of two, the weight of a sample taken with density `pdf_a`
+/// that could also have been taken with density `pdf_b`
+fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let a = pdf_a * pdf_a;
+    let b = pdf_b * pdf_b;
+    if a + b <= 0.0 {
+        return 0.0;
+    }
+    return a / (a + b);
+}
+
+pub(super) struct HitInfo {
+    pub(super) has_hit: bool,
+    pub(super) point: Vec3f,
+    /// Shading normal, facing the side the ray came from
+    pub(super) normal: Vec3f,
+    /// Interpolated vertex tangent, not orthogonal to `normal`
+    pub(super) tangent: Vec3f,
+    pub(super) bitangent_sign: f32,
+    pub(super) distance: f32,
+    pub(super) uv: Vec2f,
+    pub(super) material_id: u32,
+    pub(super) tri_index: u32,
+    pub(super) front_face: bool,
 }
 
 impl Default for HitInfo {
@@ -218,9 +823,12 @@ impl Default for HitInfo {
             has_hit: false,
             point: Vec3f::default(),
             normal: Vec3f::default(),
+            tangent: Vec3f::default(),
+            bitangent_sign: 1.0,
             distance: 1e30f32,
             uv: Vec2f::default(),
             material_id: 0,
+            tri_index: 0,
             front_face: false,
         };
     }
@@ -0,0 +1,319 @@
+use crate::math::vec2::*;
+use crate::math::{hash_combine, hash_u32};
+use crate::renderer::backend::SamplerType;
+
+/// Dimensions of the camera ray, 2D values take two
+pub const PIXEL_DIMENSION: u32 = 0;
+pub const LENS_DIMENSION: u32 = 2;
+pub const TIME_DIMENSION: u32 = 4;
+pub const WAVELENGTH_DIMENSION: u32 = 5;
+
+/// Dimensions of every bounce, relative to the start of the bounce
+pub const LIGHT_SELECTION_DIMENSION: u32 = 0;
+pub const LIGHT_POINT_DIMENSION: u32 = 1;
+pub const SUN_DIMENSION: u32 = 3;
+pub const BSDF_LOBE_DIMENSION: u32 = 5;
+pub const BSDF_DIRECTION_DIMENSION: u32 = 6;
+const FIRST_BOUNCE_DIMENSION: u32 = 6;
+/// The last dimension of a bounce is the russian roulette of rt_compute.wgsl
+const BOUNCE_DIMENSIONS: u32 = 9;
+
+/// Levels of the Morton curve of the blue noise sampler, it repeats every 256 pixels
+const MORTON_LEVELS: u32 = 8;
+
+/// Generates the random numbers of a path. Every decision takes the value of a fixed
+/// dimension, so the samples of a pixel are well distributed in each one of them. Mirrors
+/// `sample_1d` and `sample_2d` in rt_compute.wgsl.
+pub trait Sampler {
+    /// Moves to sample `sample_index` of the pixel at `x`, `y`
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32);
+    fn get_1d(&self, dimension: u32) -> f32;
+    fn get_2d(&self, dimension: u32) -> Vec2f;
+}
+
+/// Sampler of the given type for pixels taking `samples` samples
+pub fn new(sampler_type: SamplerType, samples: usize) -> Box<dyn Sampler> {
+    let samples = u32::max(samples as u32, 1);
+    match sampler_type {
+        SamplerType::Independent => return Box::new(Independent { seed: 0 }),
+        SamplerType::Stratified => {
+            return Box::new(Stratified {
+                samples,
+                pixel_seed: 0,
+                sample_index: 0,
+            });
+        }
+        SamplerType::Sobol => {
+            return Box::new(Sobol {
+                pixel_seed: 0,
+                sample_index: 0,
+            });
+        }
+        SamplerType::BlueNoise => {
+            // The sample index has to fit next to the Morton index
+            let samples_log2 = u32::min(
+                samples.next_power_of_two().trailing_zeros(),
+                32 - 2 * MORTON_LEVELS,
+            );
+            return Box::new(BlueNoise {
+                samples_log2,
+                x: 0,
+                y: 0,
+                sample_index: 0,
+            });
+        }
+    }
+}
+
+/// First dimension of `bounce`
+pub fn bounce_dimension(bounce: usize) -> u32 {
+    return FIRST_BOUNCE_DIMENSION + bounce as u32 * BOUNCE_DIMENSIONS;
+}
+
+struct Independent {
+    seed: u32,
+}
+
+impl Sampler for Independent {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.seed = hash_combine(pixel_seed(x, y), sample_index);
+    }
+
+    fn get_1d(&self, dimension: u32) -> f32 {
+        return to_unit(hash_u32(hash_combine(self.seed, dimension)));
+    }
+
+    fn get_2d(&self, dimension: u32) -> Vec2f {
+        return Vec2f::new(self.get_1d(dimension), self.get_1d(dimension + 1));
+    }
+}
+
+/// Jittered stratification over the samples of a pixel. 2D dimensions are divided into a
+/// grid, which leaves some cells empty if the sample count isn't a square. Samples past the
+/// sample count start over with different strata.
+struct Stratified {
+    samples: u32,
+    pixel_seed: u32,
+    sample_index: u32,
+}
+
+impl Stratified {
+    /// Stratum of the current sample out of `strata` and the seed of its jitter
+    fn stratum(&self, dimension: u32, strata: u32) -> (u32, u32) {
+        let round = self.sample_index / self.samples;
+        let seed = hash_u32(hash_combine(
+            hash_combine(self.pixel_seed, dimension),
+            round,
+        ));
+        let stratum = permutation_element(self.sample_index % self.samples, strata, seed);
+        return (stratum, hash_combine(seed, self.sample_index));
+    }
+}
+
+impl Sampler for Stratified {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.pixel_seed = pixel_seed(x, y);
+        self.sample_index = sample_index;
+    }
+
+    fn get_1d(&self, dimension: u32) -> f32 {
+        let (stratum, seed) = self.stratum(dimension, self.samples);
+        let jitter = to_unit(hash_u32(seed));
+        return (stratum as f32 + jitter) / self.samples as f32;
+    }
+
+    fn get_2d(&self, dimension: u32) -> Vec2f {
+        let columns = f32::ceil(f32::sqrt(self.samples as f32)) as u32;
+        let rows = self.samples.div_ceil(columns);
+        let (cell, seed) = self.stratum(dimension, columns * rows);
+        let jitter_x = to_unit(hash_u32(seed));
+        let jitter_y = to_unit(hash_u32(hash_combine(seed, 1)));
+        return Vec2f::new(
+            ((cell % columns) as f32 + jitter_x) / columns as f32,
+            ((cell / columns) as f32 + jitter_y) / rows as f32,
+        );
+    }
+}
+
+/// Owen scrambled Sobol points padded per dimension: the first two Sobol dimensions are
+/// shuffled and scrambled with a different seed for every pixel and dimension
+///
+/// https://jcgt.org/published/0009/04/01/paper.pdf
+struct Sobol {
+    pixel_seed: u32,
+    sample_index: u32,
+}
+
+impl Sampler for Sobol {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.pixel_seed = pixel_seed(x, y);
+        self.sample_index = sample_index;
+    }
+
+    fn get_1d(&self, dimension: u32) -> f32 {
+        let seed = hash_u32(hash_combine(self.pixel_seed, dimension));
+        let index = nested_uniform_scramble(self.sample_index, seed);
+        return scrambled_sobol(index, 0, seed);
+    }
+
+    fn get_2d(&self, dimension: u32) -> Vec2f {
+        let seed = hash_u32(hash_combine(self.pixel_seed, dimension));
+        let index = nested_uniform_scramble(self.sample_index, seed);
+        return Vec2f::new(
+            scrambled_sobol(index, 0, seed),
+            scrambled_sobol(index, 1, seed),
+        );
+    }
+}
+
+/// One Owen scrambled Sobol sequence for all pixels, every pixel takes the next `samples`
+/// points in the order of a scrambled Morton curve. Pixels close to each other share well
+/// stratified blocks of the sequence, which pushes their error to high frequencies.
+///
+/// https://arxiv.org/abs/2008.07817
+struct BlueNoise {
+    samples_log2: u32,
+    x: u32,
+    y: u32,
+    sample_index: u32,
+}
+
+impl BlueNoise {
+    /// Index into the sequence of `dimension` and its scrambling seed
+    fn index(&self, dimension: u32) -> (u32, u32) {
+        // The curve repeats every tile, with different scrambling for every tile and every
+        // time the pixel runs out of samples
+        let tile = hash_combine(hash_u32(self.x >> MORTON_LEVELS), self.y >> MORTON_LEVELS);
+        let round = self.sample_index >> self.samples_log2;
+        let seed = hash_u32(hash_combine(hash_combine(hash_u32(dimension), tile), round));
+
+        let morton = scrambled_morton(self.x, self.y, seed);
+        let sample = self.sample_index & ((1 << self.samples_log2) - 1);
+        return ((morton << self.samples_log2) | sample, seed);
+    }
+}
+
+impl Sampler for BlueNoise {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.x = x;
+        self.y = y;
+        self.sample_index = sample_index;
+    }
+
+    fn get_1d(&self, dimension: u32) -> f32 {
+        let (index, seed) = self.index(dimension);
+        return scrambled_sobol(index, 0, seed);
+    }
+
+    fn get_2d(&self, dimension: u32) -> Vec2f {
+        let (index, seed) = self.index(dimension);
+        return Vec2f::new(
+            scrambled_sobol(index, 0, seed),
+            scrambled_sobol(index, 1, seed),
+        );
+    }
+}
+
+fn pixel_seed(x: u32, y: u32) -> u32 {
+    return hash_u32(hash_combine(hash_u32(x), y));
+}
+
+/// Maps the upper 24 bits to [0, 1), using all 32 could round up to 1
+fn to_unit(x: u32) -> f32 {
+    return (x >> 8) as f32 / (1u32 << 24) as f32;
+}
+
+/// First two dimensions of the Sobol sequence as 32 bit fractions. The first one is the van der
+/// Corput sequence, the direction numbers of the second one are all ones in Pascal's triangle
+/// mod 2.
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    let mut result = 0;
+    let mut direction = 1u32 << 31;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    return result;
+}
+
+/// Owen scrambled point `index` of Sobol `dimension`
+fn scrambled_sobol(index: u32, dimension: u32, seed: u32) -> f32 {
+    return to_unit(nested_uniform_scramble(
+        sobol(index, dimension),
+        hash_combine(seed, dimension),
+    ));
+}
+
+/// Hash that only lets every bit affect more significant ones, which makes it an Owen scramble
+/// on reversed bits
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    return x;
+}
+
+/// Owen scramble of the fraction `x`
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return laine_karras_permutation(x.reverse_bits(), seed).reverse_bits();
+}
+
+/// Morton index of the pixel inside its tile, with the four children of every node of the
+/// quadtree randomly reordered
+fn scrambled_morton(x: u32, y: u32, seed: u32) -> u32 {
+    let mut index = 0;
+    for level in (0..MORTON_LEVELS).rev() {
+        let digit = ((x >> level) & 1) | (((y >> level) & 1) << 1);
+        // The leading one tells apart nodes of different depths
+        let node = index | (1 << (2 * (MORTON_LEVELS - 1 - level)));
+        index = (index << 2) | (digit ^ (hash_u32(hash_combine(seed, node)) & 3));
+    }
+    return index;
+}
+
+/// Element `i` of a random permutation of `0..length` chosen by `seed`
+///
+/// https://graphics.pixar.com/library/MultiJitteredSampling/paper.pdf
+fn permutation_element(i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    let mut i = i;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    return (i.wrapping_add(seed)) % length;
+}
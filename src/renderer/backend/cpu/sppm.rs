@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::bdpt::{self, LightDistribution, RAY_OFFSET, Vertex};
+use super::bsdf::{self, Frame, Lobe};
+use super::camera::FilmCamera;
+use super::ray::{HitInfo, Ray};
+use crate::math::vec::*;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+use crate::math::{hash_combine, hash_u32, rand_f32, rng_seed};
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+use rayon::prelude::*;
+
+/// Fraction of the newly gathered photons a pixel keeps when its radius shrinks, trades
+/// variance for bias
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Stochastic progressive photon mapping. Every iteration traces a path from the camera through
+/// each pixel up to the first surface that isn't perfectly specular, then shoots photons from
+/// the emitters and gathers the ones landing close to those visible points. The gather radius
+/// of every pixel shrinks as it collects photons, so the estimate converges even for caustics
+/// seen through glass, which neither the path tracer nor BDPT can sample well.
+///
+/// Iterations take the place of samples, each one sees the scene at a single time while the
+/// shutter is open. Photons are only emitted from emissive triangles, light
+/// from the sky is only gathered directly at the visible points. Participating media and
+/// subsurface scattering are ignored like in BDPT.
+///
+/// https://www.ci.i.u-tokyo.ac.jp/~hachisuka/sppm.pdf
+pub fn render(
+    renderer: Renderer,
+    scene: &Scene,
+    photons_per_iteration: usize,
+    initial_radius: f32,
+) -> Vec<Vec3f> {
+    let width = renderer.options.output_image_dimensions.0;
+    let height = renderer.options.output_image_dimensions.1;
+    let iterations = renderer.options.samples;
+    let max_depth = renderer.options.max_ray_depth;
+
+    let camera = FilmCamera::new(scene, width, height);
+    let lights = LightDistribution::new(scene);
+    let mut pixels: Vec<Pixel> = (0..width * height)
+        .map(|_| Pixel {
+            radius: initial_radius,
+            photon_count: 0.0,
+            tau: Vec3f::from(0.0),
+            direct: Vec3f::from(0.0),
+        })
+        .collect();
+
+    for iteration in 0..iterations {
+        // Photons have to land where the visible points are, so they share one time
+        let mut time_rng_state = rng_seed(iteration as u32);
+        let time = camera.sample_time(rand_f32(&mut time_rng_state));
+
+        let visible_points = pixels
+            .par_iter_mut()
+            .enumerate()
+            .map(|(index, pixel)| {
+                // Even streams are for the camera paths, odd ones for the photons
+                let mut rng_state =
+                    rng_seed(hash_combine(hash_u32(2 * iteration as u32), index as u32));
+                let (radiance, visible_point) = trace_camera_path(
+                    scene,
+                    &camera,
+                    &lights,
+                    index,
+                    time,
+                    max_depth,
+                    &mut rng_state,
+                );
+                pixel.direct += radiance;
+                return visible_point;
+            })
+            .collect::<Vec<Option<Vertex>>>();
+
+        let grid = VisiblePointGrid::new(&visible_points, &pixels);
+        let flux: Vec<AtomicU32> = (0..width * height * 3).map(|_| AtomicU32::new(0)).collect();
+        let photon_counts: Vec<AtomicU32> =
+            (0..width * height).map(|_| AtomicU32::new(0)).collect();
+
+        (0..photons_per_iteration)
+            .into_par_iter()
+            .for_each(|photon_index: usize| {
+                let mut rng_state = rng_seed(hash_combine(
+                    hash_u32(2 * iteration as u32 + 1),
+                    photon_index as u32,
+                ));
+                let mut photon = Photon {
+                    scene,
+                    grid: &grid,
+                    visible_points: &visible_points,
+                    pixels: &pixels,
+                    flux: &flux,
+                    photon_counts: &photon_counts,
+                    time,
+                };
+                photon.trace(&lights, max_depth, &mut rng_state);
+            });
+
+        // Shrink the radius of every pixel that gathered photons
+        pixels.par_iter_mut().enumerate().for_each(|(i, pixel)| {
+            let count = photon_counts[i].load(Ordering::Relaxed) as f32;
+            let Some(visible_point) = &visible_points[i] else {
+                return;
+            };
+            if count == 0.0 {
+                return;
+            }
+            let flux = Vec3f::new(
+                f32::from_bits(flux[i * 3].load(Ordering::Relaxed)),
+                f32::from_bits(flux[i * 3 + 1].load(Ordering::Relaxed)),
+                f32::from_bits(flux[i * 3 + 2].load(Ordering::Relaxed)),
+            );
+            let new_count = pixel.photon_count + ALPHA * count;
+            let new_radius = pixel.radius * f32::sqrt(new_count / (pixel.photon_count + count));
+            pixel.tau = (pixel.tau + visible_point.beta * flux)
+                * (new_radius * new_radius / (pixel.radius * pixel.radius));
+            pixel.photon_count = new_count;
+            pixel.radius = new_radius;
+        });
+    }
+
+    let photon_total = (iterations * photons_per_iteration) as f32;
+    return pixels
+        .iter()
+        .map(|pixel| {
+            return pixel.direct / iterations as f32
+                + pixel.tau / (photon_total * PI * pixel.radius * pixel.radius);
+        })
+        .collect();
+}
+
+/// Progressive estimate of a pixel, carried across iterations
+struct Pixel {
+    radius: f32,
+    /// Photons gathered so far, reduced by `ALPHA` whenever the radius shrinks
+    photon_count: f32,
+    /// Flux gathered within `radius`, scaled by the throughput of the visible points
+    tau: Vec3f,
+    /// Light found by the camera paths, summed over iterations
+    direct: Vec3f,
+}
+
+/// Follows a camera path through pixel `index` until it reaches a surface that isn't perfectly
+/// specular. Returns the light found on the way, including direct light at that surface, along
+/// with the surface as the visible point of the pixel.
+fn trace_camera_path(
+    scene: &Scene,
+    camera: &FilmCamera,
+    lights: &LightDistribution,
+    index: usize,
+    time: f32,
+    max_depth: usize,
+    rng_state: &mut u32,
+) -> (Vec3f, Option<Vertex>) {
+    let Some(mut ray) = camera.sample_ray(
+        index,
+        time,
+        Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+        Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+    ) else {
+        return (Vec3f::from(0.0), None);
+    };
+    let mut beta = Vec3f::from(1.0);
+    let mut radiance = Vec3f::from(0.0);
+
+    let mut depth: usize = 0;
+    while depth < max_depth {
+        let mut hit_info = HitInfo::default();
+        Ray::traverse_bvh(&ray, scene, &mut hit_info);
+        if !hit_info.has_hit {
+            radiance += beta * scene.sky.radiance(ray.direction, true);
+            break;
+        }
+
+        let hit_material = scene.material(hit_info.material_id);
+        if Ray::transparency_at(scene, hit_material, hit_info.uv) < rand_f32(rng_state) {
+            ray = Ray::new(
+                hit_info.point + ray.direction * RAY_OFFSET,
+                ray.direction,
+                time,
+            );
+            continue;
+        }
+
+        let vertex = Vertex::surface(scene, hit_info, ray.direction.reversed(), beta);
+        radiance += beta * vertex.emission;
+        if vertex.connectible {
+            radiance += direct_light(scene, lights, &vertex, time, rng_state);
+            return (radiance, Some(vertex));
+        }
+
+        let Some((wi, sample)) = vertex.sample(rng_state) else {
+            break;
+        };
+        beta *= sample.weight;
+        ray = Ray::new(vertex.point + wi * RAY_OFFSET, wi, time);
+        depth += 1;
+    }
+
+    return (radiance, None);
+}
+
+/// Light arriving at a visible point directly from an emitter, the sun and the sky, one sample
+/// each. Photons only carry light that bounced at least once.
+fn direct_light(
+    scene: &Scene,
+    lights: &LightDistribution,
+    vertex: &Vertex,
+    time: f32,
+    rng_state: &mut u32,
+) -> Vec3f {
+    let mut radiance = Vec3f::from(0.0);
+
+    if let Some(light) = lights.sample_light(scene, rng_state) {
+        let to_light = light.point - vertex.point;
+        let distance_sqr = Vec3f::dot(to_light, to_light);
+        let light_dir = to_light / f32::sqrt(distance_sqr);
+        let cos_light = Vec3f::dot(light.normal, light_dir.reversed());
+        if cos_light > 0.0 {
+            let value = vertex.scatter(vertex.wo, light_dir, false)
+                * light.emission
+                * (cos_light / (distance_sqr * light.pdf_fwd));
+            if Vec3f::dot(value, Vec3f::from(1.0)) > 0.0 {
+                radiance +=
+                    value * bdpt::visibility(scene, vertex.point, light.point, time, rng_state);
+            }
+        }
+    }
+
+    if scene.sky.has_sun() {
+        let sun_dir = scene
+            .sky
+            .sample_sun_direction(Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)));
+        let value = vertex.scatter(vertex.wo, sun_dir, false);
+        if Vec3f::dot(value, Vec3f::from(1.0)) > 0.0 {
+            radiance += scene.sky.sun_radiance()
+                * value
+                * scene.sky.sun_solid_angle()
+                * sky_visibility(scene, vertex.point, sun_dir, time, rng_state);
+        }
+    }
+
+    // The sun was sampled above, the rest of the sky is found by scattering once
+    if let Some((wi, sample)) = vertex.sample(rng_state) {
+        radiance += scene.sky.radiance(wi, false)
+            * sample.weight
+            * sky_visibility(scene, vertex.point, wi, time, rng_state);
+    }
+
+    return radiance * vertex.beta;
+}
+
+/// Fraction of the sky light arriving at `point` from `direction` at `time`, transparent
+/// surfaces let it through the same way camera paths pass them
+fn sky_visibility(
+    scene: &Scene,
+    point: Vec3f,
+    direction: Vec3f,
+    time: f32,
+    rng_state: &mut u32,
+) -> Vec3f {
+    let shadow_ray = Ray::new(point + direction * RAY_OFFSET, direction, time);
+    return Ray::shadow_transmittance(scene, &shadow_ray, f32::MAX, None, None, rng_state);
+}
+
+/// Where photons of the current iteration deposit their flux
+struct Photon<'a> {
+    scene: &'a Scene,
+    grid: &'a VisiblePointGrid,
+    visible_points: &'a [Option<Vertex>],
+    pixels: &'a [Pixel],
+    flux: &'a [AtomicU32],
+    photon_counts: &'a [AtomicU32],
+    time: f32,
+}
+
+impl Photon<'_> {
+    /// Shoots a photon from an emitter and adds its flux to every visible point it lands close
+    /// to
+    fn trace(&mut self, lights: &LightDistribution, max_depth: usize, rng_state: &mut u32) {
+        let scene = self.scene;
+        let Some(light) = lights.sample_light(scene, rng_state) else {
+            return;
+        };
+
+        let direction = Frame::new(light.normal).to_world(bsdf::cosine_sample_hemisphere(
+            Vec2f::new(rand_f32(rng_state), rand_f32(rng_state)),
+        ));
+        // The cosine of the emission cancels with the cosine distributed direction
+        let mut beta = light.emission * (PI / light.pdf_fwd);
+        let mut ray = Ray::new(light.point + direction * RAY_OFFSET, direction, self.time);
+
+        let mut depth: usize = 0;
+        while depth < max_depth {
+            let mut hit_info = HitInfo::default();
+            Ray::traverse_bvh(&ray, scene, &mut hit_info);
+            if !hit_info.has_hit {
+                break;
+            }
+
+            let hit_material = scene.material(hit_info.material_id);
+            if Ray::transparency_at(scene, hit_material, hit_info.uv) < rand_f32(rng_state) {
+                ray = Ray::new(
+                    hit_info.point + ray.direction * RAY_OFFSET,
+                    ray.direction,
+                    ray.time,
+                );
+                continue;
+            }
+
+            let vertex = Vertex::surface(scene, hit_info, ray.direction.reversed(), beta);
+            // Direct light is sampled at the visible points instead
+            if depth > 0 {
+                self.deposit(&vertex, beta);
+            }
+
+            let Some((wi, sample)) = vertex.sample(rng_state) else {
+                break;
+            };
+            let mut weight = sample.weight;
+            if sample.lobe.contains(Lobe::TRANSMISSION) {
+                // Photons carry flux, which isn't scaled by the squared relative IOR when
+                // refracting like radiance is
+                let eta = vertex.eta(vertex.wo);
+                weight *= eta * eta;
+            }
+
+            // Russian roulette keeps the flux of surviving photons roughly constant
+            let luminance = |v: Vec3f| -> f32 { 0.2126 * v.x() + 0.7152 * v.y() + 0.0722 * v.z() };
+            let new_beta = beta * weight;
+            let survival = f32::min(1.0, luminance(new_beta) / luminance(beta));
+            if survival.is_nan() || survival <= 0.0 || rand_f32(rng_state) > survival {
+                break;
+            }
+            beta = new_beta / survival;
+
+            ray = Ray::new(vertex.point + wi * RAY_OFFSET, wi, ray.time);
+            depth += 1;
+        }
+    }
+
+    /// Adds the flux of a photon at `vertex` to the visible points within their gather radius
+    fn deposit(&mut self, vertex: &Vertex, beta: Vec3f) {
+        for &i in self.grid.points_near(vertex.point) {
+            let Some(visible_point) = &self.visible_points[i] else {
+                continue;
+            };
+            let offset = visible_point.point - vertex.point;
+            let radius = self.pixels[i].radius;
+            if Vec3f::dot(offset, offset) > radius * radius {
+                continue;
+            }
+
+            let value = beta * visible_point.eval(visible_point.wo, vertex.wo, false);
+            for channel in 0..3 {
+                bdpt::atomic_add(&self.flux[i * 3 + channel], value.data[channel]);
+            }
+            self.photon_counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Uniform grid over the visible points of an iteration. Points are stored in every cell their
+/// gather sphere overlaps, so photons only have to look at their own cell.
+struct VisiblePointGrid {
+    bounds_min: Vec3f,
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl VisiblePointGrid {
+    fn new(visible_points: &[Option<Vertex>], pixels: &[Pixel]) -> Self {
+        let mut bounds_min = Vec3f::from(f32::MAX);
+        let mut max_radius: f32 = 0.0;
+        for (visible_point, pixel) in visible_points.iter().zip(pixels) {
+            if let Some(visible_point) = visible_point {
+                bounds_min =
+                    Vec3f::min(bounds_min, visible_point.point - Vec3f::from(pixel.radius));
+                max_radius = f32::max(max_radius, pixel.radius);
+            }
+        }
+
+        let mut grid = Self {
+            bounds_min,
+            cell_size: max_radius * 2.0,
+            cells: HashMap::new(),
+        };
+        for (i, (visible_point, pixel)) in visible_points.iter().zip(pixels).enumerate() {
+            let Some(visible_point) = visible_point else {
+                continue;
+            };
+            let low = grid.cell(visible_point.point - Vec3f::from(pixel.radius));
+            let high = grid.cell(visible_point.point + Vec3f::from(pixel.radius));
+            for x in low[0]..=high[0] {
+                for y in low[1]..=high[1] {
+                    for z in low[2]..=high[2] {
+                        grid.cells.entry([x, y, z]).or_default().push(i);
+                    }
+                }
+            }
+        }
+
+        return grid;
+    }
+
+    fn cell(&self, point: Vec3f) -> [i32; 3] {
+        let relative = (point - self.bounds_min) / self.cell_size;
+        return [
+            f32::floor(relative.x()) as i32,
+            f32::floor(relative.y()) as i32,
+            f32::floor(relative.z()) as i32,
+        ];
+    }
+
+    /// Indices of the visible points whose gather sphere might contain `point`
+    fn points_near(&self, point: Vec3f) -> &[usize] {
+        match self.cells.get(&self.cell(point)) {
+            Some(indices) => return indices.as_slice(),
+            None => return &[],
+        }
+    }
+}
@@ -1,9 +1,17 @@
 use crate::{
     bvh::Node,
+    light_bvh::LightNode,
     log_info,
     math::{mat4::*, vec3::*},
-    renderer::{Renderer, backend::gpu::texture::Texture},
-    scene::{Camera, Material, Scene, Triangle},
+    medium::Medium,
+    renderer::{
+        Renderer,
+        aov::AovPixel,
+        backend::gpu::texture::Texture,
+        post_process::{PostProcess, ToneMapping},
+    },
+    scene::{Aperture, Camera, Material, Motion, Projection, Scene, StereoLayout, Triangle},
+    sky::{Sky, SkyModel},
 };
 
 mod buffer;
@@ -11,10 +19,21 @@ mod texture;
 pub mod window;
 use buffer::Buffer;
 
-pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> Vec<u8> {
+/// Has to be odd so the last iteration writes to the first denoise texture
+const DENOISE_ITERATIONS: u32 = 5;
+
+pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> (Vec<u8>, Vec<AovPixel>) {
     let mut state = State::new(renderer, scene);
 
-    for _ in 0..renderer.options.samples {
+    let start_time = std::time::Instant::now();
+    for sample in 0..renderer.options.samples {
+        if let Some(time_budget) = renderer.options.time_budget
+            && start_time.elapsed().as_secs_f32() >= time_budget
+        {
+            log_info!("Time budget ran out after {} samples", sample);
+            break;
+        }
+
         let mut command_encoder = state
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -25,9 +44,10 @@ pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> Vec<u8
                 label: None,
                 timestamp_writes: None,
             });
-            rt_pass.set_bind_group(0, &state.rt_texture_bind_group, &[]);
+            rt_pass.set_bind_group(0, &state.rt_output_bind_group, &[]);
             rt_pass.set_bind_group(1, &state.storage_buffers.bind_group, &[]);
             rt_pass.set_bind_group(2, &state.uniform_buffers.bind_group, &[]);
+            rt_pass.set_bind_group(3, &state.aov_bind_group, &[]);
             rt_pass.set_pipeline(&state.rt_pipeline);
             rt_pass.set_immediates(0, bytemuck::cast_slice(&[state.renderer_info]));
             rt_pass.dispatch_workgroups(
@@ -37,35 +57,6 @@ pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> Vec<u8
             );
         }
 
-        // Post process compute pass
-        {
-            let mut pp_pass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: None,
-                timestamp_writes: None,
-            });
-            pp_pass.set_bind_group(0, &state.rt_texture_bind_group, &[]);
-            pp_pass.set_bind_group(1, &state.pp_texture_bind_group, &[]);
-            pp_pass.set_pipeline(&state.pp_pipeline);
-            pp_pass.dispatch_workgroups(
-                (renderer.options.output_image_dimensions.0 / 8) as u32,
-                (renderer.options.output_image_dimensions.1 / 8) as u32,
-                1,
-            );
-        }
-
-        command_encoder.copy_texture_to_buffer(
-            state.pp_texture.as_image_copy(),
-            wgpu::TexelCopyBufferInfo {
-                buffer: &state.output_staging_buffer,
-                layout: wgpu::TexelCopyBufferLayout {
-                    offset: 0,
-                    bytes_per_row: Some((renderer.options.output_image_dimensions.0 * 8) as u32),
-                    rows_per_image: Some(renderer.options.output_image_dimensions.1 as u32),
-                },
-            },
-            state.pp_texture.size(),
-        );
-
         state.renderer_info.curr_sample += 1;
 
         state.queue.submit(Some(command_encoder.finish()));
@@ -76,6 +67,57 @@ pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> Vec<u8
             .unwrap();
     }
 
+    // Denoising and post processing only need to see the final accumulation
+    let mut command_encoder = state
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    let pp_input_bind_group = state.encode_denoise_passes(&mut command_encoder);
+    state.encode_glare_passes(&mut command_encoder, pp_input_bind_group);
+
+    // Post process compute pass
+    {
+        let mut pp_pass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        pp_pass.set_bind_group(0, pp_input_bind_group, &[]);
+        pp_pass.set_bind_group(1, &state.pp_texture_bind_group, &[]);
+        pp_pass.set_bind_group(2, &state.glare_bind_group, &[]);
+        pp_pass.set_pipeline(&state.pp_pipeline);
+        pp_pass.set_immediates(0, bytemuck::cast_slice(&[state.post_process_info]));
+        pp_pass.dispatch_workgroups(
+            (renderer.options.output_image_dimensions.0 / 8) as u32,
+            (renderer.options.output_image_dimensions.1 / 8) as u32,
+            1,
+        );
+    }
+
+    // HDR formats get the linear accumulation from before post processing
+    let output_texture = if renderer.options.output_format.is_hdr() {
+        state.linear_output_texture()
+    } else {
+        &state.pp_texture
+    };
+    command_encoder.copy_texture_to_buffer(
+        output_texture.as_image_copy(),
+        wgpu::TexelCopyBufferInfo {
+            buffer: &state.output_staging_buffer,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(
+                    (renderer.options.output_image_dimensions.0
+                        * renderer.options.output_format.bytes_per_pixel())
+                        as u32,
+                ),
+                rows_per_image: Some(renderer.options.output_image_dimensions.1 as u32),
+            },
+        },
+        output_texture.size(),
+    );
+
+    state.queue.submit(Some(command_encoder.finish()));
+
     let mut output_data: Vec<u8> = vec![];
     let buffer_slice = state.output_staging_buffer.slice(..);
     buffer_slice.map_async(wgpu::MapMode::Read, |_| {});
@@ -90,7 +132,67 @@ pub async fn render_scene_to_buffer(renderer: Renderer, scene: &Scene) -> Vec<u8
     }
     state.output_staging_buffer.unmap();
 
-    return output_data;
+    let aovs = if renderer.options.aovs.is_empty() {
+        Vec::new()
+    } else {
+        read_aovs(&state)
+    };
+
+    return (output_data, aovs);
+}
+
+/// Copies the AOV textures back and interleaves them into one `AovPixel` per pixel
+fn read_aovs(state: &State) -> Vec<AovPixel> {
+    let size = state.aov_textures[0].size();
+    let pixel_count = (size.width * size.height) as usize;
+    let texture_bytes = (pixel_count * 16) as u64;
+
+    let staging_buffer = state.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("aov_staging_buffer"),
+        size: texture_bytes * state.aov_textures.len() as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut command_encoder = state
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    for (i, texture) in state.aov_textures.iter().enumerate() {
+        command_encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &staging_buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: texture_bytes * i as u64,
+                    bytes_per_row: Some(size.width * 16),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            size,
+        );
+    }
+    state.queue.submit(Some(command_encoder.finish()));
+
+    let buffer_slice = staging_buffer.slice(..);
+    buffer_slice.map_async(wgpu::MapMode::Read, |_| {});
+    state
+        .device
+        .poll(wgpu::PollType::wait_indefinitely())
+        .unwrap();
+    let texels = {
+        let view = buffer_slice.get_mapped_range().unwrap();
+        view.chunks_exact(16)
+            .map(bytemuck::pod_read_unaligned::<[f32; 4]>)
+            .collect::<Vec<[f32; 4]>>()
+    };
+    staging_buffer.unmap();
+
+    return (0..pixel_count)
+        .map(|i| {
+            let texel = |texture: usize| texels[texture * pixel_count + i];
+            return bytemuck::cast([texel(0), texel(1), texel(2), texel(3)]);
+        })
+        .collect();
 }
 
 struct State {
@@ -104,10 +206,28 @@ struct State {
     uniform_buffers: UniformBuffers,
     rt_texture: wgpu::Texture,
     rt_texture_bind_group: wgpu::BindGroup,
+    /// rt_texture and the sample statistics of every pixel, written by the ray tracing pass
+    rt_output_bind_group: wgpu::BindGroup,
     pp_texture: wgpu::Texture,
     pp_texture_bind_group: wgpu::BindGroup,
+    /// Filters the accumulation between the ray tracing and post process passes
+    denoise: bool,
+    denoise_pipeline: wgpu::ComputePipeline,
+    /// Ping-pong targets of the denoiser iterations, the result always ends up in the first one
+    denoise_textures: [wgpu::Texture; 2],
+    /// rt_texture to the first target, then back and forth between the two
+    denoise_bind_groups: [wgpu::BindGroup; 3],
+    /// First denoise target bound like rt_texture, read by the post process pass
+    denoised_bind_group: wgpu::BindGroup,
+    /// First hit data for the AOVs, laid out like `AovPixel` with four floats per texture
+    aov_textures: [wgpu::Texture; 4],
+    aov_bind_group: wgpu::BindGroup,
     output_staging_buffer: wgpu::Buffer,
     renderer_info: RendererInfo,
+    post_process_info: PostProcessInfo,
+    /// Bright pass, the two blur directions and the streaks, in the order they run
+    glare_pipelines: [wgpu::ComputePipeline; 4],
+    glare_bind_group: wgpu::BindGroup,
 }
 
 impl State {
@@ -121,12 +241,13 @@ impl State {
         let output_staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
             size: (renderer.options.output_image_dimensions.0
-                * 8
+                * renderer.options.output_format.bytes_per_pixel()
                 * renderer.options.output_image_dimensions.1) as u64,
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
             mapped_at_creation: false,
         });
 
+        // Float so the accumulated radiance isn't clamped to 1 before tone mapping
         let rt_texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some("rt_texture"),
             size: wgpu::Extent3d {
@@ -137,7 +258,7 @@ impl State {
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba16Unorm,
+            format: wgpu::TextureFormat::Rgba32Float,
             usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
             view_formats: &[],
         });
@@ -151,7 +272,7 @@ impl State {
                     visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::StorageTexture {
                         access: wgpu::StorageTextureAccess::ReadWrite,
-                        format: wgpu::TextureFormat::Rgba16Unorm,
+                        format: wgpu::TextureFormat::Rgba32Float,
                         view_dimension: wgpu::TextureViewDimension::D2,
                     },
                     count: None,
@@ -166,14 +287,107 @@ impl State {
             }],
         });
 
+        let sample_stats_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("sample_stats_texture"),
+            size: rt_texture.size(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        });
+        let sample_stats_texture_view =
+            sample_stats_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let rt_output_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("rt_output_bind_group_layout"),
+                entries: &std::array::from_fn::<_, 2, _>(|i| wgpu::BindGroupLayoutEntry {
+                    binding: i as u32,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                }),
+            });
+        let rt_output_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("rt_output_bind_group"),
+            layout: &rt_output_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&rt_texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&sample_stats_texture_view),
+                },
+            ],
+        });
+
+        // Only full size when AOVs are written or the denoiser can be turned on
+        let needs_denoise_textures = renderer.options.denoise || renderer.options.is_realtime;
+        let aov_size = if renderer.options.aovs.is_empty() && !needs_denoise_textures {
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            }
+        } else {
+            rt_texture.size()
+        };
+        let aov_textures: [wgpu::Texture; 4] = std::array::from_fn(|_| {
+            return device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("aov_texture"),
+                size: aov_size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            });
+        });
+        let aov_texture_views = aov_textures
+            .each_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        let aov_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("aov_bind_group_layout"),
+                entries: &std::array::from_fn::<_, 4, _>(|i| wgpu::BindGroupLayoutEntry {
+                    binding: i as u32,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                }),
+            });
+        let aov_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("aov_bind_group"),
+            layout: &aov_bind_group_layout,
+            entries: &std::array::from_fn::<_, 4, _>(|i| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: wgpu::BindingResource::TextureView(&aov_texture_views[i]),
+            }),
+        });
+
         let rt_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("rt_pipeline_layout"),
             bind_group_layouts: &[
-                Some(&rt_texture_bind_group_layout),
+                Some(&rt_output_bind_group_layout),
                 Some(&storage_buffers.bind_group_layout),
                 Some(&uniform_buffers.bind_group_layout),
+                Some(&aov_bind_group_layout),
             ],
-            immediate_size: 8,
+            immediate_size: size_of::<RendererInfo>() as u32,
         });
 
         let rt_shader_module =
@@ -227,13 +441,95 @@ impl State {
             }],
         });
 
+        let post_process_info = PostProcessInfo::new(renderer.options.post_process, &scene.camera);
+
+        // Only full size when the glare passes run
+        let glare_size = if post_process_info.glare == 0 {
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            }
+        } else {
+            rt_texture.size()
+        };
+        let glare_textures: [wgpu::Texture; 3] = std::array::from_fn(|_| {
+            return device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("glare_texture"),
+                size: glare_size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING,
+                view_formats: &[],
+            });
+        });
+        let glare_texture_views = glare_textures
+            .each_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        let glare_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("glare_bind_group_layout"),
+                entries: &std::array::from_fn::<_, 3, _>(|i| wgpu::BindGroupLayoutEntry {
+                    binding: i as u32,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                }),
+            });
+        let glare_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("glare_bind_group"),
+            layout: &glare_bind_group_layout,
+            entries: &std::array::from_fn::<_, 3, _>(|i| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: wgpu::BindingResource::TextureView(&glare_texture_views[i]),
+            }),
+        });
+
+        let glare_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("glare_pipeline_layout"),
+                bind_group_layouts: &[
+                    Some(&rt_texture_bind_group_layout),
+                    Some(&glare_bind_group_layout),
+                ],
+                immediate_size: size_of::<PostProcessInfo>() as u32,
+            });
+
+        let glare_shader_module =
+            device.create_shader_module(wgpu::include_wgsl!("./gpu/glare_compute.wgsl"));
+
+        let glare_pipelines = [
+            "bright_pass",
+            "blur_horizontal",
+            "blur_vertical",
+            "add_streaks",
+        ]
+        .map(|entry_point| {
+            return device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&glare_pipeline_layout),
+                module: &glare_shader_module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        });
+
         let pp_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("pp_pipeline_layout"),
             bind_group_layouts: &[
                 Some(&rt_texture_bind_group_layout),
                 Some(&pp_texture_bind_group_layout),
+                Some(&glare_bind_group_layout),
             ],
-            immediate_size: 0,
+            immediate_size: size_of::<PostProcessInfo>() as u32,
         });
 
         let pp_shader_module =
@@ -248,9 +544,124 @@ impl State {
             cache: None,
         });
 
+        let denoise_size = if needs_denoise_textures {
+            rt_texture.size()
+        } else {
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            }
+        };
+        let denoise_textures: [wgpu::Texture; 2] = std::array::from_fn(|_| {
+            return device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("denoise_texture"),
+                size: denoise_size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            });
+        });
+        let denoise_texture_views = denoise_textures
+            .each_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        let denoise_texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("denoise_texture_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::ReadOnly,
+                            format: wgpu::TextureFormat::Rgba32Float,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: wgpu::TextureFormat::Rgba32Float,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let denoise_passes = [
+            (&rt_texture_view, &denoise_texture_views[0]),
+            (&denoise_texture_views[0], &denoise_texture_views[1]),
+            (&denoise_texture_views[1], &denoise_texture_views[0]),
+        ];
+        let denoise_bind_groups = denoise_passes.map(|(input_view, output_view)| {
+            return device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("denoise_texture_bind_group"),
+                layout: &denoise_texture_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(input_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(output_view),
+                    },
+                ],
+            });
+        });
+        let denoised_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("denoised_bind_group"),
+            layout: &rt_texture_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&denoise_texture_views[0]),
+            }],
+        });
+
+        let denoise_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("denoise_pipeline_layout"),
+                bind_group_layouts: &[
+                    Some(&denoise_texture_bind_group_layout),
+                    Some(&aov_bind_group_layout),
+                ],
+                immediate_size: 4,
+            });
+
+        let denoise_shader_module =
+            device.create_shader_module(wgpu::include_wgsl!("./gpu/denoise_compute.wgsl"));
+
+        let denoise_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("denoise_pipeline"),
+            layout: Some(&denoise_pipeline_layout),
+            module: &denoise_shader_module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
         let renderer_info = RendererInfo {
             curr_sample: 1,
             max_ray_depth: renderer.options.max_ray_depth as u32,
+            spectral: renderer.options.spectral as u32,
+            aovs: (!renderer.options.aovs.is_empty() || renderer.options.denoise) as u32,
+            noise_target: renderer
+                .options
+                .adaptive_sampling
+                .map_or(0.0, |adaptive_sampling| adaptive_sampling.noise_target),
+            min_samples: renderer
+                .options
+                .adaptive_sampling
+                .map_or(0, |adaptive_sampling| adaptive_sampling.min_samples as u32),
+            sampler: renderer.options.sampler.id(),
+            samples: u32::max(renderer.options.samples as u32, 1),
         };
 
         return Self {
@@ -264,13 +675,94 @@ impl State {
             uniform_buffers,
             rt_texture,
             rt_texture_bind_group,
+            rt_output_bind_group,
             pp_texture,
             pp_texture_bind_group,
+            denoise: renderer.options.denoise,
+            denoise_pipeline,
+            denoise_textures,
+            denoise_bind_groups,
+            denoised_bind_group,
+            aov_textures,
+            aov_bind_group,
             output_staging_buffer,
             renderer_info,
+            post_process_info,
+            glare_pipelines,
+            glare_bind_group,
         };
     }
 
+    /// Encodes the denoiser iterations if it is enabled and returns the bind group the post
+    /// process pass reads the image from
+    fn encode_denoise_passes(
+        &self,
+        command_encoder: &mut wgpu::CommandEncoder,
+    ) -> &wgpu::BindGroup {
+        if !self.denoise {
+            return &self.rt_texture_bind_group;
+        }
+
+        for iteration in 0..DENOISE_ITERATIONS {
+            let bind_group = if iteration == 0 {
+                &self.denoise_bind_groups[0]
+            } else {
+                &self.denoise_bind_groups[1 + (iteration as usize + 1) % 2]
+            };
+
+            let mut denoise_pass =
+                command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: None,
+                    timestamp_writes: None,
+                });
+            denoise_pass.set_bind_group(0, bind_group, &[]);
+            denoise_pass.set_bind_group(1, &self.aov_bind_group, &[]);
+            denoise_pass.set_pipeline(&self.denoise_pipeline);
+            denoise_pass.set_immediates(0, bytemuck::cast_slice(&[iteration]));
+            denoise_pass.dispatch_workgroups(
+                self.rt_texture.width() / 8,
+                self.rt_texture.height() / 8,
+                1,
+            );
+        }
+        return &self.denoised_bind_group;
+    }
+
+    /// Encodes the glare passes over the image in `image_bind_group` if glare is enabled
+    fn encode_glare_passes(
+        &self,
+        command_encoder: &mut wgpu::CommandEncoder,
+        image_bind_group: &wgpu::BindGroup,
+    ) {
+        if self.post_process_info.glare == 0 {
+            return;
+        }
+
+        for glare_pipeline in &self.glare_pipelines {
+            let mut glare_pass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            glare_pass.set_bind_group(0, image_bind_group, &[]);
+            glare_pass.set_bind_group(1, &self.glare_bind_group, &[]);
+            glare_pass.set_pipeline(glare_pipeline);
+            glare_pass.set_immediates(0, bytemuck::cast_slice(&[self.post_process_info]));
+            glare_pass.dispatch_workgroups(
+                self.rt_texture.width() / 8,
+                self.rt_texture.height() / 8,
+                1,
+            );
+        }
+    }
+
+    /// The linear image before post processing, denoised if the denoiser is enabled
+    fn linear_output_texture(&self) -> &wgpu::Texture {
+        if self.denoise {
+            return &self.denoise_textures[0];
+        }
+        return &self.rt_texture;
+    }
+
     fn get_instance_and_adapter() -> (wgpu::Instance, wgpu::Adapter) {
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::VULKAN,
@@ -322,6 +814,10 @@ struct StorageBuffers {
     triangle_buffer: Buffer,
     bvh_buffer: Buffer,
     material_buffer: Buffer,
+    light_bvh_buffer: Buffer,
+    medium_buffer: Buffer,
+    density_grid_buffer: Buffer,
+    motion_buffer: Buffer,
 }
 
 impl StorageBuffers {
@@ -337,6 +833,27 @@ impl StorageBuffers {
                 .into_values()
                 .collect::<Vec<Material>>(),
         );
+        // Storage buffers can't be empty, a root with zero power means there are no emitters
+        let mut light_bvh_nodes = scene.light_bvh.nodes.clone();
+        if light_bvh_nodes.is_empty() {
+            light_bvh_nodes.push(LightNode::default());
+        }
+        let light_bvh_buffer = Buffer::create_storage_buffer(device, 5, &light_bvh_nodes);
+        let mut media = scene.media.clone();
+        if media.is_empty() {
+            media.push(Medium::default());
+        }
+        let medium_buffer = Buffer::create_storage_buffer(device, 6, &media);
+        let mut density_grid_data = scene.density_grid_data.clone();
+        if density_grid_data.is_empty() {
+            density_grid_data.push(0.0);
+        }
+        let density_grid_buffer = Buffer::create_storage_buffer(device, 7, &density_grid_data);
+        let mut motions = scene.motions.clone();
+        if motions.is_empty() {
+            motions.push(Motion::default());
+        }
+        let motion_buffer = Buffer::create_storage_buffer(device, 8, &motions);
         log_info!(
             "Created a storage buffer for scene triangles: {:.2} MB ({} tris)",
             triangle_buffer.buffer.size() as f32 / 1024.0 / 1024.0,
@@ -352,6 +869,22 @@ impl StorageBuffers {
             material_buffer.buffer.size() as f32 / 1024.0,
             material_buffer.buffer.size() / size_of::<Material>() as u64
         );
+        log_info!(
+            "Created a storage buffer for light BVH nodes: {:.2} KB ({} nodes)",
+            light_bvh_buffer.buffer.size() as f32 / 1024.0,
+            light_bvh_buffer.buffer.size() / size_of::<LightNode>() as u64
+        );
+        log_info!(
+            "Created a storage buffer for media: {:.2} KB ({} media, {} density voxels)",
+            (medium_buffer.buffer.size() + density_grid_buffer.buffer.size()) as f32 / 1024.0,
+            scene.media.len(),
+            scene.density_grid_data.len()
+        );
+        log_info!(
+            "Created a storage buffer for motions: {:.2} KB ({} motions)",
+            motion_buffer.buffer.size() as f32 / 1024.0,
+            scene.motions.len()
+        );
 
         let mut textures: Vec<Texture> = vec![];
         if scene.textures.is_empty() {
@@ -418,6 +951,10 @@ impl StorageBuffers {
                 material_buffer.bind_group_layout_entry,
                 textures_array_bind_group_layout_entry,
                 textures_array_sampler_bind_group_layout_entry,
+                light_bvh_buffer.bind_group_layout_entry,
+                medium_buffer.bind_group_layout_entry,
+                density_grid_buffer.bind_group_layout_entry,
+                motion_buffer.bind_group_layout_entry,
             ],
         });
 
@@ -430,6 +967,10 @@ impl StorageBuffers {
                 material_buffer.bind_group_entry(),
                 textures_array_bind_group_entry,
                 textures_array_sampler_bind_group_entry,
+                light_bvh_buffer.bind_group_entry(),
+                medium_buffer.bind_group_entry(),
+                density_grid_buffer.bind_group_entry(),
+                motion_buffer.bind_group_entry(),
             ],
         });
 
@@ -439,6 +980,10 @@ impl StorageBuffers {
             triangle_buffer,
             bvh_buffer,
             material_buffer,
+            light_bvh_buffer,
+            medium_buffer,
+            density_grid_buffer,
+            motion_buffer,
         };
     }
 }
@@ -448,31 +993,45 @@ struct UniformBuffers {
     bind_group: wgpu::BindGroup,
     bind_group_layout: wgpu::BindGroupLayout,
     camera_buffer: Buffer,
+    sky_buffer: Buffer,
+    scene_info_buffer: Buffer,
 }
 
 impl UniformBuffers {
     fn new(device: &wgpu::Device, scene: &Scene) -> Self {
-        let uniform_camera = UniformCamera {
-            look_at: scene.camera.look_at,
-            position: scene.camera.position,
-            _pad: [0; 4],
-        };
+        let uniform_camera = UniformCamera::from(scene.camera.clone());
         let camera_buffer = Buffer::create_uniform_buffer(device, 0, &[uniform_camera]);
+        let sky_buffer = Buffer::create_uniform_buffer(device, 1, &[UniformSky::from(scene.sky)]);
+        let scene_info = UniformSceneInfo {
+            global_medium_id: scene.global_medium_id.unwrap_or(u32::MAX),
+            _pad: [0; 3],
+        };
+        let scene_info_buffer = Buffer::create_uniform_buffer(device, 2, &[scene_info]);
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
-            entries: &[camera_buffer.bind_group_layout_entry],
+            entries: &[
+                camera_buffer.bind_group_layout_entry,
+                sky_buffer.bind_group_layout_entry,
+                scene_info_buffer.bind_group_layout_entry,
+            ],
         });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &bind_group_layout,
-            entries: &[camera_buffer.bind_group_entry()],
+            entries: &[
+                camera_buffer.bind_group_entry(),
+                sky_buffer.bind_group_entry(),
+                scene_info_buffer.bind_group_entry(),
+            ],
         });
 
         return Self {
             bind_group,
             bind_group_layout,
             camera_buffer,
+            sky_buffer,
+            scene_info_buffer,
         };
     }
 }
@@ -482,22 +1041,182 @@ impl UniformBuffers {
 struct UniformCamera {
     look_at: Mat4f,
     position: Vec3f,
-    _pad: [u8; 4],
+    tan_half_fov: f32,
+    lens_radius: f32,
+    focus_distance: f32,
+    aperture_shape: u32,
+    aperture_blades: u32,
+    aperture_rotation: f32,
+    aperture_texture_id: u32,
+    projection: u32,
+    projection_scale: f32,
+    stereo_layout: u32,
+    interpupillary_distance: f32,
+    convergence_distance: f32,
+    shutter_open: f32,
+    end_look_at: Mat4f,
+    end_position: Vec3f,
+    shutter_close: f32,
 }
 
 impl From<Camera> for UniformCamera {
     fn from(camera: Camera) -> Self {
+        let (aperture_shape, aperture_blades, aperture_rotation, aperture_texture_id) =
+            match camera.aperture {
+                Aperture::Circle => (0, 0, 0.0, 0),
+                Aperture::Polygon { blades, rotation } => (1, blades, rotation.to_radians(), 0),
+                Aperture::Texture(texture_id) => (2, 0, 0.0, texture_id),
+            };
+        let (projection, projection_scale) = match camera.projection {
+            Projection::Perspective => (0, 0.0),
+            Projection::Orthographic { width } => (1, width),
+            Projection::Equirectangular => (2, 0.0),
+            Projection::Fisheye { fov } => (3, fov.to_radians()),
+        };
+        let (stereo_layout, interpupillary_distance, convergence_distance) = match camera.stereo {
+            None => (0, 0.0, 0.0),
+            Some(stereo) => (
+                match stereo.layout {
+                    StereoLayout::SideBySide => 1,
+                    StereoLayout::OverUnder => 2,
+                },
+                stereo.interpupillary_distance,
+                stereo.convergence_distance,
+            ),
+        };
+        let (end_position, end_look_at) = camera.end_view();
         return Self {
             look_at: camera.look_at,
             position: camera.position,
-            _pad: [0; 4],
+            tan_half_fov: camera.tan_half_fov(),
+            lens_radius: camera.lens_radius(),
+            focus_distance: camera.focus_distance,
+            aperture_shape,
+            aperture_blades,
+            aperture_rotation,
+            aperture_texture_id,
+            projection,
+            projection_scale,
+            stereo_layout,
+            interpupillary_distance,
+            convergence_distance,
+            shutter_open: camera.shutter_open,
+            end_look_at,
+            end_position,
+            shutter_close: camera.shutter_close,
+        };
+    }
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+struct UniformSky {
+    // Perez coefficients A-E, each as (Y, x, y, 0)
+    perez: [[f32; 4]; 5],
+    normalized_zenith: Vec3f,
+    model: u32,
+    sun_direction: Vec3f,
+    sun_cos_angular_radius: f32,
+    sun_radiance: Vec3f,
+    sun_solid_angle: f32,
+    color: Vec3f,
+    strength: f32,
+    ground_albedo: Vec3f,
+    has_sun: u32,
+}
+
+impl From<Sky> for UniformSky {
+    fn from(sky: Sky) -> Self {
+        let perez = sky
+            .perez_coefficients()
+            .map(|coeffs| [coeffs.x(), coeffs.y(), coeffs.z(), 0.0]);
+        return Self {
+            perez,
+            normalized_zenith: sky.normalized_zenith_yxy(),
+            model: match sky.model {
+                SkyModel::Constant => 0,
+                SkyModel::Preetham => 1,
+            },
+            sun_direction: sky.sun_direction(),
+            sun_cos_angular_radius: sky.sun_cos_angular_radius(),
+            sun_radiance: sky.sun_radiance(),
+            sun_solid_angle: sky.sun_solid_angle(),
+            color: sky.color,
+            strength: sky.strength,
+            ground_albedo: sky.ground_albedo,
+            has_sun: sky.has_sun() as u32,
         };
     }
 }
 
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+struct UniformSceneInfo {
+    // u32::MAX when the scene is not filled with a medium
+    global_medium_id: u32,
+    _pad: [u32; 3],
+}
+
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(4))]
 struct RendererInfo {
     curr_sample: u32,
     max_ray_depth: u32,
+    /// Non zero if paths carry wavelengths instead of RGB
+    spectral: u32,
+    /// Non zero if the first hits are written to the AOV textures
+    aovs: u32,
+    /// Zero samples every pixel, see `AdaptiveSampling`
+    noise_target: f32,
+    min_samples: u32,
+    /// `SamplerType::id`
+    sampler: u32,
+    /// Samples per pixel the stratified and blue noise samplers spread their samples over
+    samples: u32,
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct PostProcessInfo {
+    color_scale: [f32; 3],
+    tone_mapping: u32,
+    white_point: f32,
+    vignette: f32,
+    chromatic_aberration: f32,
+    /// Non zero if the glare passes run
+    glare: u32,
+    glare_threshold: f32,
+    bloom: f32,
+    bloom_radius: f32,
+    streaks: f32,
+    streak_length: f32,
+    streak_count: u32,
+    streak_rotation: f32,
+    _pad: f32,
+}
+
+impl PostProcessInfo {
+    fn new(post_process: PostProcess, camera: &Camera) -> Self {
+        let white_point = match post_process.tone_mapping {
+            ToneMapping::ReinhardExtended { white_point } => white_point,
+            _ => 1.0,
+        };
+        let kernel = post_process.glare_kernel(camera);
+        return Self {
+            color_scale: post_process.color_scale().data,
+            tone_mapping: post_process.tone_mapping.id(),
+            white_point,
+            vignette: post_process.vignette,
+            chromatic_aberration: post_process.chromatic_aberration,
+            glare: post_process.glare.is_enabled() as u32,
+            glare_threshold: post_process.glare.threshold,
+            bloom: post_process.glare.bloom,
+            bloom_radius: kernel.bloom_radius,
+            streaks: post_process.glare.streaks,
+            streak_length: kernel.streak_length,
+            streak_count: kernel.streak_count,
+            streak_rotation: kernel.streak_rotation,
+            _pad: 0.0,
+        };
+    }
 }
@@ -0,0 +1,80 @@
+// Edge-avoiding À-trous wavelet filter guided by the albedo, normal and depth AOVs, one
+// iteration per dispatch. Mirrors cpu/denoise.rs.
+// https://jo.dreggn.org/home/2010_atrous.pdf
+
+@group(0) @binding(0)
+var input_texture: texture_storage_2d<rgba32float, read>;
+@group(0) @binding(1)
+var output_texture: texture_storage_2d<rgba32float, write>;
+
+@group(1) @binding(0)
+var aov_albedo_depth: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(1)
+var aov_normal_material_id: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(2)
+var aov_position_object_id: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(3)
+var aov_uv: texture_storage_2d<rgba32float, read_write>;
+
+struct DenoiseInfo {
+    iteration: u32,
+}
+
+var<immediate> denoise_info: DenoiseInfo;
+
+const COLOR_PHI = 0.5f;
+const NORMAL_PHI = 0.1f;
+const ALBEDO_PHI = 0.05f;
+const DEPTH_PHI = 0.02f;
+
+// B3 spline, the 1D kernel of the filter
+const KERNEL = array<f32, 5>(1.0f / 16.0f, 1.0f / 4.0f, 3.0f / 8.0f, 1.0f / 4.0f, 1.0f / 16.0f);
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
+    let size = vec2<i32>(textureDimensions(input_texture));
+    if tex_coords.x >= size.x || tex_coords.y >= size.y {
+        return;
+    }
+
+    let step = 1 << denoise_info.iteration;
+    let color_phi = COLOR_PHI / f32(step);
+
+    let center_color = textureLoad(input_texture, tex_coords).rgb;
+    let center_albedo_depth = textureLoad(aov_albedo_depth, tex_coords);
+    let center_normal = textureLoad(aov_normal_material_id, tex_coords).xyz;
+
+    var sum = vec3<f32>(0.0f);
+    var weight_sum = 0.0f;
+    for (var j = 0; j < 5; j++) {
+        for (var i = 0; i < 5; i++) {
+            let sample_coords = tex_coords + vec2<i32>(i - 2, j - 2) * i32(step);
+            if any(sample_coords < vec2<i32>(0)) || any(sample_coords >= size) {
+                continue;
+            }
+
+            let sample_color = textureLoad(input_texture, sample_coords).rgb;
+            let sample_albedo_depth = textureLoad(aov_albedo_depth, sample_coords);
+            let sample_normal = textureLoad(aov_normal_material_id, sample_coords).xyz;
+
+            // Compress the colors so bright pixels don't stop the filter everywhere
+            let color = center_color / (center_color + 1.0f) - sample_color / (sample_color + 1.0f);
+            let normal = center_normal - sample_normal;
+            let albedo = center_albedo_depth.rgb - sample_albedo_depth.rgb;
+            let depth = abs(center_albedo_depth.w - sample_albedo_depth.w)
+                / max(DEPTH_PHI * center_albedo_depth.w * f32(step), 1e-4f);
+
+            let weight = KERNEL[i] * KERNEL[j] * exp(
+                -dot(color, color) / color_phi
+                - dot(normal, normal) / NORMAL_PHI
+                - dot(albedo, albedo) / ALBEDO_PHI
+                - depth
+            );
+            sum += sample_color * weight;
+            weight_sum += weight;
+        }
+    }
+
+    textureStore(output_texture, tex_coords, vec4<f32>(sum / weight_sum, 1.0f));
+}
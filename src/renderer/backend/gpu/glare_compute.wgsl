@@ -0,0 +1,121 @@
+// Glare passes that run before pp_compute.wgsl, mirrors cpu/lens_effects.rs. The bright pixels
+// go to glare_bright, are blurred through glare_temp into glare_bloom, and the streaks are added
+// to the bloom in glare_temp, which is what the post process pass reads.
+
+@group(0) @binding(0)
+var image: texture_storage_2d<rgba32float, read_write>;
+
+@group(1) @binding(0)
+var glare_bright: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(1)
+var glare_temp: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(2)
+var glare_bloom: texture_storage_2d<rgba32float, read_write>;
+
+// Mirrors `PostProcessInfo` in gpu.rs
+struct PostProcessInfo {
+    // Exposure and white balance as one multiplier
+    color_scale: vec3<f32>,
+    tone_mapping: u32,
+    white_point: f32,
+    vignette: f32,
+    chromatic_aberration: f32,
+    // Non zero if glare_temp holds the glare
+    glare: u32,
+    glare_threshold: f32,
+    bloom: f32,
+    bloom_radius: f32,
+    streaks: f32,
+    streak_length: f32,
+    streak_count: u32,
+    streak_rotation: f32,
+}
+
+var<immediate> post_process_info: PostProcessInfo;
+
+const STREAK_SAMPLES = 32u;
+const MAX_BLOOM_RADIUS = 128;
+const LUMINANCE = vec3<f32>(0.2126f, 0.7152f, 0.0722f);
+const TAU = 6.28318530718f;
+
+@compute @workgroup_size(8, 8, 1)
+fn bright_pass(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
+
+    let color = textureLoad(image, tex_coords).rgb;
+    let luminance = dot(color, LUMINANCE);
+    let bright = color * (max(luminance - post_process_info.glare_threshold, 0.0f) / max(luminance, 1e-4f));
+    textureStore(glare_bright, tex_coords, vec4<f32>(bright, 1.0f));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn blur_horizontal(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
+    textureStore(glare_temp, tex_coords, vec4<f32>(blur(tex_coords, vec2<i32>(1, 0)), 1.0f));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn blur_vertical(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
+    textureStore(glare_bloom, tex_coords, vec4<f32>(blur(tex_coords, vec2<i32>(0, 1)), 1.0f));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn add_streaks(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
+    let size = vec2<i32>(textureDimensions(glare_bright));
+
+    var sum = vec3<f32>(0.0f);
+    var weight_sum = 0.0f;
+    for (var i = 0u; i < post_process_info.streak_count; i++) {
+        let angle = post_process_info.streak_rotation + f32(i) * TAU / f32(post_process_info.streak_count);
+        let direction = vec2<f32>(cos(angle), sin(angle));
+
+        for (var k = 1u; k <= STREAK_SAMPLES; k++) {
+            let t = f32(k) / f32(STREAK_SAMPLES);
+            let weight = (1.0f - t) * (1.0f - t);
+            weight_sum += weight;
+
+            let sample_coords = tex_coords + vec2<i32>(round(direction * t * post_process_info.streak_length));
+            if any(sample_coords < vec2<i32>(0)) || any(sample_coords >= size) {
+                continue;
+            }
+            sum += textureLoad(glare_bright, sample_coords).rgb * weight;
+        }
+    }
+
+    var streaks = vec3<f32>(0.0f);
+    if weight_sum > 0.0f {
+        streaks = sum / weight_sum;
+    }
+    let bloom = textureLoad(glare_bloom, tex_coords).rgb;
+    let glare = bloom * post_process_info.bloom + streaks * post_process_info.streaks;
+    textureStore(glare_temp, tex_coords, vec4<f32>(glare, 1.0f));
+}
+
+// One direction of a separable gaussian, everything outside the image is black
+fn blur(tex_coords: vec2<i32>, direction: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(glare_bright));
+    let radius = post_process_info.bloom_radius;
+    let taps = min(i32(ceil(radius)), MAX_BLOOM_RADIUS);
+    let sigma = max(radius / 3.0f, 1e-4f);
+    let horizontal = direction.x != 0;
+
+    var sum = vec3<f32>(0.0f);
+    var weight_sum = 0.0f;
+    for (var i = -taps; i <= taps; i++) {
+        let weight = exp(-f32(i * i) / (2.0f * sigma * sigma));
+        weight_sum += weight;
+
+        let sample_coords = tex_coords + i * direction;
+        if any(sample_coords < vec2<i32>(0)) || any(sample_coords >= size) {
+            continue;
+        }
+        if horizontal {
+            sum += textureLoad(glare_bright, sample_coords).rgb * weight;
+        } else {
+            sum += textureLoad(glare_temp, sample_coords).rgb * weight;
+        }
+    }
+    return sum / weight_sum;
+}
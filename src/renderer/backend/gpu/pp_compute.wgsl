@@ -1,20 +1,122 @@
 @group(0) @binding(0)
-var rt_texture: texture_storage_2d<rgba16unorm, read_write>;
+var rt_texture: texture_storage_2d<rgba32float, read_write>;
 
 @group(1) @binding(0)
 var pp_texture: texture_storage_2d<rgba16unorm, write>;
 
+// Bloom and streaks from glare_compute.wgsl
+@group(2) @binding(1)
+var glare_temp: texture_storage_2d<rgba32float, read_write>;
+
+// Mirrors `PostProcessInfo` in gpu.rs
+struct PostProcessInfo {
+    // Exposure and white balance as one multiplier
+    color_scale: vec3<f32>,
+    tone_mapping: u32,
+    white_point: f32,
+    vignette: f32,
+    chromatic_aberration: f32,
+    // Non zero if glare_temp holds the glare
+    glare: u32,
+    glare_threshold: f32,
+    bloom: f32,
+    bloom_radius: f32,
+    streaks: f32,
+    streak_length: f32,
+    streak_count: u32,
+    streak_rotation: f32,
+}
+
+var<immediate> post_process_info: PostProcessInfo;
+
+const TONE_MAPPING_NONE = 0u;
+const TONE_MAPPING_REINHARD_EXTENDED = 1u;
+const TONE_MAPPING_ACES_FITTED = 2u;
+const TONE_MAPPING_AGX = 3u;
+const TONE_MAPPING_PBR_NEUTRAL = 4u;
+
 @compute @workgroup_size(8, 8, 1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let tex_coords = vec2<i32>(i32(global_id.x), i32(global_id.y));
 
     var color = textureLoad(rt_texture, tex_coords).rgb;
-    color = linear_to_srgb(color);
-    color = aces_filmic(color);
+    color = lens_effects(color, tex_coords);
+    color *= post_process_info.color_scale;
+    color = tone_map(color);
+    color = linear_to_srgb(max(color, vec3<f32>(0.0f)));
 
     textureStore(pp_texture, tex_coords, vec4<f32>(color, 1.0f));
 }
 
+// Chromatic aberration, glare and vignetting on the linear image, mirrors cpu/lens_effects.rs
+fn lens_effects(linear: vec3<f32>, tex_coords: vec2<i32>) -> vec3<f32> {
+    let size = vec2<f32>(textureDimensions(rt_texture));
+    let position = vec2<f32>(tex_coords) + 0.5f;
+    let center = size * 0.5f;
+
+    var color = linear;
+    let aberration = post_process_info.chromatic_aberration;
+    if aberration != 0.0f {
+        color.r = sample_bilinear(center + (position - center) * (1.0f - aberration)).r;
+        color.b = sample_bilinear(center + (position - center) * (1.0f + aberration)).b;
+    }
+
+    if post_process_info.glare != 0u {
+        color += textureLoad(glare_temp, tex_coords).rgb;
+    }
+
+    if post_process_info.vignette > 0.0f {
+        let offset = position - center;
+        let r2 = dot(offset, offset) / dot(center, center);
+        let falloff = 1.0f / (1.0f + post_process_info.vignette * r2);
+        color *= falloff * falloff;
+    }
+    return color;
+}
+
+// Bilinear lookup at pixel coordinates where pixel centers are at half integers, clamped to the
+// edges of the image
+fn sample_bilinear(position: vec2<f32>) -> vec3<f32> {
+    let max_coords = vec2<i32>(textureDimensions(rt_texture)) - 1;
+    let p = position - 0.5f;
+    let p_0 = floor(p);
+    let t = p - p_0;
+    let c_0 = vec2<i32>(p_0);
+
+    let top = mix(
+        textureLoad(rt_texture, clamp(c_0, vec2<i32>(0), max_coords)).rgb,
+        textureLoad(rt_texture, clamp(c_0 + vec2<i32>(1, 0), vec2<i32>(0), max_coords)).rgb,
+        t.x
+    );
+    let bottom = mix(
+        textureLoad(rt_texture, clamp(c_0 + vec2<i32>(0, 1), vec2<i32>(0), max_coords)).rgb,
+        textureLoad(rt_texture, clamp(c_0 + vec2<i32>(1, 1), vec2<i32>(0), max_coords)).rgb,
+        t.x
+    );
+    return mix(top, bottom, t.y);
+}
+
+fn tone_map(color: vec3<f32>) -> vec3<f32> {
+    switch post_process_info.tone_mapping {
+        case TONE_MAPPING_REINHARD_EXTENDED: {
+            let white_point = post_process_info.white_point;
+            return min(color * (1.0f + color / (white_point * white_point)) / (1.0f + color), vec3<f32>(1.0f));
+        }
+        case TONE_MAPPING_ACES_FITTED: {
+            return aces_fitted(color);
+        }
+        case TONE_MAPPING_AGX: {
+            return agx(color);
+        }
+        case TONE_MAPPING_PBR_NEUTRAL: {
+            return pbr_neutral(color);
+        }
+        default: {
+            return min(color, vec3<f32>(1.0f));
+        }
+    }
+}
+
 // https://gamedev.stackexchange.com/a/194038
 fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
     let cutoff = vec3<f32>(f32(linear.r < 0.0031308f), f32(linear.g < 0.0031308f), f32(linear.b < 0.0031308f));
@@ -23,12 +125,72 @@ fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
     return mix(higher, lower, cutoff);
 }
 
-// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
-fn aces_filmic(x: vec3<f32>) -> vec3<f32> {
-    let a = 2.51f;
-    let b = 0.03f;
-    let c = 2.43f;
-    let d = 0.59f;
-    let e = 0.14f;
-    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0f), vec3<f32>(1.0f));
+// The matrices below are written row by row, so vectors are multiplied from the left
+
+// https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+fn aces_fitted(color: vec3<f32>) -> vec3<f32> {
+    let input = mat3x3<f32>(
+        0.59719f, 0.35458f, 0.04823f,
+        0.07600f, 0.90834f, 0.01566f,
+        0.02840f, 0.13383f, 0.83777f,
+    );
+    let output = mat3x3<f32>(
+        1.60475f, -0.53108f, -0.07367f,
+        -0.10208f, 1.10813f, -0.00605f,
+        -0.00327f, -0.07276f, 1.07602f,
+    );
+
+    let v = color * input;
+    let a = v * (v + 0.0245786f) - 0.000090537f;
+    let b = v * (0.983729f * v + 0.4329510f) + 0.238081f;
+    return clamp((a / b) * output, vec3<f32>(0.0f), vec3<f32>(1.0f));
+}
+
+// https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        0.842479062253094f, 0.0784335999999992f, 0.0792237451477643f,
+        0.0423282422610123f, 0.878468636469772f, 0.0791661274605434f,
+        0.0423756549057051f, 0.0784336f, 0.879142973793104f,
+    );
+    let outset = mat3x3<f32>(
+        1.19687900512017f, -0.0980208811401368f, -0.0990297440797205f,
+        -0.0528968517574562f, 1.15190312990417f, -0.0989611768448433f,
+        -0.0529716355144438f, -0.0980434501171241f, 1.15107367264116f,
+    );
+    let min_ev = -12.47393f;
+    let max_ev = 4.026069f;
+
+    let log = log2(max(color * inset, vec3<f32>(1e-10f)));
+    let x = clamp((log - min_ev) / (max_ev - min_ev), vec3<f32>(0.0f), vec3<f32>(1.0f));
+    // Polynomial fit of the default contrast curve
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let v = 15.5f * x4 * x2 - 40.14f * x4 * x + 31.96f * x4 - 6.868f * x2 * x + 0.4298f * x2 + 0.1191f * x - 0.00232f;
+    // The curve ends up in display encoding, go back to linear for the sRGB transform
+    return min(pow(max(v * outset, vec3<f32>(0.0f)), vec3<f32>(2.2f)), vec3<f32>(1.0f));
+}
+
+// https://github.com/KhronosGroup/ToneMapping/blob/main/PBR_Neutral/README.md
+fn pbr_neutral(color: vec3<f32>) -> vec3<f32> {
+    let start_compression = 0.8f - 0.04f;
+    let desaturation = 0.15f;
+
+    let x = min(color.r, min(color.g, color.b));
+    var offset = 0.04f;
+    if x < 0.08f {
+        offset = x - 6.25f * x * x;
+    }
+    var v = color - offset;
+
+    let peak = max(v.r, max(v.g, v.b));
+    if peak < start_compression {
+        return v;
+    }
+
+    let d = 1.0f - start_compression;
+    let new_peak = 1.0f - d * d / (peak + d - start_compression);
+    v *= new_peak / peak;
+    let g = 1.0f - 1.0f / (desaturation * (peak - new_peak) + 1.0f);
+    return mix(v, vec3<f32>(new_peak), g);
 }
@@ -1,7 +1,10 @@
 enable wgpu_binding_array;
 
 @group(0) @binding(0)
-var output_texture: texture_storage_2d<rgba16unorm, read_write>;
+var output_texture: texture_storage_2d<rgba32float, read_write>;
+// Sample count, running mean and sum of squared differences of the luminance of every pixel
+@group(0) @binding(1)
+var sample_stats: texture_storage_2d<rgba32float, read_write>;
 
 @group(1) @binding(0)
 var <storage, read> triangles: array<Triangle>;
@@ -18,25 +21,195 @@ var textures: binding_array<texture_2d<f32>, 128>;
 @group(1) @binding(4)
 var textures_array_sampler: sampler;
 
+@group(1) @binding(5)
+var <storage, read> light_bvh_nodes: array<LightNode>;
+
+@group(1) @binding(6)
+var <storage, read> media: array<Medium>;
+
+@group(1) @binding(7)
+var <storage, read> density_grid_data: array<f32>;
+
+@group(1) @binding(8)
+var <storage, read> motions: array<Motion>;
+
 @group(2) @binding(0)
 var <uniform> camera: Camera;
 
+@group(2) @binding(1)
+var <uniform> sky: Sky;
+
+@group(2) @binding(2)
+var <uniform> scene_info: SceneInfo;
+
+// First hit data for the AOVs, laid out like renderer::aov::AovPixel
+@group(3) @binding(0)
+var aov_albedo_depth: texture_storage_2d<rgba32float, read_write>;
+
+@group(3) @binding(1)
+var aov_normal_material_id: texture_storage_2d<rgba32float, read_write>;
+
+@group(3) @binding(2)
+var aov_position_object_id: texture_storage_2d<rgba32float, read_write>;
+
+@group(3) @binding(3)
+var aov_uv: texture_storage_2d<rgba32float, read_write>;
+
 var <immediate> renderer_info: RendererInfo;
 
+// Wavelengths in nanometers carried by the current path in spectral mode, the hero wavelength is in x
+var<private> wavelengths: vec3<f32>;
+// Time within the frame the current path sees the scene at, moving triangles are intersected where they are at this time
+var<private> ray_time: f32;
+// Pixel and sample index `sample_1d` and `sample_2d` generate values for
+var<private> sampler_pixel: vec2<u32>;
+var<private> sampler_index: u32;
+
 const PI = 3.1415926535f;
 const TWO_PI = 6.283185307f;
 const PI_OVER_2 = 1.5707963268f;
 const PI_OVER_4 = 0.7853981634f;
 const EPSILON = 0.0001f;
+// Largest f32 below 1, sample values are kept under it when they get rescaled
+const ONE_MINUS_EPSILON = 0.99999994f;
+const NO_MEDIUM = 0xFFFFFFFFu;
+const NO_MOTION = 0xFFFFFFFFu;
+const NO_LIGHT = 0xFFFFFFFFu;
+
+const MEDIUM_NONE = 0u;
+const MEDIUM_ABSORBED = 1u;
+const MEDIUM_SCATTERED = 2u;
+
+const MAX_WALK_STEPS = 256u;
+// Transparent surfaces a shadow ray passes before the light counts as blocked
+const MAX_SHADOW_SURFACES = 64u;
+
+// Aperture shapes, the same as `Aperture` in `scene.rs`
+const APERTURE_CIRCLE = 0u;
+const APERTURE_POLYGON = 1u;
+const APERTURE_TEXTURE = 2u;
+
+// Camera projections, the same as `Projection` in `scene.rs`
+const PROJECTION_PERSPECTIVE = 0u;
+const PROJECTION_ORTHOGRAPHIC = 1u;
+const PROJECTION_EQUIRECTANGULAR = 2u;
+const PROJECTION_FISHEYE = 3u;
+
+// Stereo layouts, the same as `StereoLayout` in `scene.rs` with zero for a mono camera
+const STEREO_NONE = 0u;
+const STEREO_SIDE_BY_SIDE = 1u;
+const STEREO_OVER_UNDER = 2u;
+
+// Rejection sampling attempts for aperture textures before falling back to the center
+const APERTURE_TEXTURE_TRIES = 64u;
+// Offset between rejection sampling attempts, the R2 sequence keeps the attempts spread out
+const APERTURE_TEXTURE_STEP = vec2<f32>(0.7548776662f, 0.5698402910f);
+
+// Sampler types, the same as `SamplerType::id`
+const SAMPLER_INDEPENDENT = 0u;
+const SAMPLER_STRATIFIED = 1u;
+const SAMPLER_SOBOL = 2u;
+const SAMPLER_BLUE_NOISE = 3u;
+
+// Dimensions of the sample vector, the same as in cpu/sampler.rs. 2D values take two.
+const PIXEL_DIMENSION = 0u;
+const LENS_DIMENSION = 2u;
+const TIME_DIMENSION = 4u;
+const WAVELENGTH_DIMENSION = 5u;
+// Relative to the start of every bounce
+const LIGHT_SELECTION_DIMENSION = 0u;
+const LIGHT_POINT_DIMENSION = 1u;
+const SUN_DIMENSION = 3u;
+const BSDF_LOBE_DIMENSION = 5u;
+const BSDF_DIRECTION_DIMENSION = 6u;
+const RUSSIAN_ROULETTE_DIMENSION = 8u;
+const FIRST_BOUNCE_DIMENSION = 6u;
+const BOUNCE_DIMENSIONS = 9u;
+
+// Levels of the Morton curve of the blue noise sampler, it repeats every 256 pixels
+const MORTON_LEVELS = 8u;
+
+// BSDF lobe flags, the same as `Lobe` in the CPU backend
+const LOBE_DIFFUSE = 1u;
+const LOBE_GLOSSY = 2u;
+const LOBE_SPECULAR = 4u;
+const LOBE_REFLECTION = 8u;
+const LOBE_TRANSMISSION = 16u;
+
+// Below this GGX alpha lobes are treated as perfectly smooth
+const SPECULAR_ALPHA = 1e-3f;
+
+// Sampled range of visible wavelengths in nanometers, the same as in `spectrum.rs`
+const WAVELENGTH_MIN = 380.0f;
+const WAVELENGTH_MAX = 730.0f;
+const CIE_Y_INTEGRAL = 106.91687f;
+const EQUAL_ENERGY_WHITE = vec3<f32>(1.2005764f, 0.9496515f, 0.9078579f);
+// Wavelengths standing in for the sRGB primaries when wave optics are evaluated in RGB mode
+const RGB_WAVELENGTHS = vec3<f32>(630.0f, 532.0f, 465.0f);
 
 struct RendererInfo {
     current_sample: u32,
     max_ray_depth: u32,
+    spectral: u32,
+    aovs: u32,
+    // Pixels stop once their relative error falls below this, zero samples every pixel
+    noise_target: f32,
+    min_samples: u32,
+    // `SamplerType::id`
+    sampler: u32,
+    samples: u32,
 }
 
+// Same as `AdaptiveSampling::LUMINANCE_FLOOR` in renderer.rs
+const LUMINANCE_FLOOR = 0.01f;
+
 struct Camera {
     look_at: mat4x4<f32>,
     position: vec3<f32>,
+    tan_half_fov: f32,
+    lens_radius: f32,
+    focus_distance: f32,
+    aperture_shape: u32,
+    aperture_blades: u32,
+    // In radians
+    aperture_rotation: f32,
+    aperture_texture_id: u32,
+    projection: u32,
+    // Orthographic view width or fisheye FOV in radians
+    projection_scale: f32,
+    stereo_layout: u32,
+    interpupillary_distance: f32,
+    convergence_distance: f32,
+    shutter_open: f32,
+    end_look_at: mat4x4<f32>,
+    end_position: vec3<f32>,
+    shutter_close: f32,
+}
+
+// Position on the film of one eye, y goes from -1 to 1 and x is scaled by the aspect ratio
+struct FilmPosition {
+    screen: vec2<f32>,
+    aspect: f32,
+    // -1 for the left eye, 1 for the right eye and 0 for a mono camera
+    eye: f32,
+}
+
+struct Sky {
+    perez: array<vec4<f32>, 5>,
+    normalized_zenith: vec3<f32>,
+    model: u32,
+    sun_direction: vec3<f32>,
+    sun_cos_angular_radius: f32,
+    sun_radiance: vec3<f32>,
+    sun_solid_angle: f32,
+    color: vec3<f32>,
+    strength: f32,
+    ground_albedo: vec3<f32>,
+    has_sun: u32,
+}
+
+struct SceneInfo {
+    global_medium_id: u32,
 }
 
 struct Material {
@@ -54,6 +227,39 @@ struct Material {
     metallic_tex_id: u32,
     emission_tex_id: u32,
     normal_tex_id: u32,
+    medium_id: u32,
+    subsurface: f32,
+    thin_walled: u32,
+    specular: f32,
+    subsurface_albedo: vec3<f32>,
+    subsurface_anisotropy: f32,
+    subsurface_mfp: vec3<f32>,
+    anisotropic: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_gloss: f32,
+    anisotropic_rotation: f32,
+    thin_film_thickness: f32,
+    thin_film_ior: f32,
+    thin_film_thickness_tex_id: u32,
+    sellmeier_b: vec3<f32>,
+    cauchy_b: f32,
+    sellmeier_c: vec3<f32>,
+    thin_film_thickness_min: f32,
+    emission_scale: vec3<f32>,
+    alpha_cutoff: f32,
+}
+
+struct Medium {
+    sigma_a: vec3<f32>,
+    g: f32,
+    sigma_s: vec3<f32>,
+    max_density: f32,
+    grid_min: vec3<f32>,
+    grid_offset: u32,
+    grid_max: vec3<f32>,
+    grid_resolution: vec3<u32>,
 }
 
 struct Node {
@@ -63,16 +269,39 @@ struct Node {
     num_tris: u32,
 }
 
+struct LightNode {
+    bounds_min: vec3<f32>,
+    power: f32,
+    bounds_max: vec3<f32>,
+    theta_o: f32,
+    axis: vec3<f32>,
+    theta_e: f32,
+    first_tri_or_child: u32,
+    num_tris: u32,
+    parent: u32,
+}
+
 struct Vertex {
     position: vec3<f32>,
     tex_coord_x: f32,
     normal: vec3<f32>,
     tex_coord_y: f32,
+    tangent: vec3<f32>,
+    bitangent_sign: f32,
 }
 
 struct Triangle {
     vertices: array<Vertex, 3>,
     material_id: u32,
+    motion_id: u32,
+    object_id: u32,
+    light_id: u32,
+}
+
+// Vertices move on straight lines from the start to the end transform
+struct Motion {
+    start: mat4x4<f32>,
+    end: mat4x4<f32>,
 }
 
 struct Ray {
@@ -84,184 +313,1318 @@ struct HitInfo {
     has_hit: bool,
     point: vec3<f32>,
     normal: vec3<f32>,
+    // Interpolated vertex tangent, not orthogonal to the normal
+    tangent: vec3<f32>,
+    bitangent_sign: f32,
     distance: f32,
     uv: vec2<f32>,
     material_id: u32,
+    object_id: u32,
+    light_id: u32,
     front_face: bool,
     tbn: mat3x3<f32>
 }
 
-struct BSDFType {
-    specular: bool,
-    transmitted: bool,
-    diffuse: bool
+// Parameters of the principled BSDF derived from a material, mirrors the lobes composed by
+// bsdf::from_material on the CPU
+struct Principled {
+    base_color: vec3<f32>,
+    roughness: f32,
+    sheen_color: vec3<f32>,
+    alpha_x: f32,
+    specular_color: vec3<f32>,
+    alpha_y: f32,
+    transmission_tint: vec3<f32>,
+    alpha: f32,
+    eta: f32,
+    transmission: f32,
+    metallic: f32,
+    clearcoat: f32,
+    clearcoat_alpha: f32,
+    thin_walled: bool,
+    thin_film: ThinFilm,
+    // The film of the transmission lobe is relative to the side of wo, which can be the inside
+    transmission_thin_film: ThinFilm,
+}
+
+// Thin film on top of a specular interface, a thickness of zero disables it
+struct ThinFilm {
+    // Thickness in nanometers
+    thickness: f32,
+    // IOR of the film relative to the side of wo
+    eta: f32,
+}
+
+struct BsdfSample {
+    wi: vec3<f32>,
+    // BSDF value times the cosine term divided by the pdf
+    weight: vec3<f32>,
+    // Zero if no direction could be sampled
+    lobes: u32,
+    // Solid angle density, zero for specular samples
+    pdf: f32,
+}
+
+struct ShadingPoint {
+    point: vec3<f32>,
+    normal: vec3<f32>,
+    tbn: mat3x3<f32>,
+    wo: vec3<f32>,
+    bsdf: Principled,
+    // The path continues with a white Lambert lobe after a subsurface random walk
+    subsurface_exit: bool,
+}
+
+struct MediumInteraction {
+    kind: u32,
+    point: vec3<f32>,
 }
 
 @compute @workgroup_size(8, 8, 1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    var rng_seed = renderer_info.current_sample * 6023u + (757283u * global_id.x + 872653746u * global_id.y);
     let tex_coords = vec2<u32>(global_id.xy);
 
+    // Pixels count their own samples since converged ones stop taking more
+    var stats = textureLoad(sample_stats, tex_coords);
+    if renderer_info.current_sample == 1u {
+        stats = vec4<f32>(0.0f);
+    }
+    if renderer_info.noise_target > 0.0f && is_converged(stats) {
+        return;
+    }
+    sampler_pixel = tex_coords;
+    sampler_index = u32(stats.x);
+    // Randomness that isn't taken from the sampler, like media and the random walks
+    var rng_seed = max(hash_u32(hash_combine(pixel_seed(tex_coords), sampler_index)), 1u);
+
     let texture_dimensions = vec2<f32>(f32(textureDimensions(output_texture).x), f32(textureDimensions(output_texture).y));
-    let aspect = texture_dimensions.x / texture_dimensions.y;
-    let screen_coords = vec2<f32>(
-        ((f32(global_id.x) / texture_dimensions.x) * 2.0f - 1.0f) * aspect,
-        (f32(u32(texture_dimensions.y) - global_id.y) / texture_dimensions.y) * 2.0f - 1.0f
-    );
+    var film = film_position(vec2<f32>(f32(global_id.x), texture_dimensions.y - f32(global_id.y)), texture_dimensions);
+
+    ray_time = camera.shutter_open;
+    if camera.shutter_close > camera.shutter_open {
+        ray_time = mix(camera.shutter_open, camera.shutter_close, sample_1d(TIME_DIMENSION));
+    }
 
     var ray = Ray();
-    ray.origin = camera.position;
-    let jitter = vec2<f32>(rand_f32(&rng_seed) * 2.0f - 1.0f, rand_f32(&rng_seed) * 2.0f - 1.0f) * 0.0005f;
-    ray.direction = normalize(camera.look_at * vec4<f32>(-screen_coords.x + jitter.x, screen_coords.y + jitter.y, 1.0f, 0.0f)).xyz;
+    let jitter = (sample_2d(PIXEL_DIMENSION) * 2.0f - 1.0f) * 0.0005f;
+    film.screen += vec2<f32>(-jitter.x, jitter.y);
+    let has_ray = camera_ray(film, &ray, sample_2d(LENS_DIMENSION));
+
+    if renderer_info.aovs != 0u {
+        write_aovs(ray, has_ray, tex_coords);
+    }
+
+    // Stays black outside of the image circle of a fisheye
+    var rt_color = vec3<f32>(0.0f);
+    if has_ray && renderer_info.spectral != 0u {
+        wavelengths = sample_wavelengths(sample_1d(WAVELENGTH_DIMENSION));
+        rt_color = spectrum_to_rgb(trace(&ray, &rng_seed, renderer_info.max_ray_depth));
+    } else if has_ray {
+        rt_color = trace(&ray, &rng_seed, renderer_info.max_ray_depth);
+    }
+    // Welford's algorithm for the variance
+    let count = stats.x + 1.0f;
+    let luminance = dot(rt_color, vec3<f32>(0.2126f, 0.7152f, 0.0722f));
+    let delta = luminance - stats.y;
+    let mean = stats.y + delta / count;
+    textureStore(sample_stats, tex_coords, vec4<f32>(count, mean, stats.z + delta * (luminance - mean), 0.0f));
 
-    let rt_color = trace(&ray, &rng_seed, renderer_info.max_ray_depth);
     let accumulation_color = textureLoad(output_texture, tex_coords).rgb;
-    let final_color = mix(accumulation_color, rt_color, 1.0f / f32(renderer_info.current_sample));
+    let final_color = mix(accumulation_color, rt_color, 1.0f / count);
 
     //let final_color = debug_bvh(ray, 300.0f);
 
     textureStore(output_texture, tex_coords, vec4<f32>(final_color, 1.0f));
 }
 
+// Mirrors `AdaptiveSampling::is_converged` in renderer.rs, `stats` holds the sample count, mean
+// and sum of squared differences of the luminance
+fn is_converged(stats: vec4<f32>) -> bool {
+    if stats.x < f32(renderer_info.min_samples) {
+        return false;
+    }
+    let variance = stats.z / (stats.x - 1.0f);
+    let standard_error = sqrt(variance / stats.x);
+    return standard_error / (stats.y + LUMINANCE_FLOOR) < renderer_info.noise_target;
+}
+
+// Accumulates the first surface along the camera ray that is at least half opaque into the AOV
+// textures, mirrors backend::cpu::aov. The IDs are kept from the first sample that hits.
+fn write_aovs(camera_ray: Ray, has_ray: bool, tex_coords: vec2<u32>) {
+    var albedo = vec3<f32>(0.0f);
+    var normal = vec3<f32>(0.0f);
+    var position = vec3<f32>(0.0f);
+    var uv = vec2<f32>(0.0f);
+    var depth = 0.0f;
+    var material_id = -1.0f;
+    var object_id = -1.0f;
+
+    var ray = camera_ray;
+    var distance = 0.0f;
+    for (var i = 0u; has_ray && i < renderer_info.max_ray_depth; i++) {
+        var hit_info = traverse_bvh(ray);
+        if !hit_info.has_hit {
+            break;
+        }
+
+        var hit_material = materials[hit_info.material_id];
+        set_surface_properties(&hit_info, &hit_material);
+        distance += hit_info.distance;
+        if hit_material.transparency < 0.5f {
+            ray.origin = hit_info.point + ray.direction * EPSILON;
+            continue;
+        }
+
+        albedo = hit_material.base_color;
+        normal = hit_info.normal;
+        position = hit_info.point;
+        uv = hit_info.uv;
+        depth = distance;
+        material_id = f32(hit_info.material_id);
+        object_id = f32(hit_info.object_id);
+        break;
+    }
+
+    let weight = 1.0f / f32(renderer_info.current_sample);
+    let albedo_depth = mix(textureLoad(aov_albedo_depth, tex_coords), vec4<f32>(albedo, depth), weight);
+    var normal_material_id = textureLoad(aov_normal_material_id, tex_coords);
+    var position_object_id = textureLoad(aov_position_object_id, tex_coords);
+    let uv_accumulation = mix(textureLoad(aov_uv, tex_coords).xy, uv, weight);
+    if renderer_info.current_sample == 1u || normal_material_id.w < 0.0f {
+        normal_material_id.w = material_id;
+        position_object_id.w = object_id;
+    }
+    normal_material_id = vec4<f32>(mix(normal_material_id.xyz, normal, weight), normal_material_id.w);
+    position_object_id = vec4<f32>(mix(position_object_id.xyz, position, weight), position_object_id.w);
+
+    textureStore(aov_albedo_depth, tex_coords, albedo_depth);
+    textureStore(aov_normal_material_id, tex_coords, normal_material_id);
+    textureStore(aov_position_object_id, tex_coords, position_object_id);
+    textureStore(aov_uv, tex_coords, vec4<f32>(uv_accumulation, 0.0f, 0.0f));
+}
+
+// Emitters and the sun are sampled directly at every vertex and also found by the BSDF samples,
+// both estimates are combined with the power heuristic
 fn trace(ray: ptr<function, Ray>, rng_seed: ptr<function, u32>, max_ray_depth: u32) -> vec3<f32> {
     var ray_color = vec3<f32>(1.0f);
     var incoming_light = vec3<f32>(0.0f);
 
-    var prev_hit_point = ray.origin;
-    var transmitted_distance = 0.0f;
+    // Vertex the ray left from after sampling lights, with the density of the BSDF sample that
+    // chose the ray direction. A density of zero means emitters found by the ray aren't weighted.
+    var prev_point = vec3<f32>(0.0f);
+    var prev_normal = vec3<f32>(0.0f);
+    var prev_bsdf_pdf = 0.0f;
+    var current_medium = scene_info.global_medium_id;
+    var secondary_terminated = false;
 
     var curr_ray_depth: u32 = 0u;
     while curr_ray_depth < max_ray_depth {
         var hit_info = traverse_bvh(*ray);
 
+        if current_medium != NO_MEDIUM {
+            var t_max = 1e30f;
+            if hit_info.has_hit {
+                t_max = hit_info.distance;
+            }
+            let interaction = sample_medium(spectral_medium(media[current_medium]), *ray, t_max, &ray_color, rng_seed);
+            if interaction.kind == MEDIUM_ABSORBED {
+                break;
+            }
+            if interaction.kind == MEDIUM_SCATTERED {
+                curr_ray_depth += 1u;
+                (*ray).origin = interaction.point;
+                (*ray).direction = sample_hg((*ray).direction, media[current_medium].g, rng_seed);
+                prev_bsdf_pdf = 0.0f;
+                continue;
+            }
+        }
+
         if hit_info.has_hit {
             curr_ray_depth += 1u;
 
             var hit_material = materials[hit_info.material_id];
             set_surface_properties(&hit_info, &hit_material);
+            if renderer_info.spectral != 0u {
+                set_spectral_properties(&hit_material);
+
+                // The IOR is only right for the hero wavelength, the others can't follow the
+                // refracted path
+                if is_dispersive(hit_material) && hit_material.transmission > 0.0f && !secondary_terminated {
+                    ray_color = vec3<f32>(ray_color.x * 3.0f, 0.0f, 0.0f);
+                    secondary_terminated = true;
+                }
+            }
 
-            var transmitted_distance = hit_info.distance;
-            if hit_info.front_face {
-                prev_hit_point = hit_info.point;
-            } else {
-                transmitted_distance = distance(hit_info.point, prev_hit_point);
+            // Entering or leaving a closed mesh switches the medium the ray travels through
+            var next_medium = current_medium;
+            if hit_material.medium_id != NO_MEDIUM {
+                if hit_info.front_face {
+                    next_medium = hit_material.medium_id;
+                } else {
+                    next_medium = scene_info.global_medium_id;
+                }
             }
 
             if hit_material.transparency < rand_f32(rng_seed) {
+                current_medium = next_medium;
                 (*ray).origin = hit_info.point + (*ray).direction * EPSILON;
                 continue;
             }
 
-            let alpha = clamp(hit_material.roughness * hit_material.roughness, EPSILON, 1.0f);
-            let sampled_normal = to_world(hit_info.tbn, sample_ggx_vndf(to_local(hit_info.tbn, -(*ray).direction), alpha, alpha, rng_seed));
-
-            var f0 = vec3<f32>(pow(1.0f - hit_material.ior, 2) / pow(1.0f + hit_material.ior, 2));
-            f0 = mix(f0, hit_material.base_color, hit_material.metallic);
-            let fresnel = schlick_fresnel(dot(sampled_normal, -(*ray).direction), f0);
-
-            let specular_dir = normalize(reflect((*ray).direction, sampled_normal));
-            let transmitted_dir = normalize(refract((*ray).direction, sampled_normal, hit_material.ior));
-            let diffuse_dir = normalize(to_world(hit_info.tbn, cosine_sample_hemisphere(rng_seed)));
-
-            let bsdf_type = select_bsdf(hit_material, rng_seed);
-
-            var new_dir: vec3<f32>;
-            if length(fresnel) < rand_f32(rng_seed) && !bsdf_type.specular {
-                ray_color *= hit_material.base_color;
-                if bsdf_type.transmitted {
-                    new_dir = transmitted_dir;
-                    if dot(new_dir, hit_info.normal) > 0.0f {
-                        break;
-                    }
-                    var absorption = vec3<f32>(1.0f);
-                    if !hit_info.front_face {
-                        absorption = vec3<f32>(
-                            exp(-(1.0f - hit_material.base_color.r) * transmitted_distance),
-                            exp(-(1.0f - hit_material.base_color.g) * transmitted_distance),
-                            exp(-(1.0f - hit_material.base_color.b) * transmitted_distance),
-                        );
-                    }
-                    ray_color *= absorption;
-                } else {
-                    new_dir = diffuse_dir;
+            // Emitters are one-sided like in the light BVH
+            if hit_info.front_face && any(hit_material.emission > vec3<f32>(0.0f)) {
+                var weight = 1.0f;
+                if prev_bsdf_pdf > 0.0f {
+                    weight = emitter_weight(prev_point, prev_normal, prev_bsdf_pdf, hit_info, (*ray).direction);
                 }
+                incoming_light += hit_material.emission * ray_color * weight;
+            }
+
+            // Diffuse transmission enters the mesh for a random walk
+            let subsurface = hit_info.front_face && rand_f32(rng_seed) < hit_material.subsurface;
+            if subsurface && !random_walk(&hit_info, hit_material, &ray_color, rng_seed) {
+                break;
+            }
+
+            var shading = ShadingPoint();
+            shading.point = hit_info.point;
+            shading.normal = hit_info.normal;
+            shading.tbn = hit_info.tbn;
+            shading.wo = to_local(hit_info.tbn, -(*ray).direction);
+            shading.bsdf = principled(hit_material, hit_info.front_face);
+            shading.subsurface_exit = subsurface;
+
+            var lobes = LOBE_DIFFUSE | LOBE_REFLECTION;
+            if !subsurface {
+                lobes = principled_lobes(shading.bsdf);
+            }
+
+            // Lights can't be sampled for Dirac delta lobes, those rely on hitting the emitters
+            // by chance instead
+            let dimension = bounce_dimension(curr_ray_depth - 1u);
+            let samples_lights = (lobes & (LOBE_DIFFUSE | LOBE_GLOSSY)) != 0u;
+            if samples_lights {
+                incoming_light += sample_sun(shading, current_medium, sample_2d(dimension + SUN_DIMENSION), rng_seed) * ray_color;
+                incoming_light += sample_emitters(shading, current_medium, sample_1d(dimension + LIGHT_SELECTION_DIMENSION), sample_2d(dimension + LIGHT_POINT_DIMENSION), rng_seed) * ray_color;
+            }
+
+            var sample: BsdfSample;
+            let u_direction = sample_2d(dimension + BSDF_DIRECTION_DIMENSION);
+            if subsurface {
+                // The walk already determined the color
+                sample.wi = cosine_sample_hemisphere(u_direction);
+                sample.weight = vec3<f32>(1.0f);
+                sample.lobes = LOBE_DIFFUSE | LOBE_REFLECTION;
+                sample.pdf = sample.wi.z / PI;
             } else {
-                if bsdf_type.specular {
-                    ray_color *= fresnel;
-                }
+                sample = principled_sample(shading.bsdf, shading.wo, sample_1d(dimension + BSDF_LOBE_DIMENSION), u_direction);
+            }
+            if sample.lobes == 0u {
+                break;
+            }
+            ray_color *= sample.weight;
 
-                new_dir = specular_dir;
-                if dot(new_dir, hit_info.normal) < 0.0f {
-                    break;
-                }
+            prev_bsdf_pdf = 0.0f;
+            if samples_lights && !has_lobe(sample.lobes, LOBE_SPECULAR) {
+                prev_point = shading.point;
+                prev_normal = shading.normal;
+                prev_bsdf_pdf = sample.pdf;
+            }
+
+            if has_lobe(sample.lobes, LOBE_TRANSMISSION) && hit_material.thin_walled == 0u {
+                current_medium = next_medium;
             }
 
             // Russian roulette
             var rr_probability = 1.0f;
             if curr_ray_depth >= 4 {
                 rr_probability = max(ray_color.r, max(ray_color.b, ray_color.g));
-                if rr_probability < rand_f32(rng_seed) {
+                if rr_probability < sample_1d(dimension + RUSSIAN_ROULETTE_DIMENSION) {
                     break;
                 }
             }
             ray_color /= rr_probability;
 
-            incoming_light += hit_material.emission * ray_color;
-
+            let new_dir = normalize(to_world(hit_info.tbn, sample.wi));
             (*ray).origin = hit_info.point + new_dir * EPSILON;
             (*ray).direction = new_dir;
         } else {
-            let sky_color = vec3<f32>(1.0f, 1.0f, 1.0f);
-            let sky_strength = vec3<f32>(1.0f);
+            var sky_light: vec3<f32>;
+            if in_sun((*ray).direction) {
+                var weight = 1.0f;
+                if prev_bsdf_pdf > 0.0f {
+                    weight = power_heuristic(prev_bsdf_pdf, 1.0f / sky.sun_solid_angle);
+                }
+                sky_light = sky.sun_radiance * weight;
+            } else {
+                sky_light = sky_radiance((*ray).direction, false);
+            }
+            incoming_light += spectral(sky_light) * ray_color;
+
+            break;
+        }
+    }
+
+    return incoming_light;
+}
 
-            ray_color *= sky_color;
-            incoming_light += sky_strength * ray_color;
+// Next event estimation towards the sun disk, weighted against BSDF sampling
+fn sample_sun(shading: ShadingPoint, medium_id: u32, u: vec2<f32>, rng_seed: ptr<function, u32>) -> vec3<f32> {
+    if sky.has_sun == 0u {
+        return vec3<f32>(0.0f);
+    }
+
+    let sun_dir = sample_cone(sky.sun_direction, sky.sun_cos_angular_radius, u);
+    let bsdf_value = shading_eval(shading, sun_dir);
+    if all(bsdf_value <= vec3<f32>(0.0f)) {
+        return vec3<f32>(0.0f);
+    }
+
+    var shadow_ray = Ray();
+    shadow_ray.origin = shading.point + sun_dir * EPSILON;
+    shadow_ray.direction = sun_dir;
+    let visibility = shadow_transmittance(shadow_ray, 1e30f, medium_id, rng_seed);
+    if all(visibility <= vec3<f32>(0.0f)) {
+        return vec3<f32>(0.0f);
+    }
+
+    let sun_pdf = 1.0f / sky.sun_solid_angle;
+    let weight = power_heuristic(sun_pdf, shading_pdf(shading, sun_dir));
+    return spectral(sky.sun_radiance) * visibility * bsdf_value * (weight / sun_pdf);
+}
+
+// Next event estimation towards an emissive triangle picked from the light BVH, weighted against
+// BSDF sampling. `u_select` picks the triangle and `u_point` the point on it.
+fn sample_emitters(shading: ShadingPoint, medium_id: u32, u_select: f32, u_point: vec2<f32>, rng_seed: ptr<function, u32>) -> vec3<f32> {
+    if light_bvh_nodes[0].power <= 0.0f {
+        return vec3<f32>(0.0f);
+    }
+
+    // Stochastic traversal, choosing children proportionally to their importance. The sample
+    // value is rescaled at every level so that it makes all the choices.
+    var node = light_bvh_nodes[0];
+    var pmf = 1.0f;
+    var u = u_select;
+    while node.num_tris == 0u {
+        let child_1 = light_bvh_nodes[node.first_tri_or_child];
+        let child_2 = light_bvh_nodes[node.first_tri_or_child + 1u];
+        let importance_1 = light_importance(child_1, shading.point, shading.normal);
+        let importance_2 = light_importance(child_2, shading.point, shading.normal);
+        if importance_1 + importance_2 <= 0.0f {
+            return vec3<f32>(0.0f);
+        }
+
+        let probability_1 = importance_1 / (importance_1 + importance_2);
+        if u < probability_1 {
+            node = child_1;
+            pmf *= probability_1;
+            u /= probability_1;
+        } else {
+            node = child_2;
+            pmf *= 1.0f - probability_1;
+            u = (u - probability_1) / (1.0f - probability_1);
+        }
+        u = min(u, ONE_MINUS_EPSILON);
+    }
 
+    let tri = triangles[node.first_tri_or_child];
+    let r = sqrt(u_point.x);
+    let b_0 = 1.0f - r;
+    let b_1 = u_point.y * r;
+    let b_2 = 1.0f - b_0 - b_1;
+    let point = tri.vertices[0].position * b_0 + tri.vertices[1].position * b_1 + tri.vertices[2].position * b_2;
+
+    let cross_edges = cross(tri.vertices[1].position - tri.vertices[0].position, tri.vertices[2].position - tri.vertices[0].position);
+    let area = length(cross_edges) * 0.5f;
+    let light_normal = normalize(cross_edges);
+
+    let to_light = point - shading.point;
+    let light_distance = length(to_light);
+    let light_dir = to_light / light_distance;
+    let cos_light = dot(light_normal, -light_dir);
+    if cos_light <= 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    let bsdf_value = shading_eval(shading, light_dir);
+    if all(bsdf_value <= vec3<f32>(0.0f)) {
+        return vec3<f32>(0.0f);
+    }
+
+    var shadow_ray = Ray();
+    shadow_ray.origin = shading.point + light_dir * EPSILON;
+    shadow_ray.direction = light_dir;
+    let visibility = shadow_transmittance(shadow_ray, light_distance, medium_id, rng_seed);
+    if all(visibility <= vec3<f32>(0.0f)) {
+        return vec3<f32>(0.0f);
+    }
+
+    let material = materials[tri.material_id];
+    var emission = material.emission;
+    if material.emission_tex_id != 0xFFFFFFFF {
+        let t_0 = vec2<f32>(tri.vertices[0].tex_coord_x, tri.vertices[0].tex_coord_y);
+        let t_1 = vec2<f32>(tri.vertices[1].tex_coord_x, tri.vertices[1].tex_coord_y);
+        let t_2 = vec2<f32>(tri.vertices[2].tex_coord_x, tri.vertices[2].tex_coord_y);
+        let uv = t_0 * b_0 + t_1 * b_1 + t_2 * b_2;
+        emission = pow(sample_texture(material.emission_tex_id, uv).rgb, vec3<f32>(2.2f)) * material.emission_scale;
+    }
+
+    // Density of the point in solid angle
+    let light_pdf = pmf * light_distance * light_distance / (cos_light * area);
+    let weight = power_heuristic(light_pdf, shading_pdf(shading, light_dir));
+    emission = spectral(emission) * visibility;
+    return emission * bsdf_value * (weight / light_pdf);
+}
+
+// Fraction of the light that makes it t_max along the ray, starting in medium_id. Surfaces let it
+// through as often as trace passes them and the media in between attenuate it.
+fn shadow_transmittance(ray: Ray, t_max: f32, medium_id: u32, rng_seed: ptr<function, u32>) -> vec3<f32> {
+    var shadow_ray = ray;
+    var remaining = t_max;
+    var current_medium = medium_id;
+    var transmittance = vec3<f32>(1.0f);
+    for (var i = 0u; i < MAX_SHADOW_SURFACES; i++) {
+        let hit_info = traverse_bvh(shadow_ray);
+        if !hit_info.has_hit || hit_info.distance >= remaining * 0.999f {
+            return transmittance * medium_transmittance(current_medium, shadow_ray, remaining, rng_seed);
+        }
+
+        let material = materials[hit_info.material_id];
+        let opacity = opacity_at(material, hit_info.uv);
+        if opacity >= 1.0f {
+            return vec3<f32>(0.0f);
+        }
+        transmittance *= medium_transmittance(current_medium, shadow_ray, hit_info.distance, rng_seed) * (1.0f - opacity);
+
+        if material.medium_id != NO_MEDIUM {
+            if hit_info.front_face {
+                current_medium = material.medium_id;
+            } else {
+                current_medium = scene_info.global_medium_id;
+            }
+        }
+        shadow_ray.origin = hit_info.point + shadow_ray.direction * EPSILON;
+        remaining -= hit_info.distance;
+    }
+    return vec3<f32>(0.0f);
+}
+
+// MIS weight of an emitter found by the BSDF sample leaving prev_point, against picking the same
+// point through the light BVH
+fn emitter_weight(prev_point: vec3<f32>, prev_normal: vec3<f32>, prev_bsdf_pdf: f32, hit_info: HitInfo, direction: vec3<f32>) -> f32 {
+    if hit_info.light_id == NO_LIGHT {
+        return 1.0f;
+    }
+    let tri = triangles[light_bvh_nodes[hit_info.light_id].first_tri_or_child];
+    let cross_edges = cross(tri.vertices[1].position - tri.vertices[0].position, tri.vertices[2].position - tri.vertices[0].position);
+    // Only front faces emit, so the cosine is positive
+    let cos_light = dot(normalize(cross_edges), -direction);
+
+    let area = length(cross_edges) * 0.5f;
+    let to_hit = hit_info.point - prev_point;
+    let light_pdf = light_pmf(prev_point, prev_normal, hit_info.light_id) * dot(to_hit, to_hit) / (cos_light * area);
+    return power_heuristic(prev_bsdf_pdf, light_pdf);
+}
+
+// Probability of the light BVH traversal in sample_emitters picking the leaf light_id, found by
+// walking up from the leaf
+fn light_pmf(point: vec3<f32>, normal: vec3<f32>, light_id: u32) -> f32 {
+    var index = light_id;
+    var pmf = 1.0f;
+    while index != 0u {
+        let child_1 = light_bvh_nodes[light_bvh_nodes[index].parent].first_tri_or_child;
+        let importance_1 = light_importance(light_bvh_nodes[child_1], point, normal);
+        let importance_2 = light_importance(light_bvh_nodes[child_1 + 1u], point, normal);
+        if importance_1 + importance_2 <= 0.0f {
+            return 0.0f;
+        }
+
+        let probability_1 = importance_1 / (importance_1 + importance_2);
+        pmf *= select(1.0f - probability_1, probability_1, index == child_1);
+        index = light_bvh_nodes[index].parent;
+    }
+    return pmf;
+}
+
+// Power heuristic with an exponent of two, the weight of a sample taken with density pdf_a that
+// could also have been taken with density pdf_b
+fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let a = pdf_a * pdf_a;
+    let b = pdf_b * pdf_b;
+    if a + b <= 0.0f {
+        return 0.0f;
+    }
+    return a / (a + b);
+}
+
+// Delta tracking with spectral weights, samples a collision inside the medium before t_max and
+// updates ray_color with the weight of the chosen event
+// https://cs.dartmouth.edu/~wjarosz/publications/kutz17spectral.pdf
+fn sample_medium(medium: Medium, ray: Ray, t_max: f32, ray_color: ptr<function, vec3<f32>>, rng_seed: ptr<function, u32>) -> MediumInteraction {
+    var interaction = MediumInteraction();
+    interaction.kind = MEDIUM_NONE;
+
+    let majorant = medium_majorant(medium);
+    let range = medium_ray_range(medium, ray, t_max);
+    if majorant <= 0.0f || range.x >= range.y {
+        return interaction;
+    }
+
+    var t = range.x;
+    loop {
+        t -= log(1.0f - rand_f32(rng_seed) * 0.999999f) / majorant;
+        if t >= range.y {
+            return interaction;
+        }
+
+        let point = ray.origin + ray.direction * t;
+        let density = medium_density(medium, point);
+        let sigma_a = medium.sigma_a * density;
+        let sigma_s = medium.sigma_s * density;
+        let sigma_n = vec3<f32>(majorant) - sigma_a - sigma_s;
+
+        // Event probabilities follow the current throughput so chromatic media don't produce fireflies
+        let absorb_weight = dot(sigma_a, *ray_color);
+        let scatter_weight = dot(sigma_s, *ray_color);
+        let null_weight = dot(max(sigma_n, vec3<f32>(0.0f)), *ray_color);
+        let total_weight = absorb_weight + scatter_weight + null_weight;
+        if total_weight <= 0.0f {
+            interaction.kind = MEDIUM_ABSORBED;
+            return interaction;
+        }
+        let absorb_probability = absorb_weight / total_weight;
+        let scatter_probability = scatter_weight / total_weight;
+        let null_probability = null_weight / total_weight;
+
+        let xi = rand_f32(rng_seed);
+        if xi < absorb_probability {
+            interaction.kind = MEDIUM_ABSORBED;
+            return interaction;
+        } else if xi < absorb_probability + scatter_probability {
+            *ray_color *= sigma_s / (majorant * scatter_probability);
+            interaction.kind = MEDIUM_SCATTERED;
+            interaction.point = point;
+            return interaction;
+        } else if null_probability > 0.0f {
+            *ray_color *= sigma_n / (majorant * null_probability);
+        }
+    }
+    return interaction;
+}
+
+// Transmittance up to t_max, analytic for homogeneous media and ratio tracking for heterogeneous ones
+fn medium_transmittance(medium_id: u32, ray: Ray, t_max: f32, rng_seed: ptr<function, u32>) -> vec3<f32> {
+    if medium_id == NO_MEDIUM {
+        return vec3<f32>(1.0f);
+    }
+
+    let medium = spectral_medium(media[medium_id]);
+    let sigma_t = medium.sigma_a + medium.sigma_s;
+    if medium.grid_offset == NO_MEDIUM {
+        return exp(-sigma_t * t_max);
+    }
+
+    var transmittance = vec3<f32>(1.0f);
+    let majorant = medium_majorant(medium);
+    let range = medium_ray_range(medium, ray, t_max);
+    if majorant <= 0.0f || range.x >= range.y {
+        return transmittance;
+    }
+
+    var t = range.x;
+    loop {
+        t -= log(1.0f - rand_f32(rng_seed) * 0.999999f) / majorant;
+        if t >= range.y {
             break;
         }
+
+        let density = medium_density(medium, ray.origin + ray.direction * t);
+        transmittance *= vec3<f32>(1.0f) - sigma_t * (density / majorant);
+    }
+    return transmittance;
+}
+
+fn medium_majorant(medium: Medium) -> f32 {
+    let sigma_t = medium.sigma_a + medium.sigma_s;
+    return max(sigma_t.r, max(sigma_t.g, sigma_t.b)) * medium.max_density;
+}
+
+// Parametric range of the ray inside the medium, clipped to the density grid
+fn medium_ray_range(medium: Medium, ray: Ray, t_max: f32) -> vec2<f32> {
+    if medium.grid_offset == NO_MEDIUM {
+        return vec2<f32>(0.0f, t_max);
+    }
+
+    let t_min_bounds = (medium.grid_min - ray.origin) / ray.direction;
+    let t_max_bounds = (medium.grid_max - ray.origin) / ray.direction;
+    let t_1 = min(t_min_bounds, t_max_bounds);
+    let t_2 = max(t_min_bounds, t_max_bounds);
+    let t_near = max(max(max(t_1.x, t_1.y), t_1.z), 0.0f);
+    let t_far = min(min(min(t_2.x, t_2.y), t_2.z), t_max);
+    return vec2<f32>(t_near, t_far);
+}
+
+// Trilinearly interpolated density, zero outside of the grid
+fn medium_density(medium: Medium, point: vec3<f32>) -> f32 {
+    if medium.grid_offset == NO_MEDIUM {
+        return 1.0f;
+    }
+
+    let local = (point - medium.grid_min) / (medium.grid_max - medium.grid_min);
+    if any(local < vec3<f32>(0.0f)) || any(local > vec3<f32>(1.0f)) {
+        return 0.0f;
+    }
+
+    let resolution = vec3<i32>(medium.grid_resolution);
+    let voxel = local * vec3<f32>(medium.grid_resolution) - 0.5f;
+    let base = vec3<i32>(floor(voxel));
+    let frac = voxel - floor(voxel);
+
+    var density = 0.0f;
+    for (var corner = 0u; corner < 8u; corner++) {
+        let offset = vec3<i32>(i32(corner & 1u), i32((corner >> 1u) & 1u), i32((corner >> 2u) & 1u));
+        let weights = select(vec3<f32>(1.0f) - frac, frac, offset == vec3<i32>(1));
+        let coords = vec3<u32>(clamp(base + offset, vec3<i32>(0), resolution - 1));
+        let index = medium.grid_offset + coords.x + medium.grid_resolution.x * (coords.y + medium.grid_resolution.y * coords.z);
+        density += weights.x * weights.y * weights.z * density_grid_data[index];
+    }
+    return density;
+}
+
+// Random walk subsurface scattering through the medium described by the material, starting at a
+// front face hit. On success hit_info is moved to where the walk left the mesh, with the normal
+// facing outwards
+fn random_walk(hit_info: ptr<function, HitInfo>, material: Material, ray_color: ptr<function, vec3<f32>>, rng_seed: ptr<function, u32>) -> bool {
+    let medium = subsurface_medium(material);
+
+    var walk_ray = Ray();
+    walk_ray.origin = hit_info.point - hit_info.normal * EPSILON;
+    walk_ray.direction = -normalize(to_world(hit_info.tbn, cosine_sample_hemisphere(vec2<f32>(rand_f32(rng_seed), rand_f32(rng_seed)))));
+
+    for (var i = 0u; i < MAX_WALK_STEPS; i++) {
+        let walk_hit_info = traverse_bvh(walk_ray);
+        // Open meshes let the walk escape into nothing
+        if !walk_hit_info.has_hit {
+            return false;
+        }
+
+        let interaction = sample_medium(medium, walk_ray, walk_hit_info.distance, ray_color, rng_seed);
+        if interaction.kind == MEDIUM_ABSORBED {
+            return false;
+        }
+        if interaction.kind == MEDIUM_SCATTERED {
+            walk_ray.origin = interaction.point;
+            walk_ray.direction = sample_hg(walk_ray.direction, medium.g, rng_seed);
+            continue;
+        }
+
+        (*hit_info).point = walk_hit_info.point;
+        (*hit_info).normal = -walk_hit_info.normal;
+        var tangent: vec3<f32>;
+        var bitangent: vec3<f32>;
+        build_orthonormal_basis((*hit_info).normal, &tangent, &bitangent);
+        (*hit_info).tbn = mat3x3<f32>(tangent, bitangent, (*hit_info).normal);
+        return true;
     }
 
-    if curr_ray_depth == 0u {
-        return incoming_light;
+    return false;
+}
+
+// Homogeneous medium for the random walk, the single scattering albedo is inverted from the
+// multiple scattering albedo of the material
+// https://graphics.pixar.com/library/PathTracedSubsurface/paper.pdf
+fn subsurface_medium(material: Material) -> Medium {
+    let albedo = clamp(material.subsurface_albedo, vec3<f32>(0.0f), vec3<f32>(0.999f));
+    let inversion = 4.09712f + 4.20863f * albedo - sqrt(9.59217f + 41.6808f * albedo + 17.7126f * albedo * albedo);
+    let single_scatter_albedo = 1.0f - inversion * inversion;
+    let sigma_t = 1.0f / max(material.subsurface_mfp, vec3<f32>(EPSILON));
+
+    var medium = Medium();
+    medium.sigma_s = sigma_t * single_scatter_albedo;
+    medium.sigma_a = sigma_t - medium.sigma_s;
+    medium.g = material.subsurface_anisotropy;
+    medium.max_density = 1.0f;
+    medium.grid_offset = NO_MEDIUM;
+    return medium;
+}
+
+// Samples the Henyey-Greenstein phase function around the direction of travel
+fn sample_hg(direction: vec3<f32>, g: f32, rng_seed: ptr<function, u32>) -> vec3<f32> {
+    let u_1 = rand_f32(rng_seed);
+    let u_2 = rand_f32(rng_seed);
+
+    var cos_theta: f32;
+    if abs(g) < 1e-3f {
+        cos_theta = 1.0f - 2.0f * u_1;
     } else {
-        return incoming_light / f32(curr_ray_depth);
+        let sqr_term = (1.0f - g * g) / (1.0f - g + 2.0f * g * u_1);
+        cos_theta = clamp((1.0f + g * g - sqr_term * sqr_term) / (2.0f * g), -1.0f, 1.0f);
+    }
+    let sin_theta = sqrt(max(0.0f, 1.0f - cos_theta * cos_theta));
+    let phi = TWO_PI * u_2;
+
+    var up = vec3<f32>(1.0f, 0.0f, 0.0f);
+    if abs(direction.y) < 0.999f {
+        up = vec3<f32>(0.0f, 1.0f, 0.0f);
+    }
+    let tangent = normalize(cross(up, direction));
+    let bitangent = cross(direction, tangent);
+
+    return normalize(tangent * cos(phi) * sin_theta + bitangent * sin(phi) * sin_theta + direction * cos_theta);
+}
+
+// https://fpsunflower.github.io/ckulla/data/many-lights-hpg2018.pdf
+fn light_importance(node: LightNode, point: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let center = (node.bounds_min + node.bounds_max) * 0.5f;
+    let radius = length(node.bounds_max - node.bounds_min) * 0.5f;
+    let to_node = center - point;
+    let distance_squared = dot(to_node, to_node);
+    let node_distance = sqrt(distance_squared);
+
+    let theta_u = select(asin(min(radius / node_distance, 1.0f)), PI, node_distance <= radius);
+
+    let w = to_node / max(node_distance, 1e-6f);
+    let theta = acos(clamp(dot(node.axis, -w), -1.0f, 1.0f));
+    let theta_prime = max(0.0f, theta - node.theta_o - theta_u);
+    if theta_prime >= node.theta_e {
+        return 0.0f;
+    }
+
+    let theta_i = acos(clamp(dot(normal, w), -1.0f, 1.0f));
+    let theta_i_prime = max(0.0f, theta_i - theta_u);
+    if theta_i_prime >= PI_OVER_2 {
+        return 0.0f;
+    }
+
+    return node.power * cos(theta_prime) * cos(theta_i_prime) / max(distance_squared, radius * radius);
+}
+
+fn sky_radiance(direction: vec3<f32>, include_sun: bool) -> vec3<f32> {
+    if sky.model == 0u {
+        return sky.color * sky.strength;
+    }
+
+    if include_sun && in_sun(direction) {
+        return sky.sun_radiance;
+    }
+
+    // Below the horizon we see the ground lit by the sky at the zenith
+    if direction.y <= 0.0f {
+        return sky.ground_albedo * preetham(vec3<f32>(0.0f, 1.0f, 0.0f)) * sky.strength;
+    }
+
+    return preetham(direction) * sky.strength;
+}
+
+// Whether the direction points into the visible sun disk
+fn in_sun(direction: vec3<f32>) -> bool {
+    return sky.model != 0u && sky.has_sun != 0u && dot(direction, sky.sun_direction) >= sky.sun_cos_angular_radius && direction.y > 0.0f;
+}
+
+// https://courses.cs.duke.edu/fall01/cps124/resources/p91-preetham.pdf
+fn preetham(direction: vec3<f32>) -> vec3<f32> {
+    let cos_theta = max(direction.y, 0.001f);
+    let gamma = acos(clamp(dot(direction, sky.sun_direction), -1.0f, 1.0f));
+    let cos_gamma = cos(gamma);
+
+    let a = sky.perez[0].xyz;
+    let b = sky.perez[1].xyz;
+    let c = sky.perez[2].xyz;
+    let d = sky.perez[3].xyz;
+    let e = sky.perez[4].xyz;
+    let perez = (1.0f + a * exp(b / cos_theta)) * (1.0f + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+
+    return yxy_to_linear_srgb(sky.normalized_zenith * perez);
+}
+
+fn yxy_to_linear_srgb(yxy: vec3<f32>) -> vec3<f32> {
+    let luminance = yxy.x;
+    let x = yxy.y;
+    let y = max(yxy.z, 0.0001f);
+
+    let cie_x = x * (luminance / y);
+    let cie_z = (1.0f - x - y) * (luminance / y);
+
+    return max(vec3<f32>(
+        3.2404542f * cie_x - 1.5371385f * luminance - 0.4985314f * cie_z,
+        -0.9692660f * cie_x + 1.8760108f * luminance + 0.0415560f * cie_z,
+        0.0556434f * cie_x - 0.2040259f * luminance + 1.0572252f * cie_z,
+    ), vec3<f32>(0.0f));
+}
+
+// Principled BSDF following Blender's Principled BSDF: a diffuse and sheen base under a tinted
+// dielectric specular layer, blended with glass and metal, all under a clearcoat. All directions
+// are in the local shading frame with wo on the +Z side, eta is the index of refraction on the
+// other side of the surface divided by the one on the side of wo.
+// https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+fn principled(material: Material, front_face: bool) -> Principled {
+    var p = Principled();
+    p.base_color = material.base_color;
+    p.roughness = material.roughness;
+    p.alpha = material.roughness * material.roughness;
+    let aspect = sqrt(1.0f - 0.9f * clamp(material.anisotropic, 0.0f, 1.0f));
+    p.alpha_x = p.alpha / aspect;
+    p.alpha_y = p.alpha * aspect;
+
+    let luminance = dot(material.base_color, vec3<f32>(0.3f, 0.6f, 0.1f));
+    var tint = vec3<f32>(1.0f);
+    if luminance > 0.0f {
+        tint = material.base_color / luminance;
+    }
+    p.sheen_color = mix(vec3<f32>(1.0f), tint, material.sheen_tint) * material.sheen;
+
+    let f0 = (material.ior - 1.0f) / (material.ior + 1.0f);
+    p.specular_color = min(material.specular_tint * (f0 * f0 * 2.0f * material.specular), vec3<f32>(1.0f));
+
+    p.eta = select(1.0f / material.ior, material.ior, front_face);
+    // Tinted at both interfaces, so a closed mesh ends up with the base color
+    p.transmission_tint = sqrt(material.base_color);
+    p.transmission = material.transmission;
+    p.thin_walled = material.thin_walled != 0u;
+    if p.thin_walled {
+        p.transmission_tint = material.base_color;
     }
+    p.metallic = material.metallic;
+    p.clearcoat = material.clearcoat;
+    p.clearcoat_alpha = mix(0.1f, 0.001f, material.clearcoat_gloss);
+    p.thin_film.thickness = material.thin_film_thickness;
+    p.thin_film.eta = material.thin_film_ior;
+    p.transmission_thin_film = p.thin_film;
+    if !front_face {
+        p.transmission_thin_film.eta /= material.ior;
+    }
+    return p;
+}
+
+fn principled_lobes(p: Principled) -> u32 {
+    var lobes = 0u;
+    if p.metallic < 1.0f && p.transmission < 1.0f {
+        lobes |= LOBE_DIFFUSE | glossy_or_specular(max(p.alpha_x, p.alpha_y)) | LOBE_REFLECTION;
+    }
+    if p.metallic < 1.0f && p.transmission > 0.0f {
+        if p.thin_walled {
+            lobes |= LOBE_SPECULAR;
+        } else {
+            lobes |= glossy_or_specular(p.alpha);
+        }
+        lobes |= LOBE_REFLECTION | LOBE_TRANSMISSION;
+    }
+    if p.metallic > 0.0f {
+        lobes |= glossy_or_specular(max(p.alpha_x, p.alpha_y)) | LOBE_REFLECTION;
+    }
+    if p.clearcoat > 0.0f {
+        lobes |= glossy_or_specular(p.clearcoat_alpha) | LOBE_REFLECTION;
+    }
+    return lobes;
+}
+
+// BSDF value for the pair of directions, specular lobes evaluate to zero
+fn principled_eval(p: Principled, wo: vec3<f32>, wi: vec3<f32>) -> vec3<f32> {
+    if wo.z <= 0.0f {
+        return vec3<f32>(0.0f);
+    }
+
+    let dielectric = conductor_eval(wo, wi, p.specular_color, p.alpha_x, p.alpha_y, p.thin_film)
+        + disney_diffuse_eval(p, wo, wi) * coat_transmittance(wo, wi, p.specular_color, 1.0f, p.thin_film);
+    var transmission = vec3<f32>(0.0f);
+    if !p.thin_walled {
+        transmission = rough_dielectric_eval(wo, wi, p.eta, p.alpha, p.transmission_tint, p.transmission_thin_film);
+    }
+    let metal = conductor_eval(wo, wi, p.base_color, p.alpha_x, p.alpha_y, p.thin_film);
+    let base = mix(mix(dielectric, transmission, p.transmission), metal, p.metallic);
+
+    let coat = conductor_eval(wo, wi, vec3<f32>(0.04f), p.clearcoat_alpha, p.clearcoat_alpha, ThinFilm());
+    return coat * p.clearcoat + base * coat_transmittance(wo, wi, vec3<f32>(0.04f), p.clearcoat, ThinFilm());
 }
 
-fn select_bsdf(material: Material, rng_seed: ptr<function, u32>) -> BSDFType {
-    var bsdf_type = BSDFType();
+// Solid angle density of sampling wi with principled_sample
+fn principled_pdf(p: Principled, wo: vec3<f32>, wi: vec3<f32>) -> f32 {
+    if wo.z <= 0.0f {
+        return 0.0f;
+    }
+
+    let specular_probability = coat_probability(wo, p.specular_color, 1.0f, p.thin_film);
+    var diffuse_pdf = 0.0f;
+    if wi.z > 0.0f {
+        diffuse_pdf = wi.z / PI;
+    }
+    let dielectric = specular_probability * conductor_pdf(wo, wi, p.alpha_x, p.alpha_y)
+        + (1.0f - specular_probability) * diffuse_pdf;
+    var transmission = 0.0f;
+    if !p.thin_walled {
+        transmission = rough_dielectric_pdf(wo, wi, p.eta, p.alpha, p.transmission_thin_film);
+    }
+    let metal = conductor_pdf(wo, wi, p.alpha_x, p.alpha_y);
+    let base = mix(mix(dielectric, transmission, p.transmission), metal, p.metallic);
 
-    let specular_chance = material.metallic;
-    let transmission_chance = material.transmission;
-    let diffuse_chance = 1.0f - specular_chance - transmission_chance;
+    let clearcoat_probability = coat_probability(wo, vec3<f32>(0.04f), p.clearcoat, ThinFilm());
+    return clearcoat_probability * conductor_pdf(wo, wi, p.clearcoat_alpha, p.clearcoat_alpha)
+        + (1.0f - clearcoat_probability) * base;
+}
 
-    let r = rand_f32(rng_seed);
-    if specular_chance > r {
-        bsdf_type.specular = true;
-    } else if specular_chance + transmission_chance > r {
-        bsdf_type.transmitted = true;
+// Samples a direction, `uc` picks the lobe and `u` the direction within it. Every lobe choice
+// rescales `uc` so that the next one gets a uniform value again.
+fn principled_sample(p: Principled, wo: vec3<f32>, uc: f32, u: vec2<f32>) -> BsdfSample {
+    let clearcoat_probability = coat_probability(wo, vec3<f32>(0.04f), p.clearcoat, ThinFilm());
+    var sample: BsdfSample;
+    if uc < clearcoat_probability {
+        sample = conductor_sample(wo, vec3<f32>(0.04f), p.clearcoat_alpha, p.clearcoat_alpha, ThinFilm(), u);
+        if has_lobe(sample.lobes, LOBE_SPECULAR) {
+            sample.weight *= p.clearcoat / clearcoat_probability;
+            return sample;
+        }
     } else {
-        bsdf_type.diffuse = true;
+        sample = principled_sample_base(p, wo, (uc - clearcoat_probability) / (1.0f - clearcoat_probability), u);
+        if has_lobe(sample.lobes, LOBE_SPECULAR) {
+            sample.weight *= coat_transmittance(wo, sample.wi, vec3<f32>(0.04f), p.clearcoat, ThinFilm()) / (1.0f - clearcoat_probability);
+            return sample;
+        }
+    }
+    if sample.lobes == 0u {
+        return sample;
     }
 
-    return bsdf_type;
+    // Non specular directions are weighted with the full BSDF, so that lobes sharing
+    // directions are accounted for correctly
+    let pdf = principled_pdf(p, wo, sample.wi);
+    if pdf <= 0.0f {
+        sample.lobes = 0u;
+        return sample;
+    }
+    sample.weight = principled_eval(p, wo, sample.wi) * (abs(sample.wi.z) / pdf);
+    sample.pdf = pdf;
+    return sample;
 }
 
-// Helper function to set actual material properties and other parameters of the hit surface
-fn set_surface_properties(hit_info: ptr<function, HitInfo>, hit_material: ptr<function, Material>) {
-    if hit_info.front_face {
-        (*hit_material).ior = 1.0f / (*hit_material).ior;
+// Samples the layers below the clearcoat, only the weights of specular samples are final
+fn principled_sample_base(p: Principled, wo: vec3<f32>, uc: f32, u: vec2<f32>) -> BsdfSample {
+    var u_lobe = uc;
+    if u_lobe < p.metallic {
+        return conductor_sample(wo, p.base_color, p.alpha_x, p.alpha_y, p.thin_film, u);
+    }
+    u_lobe = (u_lobe - p.metallic) / (1.0f - p.metallic);
+    if u_lobe < p.transmission {
+        u_lobe /= p.transmission;
+        if p.thin_walled {
+            return thin_dielectric_sample(wo, p.eta, p.transmission_tint, p.thin_film, u_lobe);
+        }
+        return rough_dielectric_sample(wo, p.eta, p.alpha, p.transmission_tint, p.transmission_thin_film, u_lobe, u);
+    }
+    u_lobe = (u_lobe - p.transmission) / (1.0f - p.transmission);
+
+    let specular_probability = coat_probability(wo, p.specular_color, 1.0f, p.thin_film);
+    if u_lobe < specular_probability {
+        var sample = conductor_sample(wo, p.specular_color, p.alpha_x, p.alpha_y, p.thin_film, u);
+        if has_lobe(sample.lobes, LOBE_SPECULAR) {
+            sample.weight /= specular_probability;
+        }
+        return sample;
     }
 
+    var sample: BsdfSample;
+    sample.wi = cosine_sample_hemisphere(u);
+    sample.lobes = LOBE_DIFFUSE | LOBE_REFLECTION;
+    return sample;
+}
+
+// Burley diffuse with retroreflection at grazing angles plus a sheen lobe for cloth
+fn disney_diffuse_eval(p: Principled, wo: vec3<f32>, wi: vec3<f32>) -> vec3<f32> {
+    if wo.z <= 0.0f || wi.z <= 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    let h = normalize(wo + wi);
+    let cos_d = dot(wi, h);
+    let fd90 = 0.5f + 2.0f * p.roughness * cos_d * cos_d;
+    let fl = pow(1.0f - wi.z, 5.0f);
+    let fv = pow(1.0f - wo.z, 5.0f);
+    let retro = (1.0f + (fd90 - 1.0f) * fl) * (1.0f + (fd90 - 1.0f) * fv);
+    return p.base_color * (retro / PI) + p.sheen_color * pow(1.0f - clamp(cos_d, 0.0f, 1.0f), 5.0f);
+}
+
+// Chance of picking a dielectric coat with Schlick Fresnel over the layers below it
+fn coat_probability(wo: vec3<f32>, f0: vec3<f32>, weight: f32, film: ThinFilm) -> f32 {
+    return weight * average(specular_fresnel(wo.z, f0, film));
+}
+
+// Light reaching the layers below a coat has to pass through it twice
+fn coat_transmittance(wo: vec3<f32>, wi: vec3<f32>, f0: vec3<f32>, weight: f32, film: ThinFilm) -> vec3<f32> {
+    return (1.0f - specular_fresnel(wo.z, f0, film) * weight) * (1.0f - specular_fresnel(abs(wi.z), f0, film) * weight);
+}
+
+// Anisotropic GGX reflection with Schlick Fresnel tinted by f0
+fn conductor_eval(wo: vec3<f32>, wi: vec3<f32>, f0: vec3<f32>, alpha_x: f32, alpha_y: f32, film: ThinFilm) -> vec3<f32> {
+    if max(alpha_x, alpha_y) < SPECULAR_ALPHA || wo.z <= 0.0f || wi.z <= 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    let h = normalize(wo + wi);
+    return specular_fresnel(dot(wo, h), f0, film)
+        * (ggx_d(h, alpha_x, alpha_y) * ggx_g1(wo, alpha_x, alpha_y) * ggx_g1(wi, alpha_x, alpha_y) / (4.0f * wo.z * wi.z));
+}
+
+fn conductor_pdf(wo: vec3<f32>, wi: vec3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+    if max(alpha_x, alpha_y) < SPECULAR_ALPHA || wo.z <= 0.0f || wi.z <= 0.0f {
+        return 0.0f;
+    }
+    let h = normalize(wo + wi);
+    return ggx_vndf_pdf(wo, h, alpha_x, alpha_y) / (4.0f * dot(wo, h));
+}
+
+fn conductor_sample(wo: vec3<f32>, f0: vec3<f32>, alpha_x: f32, alpha_y: f32, film: ThinFilm, u: vec2<f32>) -> BsdfSample {
+    var sample: BsdfSample;
+    if max(alpha_x, alpha_y) < SPECULAR_ALPHA {
+        sample.wi = vec3<f32>(-wo.x, -wo.y, wo.z);
+        sample.weight = specular_fresnel(wo.z, f0, film);
+        sample.lobes = LOBE_SPECULAR | LOBE_REFLECTION;
+        return sample;
+    }
+
+    let h = sample_ggx_vndf(wo, alpha_x, alpha_y, u);
+    sample.wi = reflect(-wo, h);
+    sample.lobes = LOBE_GLOSSY | LOBE_REFLECTION;
+    return sample;
+}
+
+// Rough glass with a GGX distribution
+// https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
+fn rough_dielectric_eval(wo: vec3<f32>, wi: vec3<f32>, eta: f32, alpha: f32, tint: vec3<f32>, film: ThinFilm) -> vec3<f32> {
+    if alpha < SPECULAR_ALPHA || wo.z <= 0.0f || wi.z == 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    let h = dielectric_half_vector(wo, wi, eta);
+    if all(h == vec3<f32>(0.0f)) {
+        return vec3<f32>(0.0f);
+    }
+
+    let fresnel = dielectric_fresnel(dot(wo, h), eta, film);
+    let d_g = ggx_d(h, alpha, alpha) * ggx_g1(wo, alpha, alpha) * ggx_g1(wi, alpha, alpha);
+    if wi.z > 0.0f {
+        return fresnel * (d_g / (4.0f * wo.z * wi.z));
+    }
+
+    let denom = dot(wi, h) + dot(wo, h) / eta;
+    let transmitted = d_g * abs(dot(wi, h) * dot(wo, h) / (denom * denom * wi.z * wo.z));
+    // Radiance is compressed into the smaller solid angle on the denser side
+    return tint * (1.0f - fresnel) * (transmitted / (eta * eta));
+}
+
+fn rough_dielectric_pdf(wo: vec3<f32>, wi: vec3<f32>, eta: f32, alpha: f32, film: ThinFilm) -> f32 {
+    if alpha < SPECULAR_ALPHA || wo.z <= 0.0f || wi.z == 0.0f {
+        return 0.0f;
+    }
+    let h = dielectric_half_vector(wo, wi, eta);
+    if all(h == vec3<f32>(0.0f)) {
+        return 0.0f;
+    }
+
+    let fresnel = average(dielectric_fresnel(dot(wo, h), eta, film));
+    if wi.z > 0.0f {
+        return fresnel * ggx_vndf_pdf(wo, h, alpha, alpha) / (4.0f * dot(wo, h));
+    }
+
+    let denom = dot(wi, h) + dot(wo, h) / eta;
+    let dh_dwi = abs(dot(wi, h)) / (denom * denom);
+    return (1.0f - fresnel) * ggx_vndf_pdf(wo, h, alpha, alpha) * dh_dwi;
+}
+
+fn rough_dielectric_sample(wo: vec3<f32>, eta: f32, alpha: f32, tint: vec3<f32>, film: ThinFilm, uc: f32, u: vec2<f32>) -> BsdfSample {
+    var sample: BsdfSample;
+    if alpha < SPECULAR_ALPHA {
+        let fresnel = dielectric_fresnel(wo.z, eta, film);
+        let reflect_probability = average(fresnel);
+        if uc < reflect_probability {
+            sample.wi = vec3<f32>(-wo.x, -wo.y, wo.z);
+            sample.weight = fresnel / reflect_probability;
+            sample.lobes = LOBE_SPECULAR | LOBE_REFLECTION;
+            return sample;
+        }
+        sample.wi = refract(-wo, vec3<f32>(0.0f, 0.0f, 1.0f), 1.0f / eta);
+        sample.weight = tint * (1.0f - fresnel) / ((1.0f - reflect_probability) * eta * eta);
+        sample.lobes = LOBE_SPECULAR | LOBE_TRANSMISSION;
+        if all(sample.wi == vec3<f32>(0.0f)) {
+            sample.lobes = 0u;
+        }
+        return sample;
+    }
+
+    let h = sample_ggx_vndf(wo, alpha, alpha, u);
+    if uc < average(dielectric_fresnel(dot(wo, h), eta, film)) {
+        sample.wi = reflect(-wo, h);
+        sample.lobes = LOBE_GLOSSY | LOBE_REFLECTION;
+        return sample;
+    }
+    sample.wi = refract(-wo, h, 1.0f / eta);
+    sample.lobes = LOBE_GLOSSY | LOBE_TRANSMISSION;
+    if all(sample.wi == vec3<f32>(0.0f)) {
+        sample.lobes = 0u;
+    }
+    return sample;
+}
+
+// Generalized half vector facing the same side as the normal, zero for back facing microfacets
+fn dielectric_half_vector(wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec3<f32> {
+    var h = wo + wi * select(eta, 1.0f, wi.z > 0.0f);
+    if length(h) == 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    h = normalize(h);
+    if h.z < 0.0f {
+        h = -h;
+    }
+    if dot(h, wi) * wi.z < 0.0f || dot(h, wo) * wo.z < 0.0f {
+        return vec3<f32>(0.0f);
+    }
+    return h;
+}
+
+// Infinitely thin glass sheet, light passes straight through after bouncing around between both
+// interfaces
+fn thin_dielectric_sample(wo: vec3<f32>, eta: f32, tint: vec3<f32>, film: ThinFilm, uc: f32) -> BsdfSample {
+    var reflectance = dielectric_fresnel(wo.z, eta, film);
+    let transmittance = 1.0f - reflectance;
+    reflectance = select(reflectance + transmittance * transmittance * reflectance / (1.0f - reflectance * reflectance), reflectance, reflectance >= vec3<f32>(1.0f));
+
+    let reflect_probability = average(reflectance);
+    var sample: BsdfSample;
+    if uc < reflect_probability {
+        sample.wi = vec3<f32>(-wo.x, -wo.y, wo.z);
+        sample.weight = reflectance / reflect_probability;
+        sample.lobes = LOBE_SPECULAR | LOBE_REFLECTION;
+        return sample;
+    }
+    sample.wi = -wo;
+    sample.weight = tint * (1.0f - reflectance) / (1.0f - reflect_probability);
+    sample.lobes = LOBE_SPECULAR | LOBE_TRANSMISSION;
+    return sample;
+}
+
+// Schlick Fresnel of a specular layer, modulated by the thin film on top of it
+fn specular_fresnel(cos_theta: f32, f0: vec3<f32>, film: ThinFilm) -> vec3<f32> {
+    if film.thickness <= 0.0f {
+        return schlick_fresnel(cos_theta, f0);
+    }
+
+    let cos_film = thin_film_cos(cos_theta, film);
+    if cos_film < 0.0f {
+        return vec3<f32>(1.0f);
+    }
+    // The reflection at the bottom of the film flips its phase if the surface is denser than the
+    // film, which is always the case for metals
+    let sqrt_f0 = sqrt(clamp(f0, vec3<f32>(0.0f), vec3<f32>(0.9999f)));
+    let ior = (1.0f + sqrt_f0) / (1.0f - sqrt_f0);
+    let amplitude = sqrt(schlick_fresnel(cos_film, f0));
+    let base = select(amplitude, -amplitude, ior > vec3<f32>(film.eta));
+    return thin_film_reflectance(cos_theta, cos_film, film, base, base);
+}
+
+// Fresnel reflectance of a dielectric interface, modulated by the thin film on top of it
+fn dielectric_fresnel(cos_i: f32, eta: f32, film: ThinFilm) -> vec3<f32> {
+    if film.thickness <= 0.0f {
+        return vec3<f32>(fresnel_dielectric(cos_i, eta));
+    }
+
+    let cos_film = thin_film_cos(cos_i, film);
+    if cos_film < 0.0f {
+        return vec3<f32>(1.0f);
+    }
+    let sin_2_t = film.eta * film.eta * (1.0f - cos_film * cos_film) / (eta * eta);
+    if sin_2_t >= 1.0f {
+        return vec3<f32>(1.0f);
+    }
+    let cos_t = sqrt(1.0f - sin_2_t);
+    let base_s = (film.eta * cos_film - eta * cos_t) / (film.eta * cos_film + eta * cos_t);
+    let base_p = (eta * cos_film - film.eta * cos_t) / (eta * cos_film + film.eta * cos_t);
+    return thin_film_reflectance(cos_i, cos_film, film, vec3<f32>(base_s), vec3<f32>(base_p));
+}
+
+// Cosine of the refracted direction inside the film, negative on total internal reflection
+fn thin_film_cos(cos_i: f32, film: ThinFilm) -> f32 {
+    let cos_i_clamped = clamp(cos_i, 0.0f, 1.0f);
+    let sin_2_film = (1.0f - cos_i_clamped * cos_i_clamped) / (film.eta * film.eta);
+    if sin_2_film >= 1.0f {
+        return -1.0f;
+    }
+    return sqrt(1.0f - sin_2_film);
+}
+
+// Light reflected at the top and the bottom of the film interferes, base_s and base_p are the
+// amplitude reflection coefficients at the bottom of the film per color channel
+// https://belcour.github.io/blog/research/publication/2017/05/01/brdf-thin-film.html
+fn thin_film_reflectance(cos_i: f32, cos_film: f32, film: ThinFilm, base_s: vec3<f32>, base_p: vec3<f32>) -> vec3<f32> {
+    let cos_i_clamped = clamp(cos_i, 0.0f, 1.0f);
+    let r_s = (cos_i_clamped - film.eta * cos_film) / (cos_i_clamped + film.eta * cos_film);
+    let r_p = (film.eta * cos_i_clamped - cos_film) / (film.eta * cos_i_clamped + cos_film);
+
+    // Phase difference of a round trip through the film
+    let phase = 4.0f * PI * film.eta * film.thickness * cos_film / channel_wavelengths();
+    return 0.5f * (airy(r_s, base_s, phase) + airy(r_p, base_p, phase));
+}
+
+// Reflectance of two interfaces, summed over all bounces between them
+fn airy(r_1: f32, r_2: vec3<f32>, phase: vec3<f32>) -> vec3<f32> {
+    let interference = 2.0f * r_1 * r_2 * cos(phase);
+    return clamp((r_1 * r_1 + r_2 * r_2 + interference) / (1.0f + r_1 * r_1 * r_2 * r_2 + interference), vec3<f32>(0.0f), vec3<f32>(1.0f));
+}
+
+// Wavelengths in nanometers the three color channels stand for
+fn channel_wavelengths() -> vec3<f32> {
+    if renderer_info.spectral != 0u {
+        return wavelengths;
+    }
+    return RGB_WAVELENGTHS;
+}
+
+fn average(v: vec3<f32>) -> f32 {
+    return (v.r + v.g + v.b) / 3.0f;
+}
+
+// BSDF value times the cosine term towards the world space direction wi
+fn shading_eval(shading: ShadingPoint, wi_world: vec3<f32>) -> vec3<f32> {
+    let wi = to_local(shading.tbn, wi_world);
+    if shading.subsurface_exit {
+        return vec3<f32>(max(wi.z, 0.0f) / PI);
+    }
+    return principled_eval(shading.bsdf, shading.wo, wi) * abs(wi.z);
+}
+
+fn shading_pdf(shading: ShadingPoint, wi_world: vec3<f32>) -> f32 {
+    let wi = to_local(shading.tbn, wi_world);
+    if shading.subsurface_exit {
+        return max(wi.z, 0.0f) / PI;
+    }
+    return principled_pdf(shading.bsdf, shading.wo, wi);
+}
+
+fn glossy_or_specular(alpha: f32) -> u32 {
+    if alpha < SPECULAR_ALPHA {
+        return LOBE_SPECULAR;
+    }
+    return LOBE_GLOSSY;
+}
+
+fn has_lobe(lobes: u32, lobe: u32) -> bool {
+    return (lobes & lobe) == lobe;
+}
+
+// Opacity of the material at the uv, thresholded against the alpha cutoff if it has one
+fn opacity_at(material: Material, uv: vec2<f32>) -> f32 {
+    var opacity = material.transparency;
+    if material.transparency_tex_id != 0xFFFFFFFF {
+        opacity = sample_texture(material.transparency_tex_id, uv).a;
+    }
+    if material.alpha_cutoff > 0.0f {
+        opacity = select(0.0f, 1.0f, opacity >= material.alpha_cutoff);
+    }
+    return opacity;
+}
+
+// Helper function to set actual material properties and other parameters of the hit surface
+fn set_surface_properties(hit_info: ptr<function, HitInfo>, hit_material: ptr<function, Material>) {
     // Base color
     if hit_material.base_color_tex_id != 0xFFFFFFFF {
         (*hit_material).base_color = pow(sample_texture(hit_material.base_color_tex_id, hit_info.uv).rgb, vec3<f32>(2.2f));
     }
 
     // Transparency
-    if hit_material.transparency_tex_id != 0xFFFFFFFF {
-        (*hit_material).transparency = sample_texture(hit_material.transparency_tex_id, hit_info.uv).a;
-    }
+    (*hit_material).transparency = opacity_at(*hit_material, hit_info.uv);
 
     // Roughness
     if hit_material.roughness_tex_id != 0xFFFFFFFF {
@@ -273,26 +1636,143 @@ fn set_surface_properties(hit_info: ptr<function, HitInfo>, hit_material: ptr<fu
         (*hit_material).metallic = sample_texture(hit_material.metallic_tex_id, hit_info.uv).b;
     }
 
+    // Thin film thickness, blended like KHR_materials_iridescence
+    if hit_material.thin_film_thickness_tex_id != 0xFFFFFFFF {
+        let blend = sample_texture(hit_material.thin_film_thickness_tex_id, hit_info.uv).g;
+        (*hit_material).thin_film_thickness = mix(hit_material.thin_film_thickness_min, hit_material.thin_film_thickness, blend);
+    }
+
     // Emission
     if hit_material.emission_tex_id != 0xFFFFFFFF {
-        (*hit_material).emission = pow(sample_texture(hit_material.emission_tex_id, hit_info.uv).rgb, vec3<f32>(2.2f));
+        (*hit_material).emission = pow(sample_texture(hit_material.emission_tex_id, hit_info.uv).rgb, vec3<f32>(2.2f)) * hit_material.emission_scale;
     }
 
-    // Build ONB from geometric normal
-    var tangent: vec3<f32>;
-    var bitangent: vec3<f32>;
-    build_orthonormal_basis((*hit_info).normal, &tangent, &bitangent);
-    (*hit_info).tbn = mat3x3<f32>(tangent, bitangent, (*hit_info).normal);
+    // Tangent space following the UV layout, normal maps are decoded the same way as MikkTSpace
+    // based bakers encode them
+    (*hit_info).tbn = tangent_frame((*hit_info).normal, (*hit_info).tangent, (*hit_info).bitangent_sign);
 
     if hit_material.normal_tex_id != 0xFFFFFFFF {
         (*hit_info).normal = normalize(to_world((*hit_info).tbn, sample_texture(hit_material.normal_tex_id, (*hit_info).uv).rgb * 2.0f - 1.0f));
+        (*hit_info).tbn = tangent_frame((*hit_info).normal, (*hit_info).tangent, (*hit_info).bitangent_sign);
+    }
 
-        // Rebuild ONB from texture normal
-        build_orthonormal_basis((*hit_info).normal, &tangent, &bitangent);
+    // Direction of anisotropic reflection
+    if hit_material.anisotropic_rotation != 0.0f {
+        let angle = hit_material.anisotropic_rotation * TWO_PI;
+        let tangent = (*hit_info).tbn[0] * cos(angle) + (*hit_info).tbn[1] * sin(angle);
+        let bitangent = (*hit_info).tbn[1] * cos(angle) - (*hit_info).tbn[0] * sin(angle);
         (*hit_info).tbn = mat3x3<f32>(tangent, bitangent, (*hit_info).normal);
     }
 }
 
+// Uplifts the colors of the material to the sampled wavelengths and disperses the IOR
+fn set_spectral_properties(hit_material: ptr<function, Material>) {
+    (*hit_material).base_color = uplift((*hit_material).base_color);
+    (*hit_material).specular_tint = uplift((*hit_material).specular_tint);
+    (*hit_material).emission = uplift((*hit_material).emission);
+    (*hit_material).subsurface_albedo = uplift((*hit_material).subsurface_albedo);
+    (*hit_material).subsurface_mfp = uplift((*hit_material).subsurface_mfp);
+    if is_dispersive(*hit_material) {
+        (*hit_material).ior = ior_at(*hit_material, wavelengths.x);
+    }
+}
+
+fn is_dispersive(material: Material) -> bool {
+    return material.cauchy_b != 0.0f || any(material.sellmeier_b != vec3<f32>(0.0f));
+}
+
+// Index of refraction at a wavelength in nanometers from the Sellmeier or Cauchy coefficients
+fn ior_at(material: Material, wavelength: f32) -> f32 {
+    let lambda = wavelength / 1000.0f;
+    let lambda_sqr = lambda * lambda;
+    if any(material.sellmeier_b != vec3<f32>(0.0f)) {
+        let n_sqr = 1.0f + dot(material.sellmeier_b * lambda_sqr / (vec3<f32>(lambda_sqr) - material.sellmeier_c), vec3<f32>(1.0f));
+        return sqrt(max(n_sqr, 1.0f));
+    }
+    return material.ior + material.cauchy_b * (1.0f / lambda_sqr - 1.0f / (0.5893f * 0.5893f));
+}
+
+fn spectral_medium(medium: Medium) -> Medium {
+    var spectral_medium = medium;
+    spectral_medium.sigma_a = spectral(medium.sigma_a);
+    spectral_medium.sigma_s = spectral(medium.sigma_s);
+    return spectral_medium;
+}
+
+// Returns the color uplifted to the sampled wavelengths in spectral mode, as is otherwise
+fn spectral(rgb: vec3<f32>) -> vec3<f32> {
+    if renderer_info.spectral == 0u {
+        return rgb;
+    }
+    return uplift(rgb);
+}
+
+// Hero wavelength sampling, the other two wavelengths are rotated by a third of the range
+// https://cg.cs.uni-bonn.de/backend/v1/files/publications/wilkie-2014-hero.pdf
+fn sample_wavelengths(u: f32) -> vec3<f32> {
+    let offsets = fract(vec3<f32>(u) + vec3<f32>(0.0f, 1.0f / 3.0f, 2.0f / 3.0f));
+    return WAVELENGTH_MIN + offsets * (WAVELENGTH_MAX - WAVELENGTH_MIN);
+}
+
+// Blend of smooth red, green and blue bands that add up to one, see `Wavelengths::uplift`
+fn uplift(rgb: vec3<f32>) -> vec3<f32> {
+    let correction = mat3x3<f32>(
+        vec3<f32>(1.0819644f, -0.0466869f, 0.0333491f),
+        vec3<f32>(-0.1004772f, 1.0516278f, -0.0263298f),
+        vec3<f32>(0.0185127f, -0.0049409f, 0.9929806f),
+    );
+    let weights = max(correction * rgb, vec3<f32>(0.0f));
+    let blue = 1.0f - sigmoid((wavelengths - 490.0f) / 12.0f);
+    let red = sigmoid((wavelengths - 590.0f) / 12.0f);
+    let green = 1.0f - blue - red;
+    return weights.r * red + weights.g * green + weights.b * blue;
+}
+
+fn sigmoid(x: vec3<f32>) -> vec3<f32> {
+    return 1.0f / (1.0f + exp(-x));
+}
+
+// Converts spectral values at the sampled wavelengths to linear sRGB through XYZ
+fn spectrum_to_rgb(values: vec3<f32>) -> vec3<f32> {
+    var xyz = cie_xyz(wavelengths.x) * values.x + cie_xyz(wavelengths.y) * values.y + cie_xyz(wavelengths.z) * values.z;
+    xyz *= (WAVELENGTH_MAX - WAVELENGTH_MIN) / (3.0f * CIE_Y_INTEGRAL);
+    let xyz_to_srgb = mat3x3<f32>(
+        vec3<f32>(3.2404542f, -0.9692660f, 0.0556434f),
+        vec3<f32>(-1.5371385f, 1.8760108f, -0.2040259f),
+        vec3<f32>(-0.4985314f, 0.0415560f, 1.0572252f),
+    );
+    return (xyz_to_srgb * xyz) / EQUAL_ENERGY_WHITE;
+}
+
+// Multi-lobe fit of the CIE 1931 color matching functions
+// https://jcgt.org/published/0002/02/01/paper.pdf
+fn cie_xyz(lambda: f32) -> vec3<f32> {
+    return vec3<f32>(
+        1.056f * cie_lobe(lambda, 599.8f, 37.9f, 31.0f) + 0.362f * cie_lobe(lambda, 442.0f, 16.0f, 26.7f) - 0.065f * cie_lobe(lambda, 501.1f, 20.4f, 26.2f),
+        0.821f * cie_lobe(lambda, 568.8f, 46.9f, 40.5f) + 0.286f * cie_lobe(lambda, 530.9f, 16.3f, 31.1f),
+        1.217f * cie_lobe(lambda, 437.0f, 11.8f, 36.0f) + 0.681f * cie_lobe(lambda, 459.0f, 26.0f, 13.8f),
+    );
+}
+
+fn cie_lobe(lambda: f32, mu: f32, sigma_1: f32, sigma_2: f32) -> f32 {
+    let t = (lambda - mu) / select(sigma_2, sigma_1, lambda < mu);
+    return exp(-0.5f * t * t);
+}
+
+// Orthonormal basis with the tangent following the UV layout, falls back to an arbitrary tangent
+// if the vertex tangent is degenerate
+fn tangent_frame(normal: vec3<f32>, vertex_tangent: vec3<f32>, bitangent_sign: f32) -> mat3x3<f32> {
+    var tangent = vertex_tangent - normal * dot(normal, vertex_tangent);
+    var bitangent: vec3<f32>;
+    if length(tangent) < 1e-6f {
+        build_orthonormal_basis(normal, &tangent, &bitangent);
+    } else {
+        tangent = normalize(tangent);
+        bitangent = cross(normal, tangent) * bitangent_sign;
+    }
+    return mat3x3<f32>(tangent, bitangent, normal);
+}
+
 fn intersect_tri(ray: Ray, tri: Triangle) -> HitInfo {
     let v_1 = tri.vertices[0].position;
     let v_2 = tri.vertices[1].position;
@@ -327,6 +1807,9 @@ fn intersect_tri(ray: Ray, tri: Triangle) -> HitInfo {
     let n_2 = tri.vertices[2].normal;
     let normal = n_0 * (1.0f - u - v) + (n_1 * u) + (n_2 * v);
     hit_info.normal = normalize(select(-normal, normal, front_face));
+    // Corners of a triangle always share the same handedness
+    hit_info.bitangent_sign = tri.vertices[0].bitangent_sign;
+    hit_info.tangent = tri.vertices[0].tangent * (1.0f - u - v) + (tri.vertices[1].tangent * u) + (tri.vertices[2].tangent * v);
 
     let t_0 = vec2<f32>(tri.vertices[0].tex_coord_x, tri.vertices[0].tex_coord_y);
     let t_1 = vec2<f32>(tri.vertices[1].tex_coord_x, tri.vertices[1].tex_coord_y);
@@ -334,6 +1817,8 @@ fn intersect_tri(ray: Ray, tri: Triangle) -> HitInfo {
     hit_info.uv = t_0 * (1.0f - u - v) + (t_1 * u) + (t_2 * v);
 
     hit_info.material_id = tri.material_id;
+    hit_info.object_id = tri.object_id;
+    hit_info.light_id = tri.light_id;
 
     return hit_info;
 }
@@ -348,6 +1833,30 @@ fn intersect_node(ray: Ray, node: Node, max_distance: f32) -> f32 {
     return select(1e30f, t_near, t_near <= t_far && t_near < max_distance && t_far > 0.0f);
 }
 
+// The triangle as it is at the time of the current path
+fn triangle_at_time(tri: Triangle) -> Triangle {
+    if tri.motion_id == NO_MOTION {
+        return tri;
+    }
+    let motion = motions[tri.motion_id];
+    var moved = tri;
+    for (var i = 0u; i < 3u; i++) {
+        let vertex = tri.vertices[i];
+        let start_position = (motion.start * vec4<f32>(vertex.position, 1.0f)).xyz;
+        let end_position = (motion.end * vec4<f32>(vertex.position, 1.0f)).xyz;
+        moved.vertices[i].position = mix(start_position, end_position, ray_time);
+        let start_normal = (motion.start * vec4<f32>(vertex.normal, 0.0f)).xyz;
+        let end_normal = (motion.end * vec4<f32>(vertex.normal, 0.0f)).xyz;
+        moved.vertices[i].normal = normalize(mix(start_normal, end_normal, ray_time));
+        if length(vertex.tangent) > 0.0f {
+            let start_tangent = (motion.start * vec4<f32>(vertex.tangent, 0.0f)).xyz;
+            let end_tangent = (motion.end * vec4<f32>(vertex.tangent, 0.0f)).xyz;
+            moved.vertices[i].tangent = normalize(mix(start_tangent, end_tangent, ray_time));
+        }
+    }
+    return moved;
+}
+
 fn traverse_bvh(ray: Ray) -> HitInfo {
     var hit_info = HitInfo();
     hit_info.distance = 1e30f;
@@ -359,7 +1868,7 @@ fn traverse_bvh(ray: Ray) -> HitInfo {
     loop {
         if node.num_tris > 0u {
             for (var i = 0u; i < node.num_tris; i++) {
-                let temp_hit_info = intersect_tri(ray, triangles[node.first_tri_or_child + i]);
+                let temp_hit_info = intersect_tri(ray, triangle_at_time(triangles[node.first_tri_or_child + i]));
                 if temp_hit_info.has_hit && temp_hit_info.distance < hit_info.distance {
                     hit_info = temp_hit_info;
                 }
@@ -497,13 +2006,208 @@ fn rand_f32(input: ptr<function, u32>) -> f32 {
     return f32(xor_shift(input)) / f32(0xFFFFFFFF);
 }
 
+// Value of `dimension` for the current sample, mirrors the samplers in cpu/sampler.rs
+fn sample_1d(dimension: u32) -> f32 {
+    switch renderer_info.sampler {
+        case SAMPLER_INDEPENDENT: {
+            return independent_sample(dimension);
+        }
+        case SAMPLER_STRATIFIED: {
+            let stratum = current_stratum(dimension, renderer_info.samples);
+            return (f32(stratum.x) + to_unit(hash_u32(stratum.y))) / f32(renderer_info.samples);
+        }
+        case SAMPLER_BLUE_NOISE: {
+            let index = blue_noise_index(dimension);
+            return scrambled_sobol(index.x, 0u, index.y);
+        }
+        default: {
+            let seed = hash_u32(hash_combine(pixel_seed(sampler_pixel), dimension));
+            let index = nested_uniform_scramble(sampler_index, seed);
+            return scrambled_sobol(index, 0u, seed);
+        }
+    }
+}
+
+fn sample_2d(dimension: u32) -> vec2<f32> {
+    switch renderer_info.sampler {
+        case SAMPLER_INDEPENDENT: {
+            return vec2<f32>(independent_sample(dimension), independent_sample(dimension + 1u));
+        }
+        case SAMPLER_STRATIFIED: {
+            // Grid of strata, some cells stay empty if the sample count isn't a square
+            let columns = u32(ceil(sqrt(f32(renderer_info.samples))));
+            let rows = (renderer_info.samples + columns - 1u) / columns;
+            let stratum = current_stratum(dimension, columns * rows);
+            let jitter = vec2<f32>(to_unit(hash_u32(stratum.y)), to_unit(hash_u32(hash_combine(stratum.y, 1u))));
+            let cell = vec2<f32>(f32(stratum.x % columns), f32(stratum.x / columns));
+            return (cell + jitter) / vec2<f32>(f32(columns), f32(rows));
+        }
+        case SAMPLER_BLUE_NOISE: {
+            let index = blue_noise_index(dimension);
+            return vec2<f32>(scrambled_sobol(index.x, 0u, index.y), scrambled_sobol(index.x, 1u, index.y));
+        }
+        default: {
+            let seed = hash_u32(hash_combine(pixel_seed(sampler_pixel), dimension));
+            let index = nested_uniform_scramble(sampler_index, seed);
+            return vec2<f32>(scrambled_sobol(index, 0u, seed), scrambled_sobol(index, 1u, seed));
+        }
+    }
+}
+
+// First dimension of `bounce`
+fn bounce_dimension(bounce: u32) -> u32 {
+    return FIRST_BOUNCE_DIMENSION + bounce * BOUNCE_DIMENSIONS;
+}
+
+fn independent_sample(dimension: u32) -> f32 {
+    let seed = hash_combine(pixel_seed(sampler_pixel), sampler_index);
+    return to_unit(hash_u32(hash_combine(seed, dimension)));
+}
+
+// Stratum of the current sample out of `strata` and the seed of its jitter. Samples past the
+// sample count start over with different strata.
+fn current_stratum(dimension: u32, strata: u32) -> vec2<u32> {
+    let round = sampler_index / renderer_info.samples;
+    let seed = hash_u32(hash_combine(hash_combine(pixel_seed(sampler_pixel), dimension), round));
+    let stratum = permutation_element(sampler_index % renderer_info.samples, strata, seed);
+    return vec2<u32>(stratum, hash_combine(seed, sampler_index));
+}
+
+// Index into the Sobol sequence shared by all pixels and its scrambling seed. Every pixel takes
+// the next block of samples along a scrambled Morton curve, which repeats every tile with
+// different scrambling for every tile and every time the pixel runs out of samples.
+// https://arxiv.org/abs/2008.07817
+fn blue_noise_index(dimension: u32) -> vec2<u32> {
+    // The sample index has to fit next to the Morton index
+    let samples_log2 = min(firstLeadingBit(max(renderer_info.samples, 1u) * 2u - 1u), 32u - 2u * MORTON_LEVELS);
+    let tile = hash_combine(hash_u32(sampler_pixel.x >> MORTON_LEVELS), sampler_pixel.y >> MORTON_LEVELS);
+    let round = sampler_index >> samples_log2;
+    let seed = hash_u32(hash_combine(hash_combine(hash_u32(dimension), tile), round));
+
+    let morton = scrambled_morton(sampler_pixel, seed);
+    let sample = sampler_index & ((1u << samples_log2) - 1u);
+    return vec2<u32>((morton << samples_log2) | sample, seed);
+}
+
+// Integer hash with good avalanche, a bijection so different inputs never collide
+// https://nullprogram.com/blog/2018/07/31/
+fn hash_u32(input: u32) -> u32 {
+    var x = input;
+    x ^= x >> 16u;
+    x *= 0x7feb352du;
+    x ^= x >> 15u;
+    x *= 0x846ca68bu;
+    x ^= x >> 16u;
+    return x;
+}
+
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    return seed ^ (value + 0x9e3779b9u + (seed << 6u) + (seed >> 2u));
+}
+
+fn pixel_seed(pixel: vec2<u32>) -> u32 {
+    return hash_u32(hash_combine(hash_u32(pixel.x), pixel.y));
+}
+
+// Maps the upper 24 bits to [0, 1), using all 32 could round up to 1
+fn to_unit(x: u32) -> f32 {
+    return f32(x >> 8u) / 16777216.0f;
+}
+
+// First two dimensions of the Sobol sequence as 32 bit fractions
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0u {
+        return reverseBits(index);
+    }
+    var result = 0u;
+    var direction = 1u << 31u;
+    var i = index;
+    while i != 0u {
+        if (i & 1u) != 0u {
+            result ^= direction;
+        }
+        i >>= 1u;
+        direction ^= direction >> 1u;
+    }
+    return result;
+}
+
+// Owen scrambled point `index` of Sobol `dimension`
+// https://jcgt.org/published/0009/04/01/paper.pdf
+fn scrambled_sobol(index: u32, dimension: u32, seed: u32) -> f32 {
+    return to_unit(nested_uniform_scramble(sobol(index, dimension), hash_combine(seed, dimension)));
+}
+
+fn laine_karras_permutation(input: u32, seed: u32) -> u32 {
+    var x = input + seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+// Morton index of the pixel inside its tile, with the four children of every node of the quadtree
+// randomly reordered
+fn scrambled_morton(pixel: vec2<u32>, seed: u32) -> u32 {
+    var index = 0u;
+    for (var level = i32(MORTON_LEVELS) - 1; level >= 0; level--) {
+        let digit = ((pixel.x >> u32(level)) & 1u) | (((pixel.y >> u32(level)) & 1u) << 1u);
+        // The leading one tells apart nodes of different depths
+        let node = index | (1u << (2u * (MORTON_LEVELS - 1u - u32(level))));
+        index = (index << 2u) | (digit ^ (hash_u32(hash_combine(seed, node)) & 3u));
+    }
+    return index;
+}
+
+// Element `i` of a random permutation of `0..length` chosen by `seed`
+// https://graphics.pixar.com/library/MultiJitteredSampling/paper.pdf
+fn permutation_element(index: u32, length: u32, seed: u32) -> u32 {
+    var w = length - 1u;
+    w |= w >> 1u;
+    w |= w >> 2u;
+    w |= w >> 4u;
+    w |= w >> 8u;
+    w |= w >> 16u;
+
+    var i = index;
+    loop {
+        i ^= seed;
+        i *= 0xe170893du;
+        i ^= seed >> 16u;
+        i ^= (i & w) >> 4u;
+        i ^= seed >> 8u;
+        i *= 0x0929eb3fu;
+        i ^= seed >> 23u;
+        i ^= (i & w) >> 1u;
+        i *= 1u | (seed >> 27u);
+        i *= 0x6935fa69u;
+        i ^= (i & w) >> 11u;
+        i *= 0x74dcb303u;
+        i ^= (i & w) >> 2u;
+        i *= 0x9e501cc3u;
+        i ^= (i & w) >> 2u;
+        i *= 0xc860a3dfu;
+        i &= w;
+        i ^= i >> 5u;
+        if i < length {
+            break;
+        }
+    }
+    return (i + seed) % length;
+}
+
 fn sample_texture(index: u32, uv: vec2<f32>) -> vec4<f32> {
     return textureSampleLevel(textures[index], textures_array_sampler, uv, 0.0f);
 }
 
-fn sample_ggx_vndf(ve: vec3<f32>, ax: f32, ay: f32, rng_seed: ptr<function, u32>) -> vec3<f32> {
-    let u1 = rand_f32(rng_seed);
-    let u2 = rand_f32(rng_seed);
+fn sample_ggx_vndf(ve: vec3<f32>, ax: f32, ay: f32, u: vec2<f32>) -> vec3<f32> {
+    let u1 = u.x;
+    let u2 = u.y;
 
     let Vh = normalize(vec3<f32>(ax * ve.x, ay * ve.y, ve.z));
 
@@ -524,6 +2228,27 @@ fn sample_ggx_vndf(ve: vec3<f32>, ax: f32, ay: f32, rng_seed: ptr<function, u32>
     return Ne;
 }
 
+fn ggx_d(h: vec3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+    let x = h.x / alpha_x;
+    let y = h.y / alpha_y;
+    let denom = x * x + y * y + h.z * h.z;
+    return 1.0f / (PI * alpha_x * alpha_y * denom * denom);
+}
+
+// Smith masking for a single direction
+fn ggx_g1(w: vec3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+    let cos_2 = w.z * w.z;
+    if cos_2 <= 0.0f {
+        return 0.0f;
+    }
+    let alpha_2_tan_2 = (alpha_x * alpha_x * w.x * w.x + alpha_y * alpha_y * w.y * w.y) / cos_2;
+    return 2.0f / (1.0f + sqrt(1.0f + alpha_2_tan_2));
+}
+
+fn ggx_vndf_pdf(wo: vec3<f32>, h: vec3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+    return ggx_g1(wo, alpha_x, alpha_y) * max(dot(wo, h), 0.0f) * ggx_d(h, alpha_x, alpha_y) / wo.z;
+}
+
 // https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#ConcentricSampleDisk
 fn concentric_sample_disk(u: vec2<f32>) -> vec2<f32> {
     let u_offset = 2.0f * u - vec2<f32>(1.0f);
@@ -542,16 +2267,178 @@ fn concentric_sample_disk(u: vec2<f32>) -> vec2<f32> {
     return r * vec2<f32>(cos(theta), sin(theta));
 }
 
+// Film position of a point in pixel coordinates, with y going up from the bottom of the image
+fn film_position(pixel: vec2<f32>, dimensions: vec2<f32>) -> FilmPosition {
+    var position = pixel;
+    var view = dimensions;
+    var eye = 0.0f;
+    if camera.stereo_layout == STEREO_SIDE_BY_SIDE {
+        view.x *= 0.5f;
+        eye = -1.0f;
+        if position.x >= view.x {
+            eye = 1.0f;
+            position.x -= view.x;
+        }
+    } else if camera.stereo_layout == STEREO_OVER_UNDER {
+        // The left eye is on top, y goes up
+        view.y *= 0.5f;
+        eye = 1.0f;
+        if position.y >= view.y {
+            eye = -1.0f;
+            position.y -= view.y;
+        }
+    }
+
+    var film = FilmPosition();
+    film.aspect = view.x / view.y;
+    film.screen = vec2<f32>(((position.x / view.x) * 2.0f - 1.0f) * film.aspect, (position.y / view.y) * 2.0f - 1.0f);
+    film.eye = eye;
+    return film;
+}
+
+// Ray through a position on the film of an eye, returns false if the projection doesn't cover it.
+// `u_lens` picks the point on the aperture.
+fn camera_ray(film: FilmPosition, ray: ptr<function, Ray>, u_lens: vec2<f32>) -> bool {
+    let screen = film.screen;
+    // The view at the time of the ray, the interpolated basis is made orthonormal again
+    let axis = normalize(mix(camera.look_at[2].xyz, camera.end_look_at[2].xyz, ray_time));
+    var right = mix(camera.look_at[0].xyz, camera.end_look_at[0].xyz, ray_time);
+    right = normalize(right - axis * dot(right, axis));
+    var up = mix(camera.look_at[1].xyz, camera.end_look_at[1].xyz, ray_time);
+    up = normalize(up - axis * dot(up, axis) - right * dot(up, right));
+    let position = mix(camera.position, camera.end_position, ray_time);
+    // Screen x goes right on the image, which is the negative x axis of the view
+    let image_right = -right;
+    let half_ipd = film.eye * camera.interpupillary_distance * 0.5f;
+    let convergence = camera.convergence_distance;
+
+    var eye_offset = image_right * half_ipd;
+    switch camera.projection {
+        case PROJECTION_ORTHOGRAPHIC: {
+            let scale = camera.projection_scale * 0.5f / film.aspect;
+            (*ray).origin = position + eye_offset + (image_right * screen.x + up * screen.y) * scale;
+            (*ray).direction = axis;
+            return true;
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            let phi = screen.x / film.aspect * PI;
+            let theta = screen.y * PI_OVER_2;
+            (*ray).direction = (image_right * sin(phi) + axis * cos(phi)) * cos(theta) + up * sin(theta);
+            // The eyes sit on a circle, to the sides of the horizontal viewing direction
+            eye_offset = (image_right * cos(phi) - axis * sin(phi)) * half_ipd;
+        }
+        case PROJECTION_FISHEYE: {
+            let radius = length(screen);
+            if radius > 1.0f {
+                return false;
+            }
+            let theta = radius * camera.projection_scale * 0.5f;
+            var phi_dir = vec2<f32>(0.0f);
+            if radius > 0.0f {
+                phi_dir = screen / radius;
+            }
+            (*ray).direction = (image_right * phi_dir.x + up * phi_dir.y) * sin(theta) + axis * cos(theta);
+        }
+        default: {
+            var direction = (image_right * screen.x + up * screen.y) * camera.tan_half_fov + axis;
+            // Shifts the film instead of turning the eye, so both views share the plane at the convergence distance
+            if convergence > 0.0f {
+                direction -= eye_offset / convergence;
+            }
+            let eye_position = position + eye_offset;
+            (*ray).origin = eye_position;
+            (*ray).direction = normalize(direction);
+            if camera.lens_radius > 0.0f {
+                // Thin lens, the ray passes through the point the pinhole ray reaches on the plane in focus
+                let focus_point = eye_position + direction * camera.focus_distance;
+                let lens = sample_aperture(u_lens) * camera.lens_radius;
+                (*ray).origin = eye_position + right * lens.x + up * lens.y;
+                (*ray).direction = normalize(focus_point - (*ray).origin);
+            }
+            return true;
+        }
+    }
+
+    (*ray).origin = position + eye_offset;
+    if convergence > 0.0f {
+        (*ray).direction = normalize((*ray).direction * convergence - eye_offset);
+    }
+    return true;
+}
+
+// Point on the aperture relative to its center, in units of the lens radius
+fn sample_aperture(u: vec2<f32>) -> vec2<f32> {
+    switch camera.aperture_shape {
+        case APERTURE_POLYGON: {
+            // Uniform point in the triangle between the center and the edge of one blade
+            let blades = max(camera.aperture_blades, 3u);
+            let blade = min(u32(u.x * f32(blades)), blades - 1u);
+            let step = TWO_PI / f32(blades);
+            let angle_0 = camera.aperture_rotation + f32(blade) * step;
+            let angle_1 = angle_0 + step;
+            // What is left of u.x after picking the blade is uniform again
+            var b = vec2<f32>(u.x * f32(blades) - f32(blade), u.y);
+            if b.x + b.y > 1.0f {
+                b = 1.0f - b;
+            }
+            return vec2<f32>(cos(angle_0), sin(angle_0)) * b.x + vec2<f32>(cos(angle_1), sin(angle_1)) * b.y;
+        }
+        case APERTURE_TEXTURE: {
+            for (var i = 0u; i < APERTURE_TEXTURE_TRIES; i++) {
+                let uv = fract(u + APERTURE_TEXTURE_STEP * f32(i));
+                if sample_texture(camera.aperture_texture_id, uv).r >= 0.5f {
+                    return uv * 2.0f - 1.0f;
+                }
+            }
+            return vec2<f32>(0.0f);
+        }
+        default: {
+            return concentric_sample_disk(u);
+        }
+    }
+}
+
 // https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#CosineSampleHemisphere
-fn cosine_sample_hemisphere(rng_seed: ptr<function, u32>) -> vec3<f32> {
-    let u = vec2<f32>(rand_f32(rng_seed), rand_f32(rng_seed));
+fn cosine_sample_hemisphere(u: vec2<f32>) -> vec3<f32> {
     let d = concentric_sample_disk(u);
     let z = sqrt(max(0.0f, 1.0f - d.x * d.x - d.y * d.y));
     return vec3<f32>(d.x, d.y, z);
 }
 
+// Uniformly samples a direction inside a cone around `axis`
+fn sample_cone(axis: vec3<f32>, cos_max: f32, u: vec2<f32>) -> vec3<f32> {
+    let cos_theta = 1.0f - u.x * (1.0f - cos_max);
+    let sin_theta = sqrt(max(0.0f, 1.0f - cos_theta * cos_theta));
+    let phi = TWO_PI * u.y;
+
+    var tangent: vec3<f32>;
+    var bitangent: vec3<f32>;
+    build_orthonormal_basis(axis, &tangent, &bitangent);
+    return normalize(tangent * (cos(phi) * sin_theta) + bitangent * (sin(phi) * sin_theta) + axis * cos_theta);
+}
+
 fn schlick_fresnel(n_dot_v: f32, f0: vec3<f32>) -> vec3<f32> {
-    return f0 + (1.0f - f0) * pow(1.0f - n_dot_v, 5);
+    return f0 + (1.0f - f0) * pow(1.0f - clamp(n_dot_v, 0.0f, 1.0f), 5.0f);
+}
+
+// Unpolarized Fresnel reflectance of a dielectric interface
+fn fresnel_dielectric(cos_i_in: f32, eta_in: f32) -> f32 {
+    var cos_i = clamp(cos_i_in, -1.0f, 1.0f);
+    var eta = eta_in;
+    if cos_i < 0.0f {
+        eta = 1.0f / eta;
+        cos_i = -cos_i;
+    }
+
+    let sin_2_t = (1.0f - cos_i * cos_i) / (eta * eta);
+    if sin_2_t >= 1.0f {
+        return 1.0f;
+    }
+    let cos_t = sqrt(1.0f - sin_2_t);
+
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return (r_parallel * r_parallel + r_perpendicular * r_perpendicular) * 0.5f;
 }
 
 fn to_local(tbn: mat3x3<f32>, world: vec3<f32>) -> vec3<f32> {
@@ -10,7 +10,8 @@ use winit::{
 };
 
 use crate::{
-    log_info,
+    animation::CameraRecorder,
+    log_info, log_warning,
     renderer::{
         Renderer,
         backend::gpu::{State, UniformCamera},
@@ -18,6 +19,11 @@ use crate::{
     scene::Scene,
 };
 
+/// File the recorded camera path is saved to, relative to the working directory
+const CAMERA_PATH_FILE: &str = "camera_path.json";
+/// Seconds between keyframes while recording
+const RECORD_INTERVAL: f32 = 0.25;
+
 struct AppState {
     state: Option<State>,
     window: Arc<Window>,
@@ -28,6 +34,7 @@ struct AppState {
     render_pipeline: wgpu::RenderPipeline,
     bind_group: wgpu::BindGroup,
     key_states: HashSet<PhysicalKey>,
+    recorder: CameraRecorder,
 }
 
 impl AppState {
@@ -169,6 +176,7 @@ impl AppState {
             render_pipeline,
             bind_group,
             key_states: HashSet::new(),
+            recorder: CameraRecorder::new(RECORD_INTERVAL),
         };
 
         app_state.configure_surface();
@@ -226,9 +234,10 @@ impl AppState {
                 label: None,
                 timestamp_writes: None,
             });
-            rt_pass.set_bind_group(0, &state.rt_texture_bind_group, &[]);
+            rt_pass.set_bind_group(0, &state.rt_output_bind_group, &[]);
             rt_pass.set_bind_group(1, &state.storage_buffers.bind_group, &[]);
             rt_pass.set_bind_group(2, &state.uniform_buffers.bind_group, &[]);
+            rt_pass.set_bind_group(3, &state.aov_bind_group, &[]);
             rt_pass.set_pipeline(&state.rt_pipeline);
             rt_pass.set_immediates(0, bytemuck::cast_slice(&[state.renderer_info]));
             rt_pass.dispatch_workgroups(
@@ -238,15 +247,20 @@ impl AppState {
             );
         }
 
+        let pp_input_bind_group = state.encode_denoise_passes(&mut command_encoder);
+        state.encode_glare_passes(&mut command_encoder, pp_input_bind_group);
+
         // Post process compute pass
         {
             let mut pp_pass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
                 label: None,
                 timestamp_writes: None,
             });
-            pp_pass.set_bind_group(0, &state.rt_texture_bind_group, &[]);
+            pp_pass.set_bind_group(0, pp_input_bind_group, &[]);
             pp_pass.set_bind_group(1, &state.pp_texture_bind_group, &[]);
+            pp_pass.set_bind_group(2, &state.glare_bind_group, &[]);
             pp_pass.set_pipeline(&state.pp_pipeline);
+            pp_pass.set_immediates(0, bytemuck::cast_slice(&[state.post_process_info]));
             pp_pass.dispatch_workgroups(
                 state.pp_texture.width() / 8,
                 state.pp_texture.height() / 8,
@@ -341,9 +355,17 @@ impl ApplicationHandler for App {
                     ));
                 }
 
+                let recording = if app_state.recorder.is_sampling() {
+                    format!(
+                        " | Recording: {} keyframes",
+                        app_state.recorder.keyframes.len()
+                    )
+                } else {
+                    String::new()
+                };
                 window.set_title(&format!(
-                    "Path tracer | Current sample: {}",
-                    state.renderer_info.curr_sample
+                    "Path tracer | Current sample: {}{}",
+                    state.renderer_info.curr_sample, recording
                 ));
 
                 let camera = &mut scene.borrow_mut().camera;
@@ -374,12 +396,21 @@ impl ApplicationHandler for App {
                             camera.position -= camera.up * 0.03;
                             state.renderer_info.curr_sample = 1;
                         }
+                        PhysicalKey::Code(KeyCode::KeyR) => {
+                            camera.focus_distance *= 1.02;
+                            state.renderer_info.curr_sample = 1;
+                        }
+                        PhysicalKey::Code(KeyCode::KeyF) => {
+                            camera.focus_distance = f32::max(camera.focus_distance / 1.02, 0.01);
+                            state.renderer_info.curr_sample = 1;
+                        }
                         _ => (),
                     }
                 }
 
                 // Update camera matrix
                 camera.update_view();
+                app_state.recorder.update(camera);
 
                 // Upload new camera data to the GPU
                 let new_camera_data = UniformCamera::from(camera.clone());
@@ -396,7 +427,8 @@ impl ApplicationHandler for App {
                 event,
                 is_synthetic,
             } => {
-                if event.state.is_pressed() {
+                if event.state.is_pressed() && !event.repeat {
+                    let recorder = &mut self.app_state.as_mut().unwrap().recorder;
                     match event.physical_key {
                         PhysicalKey::Code(KeyCode::KeyI) => {
                             let scene = self.scene.clone();
@@ -404,6 +436,44 @@ impl ApplicationHandler for App {
                             log_info!("Camera position: {}", camera.position);
                             log_info!("Camera pitch:    {}", camera.pitch);
                             log_info!("Camera yaw:      {}", camera.yaw);
+                            log_info!("Camera focus:    {}", camera.focus_distance);
+                        }
+                        // Start or stop taking keyframes at a fixed interval
+                        PhysicalKey::Code(KeyCode::KeyK) => {
+                            recorder.set_sampling(!recorder.is_sampling());
+                            if recorder.is_sampling() {
+                                log_info!("Started recording the camera path");
+                            } else {
+                                log_info!(
+                                    "Stopped recording the camera path, {} keyframes",
+                                    recorder.keyframes.len()
+                                );
+                            }
+                        }
+                        // Drop a single keyframe where the camera is now
+                        PhysicalKey::Code(KeyCode::KeyL) => {
+                            recorder.add_keyframe(&self.scene.borrow().camera);
+                            log_info!("Added camera keyframe {}", recorder.keyframes.len());
+                        }
+                        PhysicalKey::Code(KeyCode::KeyO) => match recorder.path() {
+                            Some(camera_path) => {
+                                camera_path.save(CAMERA_PATH_FILE);
+                            }
+                            None => {
+                                log_warning!("No camera keyframes to save");
+                            }
+                        },
+                        PhysicalKey::Code(KeyCode::Backspace) => {
+                            recorder.clear();
+                            log_info!("Cleared the recorded camera path");
+                        }
+                        PhysicalKey::Code(KeyCode::KeyN) => {
+                            let state = self.app_state.as_mut().unwrap().state.as_mut().unwrap();
+                            state.denoise = !state.denoise;
+                            // The AOVs only accumulate while the denoiser needs them
+                            state.renderer_info.aovs = state.denoise as u32;
+                            state.renderer_info.curr_sample = 1;
+                            log_info!("Denoiser {}", if state.denoise { "on" } else { "off" });
                         }
                         _ => (),
                     }
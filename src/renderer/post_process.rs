@@ -0,0 +1,285 @@
+use crate::math::vec::*;
+use crate::math::vec3::*;
+use crate::scene::{Aperture, Camera};
+
+/// F-number the glare radius and streak length are given for
+const GLARE_REFERENCE_F_STOP: f32 = 8.0;
+/// Lenses with more blades than this make a round glow instead of streaks
+const MAX_STREAK_BLADES: u32 = 8;
+
+/// Operator that maps the linear radiance into the displayable range
+#[allow(dead_code)]
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum ToneMapping {
+    /// Clamps every channel to 1
+    None,
+    /// Reinhard with a white point, radiance at or above it maps to 1
+    ReinhardExtended { white_point: f32 },
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    #[default]
+    AcesFitted,
+    /// Troy Sobotka's AgX with the base look
+    AgX,
+    /// Khronos PBR Neutral, keeps base colors intact up to the start of the highlights
+    PbrNeutral,
+}
+
+impl ToneMapping {
+    /// Index of the operator in pp_compute.wgsl
+    pub fn id(&self) -> u32 {
+        match self {
+            ToneMapping::None => return 0,
+            ToneMapping::ReinhardExtended { .. } => return 1,
+            ToneMapping::AcesFitted => return 2,
+            ToneMapping::AgX => return 3,
+            ToneMapping::PbrNeutral => return 4,
+        }
+    }
+
+    /// Mirrors `tone_map` in pp_compute.wgsl
+    pub fn apply(&self, color: Vec3f) -> Vec3f {
+        match self {
+            ToneMapping::None => return Vec3f::min(color, Vec3f::from(1.0)),
+            ToneMapping::ReinhardExtended { white_point } => {
+                let numerator = color * (Vec3f::from(1.0) + color / (white_point * white_point));
+                return Vec3f::min(numerator / (Vec3f::from(1.0) + color), Vec3f::from(1.0));
+            }
+            ToneMapping::AcesFitted => return aces_fitted(color),
+            ToneMapping::AgX => return agx(color),
+            ToneMapping::PbrNeutral => return pbr_neutral(color),
+        }
+    }
+}
+
+/// Light scattered and diffracted around bright pixels by the lens. Only radiance above
+/// `threshold` spreads out.
+#[derive(Debug, Clone, Copy)]
+pub struct Glare {
+    pub threshold: f32,
+    /// Strength of the round glow
+    pub bloom: f32,
+    /// Strength of the star streaks, polygonal apertures only
+    pub streaks: f32,
+    /// Radius of the glow and length of the streaks in pixels at f/8. Diffraction spreads light
+    /// further the more the lens is stopped down, so they grow with the f-number of the camera.
+    pub size: f32,
+}
+
+impl Default for Glare {
+    fn default() -> Self {
+        return Self {
+            threshold: 1.0,
+            bloom: 0.0,
+            streaks: 0.0,
+            size: 32.0,
+        };
+    }
+}
+
+impl Glare {
+    pub fn is_enabled(&self) -> bool {
+        return self.bloom > 0.0 || self.streaks > 0.0;
+    }
+}
+
+/// Glare of a specific camera
+#[derive(Debug, Clone, Copy)]
+pub struct GlareKernel {
+    pub bloom_radius: f32,
+    pub streak_length: f32,
+    /// Zero for round apertures
+    pub streak_count: u32,
+    /// Angle of the first streak in radians, the others are spread evenly around the circle
+    pub streak_rotation: f32,
+}
+
+/// Turns the linear radiance the backends accumulate into the displayed sRGB image. Both
+/// backends apply it the same way, pp_compute.wgsl and glare_compute.wgsl on the GPU. HDR output
+/// formats skip it.
+#[derive(Debug, Clone, Copy)]
+pub struct PostProcess {
+    /// Exposure compensation in stops, every stop doubles the brightness
+    pub exposure: f32,
+    /// Color temperature in Kelvin that is rendered as white, 6500 leaves the image unchanged.
+    /// Lower values cool the image down, higher values warm it up.
+    pub white_balance: f32,
+    pub tone_mapping: ToneMapping,
+    pub glare: Glare,
+    /// Darkening towards the corners following the cos^4 law, 0 turns it off. The angle of the
+    /// corners is `atan(sqrt(vignette))`.
+    pub vignette: f32,
+    /// Lateral chromatic aberration, red is magnified and blue shrunk by this fraction around
+    /// the image center
+    pub chromatic_aberration: f32,
+}
+
+impl Default for PostProcess {
+    fn default() -> Self {
+        return Self {
+            exposure: 0.0,
+            white_balance: 6500.0,
+            tone_mapping: ToneMapping::default(),
+            glare: Glare::default(),
+            vignette: 0.0,
+            chromatic_aberration: 0.0,
+        };
+    }
+}
+
+impl PostProcess {
+    /// Exposure and white balance as one multiplier on the linear radiance
+    pub fn color_scale(&self) -> Vec3f {
+        let white = blackbody_rgb(6500.0) / blackbody_rgb(self.white_balance);
+        // Keep the luminance so white balance doesn't change the exposure
+        let luminance = 0.2126 * white.x() + 0.7152 * white.y() + 0.0722 * white.z();
+        return white * (f32::powf(2.0, self.exposure) / luminance);
+    }
+
+    /// Shape of the glare from the aperture of `camera`. Every blade edge diffracts light into a
+    /// streak perpendicular to it. With an even blade count opposite edges share their streaks,
+    /// an odd count gives twice as many.
+    pub fn glare_kernel(&self, camera: &Camera) -> GlareKernel {
+        let f_stop_scale = if camera.f_stop > 0.0 {
+            camera.f_stop / GLARE_REFERENCE_F_STOP
+        } else {
+            1.0
+        };
+        let size = self.glare.size * f_stop_scale;
+
+        let (streak_count, streak_rotation) = match camera.aperture {
+            Aperture::Polygon { blades, rotation } if blades <= MAX_STREAK_BLADES => {
+                let count = if blades % 2 == 0 { blades } else { blades * 2 };
+                // Edge normals sit halfway between the vertices
+                (count, (rotation + 180.0 / blades as f32).to_radians())
+            }
+            _ => (0, 0.0),
+        };
+
+        return GlareKernel {
+            bloom_radius: size,
+            streak_length: size * 4.0,
+            streak_count,
+            streak_rotation,
+        };
+    }
+
+    /// Whether any pass that needs neighboring pixels is enabled
+    pub fn has_lens_effects(&self) -> bool {
+        return self.glare.is_enabled() || self.vignette > 0.0 || self.chromatic_aberration != 0.0;
+    }
+
+    /// Linear radiance to display sRGB in [0, 1]
+    pub fn apply(&self, color: Vec3f) -> Vec3f {
+        let color = self.tone_mapping.apply(color * self.color_scale());
+        return Vec3f::linear_to_srgb(Vec3f::max(color, Vec3f::from(0.0)));
+    }
+}
+
+/// Linear sRGB color of a blackbody with unit luminance, from the chromaticity on the Planckian
+/// locus
+///
+/// https://en.wikipedia.org/wiki/Planckian_locus#Approximation
+fn blackbody_rgb(temperature: f32) -> Vec3f {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let x = if t < 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / (t * t * t) + 2.107038e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t < 2222.0 {
+        -1.1063814 * x * x * x - 1.3481102 * x * x + 2.1855583 * x - 0.20219683
+    } else if t < 4000.0 {
+        -0.9549476 * x * x * x - 1.3741859 * x * x + 2.09137 * x - 0.16748867
+    } else {
+        3.081758 * x * x * x - 5.873387 * x * x + 3.7511299 * x - 0.37001483
+    };
+
+    let xyz = Vec3f::new(x / y, 1.0, (1.0 - x - y) / y);
+    return Vec3f::new(
+        3.2404542 * xyz.x() - 1.5371385 * xyz.y() - 0.4985314 * xyz.z(),
+        -0.969266 * xyz.x() + 1.8760108 * xyz.y() + 0.0415560 * xyz.z(),
+        0.0556434 * xyz.x() - 0.2040259 * xyz.y() + 1.0572252 * xyz.z(),
+    );
+}
+
+/// Multiplies `v` with the matrix given by its rows
+fn mul(rows: [[f32; 3]; 3], v: Vec3f) -> Vec3f {
+    return Vec3f::new(
+        Vec3f::dot(Vec3f::from(rows[0]), v),
+        Vec3f::dot(Vec3f::from(rows[1]), v),
+        Vec3f::dot(Vec3f::from(rows[2]), v),
+    );
+}
+
+/// https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+fn aces_fitted(color: Vec3f) -> Vec3f {
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let v = mul(INPUT, color);
+    let a = v * (v + Vec3f::from(0.0245786)) - Vec3f::from(0.000090537);
+    let b = v * (v * 0.983729 + Vec3f::from(0.432951)) + Vec3f::from(0.238081);
+    let v = Vec3f::max(mul(OUTPUT, a / b), Vec3f::from(0.0));
+    return Vec3f::min(v, Vec3f::from(1.0));
+}
+
+/// https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx(color: Vec3f) -> Vec3f {
+    const INSET: [[f32; 3]; 3] = [
+        [0.84247906, 0.0784336, 0.079223745],
+        [0.042328242, 0.87846863, 0.07916613],
+        [0.042375654, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.052896852, 1.1519031, -0.098961176],
+        [-0.052971635, -0.09804345, 1.1510737],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let mut v = mul(INSET, color);
+    for i in 0..3 {
+        let log = f32::log2(f32::max(v.data[i], 1e-10));
+        let x = ((log - MIN_EV) / (MAX_EV - MIN_EV)).clamp(0.0, 1.0);
+        // Polynomial fit of the default contrast curve
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        v.data[i] = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x
+            + 0.4298 * x2
+            + 0.1191 * x
+            - 0.00232;
+    }
+    // The curve ends up in display encoding, go back to linear for the sRGB transform
+    let v = Vec3f::max(mul(OUTSET, v), Vec3f::from(0.0));
+    return Vec3f::min(Vec3f::powf(v, 2.2), Vec3f::from(1.0));
+}
+
+/// https://github.com/KhronosGroup/ToneMapping/blob/main/PBR_Neutral/README.md
+fn pbr_neutral(color: Vec3f) -> Vec3f {
+    const START_COMPRESSION: f32 = 0.8 - 0.04;
+    const DESATURATION: f32 = 0.15;
+
+    let x = f32::min(color.x(), f32::min(color.y(), color.z()));
+    let offset = if x < 0.08 { x - 6.25 * x * x } else { 0.04 };
+    let color = color - Vec3f::from(offset);
+
+    let peak = f32::max(color.x(), f32::max(color.y(), color.z()));
+    if peak < START_COMPRESSION {
+        return color;
+    }
+
+    let d = 1.0 - START_COMPRESSION;
+    let new_peak = 1.0 - d * d / (peak + d - START_COMPRESSION);
+    let color = color * (new_peak / peak);
+    let g = 1.0 - 1.0 / (DESATURATION * (peak - new_peak) + 1.0);
+    return Vec3f::mix(color, Vec3f::from(new_peak), g);
+}
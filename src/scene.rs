@@ -1,12 +1,18 @@
 use std::collections::HashMap;
 
+use crate::animation::CameraPath;
 use crate::bvh::BVH;
+use crate::light_bvh::LightBVH;
+use crate::loader::gltf::GLTF;
+use crate::loader::mikktspace;
 use crate::loader::obj::OBJ;
 use crate::log_error;
 use crate::math::mat4::Mat4f;
 use crate::math::vec::*;
 use crate::math::vec3::*;
-use crate::texture::Texture;
+use crate::medium::Medium;
+use crate::sky::Sky;
+use crate::texture::{Texture, TextureType};
 
 /// Representation of a 3D scene for use in the ray tracer.
 #[derive(Clone, Default)]
@@ -15,7 +21,17 @@ pub struct Scene {
     pub materials: HashMap<String, Material>,
     pub textures: Vec<Texture>,
     pub bvh: BVH,
+    pub light_bvh: LightBVH,
     pub camera: Camera,
+    /// Path the camera follows when rendering a frame sequence
+    pub camera_path: Option<CameraPath>,
+    pub sky: Sky,
+    pub media: Vec<Medium>,
+    /// Transforms that moving triangles interpolate between during the shutter interval
+    pub motions: Vec<Motion>,
+    pub density_grid_data: Vec<f32>,
+    /// Medium filling the space outside of all closed meshes
+    pub global_medium_id: Option<u32>,
 }
 
 impl Scene {
@@ -28,6 +44,7 @@ impl Scene {
         let format = path.split(".").last().unwrap();
         match format {
             "obj" => Some(OBJ::load(path).into()),
+            "gltf" | "glb" => Some(GLTF::load(path)?.into()),
             _ => {
                 log_error!("Unsupported scene format '{}' at path '{}'", format, path);
                 return None;
@@ -38,6 +55,98 @@ impl Scene {
     pub fn set_camera(&mut self, camera: Camera) {
         self.camera = camera;
         self.camera.update_view();
+        if let Aperture::Texture(texture_id) = self.camera.aperture
+            && texture_id as usize >= self.textures.len()
+        {
+            log_error!(
+                "Aperture texture {} doesn't exist, using a circular aperture",
+                texture_id
+            );
+            self.camera.aperture = Aperture::Circle;
+        }
+        match self.camera.projection {
+            Projection::Orthographic { width } if width.is_nan() || width <= 0.0 => {
+                log_error!("Orthographic view width must be positive, using a perspective camera");
+                self.camera.projection = Projection::Perspective;
+            }
+            Projection::Fisheye { fov } if !(fov > 0.0 && fov <= 360.0) => {
+                log_error!("Fisheye FOV must be in (0, 360] degrees, using a perspective camera");
+                self.camera.projection = Projection::Perspective;
+            }
+            _ => (),
+        }
+        if let Some(stereo) = self.camera.stereo
+            && !(stereo.interpupillary_distance >= 0.0 && stereo.convergence_distance >= 0.0)
+        {
+            log_error!("Stereo distances can't be negative, using a mono camera");
+            self.camera.stereo = None;
+        }
+        let camera = &mut self.camera;
+        if !(camera.shutter_open >= 0.0
+            && camera.shutter_open <= camera.shutter_close
+            && camera.shutter_close <= 1.0)
+        {
+            log_error!("Shutter times must satisfy 0 <= open <= close <= 1, closing the shutter");
+            camera.shutter_open = 0.0;
+            camera.shutter_close = 0.0;
+        }
+    }
+
+    /// Moves the triangles of object `object_id` from `start` at time zero to `end` at time one,
+    /// which blurs them while the shutter is open. Objects with emissive triangles can't move
+    /// since lights are sampled at their resting position. Returns the index of the motion.
+    pub fn set_motion(&mut self, object_id: u32, start: Mat4f, end: Mat4f) -> Option<u32> {
+        let mut has_tris = false;
+        for tri in self.tris.iter().filter(|tri| tri.object_id == object_id) {
+            let material = self.material(tri.material_id);
+            let emission = material.emission;
+            if material.emission_tex_id != u32::MAX
+                || emission.x() + emission.y() + emission.z() > 0.0
+            {
+                log_error!("Can't move object {} since it is emissive", object_id);
+                return None;
+            }
+            has_tris = true;
+        }
+        if !has_tris {
+            log_error!("Can't move object {} since it doesn't exist", object_id);
+            return None;
+        }
+
+        self.motions.push(Motion { start, end });
+        let motion_id = (self.motions.len() - 1) as u32;
+        for tri in self
+            .tris
+            .iter_mut()
+            .filter(|tri| tri.object_id == object_id)
+        {
+            tri.motion_id = motion_id;
+        }
+
+        // The bounds of the moving triangles have changed
+        BVH::build(self);
+        LightBVH::build(self);
+        return Some(motion_id);
+    }
+
+    /// Loads a texture that isn't used by any material, like the shape of the aperture.
+    /// Returns its index.
+    #[allow(dead_code)]
+    pub fn add_texture(&mut self, path: &str, texture_type: TextureType) -> Option<u32> {
+        let texture = Texture::load(path, texture_type)?;
+        self.textures.push(texture);
+        return Some((self.textures.len() - 1) as u32);
+    }
+
+    pub fn set_global_medium(&mut self, medium: Medium) {
+        self.media.push(medium);
+        self.global_medium_id = Some((self.media.len() - 1) as u32);
+    }
+
+    /// Material ids index into the materials in iteration order, which matches the order they
+    /// are uploaded to the GPU in
+    pub fn material(&self, material_id: u32) -> &Material {
+        return self.materials.values().nth(material_id as usize).unwrap();
     }
 }
 
@@ -68,17 +177,74 @@ impl From<OBJ> for Scene {
                     tex_coord_x: tex_coord[0],
                     normal: normal.into(),
                     tex_coord_y: tex_coord[1],
+                    ..Default::default()
                 };
             }
-            scene
-                .tris
-                .push(Triangle::new(vertices, obj_tri.material_id));
+            scene.tris.push(Triangle::new(
+                vertices,
+                obj_tri.material_id,
+                obj_tri.object_id,
+            ));
         }
 
+        // OBJ has no way of storing tangents
+        mikktspace::generate_tangents(&mut scene.tris);
+
         scene.materials = obj.materials;
         scene.textures = obj.textures;
+        scene.media = obj.media;
+        scene.density_grid_data = obj.density_grid_data;
+        if let Some(sky) = obj.sky {
+            scene.sky = sky;
+        }
+        if let Some(medium) = obj.global_medium {
+            scene.set_global_medium(medium);
+        }
+        if obj.camera_path.is_some() {
+            scene.camera_path = obj.camera_path;
+        } else if !obj.camera_keyframes.is_empty() {
+            scene.camera_path = CameraPath::new(obj.camera_keyframes, obj.camera_interpolation);
+        }
 
         BVH::build(&mut scene);
+        LightBVH::build(&mut scene);
+
+        for (object_id, start, end) in obj.object_motions {
+            scene.set_motion(
+                object_id,
+                Mat4f::translation(start),
+                Mat4f::translation(end),
+            );
+        }
+
+        return scene;
+    }
+}
+
+impl From<GLTF> for Scene {
+    fn from(gltf: GLTF) -> Self {
+        let mut scene = Scene::default();
+
+        // Tangents are only generated for the triangles that came without them
+        let (with_tangents, without_tangents): (Vec<_>, Vec<_>) =
+            gltf.tris.iter().partition(|tri| tri.has_tangents);
+        scene.tris = without_tangents
+            .iter()
+            .map(|tri| Triangle::new(tri.vertices, tri.material_id, tri.object_id))
+            .collect();
+        mikktspace::generate_tangents(&mut scene.tris);
+        scene.tris.extend(
+            with_tangents
+                .iter()
+                .map(|tri| Triangle::new(tri.vertices, tri.material_id, tri.object_id)),
+        );
+
+        scene.materials = gltf.materials;
+        scene.textures = gltf.textures;
+        scene.media = gltf.media;
+
+        BVH::build(&mut scene);
+        LightBVH::build(&mut scene);
 
         return scene;
     }
@@ -91,6 +257,10 @@ pub struct Vertex {
     pub tex_coord_x: f32,
     pub normal: Vec3f,
     pub tex_coord_y: f32,
+    /// Points along increasing U, zero if the mesh has no usable UV layout
+    pub tangent: Vec3f,
+    /// The bitangent is `bitangent_sign * cross(normal, tangent)`, negative for mirrored UVs
+    pub bitangent_sign: f32,
 }
 
 // This needs to derive some bytemuck traits so we can put 'em in a buffer on the GPU
@@ -99,33 +269,91 @@ pub struct Vertex {
 pub struct Triangle {
     pub vertices: [Vertex; 3],
     pub material_id: u32,
-    _pad: [u8; 12],
+    /// Index into the scene motions, `NO_MOTION` for static triangles
+    pub motion_id: u32,
+    /// Object the triangle belongs to, counting the `o` lines of an OBJ file or the mesh nodes
+    /// of a glTF file
+    pub object_id: u32,
+    /// Leaf of the light BVH holding the triangle, `NO_LIGHT` for triangles that don't emit
+    pub light_id: u32,
 }
 
+pub const NO_MOTION: u32 = u32::MAX;
+pub const NO_LIGHT: u32 = u32::MAX;
+
 impl Triangle {
-    fn new(vertices: [Vertex; 3], material_id: u32) -> Self {
+    fn new(vertices: [Vertex; 3], material_id: u32, object_id: u32) -> Self {
         return Self {
             vertices,
             material_id,
-            _pad: [0; 12],
+            motion_id: NO_MOTION,
+            object_id,
+            light_id: NO_LIGHT,
         };
     }
 
-    pub fn bounds_mid(&self) -> Vec3f {
+    /// The triangle as it is at `time`
+    pub fn at_time(&self, motions: &[Motion], time: f32) -> Self {
+        if self.motion_id == NO_MOTION {
+            return *self;
+        }
+        let motion = &motions[self.motion_id as usize];
+        let mut tri = *self;
+        for vertex in &mut tri.vertices {
+            let start_position = motion.start.transform_point(vertex.position);
+            let end_position = motion.end.transform_point(vertex.position);
+            vertex.position = start_position + (end_position - start_position) * time;
+            let start_normal = motion.start * vertex.normal;
+            let end_normal = motion.end * vertex.normal;
+            vertex.normal = (start_normal + (end_normal - start_normal) * time).normalized();
+            if vertex.tangent.length() > 0.0 {
+                let start_tangent = motion.start * vertex.tangent;
+                let end_tangent = motion.end * vertex.tangent;
+                vertex.tangent =
+                    (start_tangent + (end_tangent - start_tangent) * time).normalized();
+            }
+        }
+        return tri;
+    }
+
+    /// Bounds over the whole shutter interval, moving triangles stay within the bounds of their
+    /// start and end positions
+    pub fn bounds(&self, motions: &[Motion]) -> (Vec3f, Vec3f) {
         let mut bounds_min = Vec3f::from(f32::MAX);
         let mut bounds_max = Vec3f::from(-f32::MAX);
 
-        for vertex in self.vertices {
-            for i in 0..3 {
-                bounds_min.data[i] = f32::min(bounds_min.data[i], vertex.position.data[i]);
-                bounds_max.data[i] = f32::max(bounds_max.data[i], vertex.position.data[i]);
+        let mut grow = |tri: &Triangle| {
+            for vertex in tri.vertices {
+                for i in 0..3 {
+                    bounds_min.data[i] = f32::min(bounds_min.data[i], vertex.position.data[i]);
+                    bounds_max.data[i] = f32::max(bounds_max.data[i], vertex.position.data[i]);
+                }
             }
+        };
+        grow(&self.at_time(motions, 0.0));
+        if self.motion_id != NO_MOTION {
+            grow(&self.at_time(motions, 1.0));
         }
 
+        return (bounds_min, bounds_max);
+    }
+
+    pub fn bounds_mid(&self, motions: &[Motion]) -> Vec3f {
+        let (bounds_min, bounds_max) = self.bounds(motions);
         return (bounds_min + bounds_max) / 2.0;
     }
 }
 
+/// Rigid motion of a set of triangles, their vertices move on straight lines from the start to
+/// the end transform. Normals are transformed without the inverse transpose, so the transforms
+/// should only rotate, translate and scale uniformly.
+#[derive(Clone, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct Motion {
+    pub start: Mat4f,
+    pub end: Mat4f,
+}
+
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(16))]
 pub struct Material {
@@ -143,6 +371,53 @@ pub struct Material {
     pub metallic_tex_id: u32,
     pub emission_tex_id: u32,
     pub normal_tex_id: u32,
+    /// Medium filling the inside of the closed mesh using this material
+    pub medium_id: u32,
+    /// Chance of scattering below the surface with a random walk instead of reflecting diffusely
+    pub subsurface: f32,
+    /// Transmission goes through an infinitely thin sheet instead of refracting into a volume,
+    /// used for windows and leaves that are modeled as single polygons
+    pub thin_walled: u32,
+    /// Strength of the dielectric specular reflection, 0.5 gives the Fresnel reflectance of `ior`
+    pub specular: f32,
+    /// Multiple scattering albedo, the color of the material seen from far away
+    pub subsurface_albedo: Vec3f,
+    /// Henyey-Greenstein asymmetry parameter of the scattering inside the mesh
+    pub subsurface_anisotropy: f32,
+    /// Mean free path per color channel in scene units, how far light travels before scattering
+    pub subsurface_mfp: Vec3f,
+    /// Stretches the specular highlight along the tangent, in the range 0.0 - 1.0
+    pub anisotropic: f32,
+    /// Retroreflective grazing sheen for cloth, in the range 0.0 - 1.0
+    pub sheen: f32,
+    /// Tints the sheen towards the base color
+    pub sheen_tint: f32,
+    /// Strength of a second, white specular layer on top of everything else
+    pub clearcoat: f32,
+    /// Glossiness of the clearcoat layer, 1.0 is mirror like
+    pub clearcoat_gloss: f32,
+    /// Rotates the direction of anisotropy around the normal, 1.0 is a full turn
+    pub anisotropic_rotation: f32,
+    /// Thickness of a thin film on top of the specular layers in nanometers, 0.0 disables it.
+    /// The film reflects iridescent colors like soap bubbles and oil slicks.
+    pub thin_film_thickness: f32,
+    pub thin_film_ior: f32,
+    /// The green channel blends the film thickness from `thin_film_thickness_min` to
+    /// `thin_film_thickness`, like `KHR_materials_iridescence`
+    pub thin_film_thickness_tex_id: u32,
+    /// Sellmeier B coefficients, if any of them is non zero they define the IOR in spectral mode
+    pub sellmeier_b: Vec3f,
+    /// Cauchy B coefficient in µm², disperses `ior` in spectral mode if there is no Sellmeier fit
+    pub cauchy_b: f32,
+    /// Sellmeier C coefficients in µm²
+    pub sellmeier_c: Vec3f,
+    pub thin_film_thickness_min: f32,
+    /// Multiplies the texels of `emission_tex_id`, glTF uses it for the emissive factor and
+    /// strength
+    pub emission_scale: Vec3f,
+    /// Opacity at or above the cutoff is 1 and below it 0, like glTF `MASK`, 0.0 keeps the
+    /// opacity as is
+    pub alpha_cutoff: f32,
 }
 
 impl Default for Material {
@@ -162,11 +437,139 @@ impl Default for Material {
             metallic_tex_id: u32::MAX,
             emission_tex_id: u32::MAX,
             normal_tex_id: u32::MAX,
+            medium_id: u32::MAX,
+            subsurface: 0.0,
+            thin_walled: 0,
+            specular: 0.5,
+            subsurface_albedo: Vec3f::from(0.8),
+            subsurface_anisotropy: 0.0,
+            subsurface_mfp: Vec3f::from(0.1),
+            anisotropic: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            anisotropic_rotation: 0.0,
+            thin_film_thickness: 0.0,
+            thin_film_ior: 1.3,
+            thin_film_thickness_tex_id: u32::MAX,
+            sellmeier_b: Vec3f::from(0.0),
+            cauchy_b: 0.0,
+            sellmeier_c: Vec3f::from(0.0),
+            thin_film_thickness_min: 100.0,
+            emission_scale: Vec3f::from(1.0),
+            alpha_cutoff: 0.0,
         };
     }
 }
 
-#[derive(Clone, Default)]
+impl Material {
+    /// Homogeneous medium for the random walk inside a mesh with subsurface scattering.
+    ///
+    /// The single scattering albedo is inverted from `subsurface_albedo` so that the multiple
+    /// scattering result matches it.
+    /// https://graphics.pixar.com/library/PathTracedSubsurface/paper.pdf
+    pub fn subsurface_medium(&self) -> Medium {
+        let mut sigma_a = Vec3f::from(0.0);
+        let mut sigma_s = Vec3f::from(0.0);
+        for i in 0..3 {
+            let albedo = self.subsurface_albedo.data[i].clamp(0.0, 0.999);
+            let single_scatter_albedo = 1.0
+                - f32::powi(
+                    4.09712 + 4.20863 * albedo
+                        - f32::sqrt(9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo),
+                    2,
+                );
+            let sigma_t = 1.0 / f32::max(self.subsurface_mfp.data[i], 1e-4);
+            sigma_s.data[i] = sigma_t * single_scatter_albedo;
+            sigma_a.data[i] = sigma_t - sigma_s.data[i];
+        }
+        return Medium::homogeneous(sigma_a, sigma_s, self.subsurface_anisotropy);
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        return self.cauchy_b != 0.0 || self.sellmeier_b.data != [0.0; 3];
+    }
+
+    /// Index of refraction at a wavelength in nanometers. `cauchy_b` is relative to `ior` at the
+    /// sodium D line, so the IOR stays the same in RGB mode.
+    pub fn ior_at(&self, wavelength: f32) -> f32 {
+        let lambda = wavelength / 1000.0;
+        let lambda_sqr = lambda * lambda;
+        if self.sellmeier_b.data != [0.0; 3] {
+            let mut n_sqr = 1.0;
+            for i in 0..3 {
+                n_sqr +=
+                    self.sellmeier_b.data[i] * lambda_sqr / (lambda_sqr - self.sellmeier_c.data[i]);
+            }
+            return f32::sqrt(f32::max(n_sqr, 1.0));
+        }
+        return self.ior + self.cauchy_b * (1.0 / lambda_sqr - 1.0 / (0.5893 * 0.5893));
+    }
+}
+
+/// Shape of the lens opening, out of focus highlights take this shape
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    /// Regular polygon formed by the aperture blades, `rotation` is in degrees
+    Polygon { blades: u32, rotation: f32 },
+    /// Shape read from a texture in the scene, its red channel is above one half where the lens
+    /// is open. The texture covers the square around the aperture circle.
+    Texture(u32),
+}
+
+/// How the camera maps directions onto the image
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    /// Parallel rays along the view direction, `width` is the width of the view in scene units
+    Orthographic { width: f32 },
+    /// Full 360 by 180 degree panorama, the image should have an aspect ratio of 2:1
+    Equirectangular,
+    /// Equidistant fisheye, the image circle spans the height of the image and covers `fov`
+    /// degrees
+    Fisheye { fov: f32 },
+}
+
+/// Where the views of both eyes go in a stereo image, the left eye is on the left or on top
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum StereoLayout {
+    #[default]
+    SideBySide,
+    OverUnder,
+}
+
+/// Renders one view per eye into the same image. With the equirectangular projection the eyes
+/// turn with the viewing direction, which gives an omnidirectional stereo panorama.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Stereo {
+    pub layout: StereoLayout,
+    /// Distance between the eyes in scene units
+    pub interpupillary_distance: f32,
+    /// Distance at which the views of both eyes cross, zero keeps them parallel. Ignored by the
+    /// orthographic projection.
+    pub convergence_distance: f32,
+}
+
+impl Default for Stereo {
+    fn default() -> Self {
+        return Self {
+            layout: StereoLayout::SideBySide,
+            interpupillary_distance: 0.064,
+            convergence_distance: 0.0,
+        };
+    }
+}
+
+/// Thin lens camera, scene units are taken to be meters for the lens. Depth of field is only
+/// supported by the perspective projection.
+#[derive(Clone)]
 pub struct Camera {
     pub pitch: f32,
     pub yaw: f32,
@@ -175,21 +578,117 @@ pub struct Camera {
     pub up: Vec3f,
     pub right: Vec3f,
     pub look_at: Mat4f,
+    /// Focal length of the lens in millimeters
+    pub focal_length: f32,
+    /// Height of the sensor in millimeters, its width follows from the aspect ratio of the image
+    pub sensor_height: f32,
+    /// Aperture as an f-number, zero makes the camera a pinhole with everything in focus
+    pub f_stop: f32,
+    /// Distance to the plane in focus along the view direction
+    pub focus_distance: f32,
+    pub aperture: Aperture,
+    pub projection: Projection,
+    pub stereo: Option<Stereo>,
+    /// Time the shutter opens, from zero at the start of the frame to one at its end
+    pub shutter_open: f32,
+    /// Time the shutter closes, equal to `shutter_open` for an instantaneous exposure
+    pub shutter_close: f32,
+    /// Where the camera is at the end of the frame, it moves there in a straight line from its
+    /// position at the start. None keeps it still.
+    pub end_keyframe: Option<CameraKeyframe>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        return Self {
+            pitch: 0.0,
+            yaw: 0.0,
+            position: Vec3f::default(),
+            forward: Vec3f::default(),
+            up: Vec3f::default(),
+            right: Vec3f::default(),
+            look_at: Mat4f::default(),
+            // A vertical field of view of 90 degrees
+            focal_length: 12.0,
+            sensor_height: 24.0,
+            f_stop: 0.0,
+            focus_distance: 5.0,
+            aperture: Aperture::Circle,
+            projection: Projection::Perspective,
+            stereo: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            end_keyframe: None,
+        };
+    }
 }
 
 impl Camera {
+    /// Sets the focal length that gives a vertical field of view of `fov` degrees on the sensor
+    pub fn set_fov(&mut self, fov: f32) {
+        self.focal_length = self.sensor_height / (2.0 * f32::tan(f32::to_radians(fov) * 0.5));
+    }
+
+    /// Vertical field of view in degrees
+    pub fn fov(&self) -> f32 {
+        return f32::to_degrees(2.0 * f32::atan(self.tan_half_fov()));
+    }
+
+    /// Half the height of the image plane at distance one
+    pub fn tan_half_fov(&self) -> f32 {
+        return self.sensor_height / (2.0 * self.focal_length);
+    }
+
+    /// Radius of the aperture in scene units
+    pub fn lens_radius(&self) -> f32 {
+        if self.f_stop <= 0.0 {
+            return 0.0;
+        }
+        return self.focal_length * 0.001 / (2.0 * self.f_stop);
+    }
+
     pub fn update_view(&mut self) {
-        let direction = Vec3f::new(
-            f32::cos(f32::to_radians(self.yaw)) * f32::cos(f32::to_radians(self.pitch)),
-            f32::sin(f32::to_radians(self.pitch)),
-            f32::sin(f32::to_radians(self.yaw)) * f32::cos(f32::to_radians(self.pitch)),
-        );
         let world_up = Vec3f::new(0.0, 1.0, 0.0);
 
-        self.forward = direction.normalized();
+        self.forward = view_direction(self.pitch, self.yaw);
         self.right = Vec3f::cross(world_up, self.forward).normalized();
         self.up = Vec3f::cross(self.forward, self.right);
 
         self.look_at = Mat4f::look_at(self.position, self.position + self.forward, self.up);
     }
+
+    /// Position and view matrix at the end of the frame
+    pub fn end_view(&self) -> (Vec3f, Mat4f) {
+        match self.end_keyframe {
+            Some(keyframe) => return (keyframe.position, keyframe.look_at()),
+            None => return (self.position, self.look_at),
+        }
+    }
+}
+
+/// Position and orientation of the camera at one point in time, angles are in degrees
+#[derive(Clone, Copy, Default)]
+pub struct CameraKeyframe {
+    pub position: Vec3f,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl CameraKeyframe {
+    pub fn look_at(&self) -> Mat4f {
+        let world_up = Vec3f::new(0.0, 1.0, 0.0);
+        let forward = view_direction(self.pitch, self.yaw);
+        let right = Vec3f::cross(world_up, forward).normalized();
+        let up = Vec3f::cross(forward, right);
+        return Mat4f::look_at(self.position, self.position + forward, up);
+    }
+}
+
+fn view_direction(pitch: f32, yaw: f32) -> Vec3f {
+    let direction = Vec3f::new(
+        f32::cos(f32::to_radians(yaw)) * f32::cos(f32::to_radians(pitch)),
+        f32::sin(f32::to_radians(pitch)),
+        f32::sin(f32::to_radians(yaw)) * f32::cos(f32::to_radians(pitch)),
+    );
+    return direction.normalized();
 }
@@ -0,0 +1,289 @@
+use crate::math::vec::*;
+use crate::math::vec2::*;
+use crate::math::vec3::*;
+
+/// Procedural environment used when a ray escapes the scene.
+#[derive(Clone, Copy)]
+pub struct Sky {
+    pub model: SkyModel,
+    /// Color of the sky when using `SkyModel::Constant`
+    pub color: Vec3f,
+    /// Multiplier for the sky radiance, the Preetham model outputs luminance in kcd/m^2
+    pub strength: f32,
+    /// Sun elevation above the horizon in degrees
+    pub sun_elevation: f32,
+    /// Sun azimuth in degrees, measured from +X towards +Z
+    pub sun_azimuth: f32,
+    /// Sun angular radius in degrees
+    pub sun_angular_radius: f32,
+    pub sun_strength: f32,
+    /// Atmospheric turbidity, 2.0 is a very clear sky and 10.0 is hazy
+    pub turbidity: f32,
+    /// Reflectance of the ground plane below the horizon
+    pub ground_albedo: Vec3f,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub enum SkyModel {
+    #[default]
+    Constant,
+    Preetham,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        return Self {
+            model: SkyModel::Constant,
+            color: Vec3f::from(1.0),
+            strength: 1.0,
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            sun_angular_radius: 0.5,
+            sun_strength: 1.0,
+            turbidity: 3.0,
+            ground_albedo: Vec3f::from(0.3),
+        };
+    }
+}
+
+/// Brings the Preetham luminance (kcd/m^2) down to roughly the range of scene emitters
+const PREETHAM_STRENGTH: f32 = 0.05;
+
+impl Sky {
+    pub fn physical(sun_elevation: f32, sun_azimuth: f32, turbidity: f32) -> Self {
+        return Self {
+            model: SkyModel::Preetham,
+            strength: PREETHAM_STRENGTH,
+            sun_elevation,
+            sun_azimuth,
+            turbidity: turbidity.clamp(1.7, 10.0),
+            ..Default::default()
+        };
+    }
+
+    pub fn sun_direction(&self) -> Vec3f {
+        let elevation = f32::to_radians(self.sun_elevation);
+        let azimuth = f32::to_radians(self.sun_azimuth);
+        return Vec3f::new(
+            f32::cos(elevation) * f32::cos(azimuth),
+            f32::sin(elevation),
+            f32::cos(elevation) * f32::sin(azimuth),
+        );
+    }
+
+    /// Returns the radiance arriving from `direction` (pointing away from the scene).
+    ///
+    /// The sun disk can be left out with `include_sun` when it has already been sampled
+    /// explicitly with next event estimation.
+    pub fn radiance(&self, direction: Vec3f, include_sun: bool) -> Vec3f {
+        match self.model {
+            SkyModel::Constant => return self.color * self.strength,
+            SkyModel::Preetham => {
+                if include_sun && self.in_sun(direction) {
+                    return self.sun_radiance();
+                }
+
+                // Below the horizon we see the ground lit by the sky at the zenith
+                if direction.y() <= 0.0 {
+                    return self.ground_albedo
+                        * self.preetham(Vec3f::new(0.0, 1.0, 0.0))
+                        * self.strength;
+                }
+
+                return self.preetham(direction) * self.strength;
+            }
+        }
+    }
+
+    /// Whether `direction` points into the visible sun disk
+    pub fn in_sun(&self, direction: Vec3f) -> bool {
+        return self.model == SkyModel::Preetham
+            && Vec3f::dot(direction, self.sun_direction()) >= self.sun_cos_angular_radius()
+            && direction.y() > 0.0;
+    }
+
+    pub fn has_sun(&self) -> bool {
+        return self.model == SkyModel::Preetham && self.sun_elevation > 0.0;
+    }
+
+    pub fn sun_cos_angular_radius(&self) -> f32 {
+        return f32::cos(f32::to_radians(self.sun_angular_radius));
+    }
+
+    pub fn sun_solid_angle(&self) -> f32 {
+        return 2.0 * std::f32::consts::PI * (1.0 - self.sun_cos_angular_radius());
+    }
+
+    /// Uniformly samples a direction inside the cone subtended by the sun disk
+    pub fn sample_sun_direction(&self, u: Vec2f) -> Vec3f {
+        let sun_direction = self.sun_direction();
+        let cos_theta = 1.0 - u.x() * (1.0 - self.sun_cos_angular_radius());
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * std::f32::consts::PI * u.y();
+
+        let up = if f32::abs(sun_direction.y()) < 0.999 {
+            Vec3f::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3f::new(1.0, 0.0, 0.0)
+        };
+        let tangent = Vec3f::cross(up, sun_direction).normalized();
+        let bitangent = Vec3f::cross(sun_direction, tangent);
+
+        return (tangent * (f32::cos(phi) * sin_theta)
+            + bitangent * (f32::sin(phi) * sin_theta)
+            + sun_direction * cos_theta)
+            .normalized();
+    }
+
+    /// Radiance of the sun disk after atmospheric extinction. The disk is scaled so that the
+    /// illuminance it delivers stays the same regardless of the chosen angular radius.
+    pub fn sun_radiance(&self) -> Vec3f {
+        // Roughly 100 klux of direct sunlight, in the same kcd/m^2 units as the sky
+        const SUN_ILLUMINANCE: f32 = 100.0;
+        return self.sun_transmittance() * self.sun_strength * self.strength * SUN_ILLUMINANCE
+            / self.sun_solid_angle();
+    }
+
+    // https://courses.cs.duke.edu/fall01/cps124/resources/p91-preetham.pdf
+    fn preetham(&self, direction: Vec3f) -> Vec3f {
+        let perez = self.perez_coefficients();
+        let zenith = self.normalized_zenith_yxy();
+
+        let cos_theta = f32::max(direction.y(), 0.001);
+        let gamma = f32::acos(Vec3f::dot(direction, self.sun_direction()).clamp(-1.0, 1.0));
+
+        let mut yxy = [0.0f32; 3];
+        for (i, value) in yxy.iter_mut().enumerate() {
+            *value = zenith.data[i] * Self::perez(Self::perez_channel(&perez, i), cos_theta, gamma);
+        }
+
+        return Self::yxy_to_linear_srgb(Vec3f::from(yxy));
+    }
+
+    /// Zenith values divided by the Perez function at the zenith, so that the sky only has to
+    /// evaluate the Perez function once per channel
+    pub fn normalized_zenith_yxy(&self) -> Vec3f {
+        let perez = self.perez_coefficients();
+        let zenith = self.zenith_yxy();
+        let theta_s = f32::acos(self.sun_direction().y().clamp(0.0, 1.0));
+
+        let mut normalized = [0.0f32; 3];
+        for (i, value) in normalized.iter_mut().enumerate() {
+            *value = zenith.data[i] / Self::perez(Self::perez_channel(&perez, i), 1.0, theta_s);
+        }
+
+        return Vec3f::from(normalized);
+    }
+
+    fn perez_channel(perez: &[Vec3f; 5], channel: usize) -> [f32; 5] {
+        return [
+            perez[0].data[channel],
+            perez[1].data[channel],
+            perez[2].data[channel],
+            perez[3].data[channel],
+            perez[4].data[channel],
+        ];
+    }
+
+    fn perez(coeffs: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+        let [a, b, c, d, e] = coeffs;
+        let cos_gamma = f32::cos(gamma);
+        return (1.0 + a * f32::exp(b / cos_theta))
+            * (1.0 + c * f32::exp(d * gamma) + e * cos_gamma * cos_gamma);
+    }
+
+    /// Perez distribution coefficients A-E, each stored as (Y, x, y)
+    pub fn perez_coefficients(&self) -> [Vec3f; 5] {
+        let t = self.turbidity;
+        return [
+            Vec3f::new(
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ),
+            Vec3f::new(
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ),
+            Vec3f::new(
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ),
+            Vec3f::new(
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ),
+            Vec3f::new(
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ),
+        ];
+    }
+
+    /// Zenith luminance and chromaticity as (Y, x, y)
+    pub fn zenith_yxy(&self) -> Vec3f {
+        let t = self.turbidity;
+        let theta_s = f32::acos(self.sun_direction().y().clamp(0.0, 1.0));
+        let theta_s_2 = theta_s * theta_s;
+        let theta_s_3 = theta_s_2 * theta_s;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * f32::tan(chi) - 0.2155 * t + 2.4192;
+
+        let zenith_x = t * t * (0.00166 * theta_s_3 - 0.00375 * theta_s_2 + 0.00209 * theta_s)
+            + t * (-0.02903 * theta_s_3 + 0.06377 * theta_s_2 - 0.03202 * theta_s + 0.00394)
+            + (0.11693 * theta_s_3 - 0.21196 * theta_s_2 + 0.06052 * theta_s + 0.25886);
+        let zenith_y = t * t * (0.00275 * theta_s_3 - 0.00610 * theta_s_2 + 0.00317 * theta_s)
+            + t * (-0.04214 * theta_s_3 + 0.08970 * theta_s_2 - 0.04153 * theta_s + 0.00516)
+            + (0.15346 * theta_s_3 - 0.26756 * theta_s_2 + 0.06670 * theta_s + 0.26688);
+
+        return Vec3f::new(f32::max(zenith_luminance, 0.0), zenith_x, zenith_y);
+    }
+
+    /// Spectral extinction of sunlight through the atmosphere, evaluated at R, G and B
+    pub fn sun_transmittance(&self) -> Vec3f {
+        let sun_direction = self.sun_direction();
+        if sun_direction.y() <= 0.0 {
+            return Vec3f::from(0.0);
+        }
+
+        // Kasten-Young relative optical air mass
+        let zenith_deg = 90.0 - self.sun_elevation;
+        let air_mass =
+            1.0 / (sun_direction.y() + 0.50572 * f32::powf(96.07995 - zenith_deg, -1.6364));
+
+        // Rayleigh and Angstrom aerosol optical depths, wavelengths in micrometers
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let wavelengths = [0.680f32, 0.550, 0.440];
+        let mut transmittance = [0.0f32; 3];
+        for i in 0..3 {
+            let tau_rayleigh = 0.008735 * f32::powf(wavelengths[i], -4.08);
+            let tau_aerosol = beta * f32::powf(wavelengths[i], -1.3);
+            transmittance[i] = f32::exp(-(tau_rayleigh + tau_aerosol) * air_mass);
+        }
+
+        return Vec3f::from(transmittance);
+    }
+
+    fn yxy_to_linear_srgb(yxy: Vec3f) -> Vec3f {
+        let luminance = yxy.x();
+        let x = yxy.y();
+        let y = f32::max(yxy.z(), 0.0001);
+
+        let cie_x = x * (luminance / y);
+        let cie_z = (1.0 - x - y) * (luminance / y);
+
+        return Vec3f::max(
+            Vec3f::new(
+                3.2404542 * cie_x - 1.5371385 * luminance - 0.4985314 * cie_z,
+                -0.969266 * cie_x + 1.8760108 * luminance + 0.0415560 * cie_z,
+                0.0556434 * cie_x - 0.2040259 * luminance + 1.0572252 * cie_z,
+            ),
+            Vec3f::from(0.0),
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::math::vec3::*;
+
+/// Range of visible wavelengths in nanometers that spectral rendering samples from
+pub const WAVELENGTH_MIN: f32 = 380.0;
+pub const WAVELENGTH_MAX: f32 = 730.0;
+/// Wavelengths standing in for the sRGB primaries when wave optics are evaluated in RGB mode
+pub const RGB_WAVELENGTHS: [f32; 3] = [630.0, 532.0, 465.0];
+
+/// Integral of the CIE y color matching function over the sampled range
+const CIE_Y_INTEGRAL: f32 = 106.91687;
+/// Linear sRGB color of a constant spectrum, used to keep white surfaces white
+const EQUAL_ENERGY_WHITE: [f32; 3] = [1.2005764, 0.9496515, 0.9078579];
+/// Corrects the weights of the uplifting basis so that colors survive the round trip back to
+/// RGB, see `Wavelengths::uplift`
+const UPLIFT_CORRECTION: [[f32; 3]; 3] = [
+    [1.0819644, -0.1004772, 0.0185127],
+    [-0.0466869, 1.0516278, -0.0049409],
+    [0.0333491, -0.0263298, 0.9929806],
+];
+
+/// Wavelengths carried by a path in spectral mode. Values that are colors in RGB mode hold one
+/// value per wavelength instead, the hero wavelength is in `x`.
+///
+/// https://cg.cs.uni-bonn.de/backend/v1/files/publications/wilkie-2014-hero.pdf
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: Vec3f,
+}
+
+impl Wavelengths {
+    /// Picks the hero wavelength for the sample value `u`, the other two are rotated by a third
+    /// of the range
+    pub fn sample(u: f32) -> Self {
+        let mut lambda = Vec3f::from(0.0);
+        for i in 0..3 {
+            let offset = f32::fract(u + i as f32 / 3.0);
+            lambda.data[i] = WAVELENGTH_MIN + offset * (WAVELENGTH_MAX - WAVELENGTH_MIN);
+        }
+        return Self { lambda };
+    }
+
+    pub fn hero(&self) -> f32 {
+        return self.lambda.x();
+    }
+
+    /// Turns a linear RGB color into spectral values at the sampled wavelengths.
+    ///
+    /// The spectrum is a blend of smooth red, green and blue bands that add up to one everywhere,
+    /// so white stays a constant spectrum and albedos stay energy conserving.
+    pub fn uplift(&self, rgb: Vec3f) -> Vec3f {
+        let mut weights = [0.0; 3];
+        for i in 0..3 {
+            weights[i] = f32::max(
+                UPLIFT_CORRECTION[i][0] * rgb.x()
+                    + UPLIFT_CORRECTION[i][1] * rgb.y()
+                    + UPLIFT_CORRECTION[i][2] * rgb.z(),
+                0.0,
+            );
+        }
+
+        let mut values = Vec3f::from(0.0);
+        for i in 0..3 {
+            let lambda = self.lambda.data[i];
+            let blue = 1.0 - Self::sigmoid((lambda - 490.0) / 12.0);
+            let red = Self::sigmoid((lambda - 590.0) / 12.0);
+            let green = 1.0 - blue - red;
+            values.data[i] = weights[0] * red + weights[1] * green + weights[2] * blue;
+        }
+        return values;
+    }
+
+    /// Converts the spectral values of a path into linear sRGB
+    pub fn to_rgb(self, values: Vec3f) -> Vec3f {
+        let mut xyz = Vec3f::from(0.0);
+        for i in 0..3 {
+            xyz += Self::cie_xyz(self.lambda.data[i]) * values.data[i];
+        }
+        // Monte Carlo estimate with a uniform pdf over the range
+        xyz *= (WAVELENGTH_MAX - WAVELENGTH_MIN) / (3.0 * CIE_Y_INTEGRAL);
+
+        let rgb = Vec3f::new(
+            3.2404542 * xyz.x() - 1.5371385 * xyz.y() - 0.4985314 * xyz.z(),
+            -0.969266 * xyz.x() + 1.8760108 * xyz.y() + 0.0415560 * xyz.z(),
+            0.0556434 * xyz.x() - 0.2040259 * xyz.y() + 1.0572252 * xyz.z(),
+        );
+        return rgb / Vec3f::from(EQUAL_ENERGY_WHITE);
+    }
+
+    /// Analytic multi-lobe fit of the CIE 1931 color matching functions
+    ///
+    /// https://jcgt.org/published/0002/02/01/paper.pdf
+    fn cie_xyz(lambda: f32) -> Vec3f {
+        let lobe = |mu: f32, sigma_1: f32, sigma_2: f32| -> f32 {
+            let sigma = if lambda < mu { sigma_1 } else { sigma_2 };
+            let t = (lambda - mu) / sigma;
+            return f32::exp(-0.5 * t * t);
+        };
+        return Vec3f::new(
+            1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+                - 0.065 * lobe(501.1, 20.4, 26.2),
+            0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+            1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+        );
+    }
+
+    fn sigmoid(x: f32) -> f32 {
+        return 1.0 / (1.0 + f32::exp(-x));
+    }
+}
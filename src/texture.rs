@@ -1,4 +1,7 @@
-use crate::{log_error, math::vec2::*};
+use crate::{
+    log_error,
+    math::{vec2::*, vec3::*},
+};
 
 #[derive(Clone, Default)]
 pub struct Texture {
@@ -15,19 +18,33 @@ impl Texture {
             log_error!("Could not find texture at path: '{}'", path);
             return None;
         }
-        let img = image::open(path).unwrap().flipv().to_rgba8();
+        let img = image::open(path).unwrap();
+        return Some(Self::from_image(img, texture_type));
+    }
+
+    /// Decodes an image that is already in memory, like the images embedded in glTF files
+    pub fn load_from_memory(bytes: &[u8], texture_type: TextureType) -> Option<Self> {
+        let Ok(img) = image::load_from_memory(bytes) else {
+            log_error!("Could not decode texture from memory");
+            return None;
+        };
+        return Some(Self::from_image(img, texture_type));
+    }
+
+    fn from_image(img: image::DynamicImage, texture_type: TextureType) -> Self {
+        let img = img.flipv().to_rgba8();
         let pixel_data: Vec<[u8; 4]> = img
             .pixels()
             .map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2], pixel.0[3]])
             .collect();
         let hash = Self::calculate_djb2_hash(pixel_data.as_slice());
-        return Some(Self {
+        return Self {
             texture_type,
             hash,
             width: img.width() as usize,
             height: img.height() as usize,
             pixel_data,
-        });
+        };
     }
 
     pub fn color_at(&self, uv: Vec2f) -> [u8; 4] {
@@ -37,6 +54,15 @@ impl Texture {
         return self.pixel_data[index as usize];
     }
 
+    /// Average color of the whole texture, used for estimating the power of textured emitters
+    pub fn average_color(&self) -> Vec3f {
+        let mut sum = Vec3f::from(0.0);
+        for pixel in &self.pixel_data {
+            sum += Vec3f::from(*pixel);
+        }
+        return sum / f32::max(self.pixel_data.len() as f32, 1.0);
+    }
+
     fn calculate_djb2_hash(pixel_data: &[[u8; 4]]) -> u32 {
         let mut hash: u32 = 5381;
         for i in (0..pixel_data.len()).step_by(4) {
@@ -64,4 +90,7 @@ pub enum TextureType {
     Metallic,
     Emission,
     Normal,
+    ThinFilmThickness,
+    #[allow(dead_code)]
+    Aperture,
 }